@@ -0,0 +1,5 @@
+fn main() {
+    // Only needed for the gRPC API:
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/control.proto").expect("Compiling proto/control.proto failed.");
+}
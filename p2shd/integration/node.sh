@@ -0,0 +1,66 @@
+#!/bin/sh
+# Entrypoint of the integration test containers.
+#
+#   node.sh bootstrap                   DHT bootstrap node on the wan
+#   node.sh router <cone|symmetric> <lan>   NAT router for <lan>
+#   node.sh server <name> [gateway]     sshd + `p2shd listen`, routing via gateway
+#
+# Nodes exchange what the others need (bootstrap address, peer ids, the
+# client ssh key) via the shared volume mounted at /shared.
+set -eu
+
+P2SHD="p2shd --config-dir /data --no-mdns"
+
+wan_iface() {
+    ip -o -4 addr show | awk '/ 10\.77\.0\./ { print $2; exit }'
+}
+
+wait_for() {
+    while [ ! -s "$1" ]; do sleep 0.2; done
+}
+
+role=$1
+shift
+
+case "$role" in
+    bootstrap)
+        ssh-keygen -q -t ed25519 -N '' -f /shared/client_key
+        id=$($P2SHD | awk '{ print $NF }')
+        echo "/ip4/10.77.0.2/tcp/4001/p2p/$id" > /shared/bootstrap.tmp
+        mv /shared/bootstrap.tmp /shared/bootstrap
+        # Serves no ssh daemon, it only takes part in the DHT:
+        exec $P2SHD --no-bootstrap --port 4001 listen --sshd 127.0.0.1:1
+        ;;
+    router)
+        mode=$1
+        lan=$2
+        case "$mode" in
+            cone) random="" ;;
+            symmetric) random="--random" ;;
+            *) echo "Unknown NAT type: $mode" >&2; exit 1 ;;
+        esac
+        iptables -t nat -A POSTROUTING -s "$lan" -o "$(wan_iface)" -j MASQUERADE $random
+        # Nothing on the wan may reach into the lan unsolicited:
+        iptables -A FORWARD -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT
+        iptables -A FORWARD -s "$lan" -j ACCEPT
+        iptables -P FORWARD DROP
+        exec sleep infinity
+        ;;
+    server)
+        name=$1
+        if [ $# -ge 2 ]; then
+            ip route replace default via "$2"
+        fi
+        wait_for /shared/bootstrap
+        mkdir -p /root/.ssh
+        cp /shared/client_key.pub /root/.ssh/authorized_keys
+        /usr/sbin/sshd
+        $P2SHD | awk '{ print $NF }' > "/shared/id-$name.tmp"
+        mv "/shared/id-$name.tmp" "/shared/id-$name"
+        exec $P2SHD --bootstrap "$(cat /shared/bootstrap)" listen
+        ;;
+    *)
+        echo "Unknown role: $role" >&2
+        exit 1
+        ;;
+esac
@@ -0,0 +1,103 @@
+#!/bin/sh
+# End to end tests against the NAT topology in docker-compose.yml.
+#
+# Usage: run.sh [scenario ...]    (all scenarios by default)
+#
+# Each scenario connects from one node to the p2shd of another one and runs
+# a command over ssh. Scenarios that need NAT traversal p2shd does not
+# implement yet (hole punching, relays) are expected to fail: They show up as
+# XFAIL, or as XPASS once they start working - then flip their expectation.
+set -eu
+
+cd "$(dirname "$0")"
+
+COMPOSE="docker-compose -p p2shd-integration"
+TIMEOUT=${P2SHD_TIMEOUT:-90}
+
+# name, client node, server node, path, expectation
+SCENARIOS="
+public-from-cone          behind_cone       public            tunnel   pass
+public-from-symmetric     behind_symmetric  public            tunnel   pass
+public-proxycommand       behind_cone       public            stdio    pass
+cone-from-public          public            behind_cone       tunnel   fail
+cone-from-symmetric       behind_symmetric  behind_cone       tunnel   fail
+symmetric-from-cone       behind_cone       behind_symmetric  tunnel   fail
+"
+
+SSH_OPTS="-i /shared/client_key -o BatchMode=yes -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null"
+
+cleanup() {
+    if [ -n "${P2SHD_KEEP:-}" ]; then
+        echo "Leaving topology running (P2SHD_KEEP is set)."
+    else
+        $COMPOSE down -v --remove-orphans > /dev/null 2>&1 || true
+    fi
+}
+trap cleanup EXIT
+
+node_exec() {
+    node=$1
+    shift
+    $COMPOSE exec -T "$node" "$@" < /dev/null
+}
+
+wait_for_id() {
+    i=0
+    until node_exec "$1" test -s "/shared/id-$2" 2> /dev/null; do
+        i=$((i + 1))
+        if [ $i -gt 300 ]; then
+            echo "Node $2 did not come up." >&2
+            exit 1
+        fi
+        sleep 0.2
+    done
+    node_exec "$1" cat "/shared/id-$2"
+}
+
+# Connect from `client` to `server` via `path`, succeeds if the remote command ran.
+connect() {
+    client=$1
+    server=$2
+    path=$3
+    id=$(wait_for_id "$client" "$server")
+    p2shd="p2shd --config-dir /tmp/client-$server --no-mdns --bootstrap \$(cat /shared/bootstrap)"
+    case "$path" in
+        tunnel)
+            cmd="$p2shd -u root"
+            for o in $SSH_OPTS; do
+                cmd="$cmd --ssh-arg=$o"
+            done
+            cmd="$cmd $id -- echo p2shd-ok"
+            ;;
+        stdio)
+            cmd="ssh $SSH_OPTS -o ProxyCommand=\"$p2shd --stdio %h\" root@$id echo p2shd-ok"
+            ;;
+    esac
+    node_exec "$client" timeout "$TIMEOUT" sh -c "$cmd" 2> "logs/$name.log" | grep -q p2shd-ok
+}
+
+mkdir -p logs
+$COMPOSE down -v --remove-orphans > /dev/null 2>&1 || true
+$COMPOSE up -d --build
+
+echo "$SCENARIOS" | {
+    failed=0
+    while read -r name client server path expected; do
+        [ -n "$name" ] || continue
+        if [ $# -gt 0 ] && ! echo " $* " | grep -q " $name "; then
+            continue
+        fi
+        if connect "$client" "$server" "$path"; then
+            result=pass
+        else
+            result=fail
+        fi
+        case "$result-$expected" in
+            pass-pass) echo "PASS   $name" ;;
+            fail-fail) echo "XFAIL  $name" ;;
+            pass-fail) echo "XPASS  $name (expected to fail, update SCENARIOS)" ;;
+            fail-pass) echo "FAIL   $name (see integration/logs/$name.log)"; failed=1 ;;
+        esac
+    done
+    exit $failed
+}
@@ -0,0 +1,295 @@
+//! Persistent cache of peer addresses discovered in previous runs.
+//!
+//! Discovery always starts from scratch otherwise, by remembering where we
+//! have seen peers recently we can pre-populate Kademlia and connect to a
+//! known peer before any DHT query has finished.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::path_exists,
+    control::Nat,
+    format_version::{self, FormatVersion},
+    predictor::{DialStat, Predictor},
+    sealed_state,
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+///
+/// 1.1: `last_good_seen`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 1);
+
+/// Addresses (last good ones too) not seen for longer than this are dropped on load and
+/// save.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Maximum number of addresses kept per peer, the most recently seen win.
+const MAX_ADDRS_PER_PEER: usize = 8;
+
+/// Maximum number of peers kept, the ones not seen for the longest get evicted. Applies to
+/// peers with a last good address separately.
+///
+/// A long running `listen` node discovers lots of peers, via the DHT especially.
+const MAX_PEERS: usize = 1024;
+
+/// Peer id -> address mappings, with the time each address was last seen.
+pub struct AddrCache {
+    /// Where to store the cache.
+    path: PathBuf,
+    peers: HashMap<PeerId, Vec<CachedAddr>>,
+    /// The address we last successfully connected to, per peer, and when.
+    last_good: HashMap<PeerId, CachedAddr>,
+    /// Learns from dial outcomes, which addresses to try first.
+    predictor: Predictor,
+    /// Whether there are changes not yet written to disk.
+    dirty: bool,
+}
+
+/// An address together with the time we last saw it.
+#[derive(Clone, Debug)]
+pub struct CachedAddr {
+    pub addr: Multiaddr,
+    pub last_seen: SystemTime,
+}
+
+/// On disk representation of `AddrCache`.
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    peers: Vec<PeerEntry>,
+    #[serde(default)]
+    dial_stats: Vec<DialStat>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerEntry {
+    peer_id: String,
+    addrs: Vec<AddrEntry>,
+    #[serde(default)]
+    last_good: Option<String>,
+    /// When we connected to `last_good`, seconds since the UNIX epoch. Files from before
+    /// 1.1 don't have it, their last good addresses count as seen on load.
+    #[serde(default)]
+    last_good_seen: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AddrEntry {
+    addr: String,
+    /// Seconds since the UNIX epoch.
+    last_seen: u64,
+}
+
+impl AddrCache {
+    /// Load the cache stored at `path`.
+    ///
+    /// A missing file results in an empty cache. Entries that fail to parse or
+    /// are older than `MAX_AGE` are skipped.
+    pub fn load(path: PathBuf) -> Result<AddrCache> {
+        let exists = path_exists(&path).with_context(|| error::AddrCache::Read(path.clone()))?;
+        let file = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::AddrCache::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw).with_context(|| error::AddrCache::Decode(path.clone()))?
+        } else {
+            CacheFile::default()
+        };
+
+        let now = SystemTime::now();
+        let mut peers = HashMap::new();
+        let mut last_good = HashMap::new();
+        for entry in file.peers {
+            let peer_id: PeerId = match entry.peer_id.parse() {
+                Ok(p) => p,
+                Err(_) => {
+                    log::debug!("Ignoring invalid peer id in address cache: {}", entry.peer_id);
+                    continue;
+                }
+            };
+            let fresh = |seen: SystemTime| now.duration_since(seen).unwrap_or_default() <= MAX_AGE;
+            if let Some(addr) = entry.last_good.and_then(|a| a.parse().ok()) {
+                let last_seen = entry
+                    .last_good_seen
+                    .map(|s| UNIX_EPOCH + Duration::from_secs(s))
+                    .unwrap_or(now);
+                if fresh(last_seen) {
+                    last_good.insert(peer_id.clone(), CachedAddr { addr, last_seen });
+                }
+            }
+            let addrs: Vec<CachedAddr> = entry
+                .addrs
+                .into_iter()
+                .filter_map(|a| {
+                    let addr = a.addr.parse().ok()?;
+                    let last_seen = UNIX_EPOCH + Duration::from_secs(a.last_seen);
+                    if fresh(last_seen) {
+                        Some(CachedAddr { addr, last_seen })
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            if !addrs.is_empty() {
+                peers.insert(peer_id, addrs);
+            }
+        }
+        Ok(AddrCache {
+            path,
+            peers,
+            last_good,
+            predictor: Predictor::from_stats(file.dial_stats),
+            dirty: false,
+        })
+    }
+
+    /// Record that `addr` of `peer_id` has been seen just now.
+    pub fn insert(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let now = SystemTime::now();
+        if !self.peers.contains_key(&peer_id) && self.peers.len() >= MAX_PEERS {
+            self.evict_oldest();
+        }
+        let addrs = self.peers.entry(peer_id).or_insert_with(Vec::new);
+        match addrs.iter_mut().find(|a| a.addr == addr) {
+            Some(a) => a.last_seen = now,
+            None => addrs.push(CachedAddr { addr, last_seen: now }),
+        }
+        // Most recent first:
+        addrs.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        addrs.truncate(MAX_ADDRS_PER_PEER);
+        self.dirty = true;
+    }
+
+    /// Drop the peer not seen for the longest time.
+    fn evict_oldest(&mut self) {
+        // Addresses are sorted most recent first:
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, addrs)| addrs.first().map(|a| a.last_seen))
+            .map(|(p, _)| p.clone());
+        if let Some(peer) = oldest {
+            self.peers.remove(&peer);
+        }
+    }
+
+    /// Drop addresses and last good ones not seen for longer than `MAX_AGE`, and peers left
+    /// without any.
+    fn prune(&mut self) {
+        let now = SystemTime::now();
+        let fresh = |a: &CachedAddr| now.duration_since(a.last_seen).unwrap_or_default() <= MAX_AGE;
+        let mut pruned = false;
+        self.peers.retain(|_, addrs| {
+            let len = addrs.len();
+            addrs.retain(fresh);
+            pruned |= addrs.len() != len;
+            !addrs.is_empty()
+        });
+        let len = self.last_good.len();
+        self.last_good.retain(|_, a| fresh(a));
+        pruned |= self.last_good.len() != len;
+        self.dirty |= pruned;
+    }
+
+    /// Cached addresses of the given peer, most recently seen first.
+    pub fn get(&self, peer_id: &PeerId) -> &[CachedAddr] {
+        self.peers.get(peer_id).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Remember `addr` as the address we last successfully connected to `peer_id` with.
+    pub fn set_last_good(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        if !self.last_good.contains_key(&peer_id) && self.last_good.len() >= MAX_PEERS {
+            let oldest = self
+                .last_good
+                .iter()
+                .min_by_key(|(_, a)| a.last_seen)
+                .map(|(p, _)| p.clone());
+            if let Some(peer) = oldest {
+                self.last_good.remove(&peer);
+            }
+        }
+        let last_seen = SystemTime::now();
+        self.last_good.insert(peer_id, CachedAddr { addr, last_seen });
+        self.dirty = true;
+    }
+
+    /// The address we last successfully connected to the given peer with.
+    pub fn last_good(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
+        self.last_good.get(peer_id).map(|a| &a.addr)
+    }
+
+    /// Record the outcome of dialing `addr` while in the given `nat` situation.
+    pub fn record_dial(&mut self, nat: Nat, addr: &Multiaddr, success: bool) {
+        self.predictor.record(nat, addr, success);
+        self.dirty = true;
+    }
+
+    /// Probability of a dial to `addr` succeeding, see `Predictor::predict`.
+    pub fn predict(&self, nat: Nat, addr: &Multiaddr) -> f64 {
+        self.predictor.predict(nat, addr)
+    }
+
+    /// Order `addrs` for dialing, see `Predictor::rank`.
+    pub fn rank(&self, nat: Nat, addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        self.predictor.rank(nat, addrs)
+    }
+
+    /// Iterate all cached peers and their addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &[CachedAddr])> {
+        self.peers.iter().map(|(p, a)| (p, a.as_slice()))
+    }
+
+    /// Write the cache to disk, if there were any changes since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        self.prune();
+        if !self.dirty {
+            return Ok(());
+        }
+        let no_addrs = Vec::new();
+        let only_last_good = self
+            .last_good
+            .keys()
+            .filter(|p| !self.peers.contains_key(p))
+            .map(|p| (p, &no_addrs));
+        let file = CacheFile {
+            format: FORMAT,
+            peers: self
+                .peers
+                .iter()
+                .chain(only_last_good)
+                .map(|(peer_id, addrs)| PeerEntry {
+                    peer_id: peer_id.to_base58(),
+                    last_good: self.last_good.get(peer_id).map(|a| a.addr.to_string()),
+                    last_good_seen: self.last_good.get(peer_id).map(|a| secs_since_epoch(a.last_seen)),
+                    addrs: addrs
+                        .iter()
+                        .map(|a| AddrEntry {
+                            addr: a.addr.to_string(),
+                            last_seen: secs_since_epoch(a.last_seen),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            dial_stats: self.predictor.stats(),
+        };
+        let encoded = serde_json::to_vec_pretty(&file).expect("Serializing address cache can't fail.");
+        sealed_state::write(&self.path, &encoded)
+            .with_context(|| error::AddrCache::Write(self.path.clone()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn secs_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
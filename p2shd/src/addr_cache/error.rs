@@ -0,0 +1,19 @@
+//! Errors that can happen while loading or storing the address cache.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to address cache persistence.
+#[derive(Error, Debug)]
+pub enum AddrCache {
+    #[error("Reading address cache '{0}' failed.")]
+    Read(PathBuf),
+    #[error(
+        "Invalid address cache '{0}'.
+
+You can safely delete the file, p2shd will rebuild it on the next run."
+    )]
+    Decode(PathBuf),
+    #[error("Writing address cache '{0}' failed.")]
+    Write(PathBuf),
+}
@@ -0,0 +1,50 @@
+//! Our reachable addresses, published as a signed DHT record.
+//!
+//! With `publish_addresses`, `p2shd listen` regularly publishes the addresses
+//! several peers observed it at under `/p2shd/addrs/<peer id>`, with an expiry. Clients looking for a
+//! peer fetch that record alongside the `get_closest_peers` query, a single
+//! record lookup usually finishes long before the query converges. As for
+//! published blocklists, the signature is checked against the key of the
+//! peer id, so nobody else can redirect clients.
+
+use anyhow::Result;
+use libp2p::{identity, kad::record::Key, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::signed_record::Kind;
+
+/// Address records, see `signed_record`.
+const KIND: Kind = Kind::new("/p2shd/addrs/", "Address record");
+
+/// The addresses, as signed.
+#[derive(Serialize, Deserialize)]
+struct Addrs {
+    addrs: Vec<String>,
+}
+
+/// DHT key the addresses of `peer` are published under.
+pub fn record_key(peer: &PeerId) -> Key {
+    KIND.record_key(peer)
+}
+
+/// The publisher of an address record, `None` if `key` is not an address record key.
+pub fn publisher_of(key: &Key) -> Option<PeerId> {
+    KIND.publisher_of(key)
+}
+
+/// `addrs`, valid for `ttl` and signed with `key`, for publishing them in the DHT.
+pub fn sign(key: &identity::Keypair, addrs: &[Multiaddr], ttl: Duration) -> Result<Vec<u8>> {
+    let addrs = Addrs {
+        addrs: addrs.iter().map(|a| a.to_string()).collect(),
+    };
+    KIND.sign(key, addrs, Some(ttl))
+}
+
+/// The addresses of an address record published by `publisher`, if the
+/// signature checks out and they did not expire yet.
+pub fn verify(publisher: &PeerId, raw: &[u8]) -> Result<Vec<Multiaddr>> {
+    let addrs: Addrs = KIND.verify(publisher, raw)?;
+    // Skip what we can't parse, newer versions might publish other kinds of addresses:
+    Ok(addrs.addrs.iter().filter_map(|a| a.parse().ok()).collect())
+}
@@ -0,0 +1,124 @@
+//! Exponential backoff with jitter, for retrying discovery.
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// Delay before the first retry.
+const INITIAL_DELAY: Duration = Duration::from_secs(2);
+
+/// Delays don't grow beyond this.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Delays vary randomly by up to this fraction, so peers don't query in lockstep.
+const JITTER: f64 = 0.2;
+
+/// When to give up retrying.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, unlimited if `None`.
+    pub max_attempts: Option<u32>,
+    /// Give up this long after the first attempt, never if `None`.
+    pub deadline: Option<Duration>,
+}
+
+/// Tracks attempts according to a `RetryPolicy`.
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+    started: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Backoff {
+        Backoff {
+            policy,
+            attempts: 0,
+            started: None,
+        }
+    }
+
+    /// Account for a new attempt, resolving to how long to wait for it before
+    /// the next one. `None` if the policy says to give up instead.
+    pub fn next(&mut self) -> Option<Duration> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        if let Some(max) = self.policy.max_attempts {
+            if self.attempts >= max {
+                return None;
+            }
+        }
+        let remaining = match self.policy.deadline {
+            Some(deadline) => Some(deadline.checked_sub(started.elapsed())?),
+            None => None,
+        };
+        self.attempts += 1;
+        let base = INITIAL_DELAY
+            .checked_mul(1 << (self.attempts - 1).min(16))
+            .map_or(MAX_DELAY, |d| d.min(MAX_DELAY));
+        let delay = base.mul_f64(rand::thread_rng().gen_range(1.0 - JITTER, 1.0 + JITTER));
+        Some(remaining.map_or(delay, |r| delay.min(r)))
+    }
+
+    /// Attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Time since the first attempt.
+    pub fn elapsed(&self) -> Duration {
+        self.started.map(|s| s.elapsed()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn delays_grow_up_to_max() {
+        let mut backoff = Backoff::new(RetryPolicy::default());
+        let expected = [2, 4, 8, 16, 32, 60, 60, 60];
+        for secs in &expected {
+            let base = Duration::from_secs(*secs);
+            let delay = backoff.next().unwrap();
+            assert!(delay >= base.mul_f64(1.0 - JITTER), "{:?} for {:?}", delay, base);
+            assert!(delay <= base.mul_f64(1.0 + JITTER), "{:?} for {:?}", delay, base);
+        }
+        assert_eq!(backoff.attempts(), expected.len() as u32);
+    }
+
+    #[test]
+    fn unlimited_never_gives_up() {
+        let mut backoff = Backoff::new(RetryPolicy::default());
+        for _ in 0..100 {
+            assert!(backoff.next().unwrap() <= MAX_DELAY.mul_f64(1.0 + JITTER));
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(RetryPolicy {
+            max_attempts: Some(3),
+            deadline: None,
+        });
+        for _ in 0..3 {
+            assert!(backoff.next().is_some());
+        }
+        assert_eq!(backoff.next(), None);
+        assert_eq!(backoff.attempts(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_deadline() {
+        let deadline = Duration::from_millis(50);
+        let mut backoff = Backoff::new(RetryPolicy {
+            max_attempts: None,
+            deadline: Some(deadline),
+        });
+        // Delays don't reach beyond the deadline:
+        assert!(backoff.next().unwrap() <= deadline);
+        thread::sleep(deadline * 2);
+        assert_eq!(backoff.next(), None);
+        assert!(backoff.elapsed() >= deadline * 2);
+    }
+}
@@ -1,47 +1,76 @@
 use {
-    async_std::{io, task},
-    futures::prelude::*,
+    async_std::task,
+    futures::{channel::{mpsc, oneshot}, future::{AbortHandle, BoxFuture}, prelude::*},
     libp2p::{
         identity,
         identify::{
             Identify,
             IdentifyEvent,
         },
-        build_development_transport,
-        kad::handler::KademliaHandler,
-        kad::record::store::MemoryStore,
-        kad::{record::Key, Kademlia, KademliaEvent, PutRecordOk,
-            Quorum, Record, GetClosestPeersResult,
+        kad::{record::Key, Kademlia, KademliaConfig, KademliaEvent,
+            Quorum, Record, GetClosestPeersResult, GetClosestPeersError,
             QueryId,
-            handler::KademliaHandlerIn,
         },
         mdns::{Mdns, MdnsEvent},
+        ping::{Ping, PingConfig, PingEvent, PingSuccess},
         swarm::{
+            toggle::Toggle,
             NetworkBehaviourEventProcess,
             NetworkBehaviourAction,
             NetworkBehaviour,
             PollParameters
         },
-        NetworkBehaviour, PeerId, Swarm,
+        NetworkBehaviour, PeerId,
         Multiaddr,
         multiaddr::Protocol,
-        core::connection::ConnectionId,
-        core::either::EitherOutput,
     },
     std::{
+        collections::{HashMap, HashSet, VecDeque},
         task::{Context, Poll, Waker},
         mem,
-        process::Command,
+        net::SocketAddr,
+        path::PathBuf,
         result,
-        convert::From,
+        sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex},
         time::SystemTime,
         time::Duration,
-        time::SystemTimeError,
-    },
-    structopt::StructOpt,
-    tokio::sync::{
-        watch
+        time::Instant,
     },
+    futures_timer::Delay,
+    ipnet::IpNet,
+    async_std::io as async_io,
+};
+
+use crate::{
+    addr_cache::AddrCache,
+    addr_record,
+    backoff::Backoff,
+    blocklist::{self, SharedBlocklist},
+    control::{self, Call, ControlRequest, Controller, Reply},
+    crash,
+    config::{identify_major, Bootstrap, BootstrapNode, Config, Service},
+    dns::Resolver,
+    events::{self, sanitize_addr},
+    forward::{self, Opener, PortForward, StreamRequest},
+    resources,
+    book_sync::{self, SYNC_INTERVAL},
+    log_sampling::{Sampler, SUMMARY_INTERVAL},
+    dial_report::{AddrSource, DialFailure, DialReport},
+    identify_pool::IdentifyPool,
+    ignore::IgnoreList,
+    plan::{Action, Plan, PlannedDial},
+    profile::{self, Profile},
+    prometheus,
+    rotation::{self, KnownRotations},
+    routing_table::RoutingTable,
+    ssh,
+    store::Store,
+    scheduler::{JobStatus, Scheduler, Task},
+    trace::{self, Kind, Span},
+    tunnel::{self, Request, Timeouts, Tunnel, TunnelEvent, TunnelId, TunnelStream},
+    watchdog::{Heartbeat, HEARTBEAT_INTERVAL},
+    trust::Trust,
+    vpn,
 };
 
 pub mod error;
@@ -49,134 +78,1637 @@ pub mod error;
 /// Result type with errors specific to this module.
 type Result<T> = result::Result<T, error::P2shd>;
 
+/// Minimum time between two resolutions of `/dnsaddr` bootstrap entries.
+const DNSADDR_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Port for direct ssh connections, if none is configured.
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// How often persistent state (routing table, address cache) gets written to disk.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the addresses of `Mode::Listen::warm` peers get refreshed.
+const WARM_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of cache warming queries running at the same time.
+const MAX_WARMING: usize = 4;
+
+/// How often subscribed blocklists are fetched and our own one is (re-)published.
+const BLOCKLIST_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How often `p2shd listen` (re-)publishes its address record, see `addr_record`.
+const ADDR_RECORD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long published addresses stay valid, long enough to survive a missed republish.
+const ADDR_RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Retry publishing this soon, while no peer told us yet how it sees us.
+const ADDR_RECORD_RETRY: Duration = Duration::from_secs(30);
+
+/// Distinct peers that have to observe us at an address before we publish it, so a
+/// single peer can't make us advertise an address of its choosing.
+const ADDR_CONFIRMATIONS: usize = 2;
+
+/// Observed addresses to track confirmations for, further ones are ignored.
+const MAX_OBSERVED_ADDRS: usize = 32;
+
+/// How often `p2shd listen` (re-)publishes its rotation record, see `rotation`.
+const ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often `p2shd listen` (re-)publishes its profile, see `profile`.
+const PROFILE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a published profile stays valid, long enough to survive a missed republish.
+const PROFILE_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// How long a `Call::Connect` may take.
+const CONTROL_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the daemon is supposed to do.
+#[derive(Clone, Debug)]
+pub enum Mode {
+    /// Connect ssh to the given peers (in parallel), exit once all sessions are finished.
+    Connect(Vec<PeerId>),
+    /// Like `Connect`, but finish as soon as a tunnel to the peer opened.
+    Wait(PeerId),
+    /// Serve tunnels, connecting them to the ssh daemon at `sshd`.
+    Listen {
+        sshd: SocketAddr,
+        /// Other local ports clients may ask for ssh to be connected to.
+        sshd_ports: Vec<u16>,
+        /// Peers to keep resolving in the background, so connecting to them is fast.
+        warm: Vec<PeerId>,
+        /// Peers to stay connected to.
+        keep_connected: Vec<PeerId>,
+        /// Whether to serve `Request::Tcp` tunnels (SOCKS and port forwarding).
+        allow_forwarding: bool,
+        /// Peers to serve `Request::Vpn` links for, with our address on each. Empty if they
+        /// are not served.
+        vpn: HashMap<PeerId, IpNet>,
+        /// Whether to answer `Request::Resources`.
+        advertise_resources: bool,
+    },
+    /// Only take part in the DHT, for `Call`s via `P2shd::controller`.
+    Query,
+    /// Stay connected to `peer`, for forwarding local connections via `P2shd::opener`.
+    Forward {
+        peer: PeerId,
+        /// Remote forwardings to serve `Request::Reverse` tunnels of `peer` for.
+        reverse: Vec<PortForward>,
+    },
+}
+
+/// Events for the owner of the swarm to act on.
+#[derive(Debug)]
+pub enum P2shdEvent {
+    /// The sessions to `Mode::Connect`'s peers ended (or `Mode::Wait`'s peer is online), our
+    /// state got persisted.
+    ///
+    /// Contains the highest exit code of the ssh clients, `1` for failed sessions.
+    SessionFinished(i32),
+    /// `Mode::Connect`'s single or `Mode::Wait`'s peer could not be found, giving up.
+    ///
+    /// With multiple peers, not finding one only fails its session.
+    PeerNotFound(error::P2shd),
+}
+
+/// State of the ssh session to a `Target`.
+enum Session {
+    /// No tunnel yet, waiting for the peer to be found.
+    Idle,
+    /// Tunnel requested, waiting for it to open.
+    Opening(TunnelId),
+    /// Session running, resolves to the exit code to exit with.
+    Running(BoxFuture<'static, async_io::Result<i32>>),
+    /// Session over, with this exit code.
+    Finished(i32),
+}
+
+/// A peer to run a session with, in `Mode::Connect` and `Mode::Wait`.
+struct Target {
+    peer: PeerId,
+    session: Session,
+    /// Port of the remote sshd, if it differs from what the remote daemon uses by default.
+    ssh_port: Option<u16>,
+    /// ssh command for mosh (see `ssh::mosh_ssh_command`), if running mosh instead of ssh.
+    mosh: Option<String>,
+    /// Retries of DHT queries for `peer`.
+    discovery: Backoff,
+    /// Spaces out repeated queries for `peer` that found nothing.
+    discovery_timer: Delay,
+    /// Outstanding DHT queries for `peer`.
+    queries: HashSet<QueryId>,
+    /// Known addresses failed, wait for outstanding queries before trying again.
+    wait_for_query: bool,
+    /// Address we last successfully connected to `peer` with. If known we try
+    /// to open the tunnel right away, without waiting for the DHT.
+    fast_path: Option<Multiaddr>,
+    /// How well we knew `peer` when starting, shown once its session starts.
+    trust: Trust,
+    /// Where we learned `peer`'s addresses from this run, for `DialReport`s.
+    sources: HashMap<Multiaddr, AddrSource>,
+    /// Addresses dialed so far, with why dialing failed.
+    dials: Vec<(Multiaddr, Option<DialFailure>)>,
+    /// Spans discovery, dialing and the ssh request, if tracing.
+    setup_span: Option<Span>,
+    /// Child of `setup_span` while DHT queries for `peer` are outstanding.
+    query_span: Option<Span>,
+    /// Child of `setup_span` while opening a tunnel, with dials as events.
+    tunnel_span: Option<Span>,
+    /// `--dry-run`: The `fast_path` address that would have been dialed right away.
+    planned_fast_path: Option<Multiaddr>,
+    /// Dialing failed with discovery out of attempts, the session fails on the next poll.
+    exhausted: bool,
+}
+
+/// A forwarding added to the running session, via `Call::AddForward`.
+struct AddedForward {
+    forward: PortForward,
+    remote: bool,
+    /// Stops the forwarding.
+    abort: AbortHandle,
+    /// Cleared once the forwarding ended by itself.
+    running: Arc<AtomicBool>,
+}
+
+/// State loaded from the configuration directory, written back regularly.
+pub struct PersistentState {
+    pub store: Store,
+    pub addr_cache: AddrCache,
+    pub routing_table: RoutingTable,
+    pub blocklist: SharedBlocklist,
+}
+
 #[derive(NetworkBehaviour)]
-#[behaviour(poll_method = "poll")]
+#[behaviour(poll_method = "poll", out_event = "P2shdEvent")]
 pub struct P2shd {
-    kad: Kademlia<MemoryStore>,
-    mdns: Mdns,
+    kad: Kademlia<Store>,
+    mdns: Toggle<Mdns>,
     identify: Identify,
+    ping: Ping,
+    tunnel: Tunnel,
     #[behaviour(ignore)]
     local_peer: PeerId,
     #[behaviour(ignore)]
-    /// The peer we are supposed to connect to.
-    remote_peer: PeerId,
+    /// For signing our published blocklist.
+    local_key: identity::Keypair,
+    #[behaviour(ignore)]
+    /// The peers we are supposed to connect to, none in listen mode.
+    targets: Vec<Target>,
+    #[behaviour(ignore)]
+    /// Only wait for the target to be reachable (`Mode::Wait`), no ssh session.
+    wait_only: bool,
+    #[behaviour(ignore)]
+    /// Only show what connecting to the targets would do, see `plan`.
+    dry_run: bool,
+    #[behaviour(ignore)]
+    /// Where to connect inbound tunnels to, `None` if we are not serving.
+    sshd: Option<SocketAddr>,
+    #[behaviour(ignore)]
+    /// Bridge tunnels to stdio instead of spawning ssh (for use as `ProxyCommand`).
+    stdio: bool,
+    #[behaviour(ignore)]
+    /// Arguments for the ssh client.
+    ssh_args: ssh::ClientArgs,
+    #[behaviour(ignore)]
+    /// Remote command for mosh.
+    mosh_command: Vec<String>,
     #[behaviour(ignore)]
     /// Waker of the poll function.
     waker: Option<Waker>,
     #[behaviour(ignore)]
-    querying: SystemTime,
+    /// Addresses seen in this and previous runs.
+    addr_cache: AddrCache,
+    #[behaviour(ignore)]
+    /// Mirror of Kademlia's routing table, persisted across restarts.
+    routing_table: RoutingTable,
+    #[behaviour(ignore)]
+    /// Fires when it is time to persist our state again.
+    snapshot_timer: Delay,
+    #[behaviour(ignore)]
+    /// For resolving `dnsaddr_bootstrap`.
+    resolver: Resolver,
+    #[behaviour(ignore)]
+    /// `/dnsaddr` bootstrap entries: Domain and optional peer id to filter for.
+    dnsaddr_bootstrap: Vec<(String, Option<PeerId>)>,
+    #[behaviour(ignore)]
+    /// Ongoing resolution of `dnsaddr_bootstrap`.
+    resolving: Option<BoxFuture<'static, Vec<BootstrapNode>>>,
+    #[behaviour(ignore)]
+    /// When we last started resolving `dnsaddr_bootstrap`.
+    last_resolved: Option<Instant>,
+    #[behaviour(ignore)]
+    /// Our identify protocol version, p2shd peers have to match it.
+    identify_protocol: String,
+    #[behaviour(ignore)]
+    /// Most recently measured round trip time per connected peer.
+    rtts: HashMap<PeerId, Duration>,
+    #[behaviour(ignore)]
+    /// Peers whose addresses we keep fresh in the address cache.
+    warm_peers: Vec<PeerId>,
+    #[behaviour(ignore)]
+    /// Warm peers still to be resolved in the current round.
+    warm_queue: VecDeque<PeerId>,
+    #[behaviour(ignore)]
+    /// Warm peers currently being resolved.
+    warming: HashSet<PeerId>,
+    #[behaviour(ignore)]
+    /// Fires when it is time for the next warming round.
+    warm_timer: Delay,
+    #[behaviour(ignore)]
+    /// Scheduled jobs, only in listen mode.
+    scheduler: Scheduler,
+    #[behaviour(ignore)]
+    /// Blocked peers and networks, shared with the transport.
+    blocklist: SharedBlocklist,
+    #[behaviour(ignore)]
+    /// Whether to publish our blocklist in the DHT.
+    publish_blocklist: bool,
+    #[behaviour(ignore)]
+    /// Fires when it is time to fetch subscribed blocklists and publish ours.
+    blocklist_timer: Delay,
+    #[behaviour(ignore)]
+    /// Peers to leave out of discovery, unless they are targets.
+    ignore: IgnoreList,
+    #[behaviour(ignore)]
+    /// Discovered peers to connect to for identify, targets first.
+    identify_pool: IdentifyPool,
+    #[behaviour(ignore)]
+    /// Where to write the `DialReport` when giving up on a target.
+    dial_report_file: PathBuf,
+    #[behaviour(ignore)]
+    /// Whether to publish our addresses in the DHT, only when listening.
+    publish_addresses: bool,
+    #[behaviour(ignore)]
+    /// Fires when it is time to (re-)publish our address record.
+    addr_record_timer: Delay,
+    #[behaviour(ignore)]
+    /// Peers that observed us at each of our external addresses, via identify.
+    observed_by: HashMap<Multiaddr, HashSet<PeerId>>,
+    #[behaviour(ignore)]
+    /// Our rotation records with the peer ids we rotated from, published when listening.
+    rotation_records: Vec<(PeerId, Vec<u8>)>,
+    #[behaviour(ignore)]
+    /// Fires when it is time to (re-)publish our rotation record.
+    rotation_timer: Delay,
+    #[behaviour(ignore)]
+    /// Rotations of targets we followed, persisted for later runs.
+    known_rotations: KnownRotations,
+    #[behaviour(ignore)]
+    /// Our public profile, published when listening.
+    profile: Option<Profile>,
+    #[behaviour(ignore)]
+    /// Fires when it is time to (re-)publish our profile.
+    profile_timer: Delay,
+    #[behaviour(ignore)]
+    /// Our own other devices, to sync the address book with when listening.
+    linked_devices: Vec<PeerId>,
+    #[behaviour(ignore)]
+    /// The synced part of the address book.
+    synced_book_file: PathBuf,
+    #[behaviour(ignore)]
+    /// Fires when it is time to sync with connected linked devices.
+    sync_timer: Delay,
+    #[behaviour(ignore)]
+    /// Running queries started by `find_closest_peers`, with when, for query latency metrics.
+    closest_queries: HashMap<QueryId, Instant>,
+    #[behaviour(ignore)]
+    /// Tells the watchdog we are making progress.
+    heartbeat: Heartbeat,
+    #[behaviour(ignore)]
+    /// Gets us polled regularly, for `heartbeat`.
+    heartbeat_timer: Delay,
+    #[behaviour(ignore)]
+    /// Rate limits log messages of hot paths.
+    log_sampler: Sampler,
+    #[behaviour(ignore)]
+    /// Fires when it is time to summarize suppressed log messages.
+    log_summary_timer: Delay,
+    #[behaviour(ignore)]
+    /// The only peers we serve tunnels to, everybody if `None`.
+    ///
+    /// Tunnels only exist on secio authenticated connections, so peers proved
+    /// possession of their key already.
+    authorized_peers: Option<HashSet<PeerId>>,
+    #[behaviour(ignore)]
+    /// Whether inbound `Request::Tcp` tunnels get served.
+    allow_forwarding: bool,
+    #[behaviour(ignore)]
+    /// Ports besides `sshd`'s that clients may pick for ssh, any with `allow_forwarding`.
+    sshd_ports: Vec<u16>,
+    #[behaviour(ignore)]
+    /// Peers we serve VPN links for with our address on them, empty if not serving any.
+    vpn_addrs: HashMap<PeerId, IpNet>,
+    #[behaviour(ignore)]
+    /// Our addresses on served VPN links that are up, each can only be used by one link.
+    vpn_active: Arc<Mutex<HashSet<IpNet>>>,
+    #[behaviour(ignore)]
+    /// Number of inbound tunnels currently being served.
+    active_tunnels: Arc<AtomicUsize>,
+    #[behaviour(ignore)]
+    /// Whether `Request::Resources` gets answered.
+    advertise_resources: bool,
+    #[behaviour(ignore)]
+    /// Services exposed by name, served in listen mode.
+    services: Vec<Service>,
+    #[behaviour(ignore)]
+    /// The peer of `Mode::Forward`.
+    forward_peer: Option<PeerId>,
+    #[behaviour(ignore)]
+    /// Remote forwardings we requested from the session's peer (see `session_peer`).
+    reverse_forwards: Vec<PortForward>,
+    #[behaviour(ignore)]
+    /// Forwardings requested on the command line, `true` for remote ones.
+    initial_forwards: Vec<(PortForward, bool)>,
+    #[behaviour(ignore)]
+    /// Forwardings added while running.
+    added_forwards: Vec<AddedForward>,
+    #[behaviour(ignore)]
+    /// Timeouts enforced on served ssh tunnels.
+    ssh_timeouts: Timeouts,
+    #[behaviour(ignore)]
+    /// Banner file to show clients before their ssh session.
+    banner: Option<PathBuf>,
+    #[behaviour(ignore)]
+    /// Timeouts enforced on served `Request::Tcp` tunnels.
+    forward_timeouts: Timeouts,
+    #[behaviour(ignore)]
+    /// Handed out to local servers wanting tunnels.
+    opener: Opener,
+    #[behaviour(ignore)]
+    /// Tunnels requested via `opener`.
+    stream_requests: mpsc::UnboundedReceiver<StreamRequest>,
+    #[behaviour(ignore)]
+    /// Tunnels requested via `opener`, waiting to open.
+    forwarding: HashMap<TunnelId, oneshot::Sender<async_io::Result<TunnelStream>>>,
+    #[behaviour(ignore)]
+    /// Handed out to the control socket.
+    controller: Controller,
+    #[behaviour(ignore)]
+    /// Calls received via `controller`.
+    control_requests: mpsc::UnboundedReceiver<ControlRequest>,
+    #[behaviour(ignore)]
+    /// `Call::ResolvePeer` waiting for their DHT query.
+    resolve_replies: HashMap<PeerId, Vec<oneshot::Sender<result::Result<Reply, String>>>>,
+    #[behaviour(ignore)]
+    /// `Call::LookupProfile` waiting for the record.
+    profile_replies: HashMap<PeerId, Vec<oneshot::Sender<result::Result<Reply, String>>>>,
+    #[behaviour(ignore)]
+    /// `Call::Connect` waiting for the connection, with their deadline.
+    connect_replies: Vec<(PeerId, Instant, oneshot::Sender<result::Result<Reply, String>>)>,
+    #[behaviour(ignore)]
+    /// Fires regularly while `connect_replies` is not empty, for checking deadlines.
+    connect_timer: Delay,
+    #[behaviour(ignore)]
+    /// Set by `Call::Shutdown`, no new tunnels get served from then on.
+    shutting_down: bool,
+    #[behaviour(ignore)]
+    /// Our NAT situation as of the last poll, for predicting dial success.
+    nat: control::Nat,
 }
 
 impl P2shd {
-    pub fn new(local_key: &identity::Keypair, remote_peer: PeerId) -> Result<P2shd> {
+    pub fn new(
+        cfg: &Config,
+        local_key: &identity::Keypair,
+        mode: Mode,
+        resolver: Resolver,
+        state: PersistentState,
+    ) -> Result<P2shd> {
+        let PersistentState {
+            store,
+            addr_cache,
+            routing_table,
+            blocklist,
+        } = state;
         let local_peer = PeerId::from(local_key.public());
-        let store = MemoryStore::new(local_peer.clone());
-        let mut kad = Kademlia::new(local_peer.clone(), store);
-        P2shd::add_bootstrap_nodes(&mut kad);
+        let mut kad_cfg = KademliaConfig::default();
+        if let Some(protocol) = cfg.kad_protocol() {
+            kad_cfg.set_protocol_name(protocol.as_bytes().to_vec());
+        }
+        let mut kad = Kademlia::with_config(local_peer.clone(), store, kad_cfg);
+        let mut dnsaddr_bootstrap = Vec::new();
+        for entry in &cfg.bootstrap {
+            match entry {
+                Bootstrap::Node(node) => kad.add_address(&node.peer_id, node.addr.clone()),
+                Bootstrap::Dnsaddr { domain, peer_id } => {
+                    dnsaddr_bootstrap.push((domain.clone(), peer_id.clone()))
+                }
+            }
+        }
+        // Rejoin the DHT via the peers we knew last time, not only via bootstrap nodes:
+        for (peer_id, addrs) in routing_table.iter().filter(|(p, _)| !cfg.ignore.is_ignored(p)) {
+            for a in addrs {
+                kad.add_address(peer_id, a.clone());
+            }
+        }
+        // Known addresses from previous runs, so we don't have to wait for the DHT:
+        for (peer_id, addrs) in addr_cache.iter() {
+            for a in addrs {
+                kad.add_address(peer_id, a.addr.clone());
+            }
+        }
         kad.bootstrap();
-        let identify = Identify::new("/p2shd/0.1.0".into(), "p2shd-alpha".into(), local_key.public());
+        let identify_protocol = cfg.identify_protocol();
+        let identify = Identify::new(identify_protocol.clone(), cfg.identify_agent(), local_key.public());
 
-        let mdns = Mdns::new().map_err(error::P2shd::MdnsInitialization)?;
+        let mdns = if cfg.opts.no_mdns {
+            None
+        } else {
+            if let Some(interface) = cfg.bind_interface() {
+                // libp2p's Mdns offers no way to pick interfaces:
+                log::warn!(
+                    "mDNS runs on all interfaces, not only on {}. Use --no-mdns to avoid that.",
+                    interface
+                );
+            }
+            Some(Mdns::new().map_err(error::P2shd::MdnsInitialization)?)
+        };
+        let mdns = Toggle::from(mdns);
 
-        Ok(P2shd {
+        let mut tunnel = Tunnel::new();
+        let mut forward_peer = None;
+        let mut vpn_addrs = HashMap::new();
+        let mut advertise_resources = false;
+        let mut reverse_forwards = Vec::new();
+        let mut wait_only = false;
+        let mut sshd_ports = Vec::new();
+        let (remote_peers, sshd, warm_peers, allow_forwarding) = match mode {
+            Mode::Connect(peers) => (peers, None, Vec::new(), false),
+            Mode::Wait(peer) => {
+                wait_only = true;
+                (vec![peer], None, Vec::new(), false)
+            }
+            Mode::Query => (Vec::new(), None, Vec::new(), false),
+            Mode::Forward { peer, reverse } => {
+                tunnel.keep_connected(peer.clone());
+                forward_peer = Some(peer.clone());
+                reverse_forwards = reverse;
+                (Vec::new(), None, vec![peer], false)
+            }
+            Mode::Listen {
+                sshd,
+                sshd_ports: ports,
+                mut warm,
+                keep_connected,
+                allow_forwarding,
+                vpn,
+                advertise_resources: advertise,
+            } => {
+                vpn_addrs = vpn;
+                advertise_resources = advertise;
+                sshd_ports = ports;
+                for peer in keep_connected {
+                    // Keep their addresses fresh, for redialing:
+                    if !warm.contains(&peer) {
+                        warm.push(peer.clone());
+                    }
+                    tunnel.keep_connected(peer);
+                }
+                (Vec::new(), Some(sshd), warm, allow_forwarding)
+            }
+        };
+        let initial_forwards = if forward_peer.is_some() {
+            let local = cfg.opts.connect.local_forwards.iter().map(|f| (f.clone(), false));
+            local.chain(reverse_forwards.iter().map(|f| (f.clone(), true))).collect()
+        } else {
+            Vec::new()
+        };
+        let scheduler = Scheduler::new(if sshd.is_some() { cfg.jobs.clone() } else { Vec::new() });
+        let rotation_records = match sshd {
+            None => Vec::new(),
+            Some(_) => rotation::load_own(&cfg.get_rotation_file())?,
+        };
+        let mut targets = Vec::new();
+        for peer in remote_peers {
+            let mosh = if cfg.opts.connect.mosh {
+                Some(ssh::mosh_ssh_command(cfg, &peer).map_err(error::P2shd::CurrentExe)?)
+            } else {
+                None
+            };
+            targets.push(Target {
+                session: Session::Idle,
+                ssh_port: cfg.ssh_port(&peer),
+                mosh,
+                discovery: Backoff::new(cfg.discovery_policy()),
+                // Query right away:
+                discovery_timer: Delay::new(Duration::from_secs(0)),
+                queries: HashSet::new(),
+                wait_for_query: false,
+                fast_path: addr_cache.last_good(&peer).cloned(),
+                trust: Trust::of(cfg, &addr_cache, &peer),
+                sources: HashMap::new(),
+                dials: Vec::new(),
+                setup_span: Span::start("session setup", Kind::Internal, None).map(|mut span| {
+                    span.set("peer", &peer);
+                    span
+                }),
+                query_span: None,
+                tunnel_span: None,
+                planned_fast_path: None,
+                exhausted: false,
+                peer,
+            });
+        }
+        let (opener, stream_requests) = Opener::new();
+        let (controller, control_requests) = Controller::new();
+
+        let mut p2shd = P2shd {
             kad, mdns,
             identify,
+            // Failing pings close the connection, so dead connections get detected:
+            ping: Ping::new(PingConfig::new()),
+            tunnel,
             local_peer,
-            remote_peer,
+            local_key: local_key.clone(),
+            targets,
+            wait_only,
+            dry_run: cfg.opts.connect.dry_run,
+            sshd,
+            stdio: cfg.opts.connect.stdio,
+            ssh_args: ssh::ClientArgs::from_config(cfg),
+            mosh_command: cfg.opts.connect.trailing_ssh_args.clone(),
             waker: None,
-            querying: SystemTime::now() - Duration::from_secs(10),
-        })
-    }
-
-    fn add_bootstrap_nodes(kad: &mut Kademlia<MemoryStore>) {
-        let gm_addr = "/ip4/81.223.86.162/tcp/22222".parse().expect("Bootstrap GM node has invalid format!");
-        let gm_id = "12D3KooWRmrTKbuneCQMHAjiGyUTZZu6NZP1XpTMuJJZotTdgYTm".parse().expect("GM node id is invalid!");
-        // let gm_ipfs_addr = "/ip4/81.223.86.162/tcp/4001".parse().expect("Bootstrap GM node has invalid format!");
-        // let gm_ipfs_id = "QmPqXagznBmhiX48Nd52XEcf8xpabE8d97ExLz7oWKQvd7".parse().expect("GM ipfs node id is invalid!");
-        kad.add_address(&gm_id, gm_addr);
-        // kad.add_address(&gm_ipfs_id, gm_ipfs_addr);
-    }
-
-    // pub async fn find_node(&mut self, peer_id: &PeerId) -> Result<Vec<Multiaddr>> {
-    //    let cached = self.addresses_of_peer(peer_id);
-       // In any case: refresh cache:
-    //    self.kad.get_closest_peers(peer_id.clone());
-    //    Ok(if cached.is_empty() {
-    //        self.closest_peers_recv.recv().await;
-           // Try again:
-    //        self.addresses_of_peer(peer_id)
-    //    }
-    //    else {
-    //        cached
-    //    }
-    //    )
-    // }
-
-
-
-    fn poll(&mut self, cx: &mut Context, params: &mut impl PollParameters)
-        -> Poll<NetworkBehaviourAction<EitherOutput<EitherOutput<KademliaHandlerIn<QueryId>, void::Void>, ()>, ()>> {
+            addr_cache,
+            routing_table,
+            snapshot_timer: Delay::new(SNAPSHOT_INTERVAL),
+            resolver,
+            dnsaddr_bootstrap,
+            resolving: None,
+            last_resolved: None,
+            identify_protocol,
+            rtts: HashMap::new(),
+            warm_peers,
+            warm_queue: VecDeque::new(),
+            warming: HashSet::new(),
+            // Right after start, but give bootstrapping a chance first:
+            warm_timer: Delay::new(Duration::from_secs(5)),
+            scheduler,
+            blocklist,
+            publish_blocklist: cfg.publish_blocklist(),
+            // Give bootstrapping some time first:
+            blocklist_timer: Delay::new(Duration::from_secs(10)),
+            ignore: cfg.ignore.clone(),
+            identify_pool: IdentifyPool::new(),
+            dial_report_file: cfg.get_dial_report_file(),
+            publish_addresses: sshd.is_some() && cfg.publish_addresses(),
+            addr_record_timer: Delay::new(ADDR_RECORD_RETRY),
+            observed_by: HashMap::new(),
+            rotation_records,
+            // Give bootstrapping some time first:
+            rotation_timer: Delay::new(Duration::from_secs(10)),
+            known_rotations: KnownRotations::load(cfg.get_known_rotations_file())?,
+            profile: if sshd.is_some() { cfg.profile.clone() } else { None },
+            // Give bootstrapping some time first:
+            profile_timer: Delay::new(Duration::from_secs(10)),
+            linked_devices: if sshd.is_some() { cfg.linked_devices.clone() } else { Vec::new() },
+            synced_book_file: cfg.get_synced_book_file(),
+            // Give connecting to them some time first:
+            sync_timer: Delay::new(Duration::from_secs(60)),
+            closest_queries: HashMap::new(),
+            heartbeat: Heartbeat::new("swarm"),
+            heartbeat_timer: Delay::new(HEARTBEAT_INTERVAL),
+            log_sampler: Sampler::new(),
+            log_summary_timer: Delay::new(SUMMARY_INTERVAL),
+            authorized_peers: cfg.authorized_peers.as_ref().map(|p| p.iter().cloned().collect()),
+            allow_forwarding,
+            sshd_ports,
+            vpn_addrs,
+            vpn_active: Arc::new(Mutex::new(HashSet::new())),
+            active_tunnels: Arc::new(AtomicUsize::new(0)),
+            advertise_resources,
+            services: cfg.services.clone(),
+            forward_peer,
+            reverse_forwards,
+            initial_forwards,
+            added_forwards: Vec::new(),
+            ssh_timeouts: cfg.timeouts("ssh"),
+            banner: cfg.get_banner_file(),
+            forward_timeouts: cfg.timeouts("forward"),
+            opener,
+            stream_requests,
+            forwarding: HashMap::new(),
+            controller,
+            control_requests,
+            resolve_replies: HashMap::new(),
+            profile_replies: HashMap::new(),
+            connect_replies: Vec::new(),
+            connect_timer: Delay::new(Duration::from_secs(1)),
+            shutting_down: false,
+            nat: control::Nat::Unknown,
+        };
+        p2shd.resolve_dnsaddr_bootstrap();
+        Ok(p2shd)
+    }
+
+    /// (Re-)resolve `/dnsaddr` bootstrap entries, unless we did so recently.
+    fn resolve_dnsaddr_bootstrap(&mut self) {
+        if self.dnsaddr_bootstrap.is_empty() || self.resolving.is_some() {
+            return;
+        }
+        if let Some(last) = self.last_resolved {
+            if last.elapsed() < DNSADDR_RETRY_INTERVAL {
+                return;
+            }
+        }
+        log::debug!("Resolving /dnsaddr bootstrap entries ...");
+        self.last_resolved = Some(Instant::now());
+        let resolutions = self
+            .dnsaddr_bootstrap
+            .iter()
+            .map(|(domain, peer_id)| self.resolver.resolve_dnsaddr(domain.clone(), peer_id.clone()));
+        self.resolving = Some(
+            future::join_all(resolutions)
+                .map(|nodes| nodes.into_iter().flatten().collect())
+                .boxed(),
+        );
+    }
+
+    fn poll<TEv>(&mut self, cx: &mut Context, params: &mut impl PollParameters)
+        -> Poll<NetworkBehaviourAction<TEv, P2shdEvent>> {
         self.waker = Some(cx.waker().clone());
-        let cached  = self.addresses_of_peer(&self.remote_peer.clone());
-        let still_querying = {
-            fn get_querying(querying: &SystemTime) -> std::result::Result<bool, SystemTimeError>  {
-                let q = querying.elapsed()?;
-                Ok(q < Duration::from_secs(2))
+        self.heartbeat.beat();
+        while let Poll::Ready(()) = self.heartbeat_timer.poll_unpin(cx) {
+            self.heartbeat_timer.reset(HEARTBEAT_INTERVAL);
+            self.update_crash_snapshot();
+        }
+        let listen_addrs: Vec<_> = params.listened_addresses().collect();
+        let external_addrs: Vec<_> = params.external_addresses().collect();
+        self.nat = nat_status(&listen_addrs, &external_addrs);
+        while let Poll::Ready(()) = self.snapshot_timer.poll_unpin(cx) {
+            self.snapshot_timer.reset(SNAPSHOT_INTERVAL);
+            self.save_state();
+        }
+        while let Poll::Ready(()) = self.warm_timer.poll_unpin(cx) {
+            self.warm_timer.reset(WARM_INTERVAL);
+            self.warm_queue = self.warm_peers.iter().cloned().collect();
+            self.warm_next();
+        }
+        for task in self.scheduler.poll(cx) {
+            match task {
+                Task::WarmCache => {
+                    self.warm_queue = self.warm_peers.iter().cloned().collect();
+                    self.warm_next();
+                }
+                // Run by the scheduler itself:
+                Task::Command { .. } => (),
+            }
+        }
+        while let Poll::Ready(()) = self.blocklist_timer.poll_unpin(cx) {
+            self.blocklist_timer.reset(BLOCKLIST_INTERVAL);
+            self.sync_blocklists();
+        }
+        if self.publish_addresses {
+            while let Poll::Ready(()) = self.addr_record_timer.poll_unpin(cx) {
+                let published = self.publish_addr_record(params);
+                self.addr_record_timer.reset(if published { ADDR_RECORD_INTERVAL } else { ADDR_RECORD_RETRY });
+            }
+        }
+        if !self.rotation_records.is_empty() {
+            while let Poll::Ready(()) = self.rotation_timer.poll_unpin(cx) {
+                self.rotation_timer.reset(ROTATION_INTERVAL);
+                self.publish_rotation_records();
+            }
+        }
+        if self.profile.is_some() {
+            while let Poll::Ready(()) = self.profile_timer.poll_unpin(cx) {
+                self.profile_timer.reset(PROFILE_INTERVAL);
+                self.publish_profile();
+            }
+        }
+        if !self.linked_devices.is_empty() {
+            while let Poll::Ready(()) = self.sync_timer.poll_unpin(cx) {
+                self.sync_timer.reset(SYNC_INTERVAL);
+                self.sync_linked_devices();
+            }
+        }
+        while let Poll::Ready(()) = self.log_summary_timer.poll_unpin(cx) {
+            self.log_summary_timer.reset(SUMMARY_INTERVAL);
+            self.log_sampler.summarize();
+        }
+        if let Some(resolving) = &mut self.resolving {
+            if let Poll::Ready(nodes) = resolving.poll_unpin(cx) {
+                self.resolving = None;
+                for node in &nodes {
+                    log::info!("Bootstrapping via resolved node {} at {}", node.peer_id, node.addr);
+                    self.kad.add_address(&node.peer_id, node.addr.clone());
+                }
+                if !nodes.is_empty() {
+                    self.kad.bootstrap();
+                }
+            }
+        }
+        while let Poll::Ready(Some(request)) = self.stream_requests.poll_next_unpin(cx) {
+            log::debug!("Opening forwarding tunnel to {} ...", request.peer);
+            let id = self.tunnel.open(&request.peer);
+            self.forwarding.insert(id, request.reply);
+        }
+        while let Poll::Ready(Some(request)) = self.control_requests.poll_next_unpin(cx) {
+            self.handle_call(request, params);
+        }
+        self.poll_connect_replies(cx);
+        while let Some(peer) = self.identify_pool.next() {
+            if self.tunnel.is_connected(&peer) {
+                // Identify ran on connecting already:
+                self.identify_pool.finished(&peer);
+            } else {
+                self.tunnel.connect(&peer);
+            }
+        }
+        for i in 0..self.targets.len() {
+            if let Some(action) = self.poll_target(i, cx) {
+                return Poll::Ready(action);
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Drive the session to `self.targets[i]`: Find the peer, open a tunnel, run ssh.
+    fn poll_target<TEv>(&mut self, i: usize, cx: &mut Context)
+        -> Option<NetworkBehaviourAction<TEv, P2shdEvent>> {
+        let remote_peer = self.targets[i].peer.clone();
+        let finished = match &mut self.targets[i].session {
+            Session::Idle => None,
+            Session::Opening(_) | Session::Finished(_) => return None,
+            Session::Running(session) => match session.poll_unpin(cx) {
+                Poll::Ready(r) => Some(r),
+                Poll::Pending => return None,
+            },
+        };
+        if let Some(r) = finished {
+            let code = r.unwrap_or_else(|e| {
+                log::error!("Session to {} failed: {}", remote_peer, e);
+                1
+            });
+            return self.finish(i, code);
+        }
+        if self.targets[i].exhausted {
+            return self.peer_not_found(i);
+        }
+        if self.dry_run && self.targets[i].discovery.attempts() == 0 {
+            // Plan with what discovery finds, not only with what we knew before:
+            self.targets[i].planned_fast_path = self.targets[i].fast_path.take();
+            self.query_target(i);
+            self.targets[i].wait_for_query = true;
+            return None;
+        }
+        if let Some(addr) = self.targets[i].fast_path.take() {
+            log::info!("Trying last known good address {} of {} first.", addr, remote_peer);
+            // Start resolution right away, in case the peer moved:
+            self.query_target(i);
+            let mut candidates = vec![addr];
+            for a in self.addr_cache.get(&remote_peer) {
+                if !candidates.contains(&a.addr) {
+                    candidates.push(a.addr.clone());
+                }
+            }
+            self.open_tunnel(i, candidates);
+            return None;
+        }
+        let cached  = self.addresses_of_peer(&remote_peer);
+        let querying = !self.targets[i].queries.is_empty();
+        if cached.is_empty() || (querying && self.targets[i].wait_for_query) {
+            if querying {
+                // We get woken once the query finishes or the peer got discovered otherwise:
+                // Polled on every wakeup, so sampled:
+                if self.log_sampler.sample_for("still querying", &remote_peer) {
+                    log::info!("Still querying for {} ...", remote_peer);
+                    if log::log_enabled!(log::Level::Debug) {
+                        log::debug!("Current query status:");
+                        for (n, q) in self.kad.iter_queries().enumerate() {
+                            log::debug!("Query[{}]: {:?}", n, q.info());
+                        }
+                    }
+                }
+                return None;
+            }
+            // Last query found nothing, back off before the next one:
+            if self.targets[i].discovery_timer.poll_unpin(cx).is_pending() {
+                return None;
+            }
+            if !self.query_target(i) {
+                return self.peer_not_found(i);
+            }
+            None
+        } else {
+            log::info!("Found addresses of {}: {:?}!", remote_peer, cached);
+            if let Some(span) = &mut self.targets[i].setup_span {
+                span.event(format!("found {} addresses", cached.len()));
+            }
+            if let Some(rtt) = self.rtts.get(&remote_peer) {
+                log::info!("Round trip time to peer: {:?}", rtt);
+            }
+            for a in &cached {
+                self.addr_cache.insert(remote_peer.clone(), a.clone());
+            }
+            self.save_state();
+            if self.dry_run {
+                self.show_plan(i, cached);
+            } else {
+                self.open_tunnel(i, cached);
+            }
+            None
+        }
+    }
+
+    /// Give up on `self.targets[i]`, discovery is out of attempts.
+    fn peer_not_found<TEv>(&mut self, i: usize) -> Option<NetworkBehaviourAction<TEv, P2shdEvent>> {
+        self.report_dial_failure(i);
+        let target = &mut self.targets[i];
+        let error = error::P2shd::PeerNotFound(
+            target.peer.clone(),
+            target.discovery.attempts(),
+            target.discovery.elapsed().as_secs(),
+        );
+        for span in vec![target.query_span.take(), target.setup_span.take()].into_iter().flatten() {
+            span.finish(Some(error.to_string()));
+        }
+        if self.targets.len() == 1 {
+            return Some(NetworkBehaviourAction::GenerateEvent(P2shdEvent::PeerNotFound(error)));
+        }
+        // Sessions to the other peers go on:
+        log::error!("{}", error);
+        self.finish(i, 1)
+    }
+
+    /// Start another DHT query for `self.targets[i]`, `false` if the retry policy says to give up.
+    fn query_target(&mut self, i: usize) -> bool {
+        let target = &mut self.targets[i];
+        let delay = match target.discovery.next() {
+            None => return false,
+            Some(d) => d,
+        };
+        log::info!("Querying DHT for {} (attempt {}) ...", target.peer, target.discovery.attempts());
+        target.discovery_timer.reset(delay);
+        if target.query_span.is_none() {
+            target.query_span = trace::child("dht query", Kind::Internal, target.setup_span.as_ref());
+        }
+        if let Some(span) = &mut target.query_span {
+            span.event(format!("attempt {}", target.discovery.attempts()));
+        }
+        let peer = target.peer.clone();
+        // Usually faster than the query, if the peer publishes its addresses:
+        self.kad.get_record(&addr_record::record_key(&peer), Quorum::One);
+        // In case it moved on to a new key:
+        self.kad.get_record(&rotation::record_key(&peer), Quorum::One);
+        let id = self.find_closest_peers(peer);
+        self.targets[i].queries.insert(id);
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+        true
+    }
+
+    /// Request a tunnel to `self.targets[i]`, the ssh session starts once it is open.
+    ///
+    /// The most promising of `addrs` get dialed, see `predictor`.
+    fn open_tunnel(&mut self, i: usize, addrs: Vec<Multiaddr>) {
+        let addrs = self.addr_cache.rank(self.nat, addrs);
+        let peer = self.targets[i].peer.clone();
+        log::info!("Opening tunnel to {} via {:?} ...", peer, addrs);
+        let mut span = trace::child("open tunnel", Kind::Client, self.targets[i].setup_span.as_ref());
+        if let Some(span) = &mut span {
+            span.set("addresses", addrs.len());
+        }
+        let id = self.tunnel.open_via(&peer, addrs);
+        self.targets[i].session = Session::Opening(id);
+        self.targets[i].tunnel_span = span;
+    }
+
+    /// `--dry-run`: Print what `open_tunnel` would do with `addrs` and what would run over the
+    /// tunnel, then finish the session.
+    fn show_plan(&mut self, i: usize, mut addrs: Vec<Multiaddr>) {
+        let target = &self.targets[i];
+        if let Some(addr) = &target.planned_fast_path {
+            if !addrs.contains(addr) {
+                addrs.insert(0, addr.clone());
             }
-            get_querying(&self.querying).expect("Querying elapsed time failed")
+        }
+        let ranked = self.addr_cache.rank(self.nat, addrs.clone());
+        let planned = |addr: &Multiaddr| PlannedDial {
+            addr: addr.clone(),
+            source: target.sources.get(addr).cloned().unwrap_or(AddrSource::Cache),
+            prediction: self.addr_cache.predict(self.nat, addr),
         };
-        if cached.is_empty() && !still_querying {
-            log::info!("Query again ...");
-            self.querying = SystemTime::now();
-            self.kad.get_closest_peers(self.remote_peer.clone());
-            Poll::Pending
-        }
-        else if still_querying {
-            log::info!("Still querying ...");
-            log::debug!("Current query status:");
-            for (i,q) in self.kad.iter_queries().enumerate() {
-                log::debug!("Query[{}]: {:?}", i, q.info());
-            }
-            Poll::Pending
+        let action = if self.stdio {
+            Action::Stdio
+        } else if let Some(ssh) = &target.mosh {
+            Action::Mosh(ssh.clone())
         } else {
-            log::info!("Found peer addresses {:?}!", cached);
-            let node_addrs = cached.iter()
-                .filter_map(|x| host_addr_from_multiaddr(x).ok())
-                .filter(|a| a != "127.0.0.1" && a != "::1" && a != "localhost");
-            let mut children = Vec::new();
-            children.reserve(cached.len());
-            for addr in node_addrs {
-                log::info!("Connecting to: {}", &addr);
-                let r = Command::new("ssh")
-                    .arg(&addr)
-                    .spawn();
-                children.push((addr,r));
-            }
-            let mut success = false;
-            for (addr,r) in children {
-                match r {
-                    Ok(mut h) => {
-                        h.wait();
-                        success = true;
+            Action::Ssh(ssh::client_command_line(&target.peer, &self.ssh_args))
+        };
+        let plan = Plan {
+            peer: target.peer.clone(),
+            trust: target.trust,
+            nat: self.nat,
+            attempts: target.discovery.attempts(),
+            elapsed: target.discovery.elapsed(),
+            connected: self.tunnel.is_connected(&target.peer),
+            fast_path: target.planned_fast_path.clone(),
+            dials: ranked.iter().map(planned).collect(),
+            skipped: addrs.iter().filter(|a| !ranked.contains(a)).map(planned).collect(),
+            stagger: tunnel::DIAL_STAGGER,
+            action,
+        };
+        println!("{}", plan);
+        self.targets[i].session = Session::Running(future::ready(Ok(0)).boxed());
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+
+    /// The target the tunnel `id` is being opened to, if any.
+    fn target_opening(&self, id: TunnelId) -> Option<usize> {
+        self.targets
+            .iter()
+            .position(|t| matches!(t.session, Session::Opening(opening) if opening == id))
+    }
+
+    /// Run `session` for `self.targets[i]`.
+    fn run_session(&mut self, i: usize, session: BoxFuture<'static, async_io::Result<i32>>) {
+        if !self.wait_only {
+            // On stderr, as ssh does:
+            let target = &self.targets[i];
+            eprintln!("p2shd: {} is {}.", target.peer, target.trust);
+        }
+        self.targets[i].session = Session::Running(session);
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+
+    /// `Mode::Wait`'s peer is reachable, finish.
+    fn peer_online(&mut self, i: usize) {
+        log::info!("{} is online.", self.targets[i].peer);
+        self.run_session(i, future::ready(Ok(0)).boxed());
+    }
+
+    /// Start the ssh session over a freshly opened tunnel.
+    fn start_session(&mut self, i: usize, mut stream: TunnelStream) {
+        let stdio = self.stdio;
+        let args = self.ssh_args.clone();
+        let (peer, port) = (self.targets[i].peer.clone(), self.targets[i].ssh_port);
+        let opener = self.opener.clone();
+        let setup_span = self.targets[i].setup_span.take();
+        let session = async move {
+            ssh::show_banner(peer.clone(), opener).await;
+            let context = setup_span.as_ref().map(Span::context);
+            let result = tunnel::request_traced(&mut stream, &Request::Ssh { port }, context.as_ref()).await;
+            trace::finish(setup_span, &result);
+            let timeouts = result?;
+            task::spawn(ssh::warn_expiry(timeouts));
+            if stdio {
+                ssh::run_stdio(stream).await?;
+                Ok(0)
+            } else {
+                let status = ssh::run_client(stream, &peer, &args).await?;
+                if status.code() == Some(255) {
+                    // Also what ssh exits with if the pinned host key did not match:
+                    eprintln!(
+                        "p2shd: ssh failed. If it warned about a changed host key, make sure \
+                         you know why, then run `p2shd trust refresh {}`.",
+                        peer
+                    );
+                }
+                Ok(status.code().unwrap_or(1))
+            }
+        };
+        self.run_session(i, session.boxed());
+    }
+
+    /// Start an ssh session directly to `host`, the peer got verified to be reachable there.
+    fn start_direct_session(&mut self, i: usize, host: String) {
+        let args = self.ssh_args.clone();
+        let peer = self.targets[i].peer.clone();
+        let port = self.targets[i].ssh_port.unwrap_or(DEFAULT_SSH_PORT);
+        let session = async move {
+            let status = ssh::run_direct(host, port, &peer, &args).await?;
+            Ok(status.code().unwrap_or(1))
+        };
+        self.run_session(i, session.boxed());
+    }
+
+    /// Start mosh to `host`, an address the remote peer got verified to be reachable at.
+    fn start_mosh_session(&mut self, i: usize, ssh: String, host: String) {
+        let command = self.mosh_command.clone();
+        let session = async move {
+            let status = ssh::run_mosh(ssh, host, command).await?;
+            Ok(status.code().unwrap_or(1))
+        };
+        self.run_session(i, session.boxed());
+    }
+
+    /// The session to `self.targets[i]` is over. Once all are: Persist our
+    /// state and tell the owner, it decides what's next.
+    fn finish<TEv>(&mut self, i: usize, code: i32) -> Option<NetworkBehaviourAction<TEv, P2shdEvent>> {
+        log::debug!("Session to {} finished with exit code {}.", self.targets[i].peer, code);
+        self.targets[i].session = Session::Finished(code);
+        let mut highest = 0;
+        for t in &self.targets {
+            match t.session {
+                Session::Finished(code) => highest = highest.max(code),
+                _ => return None,
+            }
+        }
+        self.save_state();
+        events::record("session finished");
+        if let Err(e) = events::dump() {
+            log::warn!("{:#}", e);
+        }
+        Some(NetworkBehaviourAction::GenerateEvent(P2shdEvent::SessionFinished(highest)))
+    }
+
+    /// For local servers (e.g. SOCKS) to request tunnels through this swarm.
+    pub fn opener(&self) -> Opener {
+        self.opener.clone()
+    }
+
+    /// For the control socket to pass calls to this swarm.
+    pub fn controller(&self) -> Controller {
+        self.controller.clone()
+    }
+
+    /// Keep the state shown in crash reports current.
+    fn update_crash_snapshot(&self) {
+        crash::update(crash::Snapshot {
+            serving: self.sshd.is_some(),
+            sessions: self.targets.iter().filter(|t| matches!(t.session, Session::Running(_))).count(),
+            active_tunnels: self.active_tunnels.load(Ordering::SeqCst),
+            connected_peers: self.tunnel.connected_peers().count(),
+            routing_table_size: self.routing_table.iter().count(),
+            nat: Some(self.nat),
+        });
+    }
+
+    /// For the watchdog to tell whether this swarm hangs.
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat.clone()
+    }
+
+    /// Prepare for exiting the process: Stop serving tunnels, terminate ssh clients (see
+    /// `ssh::wait_children`) and persist state.
+    pub fn shutdown(&mut self) {
+        self.shutting_down = true;
+        ssh::terminate_children();
+        self.save_state();
+        events::record("shutdown requested");
+        if let Err(e) = events::dump() {
+            log::warn!("{:#}", e);
+        }
+    }
+
+    /// Answer a call from the control socket, now or once its result is known.
+    fn handle_call(&mut self, request: ControlRequest, params: &mut impl PollParameters) {
+        let ControlRequest { call, reply } = request;
+        log::debug!("Control call: {:?}", call);
+        match call {
+            Call::ResolvePeer(peer) => {
+                self.find_closest_peers(peer.clone());
+                self.resolve_replies.entry(peer).or_insert_with(Vec::new).push(reply);
+            }
+            Call::LookupProfile(peer) => {
+                self.kad.get_record(&profile::record_key(&peer), Quorum::One);
+                self.profile_replies.entry(peer).or_insert_with(Vec::new).push(reply);
+            }
+            Call::Connect(peer) => {
+                if self.is_blocked(&peer) {
+                    let _ = reply.send(Err(format!("Peer {} is blocked.", peer)));
+                    return;
+                }
+                self.tunnel.connect(&peer);
+                self.connect_replies.push((peer, Instant::now() + CONTROL_CONNECT_TIMEOUT, reply));
+                if let Some(w) = self.waker.take() {
+                    w.wake();
+                }
+            }
+            Call::ListPeers => {
+                let peers = self
+                    .tunnel
+                    .connected_peers()
+                    .map(|(peer, addr)| control::PeerInfo {
+                        peer: peer.to_string(),
+                        address: addr.map(|a| a.to_string()),
+                        rtt_ms: self.rtts.get(peer).map(|rtt| rtt.as_millis() as u64),
+                    })
+                    .collect();
+                let _ = reply.send(Ok(Reply::Peers { peers }));
+            }
+            Call::Status => {
+                let jobs = self
+                    .job_status()
+                    .iter()
+                    .map(|j| control::JobInfo {
+                        name: j.name.clone(),
+                        schedule: j.schedule.clone(),
+                        last_run: j
+                            .last_run
+                            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                            .map(|d| d.as_secs()),
+                        last_result: j.last_result.clone(),
+                    })
+                    .collect();
+                let listen_addrs: Vec<_> = params.listened_addresses().collect();
+                let external_addrs: Vec<_> = params.external_addresses().collect();
+                let _ = reply.send(Ok(Reply::Status(control::Status {
+                    peer_id: self.local_peer.to_string(),
+                    serving: self.sshd.is_some(),
+                    connected_peers: self.tunnel.connected_peers().count(),
+                    active_tunnels: self.active_tunnels.load(Ordering::SeqCst),
+                    nat: nat_status(&listen_addrs, &external_addrs),
+                    listen_addrs: listen_addrs.iter().map(|a| a.to_string()).collect(),
+                    external_addrs: external_addrs.iter().map(|a| a.to_string()).collect(),
+                    routing_table_size: self.routing_table.iter().count(),
+                    queries: self.kad.iter_queries().count(),
+                    warm_peers: self.warm_peers.iter().map(|p| p.to_string()).collect(),
+                    jobs,
+                })));
+            }
+            Call::Shutdown => {
+                // The caller exits the process, once it passed on the answer:
+                self.shutdown();
+                let _ = reply.send(Ok(Reply::Done));
+            }
+            Call::AddForward { forward, remote } => self.add_forward(forward, remote, reply),
+            Call::RemoveForward { listen, remote } => {
+                self.prune_forwards();
+                let i = self.added_forwards.iter().position(|f| f.forward.listen == listen && f.remote == remote);
+                let result = match i {
+                    Some(i) => {
+                        let f = self.added_forwards.remove(i);
+                        log::info!("Removing forwarding {}", f.forward);
+                        f.abort.abort();
+                        if remote {
+                            self.reverse_forwards.retain(|r| r.listen != listen);
+                        }
+                        Ok(Reply::Done)
                     }
-                    Err(e) => {
-                        log::info!("Failed running ssh for {}, with: {:?} ", addr, e);
+                    None if self.initial_forwards.iter().any(|(f, r)| f.listen == listen && *r == remote) => {
+                        Err(format!("Forwarding of {} was set up at start, it can't be removed.", listen))
+                    }
+                    None => Err(format!("No forwarding of {}.", listen)),
+                };
+                let _ = reply.send(result);
+            }
+            Call::ListForwards => {
+                self.prune_forwards();
+                let initial = self.initial_forwards.iter().map(|(f, remote)| (f, *remote, false));
+                let added = self.added_forwards.iter().map(|f| (&f.forward, f.remote, true));
+                let forwards = initial
+                    .chain(added)
+                    .map(|(f, remote, removable)| control::ForwardInfo {
+                        forward: f.to_string(),
+                        remote,
+                        removable,
+                    })
+                    .collect();
+                let _ = reply.send(Ok(Reply::Forwards { forwards }));
+            }
+        }
+    }
+
+    /// The peer forwardings go through: The one of `Mode::Forward` or the single target of
+    /// `Mode::Connect`, `None` if there is no such session.
+    fn session_peer(&self) -> Option<&PeerId> {
+        match (&self.forward_peer, self.targets.as_slice()) {
+            (Some(peer), _) => Some(peer),
+            (None, [target]) if !self.wait_only => Some(&target.peer),
+            _ => None,
+        }
+    }
+
+    /// Handle `Call::AddForward`: Set up the forwarding, `reply` once that worked.
+    fn add_forward(
+        &mut self,
+        forward: PortForward,
+        remote: bool,
+        reply: oneshot::Sender<result::Result<Reply, String>>,
+    ) {
+        self.prune_forwards();
+        let peer = match self.session_peer() {
+            Some(peer) => peer.clone(),
+            None => {
+                let _ = reply.send(Err("No session to add forwardings to.".into()));
+                return;
+            }
+        };
+        let taken = self.initial_forwards.iter().map(|(f, r)| (f, *r));
+        let mut taken = taken.chain(self.added_forwards.iter().map(|f| (&f.forward, f.remote)));
+        if taken.any(|(f, r)| f.listen == forward.listen && r == remote) {
+            let _ = reply.send(Err(format!("Already forwarding {}.", forward.listen)));
+            return;
+        }
+        log::info!("Adding forwarding {} via {}", forward, peer);
+        if remote {
+            self.reverse_forwards.push(forward.clone());
+        }
+        let (ready, set_up) = oneshot::channel();
+        let adding = forward::add(forward.clone(), remote, peer, self.opener.clone(), ready);
+        let (adding, abort) = future::abortable(adding);
+        let running = Arc::new(AtomicBool::new(true));
+        let (still_running, name) = (running.clone(), forward.to_string());
+        task::spawn(async move {
+            if let Ok(Err(e)) = adding.await {
+                log::error!("Forwarding {} failed: {}", name, e);
+            }
+            still_running.store(false, Ordering::SeqCst);
+        });
+        task::spawn(async move {
+            let result = match set_up.await {
+                Ok(Ok(())) => Ok(Reply::Done),
+                Ok(Err(e)) => Err(format!("Setting up forwarding failed: {}", e)),
+                Err(_) => Err("Forwarding got removed.".into()),
+            };
+            let _ = reply.send(result);
+        });
+        self.added_forwards.push(AddedForward {
+            forward,
+            remote,
+            abort,
+            running,
+        });
+    }
+
+    /// Forget added forwardings which ended by themselves.
+    fn prune_forwards(&mut self) {
+        let reverse_forwards = &mut self.reverse_forwards;
+        self.added_forwards.retain(|f| {
+            let running = f.running.load(Ordering::SeqCst);
+            if !running && f.remote {
+                reverse_forwards.retain(|r| r.listen != f.forward.listen);
+            }
+            running
+        });
+    }
+
+    /// Answer `Call::Connect`s which got connected or ran out of time.
+    fn poll_connect_replies(&mut self, cx: &mut Context) {
+        if self.connect_replies.is_empty() {
+            return;
+        }
+        while let Poll::Ready(()) = self.connect_timer.poll_unpin(cx) {
+            self.connect_timer.reset(Duration::from_secs(1));
+        }
+        let now = Instant::now();
+        for (peer, deadline, reply) in mem::replace(&mut self.connect_replies, Vec::new()) {
+            if self.tunnel.is_connected(&peer) {
+                let _ = reply.send(Ok(Reply::Connected { peer: peer.to_string() }));
+            } else if now >= deadline {
+                let _ = reply.send(Err(format!("Connecting to {} timed out.", peer)));
+            } else {
+                self.connect_replies.push((peer, deadline, reply));
+            }
+        }
+    }
+
+    /// The queries `finished`, connect if they were for a target.
+    fn remote_queries_done(&mut self, finished: &[QueryId]) {
+        for target in self.targets.iter_mut() {
+            let outstanding = target.queries.len();
+            target.queries.retain(|id| !finished.contains(id));
+            if target.queries.len() == outstanding {
+                continue;
+            }
+            if target.queries.is_empty() {
+                target.wait_for_query = false;
+                if let Some(span) = target.query_span.take() {
+                    span.finish(None);
+                }
+            }
+            if let Some(w) = self.waker.take() {
+                w.wake();
+            }
+        }
+    }
+
+    /// A query for `key` finished, answer `Call::ResolvePeer`s waiting for it.
+    fn resolve_done(&mut self, key: &[u8]) {
+        if let Ok(peer) = PeerId::from_bytes(key.to_vec()) {
+            if let Some(replies) = self.resolve_replies.remove(&peer) {
+                let addresses: Vec<_> = self
+                    .kad
+                    .addresses_of_peer(&peer)
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                for reply in replies {
+                    let _ = reply.send(Ok(Reply::Addresses { addresses: addresses.clone() }));
+                }
+            }
+        }
+    }
+
+    /// Status of scheduled jobs.
+    pub fn job_status(&self) -> &[JobStatus] {
+        self.scheduler.status()
+    }
+
+    /// Start resolving queued warm peers, up to `MAX_WARMING` at a time.
+    fn warm_next(&mut self) {
+        while self.warming.len() < MAX_WARMING {
+            let peer = match self.warm_queue.pop_front() {
+                None => return,
+                Some(p) => p,
+            };
+            if self.warming.insert(peer.clone()) {
+                log::debug!("Warming address cache for {}", peer);
+                self.find_closest_peers(peer);
+            }
+        }
+    }
+
+    /// Query the DHT for `peer`, timing the query for metrics.
+    fn find_closest_peers(&mut self, peer: PeerId) -> QueryId {
+        let id = self.kad.get_closest_peers(peer);
+        self.closest_queries.insert(id, Instant::now());
+        id
+    }
+
+    /// A DHT query for `key` started by `find_closest_peers` finished.
+    fn closest_peers_done(&mut self, key: &[u8]) {
+        // Results don't tell the query, but finished ones are gone from Kademlia by now:
+        let running: HashSet<QueryId> = self.kad.iter_queries().map(|q| q.id()).collect();
+        let finished: Vec<QueryId> = self
+            .closest_queries
+            .keys()
+            .filter(|id| !running.contains(id))
+            .cloned()
+            .collect();
+        for id in &finished {
+            if let Some(start) = self.closest_queries.remove(id) {
+                prometheus::query_finished(start.elapsed());
+            }
+        }
+        self.warm_done(key);
+        self.resolve_done(key);
+        self.remote_queries_done(&finished);
+    }
+
+    /// A query for `key` finished, continue warming if it was a warming query.
+    fn warm_done(&mut self, key: &[u8]) {
+        if let Ok(peer) = PeerId::from_bytes(key.to_vec()) {
+            if self.warming.remove(&peer) {
+                for a in self.kad.addresses_of_peer(&peer) {
+                    self.addr_cache.insert(peer.clone(), a);
+                }
+                self.warm_next();
+            }
+        }
+    }
+
+    /// Fetch the blocklists we subscribed to and publish our own one, if enabled.
+    fn sync_blocklists(&mut self) {
+        let blocklist = self.blocklist.read().expect("Blocklist lock poisoned.");
+        for peer in blocklist.subscriptions() {
+            log::debug!("Fetching blocklist of {}", peer);
+            self.kad.get_record(&blocklist::record_key(peer), Quorum::One);
+        }
+        if self.publish_blocklist {
+            match blocklist.sign(&self.local_key) {
+                Ok(signed) => {
+                    let record = Record::new(blocklist::record_key(&self.local_peer), signed);
+                    if let Err(e) = self.kad.put_record(record, Quorum::One) {
+                        log::warn!("Publishing blocklist failed: {:?}", e);
                     }
                 }
+                Err(e) => log::warn!("Signing blocklist failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Publish the addresses enough peers observed us at, if there are any yet.
+    fn publish_addr_record(&mut self, params: &mut impl PollParameters) -> bool {
+        let observed_by = &self.observed_by;
+        let addrs: Vec<_> = params
+            .external_addresses()
+            .filter(|a| observed_by.get(a).map_or(false, |peers| peers.len() >= ADDR_CONFIRMATIONS))
+            .collect();
+        if addrs.is_empty() {
+            log::debug!("No confirmed observed addresses yet, not publishing an address record.");
+            return false;
+        }
+        match addr_record::sign(&self.local_key, &addrs, ADDR_RECORD_TTL) {
+            Ok(signed) => {
+                let mut record = Record::new(addr_record::record_key(&self.local_peer), signed);
+                record.expires = Some(Instant::now() + ADDR_RECORD_TTL);
+                log::debug!("Publishing address record: {:?}", addrs);
+                if let Err(e) = self.kad.put_record(record, Quorum::One) {
+                    log::warn!("Publishing address record failed: {:?}", e);
+                }
+            }
+            Err(e) => log::warn!("Signing address record failed: {:#}", e),
+        }
+        true
+    }
+
+    /// Take the addresses of a fetched address record, if it is one of a target's.
+    fn import_addr_record(&mut self, record: &Record) {
+        let publisher = match addr_record::publisher_of(&record.key) {
+            Some(p) if self.targets.iter().any(|t| t.peer == p) => p,
+            _ => return,
+        };
+        match addr_record::verify(&publisher, &record.value) {
+            Ok(addrs) => {
+                log::info!("Address record of {}: {:?}", publisher, addrs);
+                for a in addrs {
+                    self.note_source(&publisher, &a, AddrSource::AddrRecord);
+                    self.addr_cache.insert(publisher.clone(), a.clone());
+                    self.kad.add_address(&publisher, a);
+                }
+                self.wake_on_found(&publisher);
+            }
+            Err(e) => log::warn!("{:#}", e),
+        }
+    }
+
+    /// Publish our profile, signed.
+    fn publish_profile(&mut self) {
+        let profile = match &self.profile {
+            None => return,
+            Some(p) => p,
+        };
+        match profile::sign(&self.local_key, profile, PROFILE_TTL) {
+            Ok(signed) => {
+                let mut record = Record::new(profile::record_key(&self.local_peer), signed);
+                record.expires = Some(Instant::now() + PROFILE_TTL);
+                log::debug!("Publishing profile: {:?}", profile);
+                if let Err(e) = self.kad.put_record(record, Quorum::One) {
+                    log::warn!("Publishing profile failed: {:?}", e);
+                }
+            }
+            Err(e) => log::warn!("Signing profile failed: {:#}", e),
+        }
+    }
+
+    /// Answer `Call::LookupProfile`s waiting for the fetched `record`, if it is a valid profile.
+    ///
+    /// Invalid ones (e.g. stored by somebody else under the publisher's key) are
+    /// skipped, in case another record of the same query is valid.
+    fn answer_profile(&mut self, record: &Record) {
+        let publisher = match profile::publisher_of(&record.key) {
+            Some(p) => p,
+            None => return,
+        };
+        if !self.profile_replies.contains_key(&publisher) {
+            return;
+        }
+        match profile::verify(&publisher, &record.value) {
+            Ok(profile) => {
+                for reply in self.profile_replies.remove(&publisher).unwrap_or_default() {
+                    let _ = reply.send(Ok(Reply::Profile(profile.clone())));
+                }
             }
-            if success {
-                std::process::exit(0);
+            Err(e) => log::debug!("Ignoring invalid profile record of {}: {:#}", publisher, e),
+        }
+    }
+
+    /// Fetching the record at `key` finished without a valid profile, fail
+    /// `Call::LookupProfile`s waiting for it.
+    fn profile_not_found(&mut self, key: &Key, found: bool) {
+        if let Some(publisher) = profile::publisher_of(key) {
+            let error = if found {
+                format!("{} publishes no valid profile.", publisher)
+            } else {
+                format!("{} publishes no profile.", publisher)
+            };
+            for reply in self.profile_replies.remove(&publisher).unwrap_or_default() {
+                let _ = reply.send(Err(error.clone()));
+            }
+        }
+    }
+
+    /// Sync the address book with the linked devices we are connected to.
+    fn sync_linked_devices(&self) {
+        let connected = self.linked_devices.iter().filter(|d| self.tunnel.is_connected(d));
+        for device in connected.cloned() {
+            let (path, opener) = (self.synced_book_file.clone(), self.opener.clone());
+            let local = self.local_peer.clone();
+            task::spawn(async move {
+                match book_sync::sync(path, local, device.clone(), opener).await {
+                    Ok(changed) => log::info!("Synced address book with {}, {} changed entries.", device, changed),
+                    Err(e) => log::warn!("{:#}", e),
+                }
+            });
+        }
+    }
+
+    /// Publish the records telling peers using our old peer ids about the next one.
+    fn publish_rotation_records(&mut self) {
+        for (old, signed) in &self.rotation_records {
+            let mut record = Record::new(rotation::record_key(old), signed.clone());
+            // Republished long before, but gone soon once we stop:
+            record.expires = Some(Instant::now() + 2 * ROTATION_INTERVAL);
+            log::debug!("Publishing rotation record from {}.", old);
+            if let Err(e) = self.kad.put_record(record, Quorum::One) {
+                log::warn!("Publishing rotation record failed: {:?}", e);
             }
-            Poll::Ready(NetworkBehaviourAction::GenerateEvent(()))
         }
     }
 
-    /// Wake if the given peer_id matches `remote_peer`.
+    /// Follow a fetched rotation record, if it is one of a target not connected yet.
+    fn import_rotation(&mut self, record: &Record) {
+        let old = match rotation::publisher_of(&record.key) {
+            Some(p) => p,
+            None => return,
+        };
+        let i = match self
+            .targets
+            .iter()
+            .position(|t| t.peer == old && matches!(t.session, Session::Idle))
+        {
+            Some(i) => i,
+            None => return,
+        };
+        let new = match rotation::verify(&old, &record.value) {
+            Ok(new) => new,
+            Err(e) => {
+                log::warn!("{:#}", e);
+                return;
+            }
+        };
+        eprintln!("p2shd: {} rotated its key, connecting to {} instead.", old, new);
+        if let Err(e) = self.known_rotations.insert(old, new.clone()) {
+            log::warn!("{:#}", e);
+        }
+        let target = &mut self.targets[i];
+        // Our address book pins the old id, not the new one:
+        target.trust = match target.trust {
+            Trust::Allowlisted | Trust::Pinned | Trust::Rotated => Trust::Rotated,
+            _ if self.addr_cache.last_good(&new).is_some() => Trust::Seen,
+            _ => Trust::FirstContact,
+        };
+        target.peer = new;
+        // Queries for the old id don't count anymore:
+        target.queries.clear();
+        target.wait_for_query = false;
+        target.fast_path = None;
+        target.sources.clear();
+        target.dials.clear();
+        self.query_target(i);
+    }
+
+    /// Remember where we learned `addr` of `peer` from, if it is a target.
+    fn note_source(&mut self, peer: &PeerId, addr: &Multiaddr, source: AddrSource) {
+        for t in self.targets.iter_mut().filter(|t| &t.peer == peer) {
+            t.sources.entry(addr.clone()).or_insert(source);
+        }
+    }
+
+    /// Giving up on `self.targets[i]`: Tell what was tried, on stderr and in `dial_report_file`.
+    fn report_dial_failure(&self, i: usize) {
+        let target = &self.targets[i];
+        let discovery = &target.discovery;
+        let mut report = DialReport::new(&target.peer, discovery.attempts(), discovery.elapsed());
+        for (addr, failure) in &target.dials {
+            let source = target.sources.get(addr).cloned().unwrap_or(AddrSource::Cache);
+            report.push(addr, source, *failure);
+        }
+        eprintln!("p2shd: {}", report);
+        match report.save(&self.dial_report_file) {
+            Ok(()) => eprintln!("p2shd: Details in {}.", self.dial_report_file.display()),
+            Err(e) => log::warn!("{:#}", e),
+        }
+    }
+
+    fn is_ignored(&self, peer_id: &PeerId) -> bool {
+        self.ignore.is_ignored(peer_id) && !self.targets.iter().any(|t| &t.peer == peer_id)
+    }
+
+    fn is_blocked(&self, peer_id: &PeerId) -> bool {
+        self.blocklist
+            .read()
+            .expect("Blocklist lock poisoned.")
+            .is_peer_blocked(peer_id)
+    }
+
+    /// Persist records, address cache and routing table, failing to do so is not fatal.
+    fn save_state(&mut self) {
+        if let Err(e) = self.kad.store_mut().flush() {
+            log::warn!("{:#}", e);
+        }
+        if let Err(e) = self.addr_cache.save() {
+            log::warn!("{:#}", e);
+        }
+        if let Err(e) = self.routing_table.save() {
+            log::warn!("{:#}", e);
+        }
+        if let Err(e) = self.blocklist.write().expect("Blocklist lock poisoned.").save() {
+            log::warn!("{:#}", e);
+        }
+    }
+
+    /// Newly found addresses of `peer_id` via mDNS or the DHT: Queue it for identify.
+    fn discovered(&mut self, peer_id: &PeerId) {
+        let target = self.targets.iter().any(|t| &t.peer == peer_id);
+        self.identify_pool.discovered(peer_id, target);
+        self.wake_on_found(peer_id);
+    }
+
+    /// Wake if the given peer_id is one of our targets.
     ///
     /// Clearing the waker afterwards (only one
     /// wake).
     fn wake_on_found(&mut self, peer_id: &PeerId) {
-        if *peer_id == self.remote_peer {
+        if self.targets.iter().any(|t| &t.peer == peer_id) {
             match mem::replace(&mut self.waker, None) {
                 None => (),
                 Some(w) => w.wake(),
@@ -190,13 +1722,21 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for P2shd {
     fn inject_event(&mut self, event: MdnsEvent) {
         if let MdnsEvent::Discovered(list) = event {
             for (peer_id, multiaddr) in list {
-                log::trace!(
-                    "MDNS, discovered peer {} with address {}!",
-                    peer_id, multiaddr
-                );
+                if self.is_blocked(&peer_id) || self.is_ignored(&peer_id) {
+                    continue;
+                }
+                if self.log_sampler.sample("mdns discovered") {
+                    log::trace!(
+                        "MDNS, discovered peer {} with address {}!",
+                        peer_id, multiaddr
+                    );
+                }
+                events::record(format!("mdns: discovered {} at {}", peer_id, sanitize_addr(&multiaddr)));
+                self.note_source(&peer_id, &multiaddr, AddrSource::Mdns);
+                self.addr_cache.insert(peer_id.clone(), multiaddr.clone());
                 self.kad.add_address(&peer_id, multiaddr);
                 self.kad.bootstrap();
-                self.wake_on_found(&peer_id);
+                self.discovered(&peer_id);
             }
         }
     }
@@ -205,16 +1745,78 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for P2shd {
 impl NetworkBehaviourEventProcess<KademliaEvent> for P2shd {
     // Called when `kademlia` produces an event.
     fn inject_event(&mut self, message: KademliaEvent) {
+        if let KademliaEvent::Discovered { peer_id, .. } = &message {
+            if self.is_ignored(peer_id) {
+                return;
+            }
+        }
+        events::record(format!("kad: {}", kad_event_summary(&message)));
         match message {
             KademliaEvent::Discovered {
                 peer_id,
                 addresses,
                 ty,
             } => {
-                log::trace!("Discovered peer: {}", peer_id);
-                log::trace!("Addresses of that peer: {:?}", addresses);
-                log::trace!("Connection status: {:?}", ty);
-                self.wake_on_found(&peer_id);
+                if self.log_sampler.sample("kad discovered") {
+                    log::trace!("Discovered peer: {}", peer_id);
+                    log::trace!("Addresses of that peer: {:?}", addresses);
+                    log::trace!("Connection status: {:?}", ty);
+                }
+                if self.is_blocked(&peer_id) {
+                    return;
+                }
+                for a in addresses {
+                    self.note_source(&peer_id, &a, AddrSource::Dht);
+                    self.addr_cache.insert(peer_id.clone(), a);
+                }
+                self.discovered(&peer_id);
+            }
+            KademliaEvent::GetRecordResult(Ok(ok)) => {
+                for record in &ok.records {
+                    self.import_addr_record(record);
+                    self.import_rotation(record);
+                    self.answer_profile(record);
+                }
+                // The query is over, still waiting means none of them was valid:
+                if let Some(record) = ok.records.first() {
+                    self.profile_not_found(&record.key, true);
+                }
+                let mut blocklist = self.blocklist.write().expect("Blocklist lock poisoned.");
+                for record in ok.records {
+                    if let Some(publisher) = blocklist::publisher_of(&record.key) {
+                        if let Err(e) = blocklist.import(&publisher, &record.value) {
+                            log::warn!("{:#}", e);
+                        }
+                    }
+                }
+            }
+            KademliaEvent::GetRecordResult(Err(e)) => {
+                log::debug!("Fetching record failed: {:?}", e);
+                self.profile_not_found(e.key(), false);
+            }
+            KademliaEvent::GetClosestPeersResult(Ok(ok)) => self.closest_peers_done(&ok.key),
+            KademliaEvent::GetClosestPeersResult(Err(GetClosestPeersError::Timeout { key, .. })) => {
+                self.closest_peers_done(&key)
+            }
+            KademliaEvent::BootstrapResult(Err(e)) => {
+                log::debug!("Bootstrap failed: {:?}", e);
+                // Bootstrap servers might have been rotated:
+                self.resolve_dnsaddr_bootstrap();
+            }
+            KademliaEvent::RoutingUpdated {
+                peer,
+                addresses,
+                old_peer,
+            } => {
+                if self.log_sampler.sample("routing updated") {
+                    log::trace!("Routing table updated with peer: {}", peer);
+                }
+                if let Some(old) = old_peer {
+                    self.routing_table.remove(&old);
+                }
+                if !self.is_ignored(&peer) {
+                    self.routing_table.update(peer, addresses.iter().cloned().collect());
+                }
             }
             _ => { log::debug!("Kademlia event: {:?}", message);
             }
@@ -222,6 +1824,327 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for P2shd {
     }
 }
 
+impl NetworkBehaviourEventProcess<PingEvent> for P2shd {
+    // Called when `ping` produces an event.
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                if self.targets.iter().any(|t| t.peer == event.peer) {
+                    log::info!("Round trip time to {}: {:?}", event.peer, rtt);
+                } else if self.log_sampler.sample("round trip time") {
+                    log::debug!("Round trip time to {}: {:?}", event.peer, rtt);
+                }
+                self.rtts.insert(event.peer, rtt);
+            }
+            Ok(PingSuccess::Pong) => (),
+            Err(e) => {
+                events::record(format!("ping: {} failed: {}", event.peer, e));
+                log::debug!("Ping to {} failed: {}", event.peer, e);
+                self.rtts.remove(&event.peer);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<TunnelEvent> for P2shd {
+    // Called when `tunnel` produces an event.
+    fn inject_event(&mut self, event: TunnelEvent) {
+        match event {
+            TunnelEvent::Dialed { peer, addr, failure } => {
+                self.addr_cache.record_dial(self.nat, &addr, failure.is_none());
+                if let Some(f) = failure {
+                    prometheus::dial_failed(f);
+                }
+                for t in self.targets.iter_mut().filter(|t| t.peer == peer) {
+                    if let Some(span) = &mut t.tunnel_span {
+                        match failure {
+                            Some(f) => span.event(format!("dial {} failed: {}", sanitize_addr(&addr), f)),
+                            None => span.event(format!("dial {} succeeded", sanitize_addr(&addr))),
+                        }
+                    }
+                    t.dials.push((addr.clone(), failure));
+                }
+            }
+            TunnelEvent::Inbound { peer, stream } if self.shutting_down => {
+                log::debug!("Shutting down, dropping tunnel from {}", peer);
+                drop(stream);
+            }
+            TunnelEvent::Inbound { peer, mut stream } => {
+                events::record(format!("tunnel: inbound from {}", peer));
+                let sshd = self.sshd;
+                let allow_forwarding = self.allow_forwarding;
+                let sshd_ports = self.sshd_ports.clone();
+                let (ssh_timeouts, forward_timeouts) = (self.ssh_timeouts, self.forward_timeouts);
+                let opener = self.opener.clone();
+                let vpn_addr = match self.vpn_addrs.get(&peer) {
+                    Some(addr) => Ok(*addr),
+                    None if self.vpn_addrs.is_empty() => Err("vpn not enabled"),
+                    None => Err("vpn not allowed"),
+                };
+                let vpn_active = self.vpn_active.clone();
+                let active_tunnels = self.active_tunnels.clone();
+                let advertise_resources = self.advertise_resources;
+                let banner = self.banner.clone();
+                let authorized = self.authorized_peers.as_ref().map_or(true, |a| a.contains(&peer));
+                let linked = self.linked_devices.contains(&peer);
+                let synced_book_file = self.synced_book_file.clone();
+                let local_peer = self.local_peer.clone();
+                let services: Vec<_> =
+                    self.services.iter().filter(|s| s.is_allowed(&peer)).cloned().collect();
+                // Only the peer we asked to listen may send connections back:
+                let reverse = if self.session_peer() == Some(&peer) {
+                    self.reverse_forwards.clone()
+                } else {
+                    Vec::new()
+                };
+                // Not even telling which services or ssh port there are, and
+                // not waiting for the request either:
+                if !authorized && reverse.is_empty() {
+                    log::info!("Rejecting tunnel from {}, not in authorized_peers.", peer);
+                    task::spawn(async move {
+                        let reject = tunnel::reject(&mut stream, "not authorized");
+                        let _ = async_std::future::timeout(tunnel::REQUEST_TIMEOUT, reject).await;
+                    });
+                    return;
+                }
+                active_tunnels.fetch_add(1, Ordering::SeqCst);
+                task::spawn(async move {
+                    let start = Instant::now();
+                    let (request, context) = match tunnel::read_traced_request(&mut stream).await {
+                        Ok((request, context)) => (Ok(request), context),
+                        Err(e) => (Err(e), None),
+                    };
+                    let span = context.and_then(|c| Span::start("serve tunnel", Kind::Server, Some(&c)));
+                    let span = span.map(|mut span| {
+                        span.set("peer", &peer);
+                        if let Ok(request) = &request {
+                            span.set("request", request);
+                        }
+                        span
+                    });
+                    // Quick requests like banners would skew session durations:
+                    let session = match &request {
+                        Ok(Request::Ssh { .. })
+                        | Ok(Request::Tcp { .. })
+                        | Ok(Request::Listen { .. })
+                        | Ok(Request::Reverse { .. })
+                        | Ok(Request::Forwards(_))
+                        | Ok(Request::Service { .. })
+                        | Ok(Request::Vpn) => true,
+                        _ => false,
+                    };
+                    let result = match (request, sshd) {
+                        (Ok(Request::Reverse { addr }), _) => {
+                            match reverse.iter().find(|f| f.listen == addr) {
+                                Some(f) => {
+                                    forward::serve_tcp(stream, &f.host, f.port, Timeouts::default()).await
+                                }
+                                None => tunnel::reject(&mut stream, "no such forwarding").await,
+                            }
+                        }
+                        // Not even telling which services or ssh port there are:
+                        (Ok(_), _) if !authorized => {
+                            log::info!("Rejecting tunnel from {}, not in authorized_peers.", peer);
+                            tunnel::reject(&mut stream, "not authorized").await
+                        }
+                        (Ok(Request::Service { name }), Some(_)) => {
+                            match services.into_iter().find(|s| s.name == name) {
+                                Some(s) => {
+                                    log::info!("Connecting {} to service '{}'", peer, name);
+                                    let host = s.addr.ip().to_string();
+                                    forward::serve_tcp(stream, &host, s.addr.port(), s.timeouts).await
+                                }
+                                None => tunnel::reject(&mut stream, "no such service").await,
+                            }
+                        }
+                        (Ok(Request::Vpn), Some(_)) => match vpn_addr {
+                            Err(reason) => tunnel::reject(&mut stream, reason).await,
+                            Ok(addr) if !vpn_active.lock().unwrap().insert(addr) => {
+                                tunnel::reject(&mut stream, "vpn link already in use").await
+                            }
+                            Ok(addr) => {
+                                let result = match vpn::Tun::create(addr).await {
+                                    Ok(tun) => {
+                                        log::info!("VPN link from {} on {}", peer, tun.name());
+                                        match tunnel::accept(&mut stream, &Timeouts::default()).await {
+                                            Ok(()) => vpn::run(stream, tun).await,
+                                            Err(e) => Err(e),
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let _ = tunnel::reject(&mut stream, "creating interface failed").await;
+                                        Err(e)
+                                    }
+                                };
+                                vpn_active.lock().unwrap().remove(&addr);
+                                result
+                            }
+                        },
+                        (Ok(Request::Resources), Some(_)) if advertise_resources => {
+                            let lines = resources::gather(&services).await;
+                            forward::serve_lines(stream, lines).await
+                        }
+                        (Ok(Request::Resources), Some(_)) => {
+                            tunnel::reject(&mut stream, "resources not advertised").await
+                        }
+                        (Ok(Request::Banner), Some(_)) => match banner {
+                            None => tunnel::reject(&mut stream, "no banner").await,
+                            // Read on each request, so edits apply right away:
+                            Some(path) => match async_std::fs::read_to_string(&path).await {
+                                Ok(text) => {
+                                    forward::serve_lines(stream, text.lines().map(String::from).collect()).await
+                                }
+                                Err(e) => {
+                                    log::warn!("Reading banner {} failed: {}", path.display(), e);
+                                    tunnel::reject(&mut stream, "no banner").await
+                                }
+                            },
+                        },
+                        (Ok(Request::Sync), Some(_)) if linked => {
+                            log::info!("Syncing address book with {}", peer);
+                            book_sync::serve(stream, synced_book_file, local_peer).await
+                        }
+                        (Ok(Request::Sync), Some(_)) => tunnel::reject(&mut stream, "not a linked device").await,
+                        (Ok(Request::Services), Some(_)) => {
+                            let names = services.into_iter().map(|s| s.name).collect();
+                            forward::serve_lines(stream, names).await
+                        }
+                        // Picking any port would be forwarding to any local service:
+                        (Ok(Request::Ssh { port: Some(port) }), Some(sshd))
+                            if !allow_forwarding && port != sshd.port() && !sshd_ports.contains(&port) =>
+                        {
+                            tunnel::reject(&mut stream, "port not allowed").await
+                        }
+                        (Ok(Request::Ssh { port }), Some(mut sshd)) => {
+                            if let Some(port) = port {
+                                sshd.set_port(port);
+                            }
+                            ssh::serve(stream, sshd, ssh_timeouts).await
+                        }
+                        (Ok(Request::Tcp { host, port }), Some(_)) if allow_forwarding => {
+                            log::info!("Forwarding tunnel from {} to {}:{}", peer, host, port);
+                            forward::serve_tcp(stream, &host, port, forward_timeouts).await
+                        }
+                        (Ok(Request::Listen { addr }), Some(_)) if allow_forwarding => {
+                            forward::serve_listen(stream, addr, peer.clone(), opener, forward_timeouts)
+                                .await
+                        }
+                        (Ok(Request::Forwards(entries)), Some(_)) => {
+                            forward::serve_forwards(
+                                stream,
+                                entries,
+                                allow_forwarding,
+                                peer.clone(),
+                                opener,
+                                forward_timeouts,
+                            )
+                            .await
+                        }
+                        (Ok(Request::Tcp { .. }), Some(_)) | (Ok(Request::Listen { .. }), Some(_)) => {
+                            tunnel::reject(&mut stream, "forwarding not allowed").await
+                        }
+                        (Ok(_), None) => tunnel::reject(&mut stream, "not serving").await,
+                        (Err(e), _) => Err(e),
+                    };
+                    active_tunnels.fetch_sub(1, Ordering::SeqCst);
+                    if session {
+                        prometheus::session_finished(start.elapsed());
+                    }
+                    trace::finish(span, &result);
+                    if let Err(e) = result {
+                        log::info!("Tunnel from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            TunnelEvent::Outbound { peer, id, stream, addr } => {
+                if let Some(reply) = self.forwarding.remove(&id) {
+                    let _ = reply.send(Ok(stream));
+                    return;
+                }
+                let i = match self.target_opening(id) {
+                    Some(i) => i,
+                    None => return,
+                };
+                if let Some(span) = self.targets[i].tunnel_span.take() {
+                    span.finish(None);
+                }
+                events::record(format!(
+                    "tunnel: opened to {} via {}",
+                    peer,
+                    addr.as_ref().map(sanitize_addr).unwrap_or_else(|| "inbound connection".into())
+                ));
+                let host = addr.as_ref().and_then(direct_host);
+                if let Some(addr) = addr {
+                    self.addr_cache.set_last_good(peer.clone(), addr);
+                }
+                if self.wait_only {
+                    // Dropping the stream closes the tunnel, it is not needed:
+                    return self.peer_online(i);
+                }
+                if let Some(ssh) = self.targets[i].mosh.clone() {
+                    match host {
+                        // mosh-server gets started via its own tunnel, this one is not needed:
+                        Some(host) => return self.start_mosh_session(i, ssh, host),
+                        None => log::warn!(
+                            "No direct address of {} known, which mosh needs. Running ssh instead.",
+                            peer
+                        ),
+                    }
+                }
+                self.start_session(i, stream);
+            }
+            TunnelEvent::Failed {
+                peer,
+                id,
+                error,
+                unsupported,
+                addr,
+            } => {
+                if let Some(reply) = self.forwarding.remove(&id) {
+                    log::info!("Opening forwarding tunnel to {} failed: {}", peer, error);
+                    let _ = reply.send(Err(async_io::Error::new(async_io::ErrorKind::Other, error)));
+                    return;
+                }
+                let i = match self.target_opening(id) {
+                    Some(i) => i,
+                    None => return,
+                };
+                events::record(format!("tunnel: opening to {} failed: {}", peer, error));
+                if let Some(span) = self.targets[i].tunnel_span.take() {
+                    span.finish(Some(error.to_string()));
+                }
+                if unsupported && self.wait_only {
+                    // Authenticated connection, so it is up, just without tunnel support:
+                    return self.peer_online(i);
+                }
+                if unsupported && !self.stdio {
+                    // We are connected to `addr` and the connection got authenticated, so the
+                    // machine there really is `peer`:
+                    if let Some(host) = addr.as_ref().and_then(direct_host) {
+                        log::warn!(
+                            "{} does not support tunnels, falling back to ssh to verified address {}.",
+                            peer, host
+                        );
+                        self.start_direct_session(i, host);
+                        return;
+                    }
+                }
+                log::info!("Opening tunnel to {} failed: {}, resolving again ...", peer, error);
+                self.targets[i].session = Session::Idle;
+                if self.query_target(i) {
+                    self.targets[i].wait_for_query = true;
+                } else {
+                    // Out of attempts: Redialing the addresses we know would just fail again:
+                    self.targets[i].exhausted = true;
+                    if let Some(w) = self.waker.take() {
+                        w.wake();
+                    }
+                }
+            }
+        }
+    }
+}
+
 impl NetworkBehaviourEventProcess<IdentifyEvent> for P2shd {
     // Called when `kademlia` produces an event.
     fn inject_event(&mut self, message: IdentifyEvent) {
@@ -234,44 +2157,99 @@ impl NetworkBehaviourEventProcess<IdentifyEvent> for P2shd {
                 /// The address observed by the peer for the local node.
                 observed_addr,
             } => {
-                log::info!("Identified peer: {}", &peer_id);
+                self.identify_pool.finished(&peer_id);
+                if self.ignore.check_agent(&peer_id, &info.agent_version) && self.is_ignored(&peer_id) {
+                    self.routing_table.remove(&peer_id);
+                    return;
+                }
+                log::info!("Identified peer: {} ({}, {})", &peer_id, info.agent_version, info.protocol_version);
+                events::record(format!(
+                    "identify: {} ({}, {}) listening on {}",
+                    peer_id,
+                    info.agent_version,
+                    info.protocol_version,
+                    info.listen_addrs.iter().map(sanitize_addr).collect::<Vec<_>>().join(", ")
+                ));
+                if self.is_blocked(&peer_id) {
+                    return;
+                }
+                let theirs = identify_major(&info.protocol_version);
+                // Still worth knowing for routing, tunnel protocols get negotiated anyway:
+                if theirs.is_some()
+                    && theirs != identify_major(&self.identify_protocol)
+                    && self.log_sampler.sample_for("incompatible protocol", &peer_id)
+                {
+                    log::warn!(
+                        "Peer {} speaks protocol version {}, ours is {}. Things might not work.",
+                        peer_id, info.protocol_version, self.identify_protocol
+                    );
+                }
                 for a in &info.listen_addrs {
                     log::info!("  Listen addr for that peer: {:?}", a);
                 }
                 log::info!("  Observed addr: {:?}", &observed_addr);
+                if self.observed_by.len() < MAX_OBSERVED_ADDRS || self.observed_by.contains_key(&observed_addr) {
+                    self.observed_by.entry(observed_addr.clone()).or_default().insert(peer_id.clone());
+                }
                 let valid_addrs = info.listen_addrs.into_iter().filter(|a| !a.to_string().contains("127.0.0.1"));
                 for addr in valid_addrs {
+                    self.note_source(&peer_id, &addr, AddrSource::Identify);
+                    self.addr_cache.insert(peer_id.clone(), addr.clone());
                     self.kad.add_address(&peer_id, addr);
                 }
                 // self.inject_new_external_addr(&observed_addr);
             }
+            IdentifyEvent::Error { peer_id, error } => {
+                log::debug!("Identifying {} failed: {:?}", peer_id, error);
+                self.identify_pool.finished(&peer_id);
+            }
             _ => { log::debug!("Kademlia event: {:?}", message);
             }
         }
     }
 }
 
-/// Get host addr (dns name, IPv4, IPv6 address) from the given multiaddr as `String` ready to be
-/// passed to ssh for example.
-fn host_addr_from_multiaddr(m_addr: &Multiaddr) -> Result<String> {
-    let ips = m_addr
-        .iter()
-        .filter_map(to_host_addr);
-    match ips.collect::<Vec<String>>().as_slice() {
-        [] => Err(error::P2shd::NoIPAddrInMultiaddr(m_addr.clone())),
-        [a] => Ok(a.clone()),
-        _ => Err(error::P2shd::MultipleIPAddrInMultiaddr(m_addr.clone())),
+/// Short description of a Kademlia event, without any addresses.
+fn kad_event_summary(event: &KademliaEvent) -> String {
+    match event {
+        KademliaEvent::Discovered { peer_id, ty, .. } => format!("discovered {} ({:?})", peer_id, ty),
+        KademliaEvent::RoutingUpdated { peer, .. } => format!("routing updated {}", peer),
+        KademliaEvent::UnroutablePeer { peer } => format!("unroutable {}", peer),
+        KademliaEvent::BootstrapResult(Ok(_)) => "bootstrap ok".to_string(),
+        KademliaEvent::BootstrapResult(Err(e)) => format!("bootstrap failed: {:?}", e),
+        KademliaEvent::GetClosestPeersResult(Ok(ok)) => {
+            format!("get_closest_peers ok, {} peers", ok.peers.len())
+        }
+        KademliaEvent::GetClosestPeersResult(Err(e)) => format!("get_closest_peers failed: {:?}", e),
+        _ => "other".to_string(),
     }
 }
 
-fn to_host_addr(p: Protocol) -> Option<String> {
-    use Protocol::{*};
-    match p {
-        Dnsaddr(a)  => Some(format!("{}", a)),
-        Dns6(a) => Some(format!("{}", a)),
-        Dns4(a) => Some(format!("{}", a)),
-        Ip4(a)  => Some(format!("{}", a)),
-        Ip6(a)  => Some(format!("{}", a)),
+/// Host to ssh to directly, for an address we are connected to a peer at.
+///
+/// `None` for relayed addresses and DNS names, a name could resolve to a
+/// different machine for ssh than it did for us.
+fn direct_host(addr: &Multiaddr) -> Option<String> {
+    if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        return None;
+    }
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.to_string()),
+        Protocol::Ip6(ip) => Some(ip.to_string()),
         _ => None,
     }
 }
+
+/// Judge whether we are behind a NAT, by comparing the IPs peers observe us
+/// at with those we listen on (ports differ for outbound connections anyway).
+fn nat_status(listen_addrs: &[Multiaddr], external_addrs: &[Multiaddr]) -> control::Nat {
+    let ip = |a: &Multiaddr| a.iter().next().filter(|p| matches!(p, Protocol::Ip4(_) | Protocol::Ip6(_)));
+    let listen_ips: Vec<_> = listen_addrs.iter().filter_map(ip).collect();
+    if external_addrs.is_empty() {
+        control::Nat::Unknown
+    } else if external_addrs.iter().filter_map(ip).any(|a| listen_ips.contains(&a)) {
+        control::Nat::Public
+    } else {
+        control::Nat::Private
+    }
+}
@@ -2,7 +2,7 @@
 
 use thiserror::Error;
 
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
 
 /// Errors related to keypair serialization.
 #[derive(Error, Debug)]
@@ -14,8 +14,15 @@ pub enum P2shd {
 Such addresses are not yet supported by p2shd.")
     ]
     MultipleIPAddrInMultiaddr(Multiaddr),
-    #[error("Initializing mdns for LAN IP discovery failed.")]
+    #[error("Initializing mdns for LAN IP discovery failed. Use --no-mdns to run without it.")]
     MdnsInitialization(#[source] std::io::Error),
+    #[error("Finding the p2shd executable, for mosh's ssh command, failed.")]
+    CurrentExe(#[source] std::io::Error),
     #[error("Spawning ssh failed for address '{0}'")]
     SpawningSshFailed(String, #[source] std::io::Error),
+    #[error(
+"Peer {0} could not be found ({1} DHT queries in {2} seconds).
+Is it online? See --discovery-attempts and --discovery-timeout for retrying longer.")
+    ]
+    PeerNotFound(PeerId, u32, u64),
 }
@@ -0,0 +1,367 @@
+//! Blocklist of peers and address ranges we refuse to talk to.
+//!
+//! Besides our own entries, blocklists published by trusted peers can be
+//! subscribed to. Those are published as signed DHT records under
+//! `/p2shd/blocklist/<peer id>`, the signature is checked against the key of
+//! the peer id we subscribed to.
+//!
+//! Connections are rejected in the transport: Blocked addresses before any
+//! handshake (dials already before connecting, after resolving DNS names),
+//! blocked peers right after authentication.
+
+use anyhow::{Context as AnyhowContext, Result};
+use ipnet::IpNet;
+use libp2p::{
+    identity,
+    kad::record::Key,
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{lock_file, path_exists},
+    format_version::{self, FormatVersion},
+    sealed_state,
+    signed_record::Kind,
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// Blocklist shared between transport and behaviour.
+pub type SharedBlocklist = Arc<RwLock<Blocklist>>;
+
+/// A single blocklist entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    Peer(PeerId),
+    Network(IpNet),
+}
+
+impl FromStr for Entry {
+    type Err = error::Blocklist;
+
+    fn from_str(s: &str) -> std::result::Result<Entry, Self::Err> {
+        if let Ok(peer) = s.parse() {
+            return Ok(Entry::Peer(peer));
+        }
+        if let Ok(net) = s.parse() {
+            return Ok(Entry::Network(net));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Entry::Network(IpNet::from(ip)));
+        }
+        Err(error::Blocklist::InvalidEntry(s.into()))
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Peer(p) => write!(f, "{}", p),
+            Entry::Network(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Our own and imported blocklist entries.
+pub struct Blocklist {
+    /// Where to store the blocklist.
+    path: PathBuf,
+    peers: HashSet<PeerId>,
+    networks: Vec<IpNet>,
+    /// Peers whose published blocklists we honour.
+    subscriptions: HashSet<PeerId>,
+    /// Latest verified blocklists of `subscriptions`.
+    imported: HashMap<PeerId, List>,
+    /// Changes to our own entries and subscriptions not yet written to disk, replayed on top of
+    /// the stored list on save so concurrent changes by others are kept.
+    changes: Vec<Change>,
+    /// Whether there are changes not yet written to disk.
+    dirty: bool,
+}
+
+/// A change to our own entries or subscriptions.
+#[derive(Clone)]
+enum Change {
+    Block(Entry),
+    Unblock(Entry),
+    Subscribe(PeerId),
+    Unsubscribe(PeerId),
+}
+
+/// The entries of a blocklist, as stored and published.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct List {
+    peers: Vec<String>,
+    networks: Vec<String>,
+    /// When the list got published (Unix time in seconds), so older ones can't be replayed over
+    /// newer ones. Unset for our own list and lists published before there was this field.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    published: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// On disk representation of `Blocklist`.
+#[derive(Serialize, Deserialize, Default)]
+struct BlocklistFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    #[serde(flatten)]
+    own: List,
+    subscriptions: Vec<String>,
+    imported: HashMap<String, List>,
+}
+
+impl Blocklist {
+    /// Load the blocklist stored at `path`, a missing file results in an empty list.
+    pub fn load(path: PathBuf) -> Result<Blocklist> {
+        let exists = path_exists(&path).with_context(|| error::Blocklist::Read(path.clone()))?;
+        let file: BlocklistFile = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::Blocklist::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw).with_context(|| error::Blocklist::Decode(path.clone()))?
+        } else {
+            BlocklistFile::default()
+        };
+        Ok(Blocklist {
+            path,
+            peers: file.own.peers.iter().filter_map(|p| p.parse().ok()).collect(),
+            networks: file.own.networks.iter().filter_map(|n| n.parse().ok()).collect(),
+            subscriptions: file.subscriptions.iter().filter_map(|p| p.parse().ok()).collect(),
+            imported: file
+                .imported
+                .into_iter()
+                .filter_map(|(p, l)| Some((p.parse().ok()?, l)))
+                .collect(),
+            changes: Vec::new(),
+            dirty: false,
+        })
+    }
+
+    /// Load the blocklist, ready for sharing.
+    pub fn load_shared(path: PathBuf) -> Result<SharedBlocklist> {
+        Ok(Arc::new(RwLock::new(Blocklist::load(path)?)))
+    }
+
+    /// Add an entry to our own blocklist.
+    pub fn block(&mut self, entry: Entry) {
+        self.change(Change::Block(entry));
+    }
+
+    /// Remove an entry from our own blocklist, returns whether it was present.
+    pub fn unblock(&mut self, entry: &Entry) -> bool {
+        self.change(Change::Unblock(entry.clone()))
+    }
+
+    /// Honour the blocklist published by `peer`.
+    pub fn subscribe(&mut self, peer: PeerId) {
+        self.change(Change::Subscribe(peer));
+    }
+
+    /// Stop honouring the blocklist published by `peer`, returns whether we were subscribed.
+    pub fn unsubscribe(&mut self, peer: &PeerId) -> bool {
+        self.change(Change::Unsubscribe(peer.clone()))
+    }
+
+    /// Apply `change` and remember it for `save`, returns whether it changed anything.
+    fn change(&mut self, change: Change) -> bool {
+        let changed = self.apply(change.clone());
+        if changed {
+            self.changes.push(change);
+            self.dirty = true;
+        }
+        changed
+    }
+
+    /// Apply `change`, returns whether it changed anything.
+    fn apply(&mut self, change: Change) -> bool {
+        match change {
+            Change::Block(Entry::Peer(p)) => self.peers.insert(p),
+            Change::Block(Entry::Network(n)) => {
+                if self.networks.contains(&n) {
+                    return false;
+                }
+                self.networks.push(n);
+                true
+            }
+            Change::Unblock(Entry::Peer(p)) => self.peers.remove(&p),
+            Change::Unblock(Entry::Network(n)) => {
+                let len = self.networks.len();
+                self.networks.retain(|x| *x != n);
+                len != self.networks.len()
+            }
+            Change::Subscribe(p) => self.subscriptions.insert(p),
+            Change::Unsubscribe(p) => {
+                self.imported.remove(&p);
+                self.subscriptions.remove(&p)
+            }
+        }
+    }
+
+    pub fn subscriptions(&self) -> impl Iterator<Item = &PeerId> {
+        self.subscriptions.iter()
+    }
+
+    /// Our own entries.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.peers
+            .iter()
+            .cloned()
+            .map(Entry::Peer)
+            .chain(self.networks.iter().cloned().map(Entry::Network))
+    }
+
+    /// Number of entries imported from each subscription.
+    pub fn imported_counts(&self) -> impl Iterator<Item = (&PeerId, usize)> {
+        self.imported
+            .iter()
+            .map(|(p, l)| (p, l.peers.len() + l.networks.len()))
+    }
+
+    /// Whether the peer is blocked by us or any subscription.
+    pub fn is_peer_blocked(&self, peer: &PeerId) -> bool {
+        if self.peers.contains(peer) {
+            return true;
+        }
+        let peer = peer.to_base58();
+        self.imported.values().any(|l| l.peers.contains(&peer))
+    }
+
+    /// Whether the IP address of `addr` is blocked by us or any subscription.
+    ///
+    /// DNS names are not resolved, addresses without an IP are never blocked.
+    pub fn is_addr_blocked(&self, addr: &Multiaddr) -> bool {
+        match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => self.is_ip_blocked(&ip.into()),
+            Some(Protocol::Ip6(ip)) => self.is_ip_blocked(&ip.into()),
+            _ => false,
+        }
+    }
+
+    /// Whether there are any blocked networks at all, e.g. to skip resolving names for nothing.
+    pub fn has_networks(&self) -> bool {
+        !self.networks.is_empty() || self.imported.values().any(|l| !l.networks.is_empty())
+    }
+
+    /// Whether `ip` is blocked by us or any subscription.
+    pub fn is_ip_blocked(&self, ip: &IpAddr) -> bool {
+        let ip = *ip;
+        if self.networks.iter().any(|n| n.contains(&ip)) {
+            return true;
+        }
+        self.imported.values().any(|l| {
+            l.networks
+                .iter()
+                .filter_map(|n| n.parse::<IpNet>().ok())
+                .any(|n| n.contains(&ip))
+        })
+    }
+
+    /// Our own entries, signed with `key`, for publishing them in the DHT.
+    pub fn sign(&self, key: &identity::Keypair) -> Result<Vec<u8>> {
+        let list = List {
+            peers: self.peers.iter().map(|p| p.to_base58()).collect(),
+            networks: self.networks.iter().map(|n| n.to_string()).collect(),
+            published: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+        // Replays are caught by `published`, no need for an expiry:
+        KIND.sign(key, list, None)
+    }
+
+    /// Import a blocklist published by `publisher`, if we subscribed to it and the signature
+    /// checks out.
+    pub fn import(&mut self, publisher: &PeerId, raw: &[u8]) -> Result<()> {
+        if !self.subscriptions.contains(publisher) {
+            return Ok(());
+        }
+        let list: List = KIND.verify(publisher, raw)?;
+        if let Some(current) = self.imported.get(publisher) {
+            // Fetched again without having been republished:
+            if list.published == current.published {
+                return Ok(());
+            }
+            if list.published < current.published {
+                return Err(error::Import::Stale(publisher.clone()).into());
+            }
+        }
+        log::info!(
+            "Imported blocklist of {} with {} entries.",
+            publisher,
+            list.peers.len() + list.networks.len()
+        );
+        self.imported.insert(publisher.clone(), list);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Write the blocklist to disk, if there were any changes since the last save.
+    ///
+    /// Our changes are merged with changes saved by others since we loaded it (e.g. `p2shd
+    /// block` while `p2shd listen` runs), afterwards we hold the merged list.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let write_err = || error::Blocklist::Write(self.path.clone());
+        let _lock = lock_file(&self.path).with_context(write_err)?;
+        let mut stored = sealed_state::load_or_reset(self.path.clone(), Blocklist::load)?;
+        // Only taken over once written, so a failed write keeps them for the next try:
+        for change in &self.changes {
+            stored.apply(change.clone());
+        }
+        for (publisher, list) in &self.imported {
+            let newer = stored.imported.get(publisher).map_or(true, |s| s.published <= list.published);
+            if stored.subscriptions.contains(publisher) && newer {
+                stored.imported.insert(publisher.clone(), list.clone());
+            }
+        }
+        let file = BlocklistFile {
+            format: FORMAT,
+            own: List {
+                peers: stored.peers.iter().map(|p| p.to_base58()).collect(),
+                networks: stored.networks.iter().map(|n| n.to_string()).collect(),
+                published: 0,
+            },
+            subscriptions: stored.subscriptions.iter().map(|p| p.to_base58()).collect(),
+            imported: stored
+                .imported
+                .iter()
+                .map(|(p, l)| (p.to_base58(), l.clone()))
+                .collect(),
+        };
+        let encoded = serde_json::to_vec_pretty(&file).expect("Serializing blocklist can't fail.");
+        sealed_state::write(&self.path, &encoded).with_context(write_err)?;
+        *self = stored;
+        Ok(())
+    }
+}
+
+/// Published blocklists, see `signed_record`.
+const KIND: Kind = Kind::new("/p2shd/blocklist/", "Blocklist");
+
+/// DHT key the blocklist of `peer` is published under.
+pub fn record_key(peer: &PeerId) -> Key {
+    KIND.record_key(peer)
+}
+
+/// The publisher of a blocklist record, `None` if `key` is not a blocklist key.
+pub fn publisher_of(key: &Key) -> Option<PeerId> {
+    KIND.publisher_of(key)
+}
@@ -0,0 +1,25 @@
+//! Errors that can happen while managing the blocklist.
+
+use libp2p::PeerId;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to blocklist persistence and entries.
+#[derive(Error, Debug)]
+pub enum Blocklist {
+    #[error("Reading blocklist '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Invalid blocklist '{0}'.")]
+    Decode(PathBuf),
+    #[error("Writing blocklist '{0}' failed.")]
+    Write(PathBuf),
+    #[error("'{0}' is neither a peer id nor an IP network (like 10.0.0.0/8).")]
+    InvalidEntry(String),
+}
+
+/// Errors related to blocklists published by other peers.
+#[derive(Error, Debug)]
+pub enum Import {
+    #[error("Blocklist published by {0} is older than the one we have, ignoring it.")]
+    Stale(PeerId),
+}
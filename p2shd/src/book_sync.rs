@@ -0,0 +1,398 @@
+//! Syncing the address book between a user's own devices.
+//!
+//! Besides `[peers]` in `config.toml`, which p2shd never writes, names can
+//! be added via `p2shd peer add`. These are kept in `synced_peers.json` and
+//! exchanged with the `linked_devices`: On `p2shd sync <device>` and by
+//! `p2shd listen` every `SYNC_INTERVAL`, with linked devices it is connected
+//! to. Entries in `config.toml` take precedence over synced ones.
+//!
+//! Entries also carry the ssh host keys the user approved for the peer, see
+//! `trust`: Before each sync, keys newly pinned in `known_hosts` are taken
+//! over into our entries. After it, keys approved on other devices get pinned
+//! for peers that have none pinned yet. Differing pins are never replaced,
+//! they get reported instead.
+//!
+//! The synced book is a last-writer-wins map, a CRDT: Each entry carries
+//! when and on which device it was last changed, removals leave a tombstone.
+//! Merging keeps the newer version of each entry (ties broken by device id),
+//! so all devices end up with the same book, no matter in which order they
+//! sync.
+//!
+//! After the `sync` request got accepted, the opening side sends its book
+//! as one JSON line, the accepting side merges it and answers with the
+//! merged book, for the opening side to merge in turn.
+
+use anyhow::{Context as AnyhowContext, Result};
+use futures::{io::BufReader, prelude::*};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::{lock_file, path_exists},
+    format_version::{self, FormatVersion},
+    forward::Opener,
+    sealed_state,
+    trust,
+    tunnel::{self, Request, Timeouts, TunnelStream},
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 1);
+
+/// How often `p2shd listen` syncs with connected linked devices.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Books sent by peers may not be larger than this.
+const MAX_BOOK_SIZE: u64 = 1024 * 1024;
+
+/// A synced address book entry, the latest change to a name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncedEntry {
+    /// The peer's id, `None` if the name got removed.
+    pub id: Option<String>,
+    pub port: Option<u16>,
+    /// ssh host keys approved for the peer, as returned by `trust::pinned_host_keys`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_keys: Vec<String>,
+    /// Milliseconds since the Unix epoch, when the entry got changed.
+    pub updated: u64,
+    /// Peer id of the device the entry got changed on.
+    pub device: String,
+}
+
+impl SyncedEntry {
+    /// Later versions win merges.
+    fn version(&self) -> (u64, &str) {
+        (self.updated, &self.device)
+    }
+}
+
+/// On disk and wire representation of `SyncedBook`.
+#[derive(Serialize, Deserialize, Default)]
+struct BookFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    peers: BTreeMap<String, SyncedEntry>,
+}
+
+/// The synced part of the address book.
+pub struct SyncedBook {
+    path: PathBuf,
+    peers: BTreeMap<String, SyncedEntry>,
+}
+
+impl SyncedBook {
+    /// Load the book stored at `path`, a missing file results in an empty book.
+    pub fn load(path: PathBuf) -> Result<SyncedBook> {
+        let exists = path_exists(&path).with_context(|| error::BookSync::Read(path.clone()))?;
+        let file = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::BookSync::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw).with_context(|| error::BookSync::Decode(path.clone()))?
+        } else {
+            BookFile::default()
+        };
+        Ok(SyncedBook { path, peers: file.peers })
+    }
+
+    /// An empty book to be stored at `path`, without looking at what is there.
+    pub fn empty(path: PathBuf) -> SyncedBook {
+        SyncedBook {
+            path,
+            peers: BTreeMap::new(),
+        }
+    }
+
+    /// Write the book, merged with changes saved by others since we loaded it.
+    pub fn save(&mut self) -> Result<()> {
+        let write_err = || error::BookSync::Write(self.path.clone());
+        let _lock = lock_file(&self.path).with_context(write_err)?;
+        let stored = SyncedBook::load(self.path.clone())?;
+        self.merge(stored.peers);
+        sealed_state::write(&self.path, self.encode().as_bytes()).with_context(write_err)
+    }
+
+    /// Add `name` for `peer` or change it, on `device` (our peer id).
+    pub fn set(&mut self, name: &str, peer: &PeerId, port: Option<u16>, device: &PeerId) {
+        let id = peer.to_base58();
+        // Approved keys stay with the peer, not with the name:
+        let host_keys = match self.peers.get(name) {
+            Some(e) if e.id.as_ref() == Some(&id) => e.host_keys.clone(),
+            _ => Vec::new(),
+        };
+        self.change(name, Some(id), port, host_keys, device);
+    }
+
+    /// Remove `name`, on `device`. Whether there was such an entry.
+    pub fn remove(&mut self, name: &str, device: &PeerId) -> bool {
+        let existed = self.peers.get(name).map_or(false, |e| e.id.is_some());
+        if existed {
+            self.change(name, None, None, Vec::new(), device);
+        }
+        existed
+    }
+
+    /// Names currently in the book, with peer id and sshd port.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, PeerId, Option<u16>)> {
+        self.peers.iter().filter_map(|(name, e)| {
+            let id = e.id.as_ref()?.parse().ok()?;
+            Some((name.as_str(), id, e.port))
+        })
+    }
+
+    /// Merge a book received from another device, resolving to the number of changed names.
+    fn merge(&mut self, other: BTreeMap<String, SyncedEntry>) -> usize {
+        let mut changed = 0;
+        for (name, theirs) in other {
+            let newer = self.peers.get(&name).map_or(true, |ours| theirs.version() > ours.version());
+            if newer {
+                self.peers.insert(name, theirs);
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// Take over the host keys pinned for our entries' peers, as changed on `device` (our peer id).
+    ///
+    /// Keys last changed on another device are left alone, differing pins are reported. Resolves
+    /// to the number of changed entries.
+    fn take_pinned_host_keys(&mut self, device: &PeerId) -> usize {
+        let ours = device.to_base58();
+        let mut changed = Vec::new();
+        for (name, peer, _) in self.entries() {
+            let pinned = match trust::pinned_host_keys(&peer) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    log::debug!("Looking up host keys of {} failed: {:#}", peer, e);
+                    continue;
+                }
+            };
+            let entry = &self.peers[name];
+            if pinned.is_empty() || pinned == entry.host_keys {
+                continue;
+            }
+            if entry.host_keys.is_empty() || entry.device == ours {
+                changed.push((name.to_string(), pinned));
+            } else {
+                log::warn!(
+                    "Host keys pinned for {} ({}) differ from the ones approved on {}, see `p2shd trust refresh`.",
+                    name,
+                    peer,
+                    entry.device
+                );
+            }
+        }
+        let count = changed.len();
+        for (name, host_keys) in changed {
+            let entry = self.peers[&name].clone();
+            self.change(&name, entry.id, entry.port, host_keys, device);
+        }
+        count
+    }
+
+    /// Pin the host keys approved on other devices, for peers without pinned keys.
+    fn pin_host_keys(&self) {
+        for (name, peer, _) in self.entries() {
+            let entry = &self.peers[name];
+            match trust::pin_host_keys(&peer, &entry.host_keys) {
+                Ok(true) => log::info!("Pinned host keys of {} ({}) approved on {}", name, peer, entry.device),
+                Ok(false) => (),
+                Err(e) => log::warn!("Pinning host keys of {} failed: {:#}", peer, e),
+            }
+        }
+    }
+
+    fn change(
+        &mut self,
+        name: &str,
+        id: Option<String>,
+        port: Option<u16>,
+        host_keys: Vec<String>,
+        device: &PeerId,
+    ) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        // Always newer than what we have, even if our clock went backwards:
+        let updated = self.peers.get(name).map_or(now, |e| now.max(e.updated + 1));
+        let entry = SyncedEntry {
+            id,
+            port,
+            host_keys,
+            updated,
+            device: device.to_base58(),
+        };
+        self.peers.insert(name.into(), entry);
+    }
+
+    fn encode(&self) -> String {
+        let file = BookFile {
+            format: FORMAT,
+            peers: self.peers.clone(),
+        };
+        serde_json::to_string(&file).expect("Serializing address book can't fail.")
+    }
+}
+
+/// Sync the book at `path` with the linked `device`, resolving to the number of names we got.
+///
+/// `local` is our own peer id.
+pub async fn sync(path: PathBuf, local: PeerId, device: PeerId, opener: Opener) -> Result<usize> {
+    let mut book = SyncedBook::load(path)?;
+    let taken = book.take_pinned_host_keys(&local);
+    let mut stream = opener.open(device.clone(), &Request::Sync).await?;
+    stream.write_all(format!("{}\n", book.encode()).as_bytes()).await?;
+    stream.flush().await?;
+    let theirs = read_book(stream)
+        .await
+        .with_context(|| error::BookSync::Exchange(device))?;
+    let changed = book.merge(theirs);
+    book.pin_host_keys();
+    if changed + taken > 0 {
+        book.save()?;
+    }
+    Ok(changed)
+}
+
+/// Answer a `Request::Sync` of a linked device, with the book at `path`. `local` is our own
+/// peer id.
+pub async fn serve(mut stream: TunnelStream, path: PathBuf, local: PeerId) -> io::Result<()> {
+    let to_io = |e: anyhow::Error| io::Error::new(io::ErrorKind::Other, format!("{:#}", e));
+    let mut book = SyncedBook::load(path).map_err(to_io)?;
+    let taken = book.take_pinned_host_keys(&local);
+    tunnel::accept(&mut stream, &Timeouts::default()).await?;
+    let theirs = read_book(&mut stream).await?;
+    let changed = book.merge(theirs);
+    book.pin_host_keys();
+    if changed + taken > 0 {
+        book.save().map_err(to_io)?;
+    }
+    stream.write_all(format!("{}\n", book.encode()).as_bytes()).await?;
+    stream.close().await
+}
+
+/// Read a book sent as JSON line.
+async fn read_book<S: AsyncRead + Unpin>(stream: S) -> io::Result<BTreeMap<String, SyncedEntry>> {
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_BOOK_SIZE)).read_line(&mut line).await?;
+    let file: BookFile = serde_json::from_str(&line)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if file.format.major > FORMAT.major {
+        let msg = format!("address book in format {}, from a newer p2shd", file.format);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(file.peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: Option<&str>, updated: u64, device: &str) -> SyncedEntry {
+        SyncedEntry {
+            id: id.map(Into::into),
+            port: None,
+            host_keys: Vec::new(),
+            updated,
+            device: device.into(),
+        }
+    }
+
+    fn book(entries: &[(&str, SyncedEntry)]) -> SyncedBook {
+        let mut book = SyncedBook::empty(PathBuf::from("synced_peers.json"));
+        book.peers = entries.iter().map(|(n, e)| (n.to_string(), e.clone())).collect();
+        book
+    }
+
+    fn merged(
+        ours: &[(&str, SyncedEntry)],
+        theirs: &[(&str, SyncedEntry)],
+    ) -> BTreeMap<String, SyncedEntry> {
+        let mut ours = book(ours);
+        ours.merge(book(theirs).peers);
+        ours.peers
+    }
+
+    #[test]
+    fn merge_is_commutative() {
+        let a = [
+            ("laptop", entry(Some("a1"), 10, "dev-a")),
+            ("server", entry(Some("a2"), 30, "dev-a")),
+            ("old", entry(None, 5, "dev-a")),
+        ];
+        let b = [
+            ("laptop", entry(Some("b1"), 20, "dev-b")),
+            ("server", entry(Some("b2"), 30, "dev-b")),
+            ("phone", entry(Some("b3"), 1, "dev-b")),
+        ];
+        assert_eq!(merged(&a, &b), merged(&b, &a));
+        assert_eq!(merged(&a, &b).len(), 4);
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let theirs = book(&[("laptop", entry(Some("a1"), 10, "dev-a"))]);
+        let mut ours = book(&[("server", entry(Some("b1"), 20, "dev-b"))]);
+        assert_eq!(ours.merge(theirs.peers.clone()), 1);
+        let once = ours.peers.clone();
+        assert_eq!(ours.merge(theirs.peers.clone()), 0);
+        assert_eq!(ours.merge(once.clone()), 0);
+        assert_eq!(ours.peers, once);
+    }
+
+    #[test]
+    fn tombstones_beat_older_entries() {
+        let peers = merged(
+            &[("laptop", entry(Some("a1"), 10, "dev-a"))],
+            &[("laptop", entry(None, 11, "dev-b"))],
+        );
+        assert_eq!(peers["laptop"].id, None);
+        // Re-adding after the removal wins again:
+        let peers = merged(
+            &[("laptop", entry(None, 11, "dev-b"))],
+            &[("laptop", entry(Some("a1"), 12, "dev-a"))],
+        );
+        assert_eq!(peers["laptop"].id.as_deref(), Some("a1"));
+    }
+
+    #[test]
+    fn ties_are_broken_by_device() {
+        let a = [("laptop", entry(Some("a1"), 10, "dev-a"))];
+        let b = [("laptop", entry(Some("b1"), 10, "dev-b"))];
+        assert_eq!(merged(&a, &b)["laptop"].device, "dev-b");
+        assert_eq!(merged(&b, &a)["laptop"].device, "dev-b");
+    }
+
+    #[test]
+    fn host_keys_stay_with_the_peer() {
+        let random_peer = || PeerId::from(libp2p::identity::Keypair::generate_ed25519().public());
+        let (peer, other, device) = (random_peer(), random_peer(), random_peer());
+        let mut book = book(&[]);
+        book.set("nas", &peer, None, &device);
+        book.peers.get_mut("nas").unwrap().host_keys = vec!["ssh-ed25519 AAAA".into()];
+        book.set("nas", &peer, Some(2222), &device);
+        assert_eq!(book.peers["nas"].host_keys, vec!["ssh-ed25519 AAAA".to_string()]);
+        book.set("nas", &other, None, &device);
+        assert!(book.peers["nas"].host_keys.is_empty());
+    }
+
+    #[test]
+    fn removed_names_are_no_entries() {
+        let book = book(&[
+            ("gone", entry(None, 10, "dev-a")),
+            ("invalid", entry(Some("not a peer id"), 10, "dev-a")),
+        ]);
+        assert_eq!(book.entries().count(), 0);
+    }
+}
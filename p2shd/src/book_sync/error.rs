@@ -0,0 +1,22 @@
+//! Errors that can happen while syncing the address book.
+
+use libp2p::PeerId;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to the synced address book.
+#[derive(Error, Debug)]
+pub enum BookSync {
+    #[error("Reading synced address book '{0}' failed.")]
+    Read(PathBuf),
+    #[error(
+        "Invalid synced address book '{0}'.
+
+Syncing with a linked device again restores it, after deleting the file."
+    )]
+    Decode(PathBuf),
+    #[error("Writing synced address book '{0}' failed.")]
+    Write(PathBuf),
+    #[error("Exchanging address books with {0} failed.")]
+    Exchange(PeerId),
+}
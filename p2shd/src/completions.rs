@@ -0,0 +1,71 @@
+//! Shell completion scripts, `p2shd completions <shell>`.
+//!
+//! The scripts are generated from the command line definition by clap. For
+//! bash, zsh and fish they also complete the remote to connect to with the
+//! names currently in the address book. clap has no notion of such dynamic
+//! values, so the generated scripts get a hook appended that asks
+//! `p2shd complete-peers` on every completion.
+
+use clap::Shell;
+use std::io::{self, Write};
+use structopt::StructOpt;
+
+use crate::config::{Config, Opts};
+
+/// Name of the binary, as the scripts complete it.
+const BIN: &str = "p2shd";
+
+const BASH_PEERS: &str = r#"
+_p2shd_peers() {
+    _p2shd "$@"
+    if [[ ${COMP_CWORD} -eq 1 && ${COMP_WORDS[1]} != -* ]]; then
+        COMPREPLY+=( $(compgen -W "$(p2shd complete-peers 2>/dev/null)" -- "${COMP_WORDS[1]}") )
+    fi
+}
+
+complete -F _p2shd_peers -o bashdefault -o default p2shd
+"#;
+
+const ZSH_PEERS: &str = r#"_p2shd_peers() {
+    if (( CURRENT == 2 )) && [[ ${words[CURRENT]} != -* ]]; then
+        local -a peers
+        peers=(${(f)"$(p2shd complete-peers 2>/dev/null)"})
+        compadd -a peers
+    fi
+    _p2shd "$@"
+}
+
+_p2shd_peers "$@"
+"#;
+
+const FISH_PEERS: &str = r#"complete -c p2shd -n "__fish_use_subcommand" -f -a "(p2shd complete-peers 2>/dev/null)" -d "Address book entry"
+"#;
+
+/// Write the completion script for `shell` to `out`.
+pub fn generate(shell: Shell, out: &mut impl Write) -> io::Result<()> {
+    let mut script = Vec::new();
+    Opts::clap().gen_completions_to(BIN, shell, &mut script);
+    let mut script = String::from_utf8(script).expect("clap generates UTF-8.");
+    match shell {
+        Shell::Bash => script.push_str(BASH_PEERS),
+        Shell::Zsh => {
+            // Call our wrapper instead of clap's function directly:
+            let call = format!("_{} \"$@\"", BIN);
+            if let Some(pos) = script.rfind(&call) {
+                script.truncate(pos);
+            }
+            script.push_str(ZSH_PEERS);
+        }
+        Shell::Fish => script.push_str(FISH_PEERS),
+        _ => (),
+    }
+    out.write_all(script.as_bytes())
+}
+
+/// `p2shd complete-peers`: Names in the address book, one per line.
+pub fn peer_names(cfg: &Config) -> String {
+    cfg.address_book
+        .iter()
+        .map(|e| format!("{}\n", e.name))
+        .collect()
+}
@@ -2,16 +2,46 @@
 
 use anyhow::{Context as AnyhowContext, Result};
 use async_std::io;
+use ipnet::IpNet;
 
-use libp2p::{identity, identity::ed25519};
+use libp2p::{
+    identity::{self, ed25519, rsa, secp256k1},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
 use std::os::unix::fs::PermissionsExt;
 use std::{
+    collections::HashMap,
     fs,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 use structopt::StructOpt;
 
+use crate::{
+    backoff::RetryPolicy,
+    blocklist::Entry,
+    book_sync::SyncedBook,
+    dns::DnsProtocol,
+    forward::{self, PortForward},
+    ignore::IgnoreList,
+    log_format::LogFormat,
+    rotation::KnownRotations,
+    key,
+    metrics::{self, Exporter},
+    profile::Profile,
+    scheduler::{self, Job},
+    sealed_state,
+    transport::proxy::Proxy,
+    tunnel::Timeouts,
+};
+
 mod error;
+mod file;
+
+pub use file::{ConfigFile, ExposeEntry, WarmCache};
 
 #[derive(StructOpt, Debug)]
 /// Command line options.
@@ -25,20 +55,673 @@ pub struct Opts {
     #[structopt(long, parse(from_os_str))]
     key_file: Option<PathBuf>,
 
-    /// Peer id of the remote node to connect to. If not given, this program will just print our
-    /// own peer id and exit.
-    #[structopt()]
-    pub remote_id: Option<libp2p::PeerId>,
+    /// Type of the node key to generate, if there is none yet: `ed25519` or `secp256k1`.
+    /// Existing keys of any of these types (and RSA keys in PKCS#8 DER format) are detected
+    /// automatically.
+    #[structopt(long, default_value = "ed25519")]
+    pub key_type: KeyType,
+
+    /// Format of log output: `text`, or `json` for one object per line with fields like
+    /// `peer_id` and `multiaddr`, for log collectors.
+    #[structopt(long, default_value = "text")]
+    pub log_format: LogFormat,
 
     /// Port this daemon should listen on.
     /// By default some randome free port will be used.
     #[structopt(long, short)]
     pub port: Option<u16>,
+
+    /// Route all outbound TCP connections through this proxy.
+    /// Supported are `socks5://host:port` and `http://host:port` (HTTP CONNECT).
+    #[structopt(long)]
+    pub proxy: Option<Proxy>,
+
+    /// Dial destinations in this IP network (e.g. `10.0.0.0/8`) directly, instead of via `--proxy`.
+    /// Can be given multiple times. Loopback, link local and private LAN addresses are always
+    /// dialed directly.
+    #[structopt(long = "proxy-bypass", number_of_values = 1)]
+    pub proxy_bypass: Vec<IpNet>,
+
+    /// Persist the DHT records we hold for the network in `config_dir`, so they survive restarts.
+    /// By default they are kept in memory only.
+    #[structopt(long)]
+    pub persistent_records: bool,
+
+    /// Kademlia protocol id to use, e.g. `/p2shd/kad/1.0.0` for forming a DHT of p2shd nodes
+    /// only. Defaults to the standard libp2p Kademlia protocol.
+    #[structopt(long)]
+    pub kad_protocol: Option<String>,
+
+    /// Bootstrap node to join the DHT via, as full multiaddr including the peer id, e.g.
+    /// `/ip4/1.2.3.4/tcp/4001/p2p/12D3KooW...`, or as `/dnsaddr/bootstrap.example.org` to be
+    /// resolved via DNS. Can be given multiple times, replaces the bootstrap nodes from the
+    /// configuration file.
+    #[structopt(long = "bootstrap", number_of_values = 1)]
+    pub bootstrap: Vec<String>,
+
+    /// Don't use any bootstrap nodes, for LAN only operation.
+    #[structopt(long, conflicts_with = "bootstrap")]
+    pub no_bootstrap: bool,
+
+    /// DNS server to use instead of the system resolver. Can be given multiple times.
+    #[structopt(long = "dns-server", number_of_values = 1)]
+    pub dns_servers: Vec<IpAddr>,
+
+    /// How to talk to the DNS servers given via `--dns-server`: `udp`, `tls` (DNS over TLS) or
+    /// `https` (DNS over HTTPS).
+    #[structopt(long)]
+    pub dns_protocol: Option<DnsProtocol>,
+
+    /// Port of the DNS servers, defaults to the standard port of `--dns-protocol`.
+    #[structopt(long)]
+    pub dns_port: Option<u16>,
+
+    /// Name the DNS servers' TLS certificates are issued for, needed for `tls` and `https`.
+    #[structopt(long)]
+    pub dns_tls_name: Option<String>,
+
+    /// Identify protocol version to announce, defaults to `/p2shd/0.1.0`. Other p2shd nodes
+    /// announcing a different major version get warned about, but are still used.
+    #[structopt(long)]
+    pub identify_protocol: Option<String>,
+
+    /// Identify agent string to announce, for telling deployments apart. The p2shd version gets
+    /// appended automatically.
+    #[structopt(long)]
+    pub identify_agent: Option<String>,
+
+    /// Record the last N swarm and behaviour events (sanitized), dumping them on exit and on
+    /// crashes. See `p2shd debug dump-events`.
+    #[structopt(long)]
+    pub record_events: Option<usize>,
+
+    /// Export traces of session setup (discovery, dialing, tunnel requests) to the OpenTelemetry
+    /// collector at this OTLP/HTTP endpoint, e.g. `http://127.0.0.1:4318`. The trace context gets
+    /// passed on in tunnel requests, to peers speaking `/p2shd/tunnel/1.1.0` only.
+    #[structopt(long)]
+    pub otlp_traces: Option<String>,
+
+    /// Don't use mDNS for discovering peers in the LAN.
+    #[structopt(long)]
+    pub no_mdns: bool,
+
+    /// Give up finding the peer to connect to after this many DHT queries. Retries back off
+    /// exponentially, by default there is no limit.
+    #[structopt(long)]
+    pub discovery_attempts: Option<u32>,
+
+    /// Give up finding the peer to connect to after this many seconds.
+    #[structopt(long)]
+    pub discovery_timeout: Option<u64>,
+
+    /// Publish our own blocklist (signed) in the DHT, so other nodes can subscribe to it via
+    /// `p2shd auth subscribe`.
+    #[structopt(long)]
+    pub publish_blocklist: bool,
+
+    /// Dial all connections from the same local port (not the one we listen on), so NATs
+    /// keep mapping us to the same external port across reconnects and restarts. The port
+    /// gets picked on the first dial and is remembered in `sticky_port.json` in `config_dir`.
+    #[structopt(long)]
+    pub sticky_port: bool,
+
+    /// Only listen on and dial from the addresses of this network interface, e.g. `wlan0`.
+    #[structopt(long)]
+    pub bind_interface: Option<String>,
+
+    /// Connecting without `connect`, as `p2shd <peer>`.
+    #[structopt(flatten)]
+    pub connect: ConnectOpts,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+/// Options for connecting to peers, `p2shd connect` or just `p2shd <peer>`.
+#[derive(StructOpt, Debug, Default)]
+pub struct ConnectOpts {
+    /// Peer id or address book name of the remote node to connect to via ssh. If not given,
+    /// our own peer id gets printed, like `p2shd id` does. Use `p2shd listen` for making
+    /// this node reachable.
+    #[structopt()]
+    pub remote_id: Option<String>,
+
+    /// Further peer to connect to, in parallel. Can be given multiple times, this needs a
+    /// remote command (after `--`), as there is only one terminal, e.g.
+    /// `p2shd web1 --remote web2 -- uptime`.
+    #[structopt(long = "remote", number_of_values = 1)]
+    pub remotes: Vec<String>,
+
+    /// Bridge the tunnel to stdin/stdout instead of spawning ssh, for use as ssh
+    /// `ProxyCommand`, e.g. `ssh -o ProxyCommand="p2shd --stdio %h" <peer id>`.
+    #[structopt(long)]
+    pub stdio: bool,
+
+    /// Run mosh instead of ssh, for sessions surviving roaming and flaky networks. ssh
+    /// for starting mosh-server goes through the tunnel, mosh's UDP traffic goes directly to
+    /// the address the peer got connected at, which hence has to be reachable. Arguments after
+    /// `--` are the remote command.
+    #[structopt(long, conflicts_with = "stdio")]
+    pub mosh: bool,
+
+    /// Port of the ssh daemon on the remote machine. By default the remote p2shd decides (see
+    /// `p2shd listen --sshd`), for address book peers the `port` entry is used. The remote
+    /// p2shd has to allow the port, see `p2shd listen --sshd-port`.
+    #[structopt(long)]
+    pub ssh_port: Option<u16>,
+
+    /// Login name on the remote machine, passed to ssh as `-l`.
+    #[structopt(long, short)]
+    pub user: Option<String>,
+
+    /// Extra argument to pass to ssh, e.g. `--ssh-arg=-A` or `--ssh-arg=-i --ssh-arg=key`.
+    /// Can be given multiple times.
+    #[structopt(long = "ssh-arg", number_of_values = 1, allow_hyphen_values = true)]
+    pub ssh_args: Vec<String>,
+
+    /// Environment variable to pass to the remote shell (ssh `SendEnv`, wildcards allowed),
+    /// e.g. `--send-env LANG --send-env 'LC_*'`. Can be given multiple times, defaults to
+    /// `LANG`, `LC_*` and `COLORTERM`. `TERM` is always passed. The peer's sshd has to accept
+    /// them (`AcceptEnv`).
+    #[structopt(long = "send-env", number_of_values = 1)]
+    pub send_env: Vec<String>,
+
+    /// Everything after `--` is passed to ssh after the host, e.g. options or a command to run:
+    /// `p2shd <peer> -- -A uptime`.
+    #[structopt(last = true)]
+    pub trailing_ssh_args: Vec<String>,
+
+    /// Forward a local port through the remote peer instead of running ssh, e.g.
+    /// `-L 8080:localhost:80` or `-L 0.0.0.0:5432:db.lan:5432`. Can be given multiple
+    /// times. The peer has to run `p2shd listen --allow-forwarding`.
+    #[structopt(short = "L", long = "local-forward", number_of_values = 1)]
+    pub local_forwards: Vec<PortForward>,
+
+    /// Have the remote peer listen and forward connections back to us, e.g.
+    /// `-R 8080:localhost:3000` exposes our local port 3000 as port 8080 on the peer's
+    /// loopback interface. Like `-L` otherwise.
+    #[structopt(short = "R", long = "remote-forward", number_of_values = 1)]
+    pub remote_forwards: Vec<PortForward>,
+
+    /// Name this session, so forwardings can be added to and removed from it while it runs,
+    /// via `p2shd forward`. Its control socket is `sessions/<name>.sock` in `config_dir`.
+    #[structopt(long)]
+    pub session: Option<String>,
+
+    /// Find the peer and print what connecting would do (addresses by source, dialing order,
+    /// the ssh command), without connecting.
+    #[structopt(long, conflicts_with_all = &["local-forwards", "remote-forwards", "session"])]
+    pub dry_run: bool,
+}
+
+/// Subcommands, instead of connecting to `remote_id`.
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Connect to a peer via ssh, the same as `p2shd <peer>`.
+    ///
+    /// Connection options go after `connect`, e.g. `p2shd connect --user admin web1`.
+    Connect(ConnectOpts),
+    /// Run as daemon, making the local ssh daemon reachable via tunnels.
+    Listen {
+        /// Address of the local ssh daemon.
+        #[structopt(long, default_value = "127.0.0.1:22")]
+        sshd: SocketAddr,
+        /// Further local port clients may pick for ssh (`--ssh-port`), e.g. of a second sshd.
+        /// Can be given multiple times. With `--allow-forwarding` any port can be picked.
+        #[structopt(long = "sshd-port", number_of_values = 1)]
+        sshd_ports: Vec<u16>,
+        /// Let peers open TCP connections to anything reachable from this machine
+        /// (`p2shd socks`), not only to the ssh daemon.
+        #[structopt(long)]
+        allow_forwarding: bool,
+        /// Accept VPN links (`p2shd vpn`) from the peers listed in `[vpn]` of the
+        /// configuration file.
+        #[structopt(long)]
+        vpn: bool,
+        /// Tell peers our load, uptime and the health of exposed services (`p2shd resources`).
+        #[structopt(long)]
+        advertise_resources: bool,
+        /// Log a state dump if the swarm makes no progress for this many seconds (at least 5).
+        #[structopt(long)]
+        watchdog: Option<u64>,
+        /// Exit when the watchdog detects a hang, for a supervisor (e.g. systemd) to restart us.
+        #[structopt(long, requires = "watchdog")]
+        watchdog_exit: bool,
+        /// Serve `/status`, `/peers`, `/healthz` and `/metrics` via HTTP on this address,
+        /// e.g. `127.0.0.1:8042`. Not authenticated, keep it on localhost.
+        #[structopt(long)]
+        http_status: Option<SocketAddr>,
+        /// Serve the gRPC control API on this address, e.g. `127.0.0.1:50051`.
+        #[cfg(feature = "grpc")]
+        #[structopt(long)]
+        grpc: Option<SocketAddr>,
+    },
+    /// Show load, uptime and service health of a peer running `p2shd listen
+    /// --advertise-resources`.
+    Resources {
+        /// Peer id or name.
+        peer: String,
+    },
+    /// Set up a layer 3 link to a peer running `p2shd listen --vpn`, via TUN interfaces.
+    ///
+    /// Needs `CAP_NET_ADMIN` on both ends, Linux only.
+    Vpn {
+        /// Peer id or name of the peer to link to.
+        peer: String,
+        /// Address of our end, e.g. `10.99.0.2/30`.
+        #[structopt(long)]
+        address: IpNet,
+    },
+    /// Run a local SOCKS5 proxy, forwarding all connections through a peer.
+    ///
+    /// The peer has to run `p2shd listen --allow-forwarding`.
+    Socks {
+        /// Peer id or name of the peer to forward through.
+        peer: String,
+        /// Where to accept SOCKS5 connections.
+        #[structopt(long, default_value = "127.0.0.1:1080")]
+        listen: SocketAddr,
+    },
+    /// Connect to a service the peer exposes by name, e.g. `p2shd open laptop grafana --local 8080`.
+    ///
+    /// Without `--local` the service gets bridged to stdin/stdout, without a
+    /// service name the peer's services are listed.
+    Open {
+        /// Peer id or name of the peer exposing the service.
+        peer: String,
+        service: Option<String>,
+        /// Where to accept connections to the service: A port on the loopback interface or an
+        /// address.
+        #[structopt(long, parse(try_from_str = forward::parse_listen_addr))]
+        local: Option<SocketAddr>,
+    },
+    /// Wait until a peer is online, e.g. `p2shd wait workstation && p2shd workstation`.
+    ///
+    /// Exits successfully as soon as a tunnel to the peer could be opened.
+    Wait {
+        /// Peer id or name of the peer to wait for.
+        peer: String,
+        /// Give up after this many seconds, exiting with status 1.
+        #[structopt(long)]
+        timeout: Option<u64>,
+    },
+    /// Copy files from and to peers via scp, e.g. `p2shd cp notes.txt workstation:docs/`.
+    Cp {
+        /// Copy directories recursively.
+        #[structopt(short, long)]
+        recursive: bool,
+        /// Files to copy, remote ones as `[user@]<peer id or name>:<path>`.
+        #[structopt(required = true, min_values = 2)]
+        paths: Vec<String>,
+    },
+    /// Show the status of the running `p2shd listen`: Addresses, NAT, routing table, jobs, ...
+    Status {
+        /// Print the status as JSON, as the control API returns it.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// List the peers the running `p2shd listen` is connected to.
+    Peers,
+    /// Live view of the running `p2shd listen`: NAT, routing table, queries, peers, tunnels.
+    Dashboard {
+        /// Seconds between refreshes without any events.
+        #[structopt(long, default_value = "5")]
+        interval: u64,
+    },
+    /// Print our peer id, public key and, if `p2shd listen` is running, its addresses.
+    Id {
+        /// Also show the peer id as QR code, for scanning it on another device.
+        #[structopt(long)]
+        qr: bool,
+    },
+    /// Generate a node key and print its peer id, instead of having one generated on first use.
+    Keygen {
+        /// Type of the key: `ed25519` or `secp256k1`, `--key-type` if not given.
+        #[structopt(long = "type")]
+        key_type: Option<KeyType>,
+        /// Where to write the key, `--key-file` or `node_key` in the configuration directory
+        /// if not given.
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Overwrite an existing key file. The old identity is lost for good.
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Manage node keys.
+    Key(KeyCommand),
+    /// Debugging helpers.
+    Debug(DebugCommand),
+    /// Manage which peers are allowed to talk to us.
+    Auth(AuthCommand),
+    /// Deal with changed ssh host keys of peers.
+    Trust(TrustCommand),
+    /// Change the forwardings of a running session (started with `--session`), like ssh's
+    /// `~C` command line.
+    Forward(ForwardCommand),
+    /// Manage the address book entries synced between own devices.
+    Peer(PeerCommand),
+    /// Fetch and verify the public profile a peer publishes (see `[profile]`).
+    LookupProfile {
+        /// Peer id or name.
+        peer: String,
+    },
+    /// Sync the address book with a linked device (see `linked_devices`) right away.
+    Sync {
+        /// Peer id or name of the device.
+        device: String,
+    },
+    /// Print a completion script for `shell`, e.g. `p2shd completions bash > /etc/bash_completion.d/p2shd`.
+    Completions {
+        #[structopt(possible_values = &clap::Shell::variants(), case_insensitive = true)]
+        shell: clap::Shell,
+    },
+    /// Print the names in the address book, for the completion scripts.
+    #[structopt(setting = clap::AppSettings::Hidden)]
+    CompletePeers,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum PeerCommand {
+    /// Add a name to the synced address book, or change it.
+    Add {
+        name: String,
+        /// The peer's id.
+        peer: PeerId,
+        /// Port sshd listens on at the peer.
+        #[structopt(long)]
+        port: Option<u16>,
+    },
+    /// Remove a name from the synced address book, on all linked devices.
+    Remove { name: String },
+    /// List the whole address book, telling which entries are synced.
+    List,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum AuthCommand {
+    /// Reject connections from and to a peer id or IP network (e.g. `10.0.0.0/8`).
+    Block {
+        entry: Entry,
+    },
+    /// Remove an entry from the blocklist again.
+    Unblock {
+        entry: Entry,
+    },
+    /// Also honour the blocklist published by the given (trusted) peer.
+    Subscribe {
+        peer: PeerId,
+    },
+    /// Stop honouring the blocklist of the given peer.
+    Unsubscribe {
+        peer: PeerId,
+    },
+    /// Print blocklist entries and subscriptions.
+    List,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum TrustCommand {
+    /// Forget the pinned ssh host key of a peer, e.g. after it got reinstalled. Asks for
+    /// confirmation, the old key is kept in `known_hosts.quarantine` in `config_dir` and the
+    /// next session asks to confirm the new one.
+    Refresh {
+        /// Peer id or name.
+        peer: String,
+    },
+    /// Print the host keys moved to quarantine.
+    Quarantine,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ForwardCommand {
+    /// Add a forwarding, e.g. `p2shd forward add --session build 5432:db:5432`.
+    Add {
+        /// Name of the session, as given via `--session`.
+        #[structopt(long)]
+        session: String,
+        /// Have the peer listen, as for `-R`. Otherwise like `-L`.
+        #[structopt(long)]
+        remote: bool,
+        forward: PortForward,
+    },
+    /// Stop a forwarding added via `p2shd forward add`.
+    Remove {
+        /// Name of the session, as given via `--session`.
+        #[structopt(long)]
+        session: String,
+        /// Remove a remote (`-R`) forwarding.
+        #[structopt(long)]
+        remote: bool,
+        /// Its listen address: A port on the loopback interface or an address.
+        #[structopt(parse(try_from_str = forward::parse_listen_addr))]
+        listen: SocketAddr,
+    },
+    /// Print the forwardings of a session.
+    List {
+        /// Name of the session, as given via `--session`.
+        #[structopt(long)]
+        session: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DebugCommand {
+    /// Print the events recorded (with --record-events) by the last run.
+    DumpEvents,
+    /// Print what was tried on the last failed connect: Every address dialed, why dialing
+    /// failed and where the address was learned from.
+    DialReport {
+        /// Print the report as JSON.
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum KeyCommand {
+    /// Print public key, peer id (in all common encodings) and fingerprints of a key file.
+    Inspect {
+        /// The key file to inspect.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Encrypt a key file with a passphrase, asked for on every start (or read from
+    /// `P2SHD_KEY_PASSPHRASE`).
+    Encrypt {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Store an encrypted key file unencrypted again.
+    Decrypt {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Replace the node key by a new one (of `--key-type`), signing a record with the old
+    /// one that tells peers using the old peer id about the new one.
+    Rotate,
+}
+
+/// Type of a node key, see `Opts::key_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+impl FromStr for KeyType {
+    type Err = error::Keypair;
+
+    fn from_str(s: &str) -> std::result::Result<KeyType, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            _ => Err(error::Keypair::UnknownType(s.into())),
+        }
+    }
+}
+
+/// Prefix of identify protocol versions of p2shd nodes.
+pub const IDENTIFY_PROTOCOL_PREFIX: &str = "/p2shd/";
+
+/// Identify protocol version we announce by default.
+///
+/// Independent of the crate version, so releases keep talking to each other. Only the major
+/// version gets compared, bump it for incompatible changes only.
+pub const IDENTIFY_PROTOCOL: &str = "/p2shd/0.1.0";
+
+/// Major version of a p2shd identify protocol version, `None` for other protocols.
+pub fn identify_major(protocol: &str) -> Option<&str> {
+    let version = protocol.strip_prefix(IDENTIFY_PROTOCOL_PREFIX)?;
+    version.split('.').next()
+}
+
+/// Services served by `p2shd listen`, timeouts can be configured for each.
+pub const SERVICES: &[&str] = &["ssh", "forward"];
+
+/// Bootstrap nodes used if none are configured.
+const DEFAULT_BOOTSTRAP_NODES: &[&str] =
+    &["/ip4/81.223.86.162/tcp/22222/p2p/12D3KooWRmrTKbuneCQMHAjiGyUTZZu6NZP1XpTMuJJZotTdgYTm"];
+
+/// Name of the synced address book file in the configuration directory.
+const SYNCED_BOOK_FILE: &str = "synced_peers.json";
+
+/// Name of the file with the salt for deriving the state key from a passphrase.
+const STATE_SALT_FILE: &str = "state_salt";
+
+/// Name of the file with the random state key of `encrypt_state = "key_file"`.
+const STATE_KEY_FILE: &str = "state_key";
+
+/// Environment variables passed to remote shells if not configured otherwise.
+const DEFAULT_SEND_ENV: &[&str] = &["LANG", "LC_*", "COLORTERM"];
+
+/// A configured bootstrap entry.
+#[derive(Clone, Debug)]
+pub enum Bootstrap {
+    /// A node with a concrete address.
+    Node(BootstrapNode),
+    /// `/dnsaddr/<domain>`, resolved via the `_dnsaddr.<domain>` TXT records.
+    Dnsaddr {
+        domain: String,
+        /// Only use the resolved node with this id, if given as `/dnsaddr/<domain>/p2p/<id>`.
+        peer_id: Option<PeerId>,
+    },
+}
+
+/// A node to join the DHT via.
+#[derive(Clone, Debug)]
+pub struct BootstrapNode {
+    pub peer_id: PeerId,
+    /// The node's address, without the `/p2p/` part.
+    pub addr: Multiaddr,
+}
+
+/// A local service exposed by name, see `[expose]` in the configuration file.
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Peers allowed to use the service.
+    pub allow: Vec<PeerId>,
+    pub timeouts: Timeouts,
+}
+
+impl Service {
+    pub fn is_allowed(&self, peer: &PeerId) -> bool {
+        self.allow.contains(peer)
+    }
+}
+
+/// A peer in the address book.
+#[derive(Clone, Debug)]
+pub struct AddressBookEntry {
+    pub name: String,
+    pub peer_id: PeerId,
+    /// Remote sshd port.
+    pub port: Option<u16>,
+    /// Stay connected to this peer, when running as daemon.
+    pub keep_connected: bool,
 }
 
 /// Runtime configuration, read from config files and command line arguments.
 pub struct Config {
     pub opts: Opts,
+    /// Settings from `config.toml`, overridden by `opts`.
+    pub file: ConfigFile,
+    /// Validated bootstrap entries, from `opts`, `file` or the defaults.
+    pub bootstrap: Vec<Bootstrap>,
+    /// Validated address book, sorted by name.
+    pub address_book: Vec<AddressBookEntry>,
+    /// `remote_id` and `remotes` of `opts.connect`, resolved via the address book if necessary.
+    pub remote_peers: Vec<PeerId>,
+    /// The only peers allowed to connect to us, `None` if everybody is.
+    pub allowed_peers: Option<Vec<PeerId>>,
+    /// The only peers we serve tunnels to, `None` if everybody can have them.
+    pub authorized_peers: Option<Vec<PeerId>>,
+    /// Peers to leave out of discovery.
+    pub ignore: IgnoreList,
+    /// Our own other devices, to sync the address book with.
+    pub linked_devices: Vec<PeerId>,
+    /// Validated public profile, if we publish one.
+    pub profile: Option<Profile>,
+    /// Validated scheduled jobs.
+    pub jobs: Vec<Job>,
+    /// Validated metrics exporters.
+    pub metrics: Vec<Exporter>,
+    /// Validated exposed services, sorted by name.
+    pub services: Vec<Service>,
+    /// Peers we accept VPN links from, with the address of our end.
+    pub vpn_peers: HashMap<PeerId, IpNet>,
+}
+
+/// Combine the connect flags given before `connect` (`p2shd --mosh connect web1`) with the ones
+/// after it.
+///
+/// Flags that can be given multiple times add up, the others must not be given both before and
+/// after with different values.
+fn merge_connect_opts(
+    top: ConnectOpts,
+    sub: ConnectOpts,
+) -> std::result::Result<ConnectOpts, error::Connect> {
+    fn single<T: PartialEq>(
+        name: &'static str,
+        top: Option<T>,
+        sub: Option<T>,
+    ) -> std::result::Result<Option<T>, error::Connect> {
+        match (top, sub) {
+            (Some(a), Some(b)) if a != b => Err(error::Connect::Conflict(name)),
+            (a, b) => Ok(b.or(a)),
+        }
+    }
+    fn concat<T>(mut top: Vec<T>, sub: Vec<T>) -> Vec<T> {
+        top.extend(sub);
+        top
+    }
+    let merged = ConnectOpts {
+        remote_id: single("<remote-id>", top.remote_id, sub.remote_id)?,
+        remotes: concat(top.remotes, sub.remotes),
+        stdio: top.stdio || sub.stdio,
+        mosh: top.mosh || sub.mosh,
+        ssh_port: single("--ssh-port", top.ssh_port, sub.ssh_port)?,
+        user: single("--user", top.user, sub.user)?,
+        ssh_args: concat(top.ssh_args, sub.ssh_args),
+        send_env: concat(top.send_env, sub.send_env),
+        trailing_ssh_args: concat(top.trailing_ssh_args, sub.trailing_ssh_args),
+        local_forwards: concat(top.local_forwards, sub.local_forwards),
+        remote_forwards: concat(top.remote_forwards, sub.remote_forwards),
+        session: single("--session", top.session, sub.session)?,
+        dry_run: top.dry_run || sub.dry_run,
+    };
+    // Conflicts structopt only checks within each of the two sets:
+    if merged.stdio && merged.mosh {
+        return Err(error::Connect::Incompatible("--mosh", "--stdio"));
+    }
+    if merged.dry_run && (!merged.local_forwards.is_empty() || !merged.remote_forwards.is_empty()) {
+        return Err(error::Connect::Incompatible("--dry-run", "port forwardings"));
+    }
+    if merged.dry_run && merged.session.is_some() {
+        return Err(error::Connect::Incompatible("--dry-run", "--session"));
+    }
+    Ok(merged)
 }
 
 impl Config {
@@ -46,10 +729,186 @@ impl Config {
     ///
     /// This includes creating the configuration directory and a node key if
     /// necessary.
-    pub fn new(opts: Opts) -> Result<Config> {
+    pub fn new(mut opts: Opts) -> Result<Config> {
+        // `p2shd connect <peer>` is just the explicit form of `p2shd <peer>`:
+        if let Some(Command::Connect(connect)) = &mut opts.cmd {
+            let top = std::mem::take(&mut opts.connect);
+            opts.connect = merge_connect_opts(top, std::mem::take(connect))?;
+            opts.cmd = None;
+        }
         create_config_dir(&opts.config_dir)?;
+        let file = read_config_file(&opts.config_dir.join("config.toml"))?;
+
+        let bootstrap = if opts.no_bootstrap {
+            Vec::new()
+        } else if !opts.bootstrap.is_empty() {
+            parse_bootstrap_nodes(opts.bootstrap.iter())?
+        } else {
+            match &file.bootstrap {
+                Some(nodes) => parse_bootstrap_nodes(nodes.iter())?,
+                None => parse_bootstrap_nodes(DEFAULT_BOOTSTRAP_NODES.iter())?,
+            }
+        };
+        if bootstrap.is_empty() {
+            log::info!("No bootstrap nodes configured, relying on LAN discovery only.");
+        }
+
+        if let Some(source) = file.encrypt_state {
+            let (key_file, salt_file) = (opts.config_dir.join(STATE_KEY_FILE), opts.config_dir.join(STATE_SALT_FILE));
+            sealed_state::enable(source, key_file, salt_file);
+        }
+        // Sealed state may need a passphrase, so only load it for commands dealing with peers:
+        let uses_state = uses_state(opts.cmd.as_ref());
+        let synced = if uses_state {
+            SyncedBook::load(opts.config_dir.join(SYNCED_BOOK_FILE))?
+        } else {
+            SyncedBook::empty(opts.config_dir.join(SYNCED_BOOK_FILE))
+        };
+        // Access policies only resolve names of `config.toml`: A linked device must not be able
+        // to change who gets in by (re)binding a synced name.
+        let own_book = parse_address_book(&file)?;
+        let address_book = with_synced(own_book.clone(), &file, &synced);
+        let remote_peers = opts
+            .connect
+            .remote_id
+            .iter()
+            .chain(opts.connect.remotes.iter())
+            .map(|remote| lookup_peer(&address_book, remote))
+            .collect::<Result<Vec<_>>>()?;
+        if remote_peers.len() > 1 {
+            check_multiple_remotes(&opts)?;
+        }
+        if let Some(name) = &opts.connect.session {
+            check_session_name(name)?;
+        }
+        let allowed_peers = lookup_peers(&own_book, file.allowed_peers.as_deref())?;
+        let authorized_peers = lookup_peers(&own_book, file.authorized_peers.as_deref())?;
+        let linked_devices = lookup_peers(&own_book, file.linked_devices.as_deref())?.unwrap_or_default();
+        let ignore = IgnoreList::parse(file.ignore.as_deref().unwrap_or(&[]))?;
+        let jobs = scheduler::parse_jobs(file.jobs.as_deref().unwrap_or(&[]))?;
+        let metrics = metrics::parse_exporters(file.metrics.as_deref().unwrap_or(&[]))?;
+        let mut services = parse_services(&file, &own_book, authorized_peers.as_deref())?;
+        check_timeouts(&file, &services)?;
+        let profile = parse_profile(&file, &services)?;
+        let vpn_peers = parse_vpn_peers(&file, &own_book)?;
+
+        let mut cfg = Config {
+            opts,
+            file,
+            bootstrap,
+            address_book,
+            remote_peers,
+            allowed_peers,
+            authorized_peers,
+            ignore,
+            linked_devices,
+            profile,
+            jobs,
+            metrics,
+            services: Vec::new(),
+            vpn_peers,
+        };
+        for service in &mut services {
+            service.timeouts = cfg.timeouts(&service.name);
+        }
+        cfg.services = services;
+        if !uses_state {
+            return Ok(cfg);
+        }
+        // Peers that rotated their key, as learned from their rotation records:
+        let rotations = KnownRotations::load(cfg.get_known_rotations_file())?;
+        for peer in &mut cfg.remote_peers {
+            let current = rotations.follow(peer);
+            if current != *peer {
+                log::warn!("{} rotated its key to {}, consider updating your configuration.", peer, current);
+                *peer = current;
+            }
+        }
+        Ok(cfg)
+    }
+
+    /// Environment variables to pass to the remote shell, as ssh `SendEnv` patterns.
+    pub fn send_env(&self) -> Vec<String> {
+        if !self.opts.connect.send_env.is_empty() {
+            return self.opts.connect.send_env.clone();
+        }
+        self.file
+            .send_env
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SEND_ENV.iter().map(|v| v.to_string()).collect())
+    }
+
+    /// Port of the sshd at `remote`: `--ssh-port` or the address book entry of the peer.
+    pub fn ssh_port(&self, remote: &PeerId) -> Option<u16> {
+        self.opts.connect.ssh_port.or_else(|| {
+            self.address_book
+                .iter()
+                .find(|e| e.peer_id == *remote)
+                .and_then(|e| e.port)
+        })
+    }
+
+    /// Timeouts to enforce on tunnels to `service` (one of `SERVICES`).
+    pub fn timeouts(&self, service: &str) -> Timeouts {
+        let entry = self.file.timeouts.as_ref().and_then(|t| t.get(service));
+        Timeouts {
+            idle: entry.and_then(|e| e.idle).map(Duration::from_secs),
+            absolute: entry.and_then(|e| e.absolute).map(Duration::from_secs),
+        }
+    }
+
+    /// Address book peers the daemon stays connected to.
+    pub fn keep_connected_peers(&self) -> Vec<PeerId> {
+        self.address_book
+            .iter()
+            .filter(|e| e.keep_connected)
+            .map(|e| e.peer_id.clone())
+            .collect()
+    }
+
+    /// Address book peers to keep resolving in the background, according to `warm_cache`.
+    pub fn warm_peers(&self) -> Result<Vec<PeerId>> {
+        match self.file.warm_cache.clone().unwrap_or(WarmCache::All(true)) {
+            WarmCache::All(false) => Ok(Vec::new()),
+            WarmCache::All(true) => Ok(self.address_book.iter().map(|e| e.peer_id.clone()).collect()),
+            WarmCache::List(names) => names
+                .iter()
+                .map(|n| lookup_peer(&self.address_book, n))
+                .collect(),
+        }
+    }
 
-        Ok(Config { opts })
+    /// Whether to dial all connections from the same local port.
+    pub fn sticky_port(&self) -> bool {
+        self.opts.sticky_port || self.file.sticky_port.unwrap_or(false)
+    }
+
+    /// When to give up finding the peer to connect to.
+    pub fn discovery_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.opts.discovery_attempts.or(self.file.discovery_attempts),
+            deadline: self
+                .opts
+                .discovery_timeout
+                .or(self.file.discovery_timeout)
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Network interface to restrict listening and dialing to, if any.
+    pub fn bind_interface(&self) -> Option<&str> {
+        self.opts
+            .bind_interface
+            .as_deref()
+            .or_else(|| self.file.bind_interface.as_deref())
+    }
+
+    /// Kademlia protocol id, if it should differ from the libp2p default.
+    pub fn kad_protocol(&self) -> Option<&str> {
+        self.opts
+            .kad_protocol
+            .as_deref()
+            .or_else(|| self.file.kad_protocol.as_deref())
     }
 
     /// Read key from file retrieved by `get_key_file`.
@@ -57,13 +916,170 @@ impl Config {
     /// Or create a new one if it does not exist, storing it in the path
     /// returned by `get_key_file` for the next time.
     pub fn get_node_key(&self) -> Result<identity::Keypair> {
-        Ok(identity::Keypair::Ed25519(gen_or_get_key(
-            &self.get_key_file(),
-        )?))
+        gen_or_get_key(&self.get_key_file(), self.opts.key_type)
+    }
+
+    /// Like `get_node_key`, but fails instead of generating a key if there is none.
+    pub fn get_existing_node_key(&self) -> Result<identity::Keypair> {
+        let key_path = self.get_key_file();
+        let key_exists =
+            path_exists(&key_path).with_context(|| error::Keypair::Access(key_path.clone()))?;
+        if !key_exists {
+            return Err(error::Keypair::Missing(key_path).into());
+        }
+        read_key(&key_path)
+    }
+
+    /// File the discovered peer addresses are cached in.
+    pub fn get_addr_cache_file(&self) -> PathBuf {
+        self.opts.config_dir.join("addr_cache.json")
+    }
+
+    /// File the Kademlia routing table is snapshotted to.
+    pub fn get_routing_table_file(&self) -> PathBuf {
+        self.opts.config_dir.join("routing_table.json")
+    }
+
+    /// File the local port dials go out from is kept in, see `--sticky-port`.
+    pub fn get_sticky_port_file(&self) -> PathBuf {
+        self.opts.config_dir.join("sticky_port.json")
+    }
+
+    /// File the report on the last failed connect is written to.
+    pub fn get_dial_report_file(&self) -> PathBuf {
+        self.opts.config_dir.join("dial_report.json")
+    }
+
+    /// Our rotation record, written by `p2shd key rotate`.
+    pub fn get_rotation_file(&self) -> PathBuf {
+        self.opts.config_dir.join("rotation.json")
+    }
+
+    /// File rotations of other peers we learned are stored in.
+    pub fn get_known_rotations_file(&self) -> PathBuf {
+        self.opts.config_dir.join("known_rotations.json")
+    }
+
+    /// File the synced part of the address book is stored in, see `book_sync`.
+    pub fn get_synced_book_file(&self) -> PathBuf {
+        self.opts.config_dir.join(SYNCED_BOOK_FILE)
+    }
+
+    /// File host keys replaced via `p2shd trust refresh` are kept in.
+    pub fn get_quarantine_file(&self) -> PathBuf {
+        self.opts.config_dir.join("known_hosts.quarantine")
+    }
+
+    /// File the blocklist and imported blocklists are stored in.
+    pub fn get_blocklist_file(&self) -> PathBuf {
+        self.opts.config_dir.join("blocklist.json")
+    }
+
+    /// Banner to show clients before their ssh session, `None` if there is none.
+    pub fn get_banner_file(&self) -> Option<PathBuf> {
+        self.file.banner.as_ref().map(|b| self.opts.config_dir.join(b))
+    }
+
+    /// Whether to publish our blocklist in the DHT.
+    pub fn publish_blocklist(&self) -> bool {
+        self.opts.publish_blocklist || self.file.publish_blocklist.unwrap_or(false)
+    }
+
+    /// Whether to publish our addresses in the DHT, when listening.
+    pub fn publish_addresses(&self) -> bool {
+        self.file.publish_addresses.unwrap_or(false)
+    }
+
+    /// File the report on the last crash is written to, see `crash`.
+    pub fn get_crash_report_file(&self) -> PathBuf {
+        self.opts.config_dir.join("crash_report.txt")
+    }
+
+    /// File recorded events get dumped to.
+    pub fn get_events_dump_file(&self) -> PathBuf {
+        self.opts.config_dir.join("events.dump")
+    }
+
+    /// Unix socket the JSON-RPC control API is served on, see `control`.
+    pub fn get_control_socket_file(&self) -> PathBuf {
+        self.opts.config_dir.join("control.sock")
+    }
+
+    /// Control socket of the session named `name`, see `Opts::session`.
+    pub fn get_session_socket_file(&self, name: &str) -> PathBuf {
+        self.opts.config_dir.join("sessions").join(format!("{}.sock", name))
+    }
+
+    /// File DHT records are persisted to, `None` if they should be kept in memory only.
+    pub fn get_record_store_file(&self) -> Option<PathBuf> {
+        if self.opts.persistent_records {
+            Some(self.opts.config_dir.join("records.json"))
+        } else {
+            None
+        }
+    }
+
+    /// Identify protocol version, `IDENTIFY_PROTOCOL` by default.
+    pub fn identify_protocol(&self) -> String {
+        self.opts
+            .identify_protocol
+            .clone()
+            .or_else(|| self.file.identify_protocol.clone())
+            .unwrap_or_else(|| IDENTIFY_PROTOCOL.into())
+    }
+
+    /// Identify agent string, always including the crate version.
+    pub fn identify_agent(&self) -> String {
+        let version = concat!("p2shd/", env!("CARGO_PKG_VERSION"));
+        match self
+            .opts
+            .identify_agent
+            .as_deref()
+            .or_else(|| self.file.identify_agent.as_deref())
+        {
+            None => version.to_string(),
+            Some(agent) => format!("{} {}", agent, version),
+        }
+    }
+
+    /// DNS servers to use, empty if the system resolver should be used.
+    pub fn dns_servers(&self) -> &[IpAddr] {
+        if !self.opts.dns_servers.is_empty() {
+            &self.opts.dns_servers
+        } else {
+            self.file.dns_servers.as_deref().unwrap_or(&[])
+        }
+    }
+
+    pub fn dns_protocol(&self) -> DnsProtocol {
+        self.opts
+            .dns_protocol
+            .or(self.file.dns_protocol)
+            .unwrap_or_default()
+    }
+
+    pub fn dns_port(&self) -> Option<u16> {
+        self.opts.dns_port.or(self.file.dns_port)
+    }
+
+    pub fn dns_tls_name(&self) -> Option<&str> {
+        self.opts
+            .dns_tls_name
+            .as_deref()
+            .or_else(|| self.file.dns_tls_name.as_deref())
+    }
+
+    /// Find a peer by name in the address book, or parse it as peer id.
+    pub fn lookup_peer(&self, name: &str) -> Result<PeerId> {
+        lookup_peer(&self.address_book, name)
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.opts.config_dir
     }
 
     /// Get the configured key_file, picking a default if not specified.
-    fn get_key_file(&self) -> PathBuf {
+    pub fn get_key_file(&self) -> PathBuf {
         match &self.opts.key_file {
             None => [self.opts.config_dir.as_path(), Path::new("node_key")]
                 .iter()
@@ -89,6 +1105,275 @@ fn create_config_dir(config_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Parse bootstrap entries, making sure each of them contains a valid peer id or is a
+/// `/dnsaddr`.
+fn parse_bootstrap_nodes<S: AsRef<str>>(
+    nodes: impl Iterator<Item = S>,
+) -> Result<Vec<Bootstrap>> {
+    nodes.map(|n| parse_bootstrap(n.as_ref())).collect()
+}
+
+fn parse_bootstrap(node: &str) -> Result<Bootstrap> {
+    let addr: Multiaddr = node
+        .parse()
+        .map_err(|_| error::Bootstrap::InvalidAddr(node.into()))?;
+    let mut iter = addr.iter();
+    if let Some(Protocol::Dnsaddr(domain)) = iter.next() {
+        let peer_id = match iter.next() {
+            None => None,
+            Some(Protocol::P2p(hash)) if iter.next().is_none() => Some(
+                PeerId::from_multihash(hash)
+                    .map_err(|_| error::Bootstrap::InvalidPeerId(addr.clone()))?,
+            ),
+            Some(_) => return Err(error::Bootstrap::InvalidAddr(node.into()).into()),
+        };
+        return Ok(Bootstrap::Dnsaddr {
+            domain: domain.into_owned(),
+            peer_id,
+        });
+    }
+    Ok(Bootstrap::Node(parse_bootstrap_node(addr)?))
+}
+
+/// Validate `[profile]`, offered services have to be exposed and usable by somebody.
+fn parse_profile(file: &ConfigFile, services: &[Service]) -> Result<Option<Profile>> {
+    let entry = match &file.profile {
+        None => return Ok(None),
+        Some(e) => e,
+    };
+    let offered = entry.services.clone().unwrap_or_default();
+    for name in &offered {
+        let service = services
+            .iter()
+            .find(|s| &s.name == name)
+            .ok_or_else(|| error::Profile::UnknownService(name.clone()))?;
+        if service.allow.is_empty() {
+            return Err(error::Profile::Unusable(name.clone()).into());
+        }
+    }
+    Ok(Some(Profile {
+        name: entry.name.clone(),
+        contact: entry.contact.clone(),
+        services: offered,
+    }))
+}
+
+/// Make sure timeouts are only configured for services that exist.
+fn check_timeouts(file: &ConfigFile, services: &[Service]) -> Result<()> {
+    let known = || {
+        SERVICES
+            .iter()
+            .map(|s| s.to_string())
+            .chain(services.iter().map(|s| s.name.clone()))
+            .collect::<Vec<_>>()
+    };
+    for service in file.timeouts.iter().flat_map(|t| t.keys()) {
+        if !known().contains(service) {
+            return Err(error::Timeouts::UnknownService(service.clone(), known().join(", ")).into());
+        }
+    }
+    Ok(())
+}
+
+/// Validate `[expose]`, timeouts are filled in later.
+/// Services without `allow` are for `authorized`, for nobody if that is not configured either.
+fn parse_services(
+    file: &ConfigFile,
+    book: &[AddressBookEntry],
+    authorized: Option<&[PeerId]>,
+) -> Result<Vec<Service>> {
+    let entries = match &file.expose {
+        None => return Ok(Vec::new()),
+        Some(e) => e,
+    };
+    let mut services = entries
+        .iter()
+        .map(|(name, entry)| {
+            let valid_char = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+            if name.is_empty() || !name.chars().all(valid_char) {
+                return Err(error::Expose::InvalidName(name.clone()).into());
+            }
+            if SERVICES.contains(&name.as_str()) {
+                return Err(error::Expose::ReservedName(name.clone()).into());
+            }
+            let (addr, allow) = match entry {
+                ExposeEntry::Addr(addr) => (addr, None),
+                ExposeEntry::Table(t) => (&t.addr, t.allow.as_ref()),
+            };
+            let allow = match allow {
+                None => authorized.map(<[_]>::to_vec).unwrap_or_default(),
+                Some(allow) => allow
+                    .iter()
+                    .map(|p| lookup_peer(book, p))
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| error::Expose::InvalidAllow(name.clone()))?,
+            };
+            Ok(Service {
+                name: name.clone(),
+                addr: addr
+                    .parse()
+                    .map_err(|_| error::Expose::InvalidAddr(name.clone(), addr.clone()))?,
+                allow,
+                timeouts: Timeouts::default(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(services)
+}
+
+/// Validate `[vpn]`.
+fn parse_vpn_peers(file: &ConfigFile, book: &[AddressBookEntry]) -> Result<HashMap<PeerId, IpNet>> {
+    let entries = match &file.vpn {
+        None => return Ok(HashMap::new()),
+        Some(e) => e,
+    };
+    entries
+        .iter()
+        .map(|(name, addr)| {
+            let peer = lookup_peer(book, name).with_context(|| error::Vpn::InvalidPeer(name.clone()))?;
+            let addr = addr
+                .parse()
+                .map_err(|_| error::Vpn::InvalidAddr(name.clone(), addr.clone()))?;
+            Ok((peer, addr))
+        })
+        .collect()
+}
+
+/// Validate the `[peers]` section of the configuration file, entries are sorted by name.
+fn parse_address_book(file: &ConfigFile) -> Result<Vec<AddressBookEntry>> {
+    let empty = HashMap::new();
+    let peers = file.peers.as_ref().unwrap_or(&empty);
+    let mut book = peers
+        .iter()
+        .map(|(name, entry)| {
+            let peer_id = entry
+                .id
+                .parse()
+                .map_err(|_| error::AddressBook::InvalidPeerId(name.clone(), entry.id.clone()))?;
+            Ok(AddressBookEntry {
+                name: name.clone(),
+                peer_id,
+                port: entry.port,
+                keep_connected: entry.keep_connected.unwrap_or(false),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    book.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(book)
+}
+
+/// `book` of `file` with the synced entries added, the former taking precedence.
+fn with_synced(mut book: Vec<AddressBookEntry>, file: &ConfigFile, synced: &SyncedBook) -> Vec<AddressBookEntry> {
+    let in_file = |name: &str| file.peers.as_ref().map_or(false, |p| p.contains_key(name));
+    for (name, peer_id, port) in synced.entries() {
+        if !in_file(name) {
+            book.push(AddressBookEntry {
+                name: name.into(),
+                peer_id,
+                port,
+                keep_connected: false,
+            });
+        }
+    }
+    book.sort_by(|a, b| a.name.cmp(&b.name));
+    book
+}
+
+/// Find a peer by name in the address book, or parse it as peer id.
+fn lookup_peer(book: &[AddressBookEntry], name: &str) -> Result<PeerId> {
+    if let Some(entry) = book.iter().find(|e| e.name == name) {
+        return Ok(entry.peer_id.clone());
+    }
+    name.parse()
+        .map_err(|_| error::AddressBook::UnknownPeer(name.into()).into())
+}
+
+/// Look up a configured list of peer ids or names, if there is one.
+fn lookup_peers(book: &[AddressBookEntry], names: Option<&[String]>) -> Result<Option<Vec<PeerId>>> {
+    names
+        .map(|names| names.iter().map(|n| lookup_peer(book, n)).collect())
+        .transpose()
+}
+
+/// Split a full node address into address and peer id.
+pub(crate) fn parse_bootstrap_node(mut addr: Multiaddr) -> Result<BootstrapNode> {
+    let full = addr.clone();
+    match addr.pop() {
+        Some(Protocol::P2p(hash)) => {
+            let peer_id =
+                PeerId::from_multihash(hash).map_err(|_| error::Bootstrap::InvalidPeerId(full))?;
+            Ok(BootstrapNode { peer_id, addr })
+        }
+        _ => Err(error::Bootstrap::MissingPeerId(full).into()),
+    }
+}
+
+/// Whether `cmd` needs the synced address book and known rotations.
+///
+/// Commands not dealing with peers must not ask for the state passphrase, shell
+/// completion (`complete-peers`) only offers the names in `config.toml`.
+fn uses_state(cmd: Option<&Command>) -> bool {
+    match cmd {
+        Some(Command::Status { .. })
+        | Some(Command::Peers)
+        | Some(Command::Dashboard { .. })
+        | Some(Command::Id { .. })
+        | Some(Command::Completions { .. })
+        | Some(Command::CompletePeers)
+        | Some(Command::Keygen { .. })
+        | Some(Command::Key(_))
+        | Some(Command::Debug(_)) => false,
+        _ => true,
+    }
+}
+
+/// Session names end up in file names, so they are restricted like service names.
+fn check_session_name(name: &str) -> Result<()> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(valid_char) {
+        return Err(error::Session::InvalidName(name.into()).into());
+    }
+    Ok(())
+}
+
+/// Multiple remote peers only work for running a command non-interactively on each.
+fn check_multiple_remotes(opts: &Opts) -> Result<()> {
+    let connect = &opts.connect;
+    let conflict = if connect.stdio {
+        Some("--stdio")
+    } else if connect.mosh {
+        Some("--mosh")
+    } else if !connect.local_forwards.is_empty() || !connect.remote_forwards.is_empty() {
+        Some("port forwarding")
+    } else if connect.session.is_some() {
+        Some("--session")
+    } else if opts.cmd.is_some() {
+        Some("subcommands")
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        return Err(error::Remotes::Conflict(conflict).into());
+    }
+    if connect.trailing_ssh_args.is_empty() {
+        return Err(error::Remotes::NoCommand.into());
+    }
+    Ok(())
+}
+
+/// Read and parse the configuration file, a missing file is equivalent to an empty one.
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    let exists =
+        path_exists(path).with_context(|| error::ConfigFile::Read(PathBuf::from(path)))?;
+    if !exists {
+        return Ok(ConfigFile::default());
+    }
+    let raw =
+        fs::read_to_string(path).with_context(|| error::ConfigFile::Read(PathBuf::from(path)))?;
+    toml::from_str(&raw).with_context(|| error::ConfigFile::Parse(PathBuf::from(path)))
+}
+
 /// Load key from given file path (if present) or generate one and store it.
 ///
 /// # Errors
@@ -97,8 +1382,8 @@ fn create_config_dir(config_path: &Path) -> Result<()> {
 /// 2. Decoding of key fails.
 /// 3. File cannot be written.
 ///
-/// If the given file exists but does not contain a valid Ed25519 key.
-fn gen_or_get_key(key_path: &Path) -> Result<ed25519::Keypair> {
+/// If the given file exists but does not contain a valid key, new keys are of type `key_type`.
+fn gen_or_get_key(key_path: &Path, key_type: KeyType) -> Result<identity::Keypair> {
     let key_exists =
         path_exists(key_path).with_context(|| error::Keypair::Access(PathBuf::from(key_path)))?;
 
@@ -106,24 +1391,46 @@ fn gen_or_get_key(key_path: &Path) -> Result<ed25519::Keypair> {
         read_key(key_path)
     } else {
         log::debug!("Writting key: {:?}", key_path);
-        gen_and_write_key(key_path)
+        gen_and_write_key(key_path, key_type)
     }
 }
 
-/// Read key file.
-fn read_key(key_path: &Path) -> Result<ed25519::Keypair> {
+/// Read key file, decrypting it if it is encrypted (see `key`).
+///
+/// The type is told by the format: 64 bytes are an Ed25519 keypair, 32 bytes
+/// a raw Secp256k1 secret key, anything else has to be an RSA key in PKCS#8
+/// or a Secp256k1 key in DER format.
+pub(crate) fn read_key(key_path: &Path) -> Result<identity::Keypair> {
     let mut raw =
         fs::read(key_path).with_context(|| error::Keypair::Read(PathBuf::from(key_path)))?;
-
-    ed25519::Keypair::decode(&mut raw)
-        .with_context(|| error::Keypair::Decode(PathBuf::from(key_path)))
+    if key::is_encrypted(&raw) {
+        raw = key::decrypt(&raw, key_path)?;
+    }
+    let decode_err = || error::Keypair::Decode(PathBuf::from(key_path));
+    match raw.len() {
+        64 => {
+            let key = ed25519::Keypair::decode(&mut raw).with_context(decode_err)?;
+            Ok(identity::Keypair::Ed25519(key))
+        }
+        32 => {
+            let secret = secp256k1::SecretKey::from_bytes(&mut raw).with_context(decode_err)?;
+            Ok(identity::Keypair::Secp256k1(secret.into()))
+        }
+        _ => {
+            // Decoding zeroes the buffer, even if it fails:
+            if let Ok(key) = rsa::Keypair::from_pkcs8(&mut raw.clone()) {
+                return Ok(identity::Keypair::Rsa(key));
+            }
+            let secret = secp256k1::SecretKey::from_der(&mut raw).with_context(decode_err)?;
+            Ok(identity::Keypair::Secp256k1(secret.into()))
+        }
+    }
 }
 
-/// Generate a key and write it to the file given by path.
-fn gen_and_write_key(key_path: &Path) -> Result<ed25519::Keypair> {
-    let key = ed25519::Keypair::generate();
-    let encoded: &[u8] = &key.encode();
-    fs::write(key_path, encoded).with_context(|| error::Keypair::Write(PathBuf::from(key_path)))?;
+/// Generate a key of type `key_type` and write it to the file given by path.
+fn gen_and_write_key(key_path: &Path, key_type: KeyType) -> Result<identity::Keypair> {
+    let (key, encoded) = generate_key(key_type);
+    fs::write(key_path, &encoded).with_context(|| error::Keypair::Write(PathBuf::from(key_path)))?;
 
     // Only user should be able to read the file:
     fs::set_permissions(key_path, PermissionsExt::from_mode(0o400))
@@ -131,6 +1438,22 @@ fn gen_and_write_key(key_path: &Path) -> Result<ed25519::Keypair> {
     Ok(key)
 }
 
+/// Generate a key of type `key_type`, with its key file encoding.
+pub(crate) fn generate_key(key_type: KeyType) -> (identity::Keypair, Vec<u8>) {
+    match key_type {
+        KeyType::Ed25519 => {
+            let key = ed25519::Keypair::generate();
+            let encoded = key.encode().to_vec();
+            (identity::Keypair::Ed25519(key), encoded)
+        }
+        KeyType::Secp256k1 => {
+            let key = secp256k1::Keypair::generate();
+            let encoded = key.secret().to_bytes().to_vec();
+            (identity::Keypair::Secp256k1(key), encoded)
+        }
+    }
+}
+
 /// Check whether a path exists.
 ///
 /// In contrast to Path::exists() this function really checks whether the path
@@ -140,7 +1463,7 @@ fn gen_and_write_key(key_path: &Path) -> Result<ed25519::Keypair> {
 /// This improves reporting errors early and more correctly. E.g. Don't tell
 /// user that a write failed, when in reality a failed read should have been
 /// reported.
-fn path_exists(key_path: &Path) -> io::Result<bool> {
+pub(crate) fn path_exists(key_path: &Path) -> io::Result<bool> {
     match fs::metadata(key_path) {
         Ok(_) => Ok(true),
         Err(err) => {
@@ -152,3 +1475,45 @@ fn path_exists(key_path: &Path) -> io::Result<bool> {
         }
     }
 }
+
+/// Write to a temporary file first and rename it, so a crash never leaves a
+/// truncated file behind.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Write a file only we may read, for files revealing whom we talk to (event dumps, crash
+/// reports).
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies to new files:
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+/// Lock the state file at `path` against other p2shd processes, until the result gets dropped.
+///
+/// For read-modify-write cycles, e.g. `p2shd peer add` while `p2shd listen` syncs. The lock
+/// is on `.lock` next to the file, as the file itself gets replaced on writes.
+pub(crate) fn lock_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(path.with_extension("lock"))?;
+    // Safe: `file` is open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
@@ -1,5 +1,6 @@
 //! Errors that can happen during configuration handling.
 
+use libp2p::Multiaddr;
 use std::path::PathBuf;
 use thiserror::Error;
 
@@ -9,8 +10,9 @@ pub enum Keypair {
     #[error(
         "Invalid keyfile '{0}'.
 
-Make sure '{0}' is a valid ED25519 keypair,
-which is a private + public key concatenated in binary format.
+Make sure '{0}' is a valid keypair: An Ed25519 private + public key
+concatenated in binary format, a Secp256k1 secret key (raw or DER) or an
+RSA key in PKCS#8 DER format.
 
 If you don't mind the node to have a new identity,
 you can simply delete the file to have p2shd
@@ -26,6 +28,10 @@ generate a valid one for you.
     Write(PathBuf),
     #[error("Setting permissions for keyfile '{0}' failed.")]
     SetPermissions(PathBuf),
+    #[error("No keyfile at '{0}' yet, create one with 'p2shd keygen' or by running 'p2shd listen'.")]
+    Missing(PathBuf),
+    #[error("Unknown key type '{0}', expected 'ed25519' or 'secp256k1'.")]
+    UnknownType(String),
 }
 
 /// Errors related to configuration directory handling.
@@ -38,3 +44,109 @@ pub enum ConfigDir {
     #[error("Setting permissons for the configuration directory at '{0}' failed.")]
     SetPermissions(PathBuf),
 }
+
+/// Errors related to the configuration file.
+#[derive(Error, Debug)]
+pub enum ConfigFile {
+    #[error("Reading the configuration file '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Invalid configuration file '{0}'.")]
+    Parse(PathBuf),
+}
+
+/// Errors related to bootstrap node configuration.
+#[derive(Error, Debug)]
+pub enum Bootstrap {
+    #[error("Invalid bootstrap node address '{0}'.")]
+    InvalidAddr(String),
+    #[error(
+        "Bootstrap node address '{0}' does not contain a peer id.
+
+Bootstrap nodes have to be given as full address, e.g.:
+/ip4/1.2.3.4/tcp/4001/p2p/12D3KooW..."
+    )]
+    MissingPeerId(Multiaddr),
+    #[error("Bootstrap node address '{0}' contains an invalid peer id.")]
+    InvalidPeerId(Multiaddr),
+}
+
+/// Errors related to the address book (`[peers]` in the configuration file).
+#[derive(Error, Debug)]
+pub enum AddressBook {
+    #[error("Invalid peer id '{1}' for peer '{0}' in the address book.")]
+    InvalidPeerId(String, String),
+    #[error(
+        "'{0}' is neither a valid peer id nor a name in the address book.
+
+Add peers to the address book via config.toml:
+[peers.{0}]
+id = \"12D3KooW...\""
+    )]
+    UnknownPeer(String),
+}
+
+/// Errors related to `[expose]` in the configuration file.
+#[derive(Error, Debug)]
+pub enum Expose {
+    #[error("Invalid address '{1}' of exposed service '{0}', expected e.g. '127.0.0.1:3000'.")]
+    InvalidAddr(String, String),
+    #[error("Invalid service name '{0}', only letters, digits, '-', '_' and '.' are allowed.")]
+    InvalidName(String),
+    #[error("Service name '{0}' is reserved, pick another one.")]
+    ReservedName(String),
+    #[error("Invalid entry in the allow list of exposed service '{0}'.")]
+    InvalidAllow(String),
+}
+
+/// Errors related to `[vpn]` in the configuration file.
+#[derive(Error, Debug)]
+pub enum Vpn {
+    #[error("Unknown peer '{0}' in [vpn].")]
+    InvalidPeer(String),
+    #[error("Invalid VPN address '{1}' for peer '{0}', expected e.g. '10.99.0.1/30'.")]
+    InvalidAddr(String, String),
+}
+
+/// Errors related to `[timeouts]` in the configuration file.
+#[derive(Error, Debug)]
+pub enum Timeouts {
+    #[error("Timeouts configured for unknown service '{0}', known are: {1}.")]
+    UnknownService(String, String),
+}
+
+/// Errors related to `[profile]` in the configuration file.
+#[derive(Error, Debug)]
+pub enum Profile {
+    #[error("Profile offers service '{0}', which is not exposed (see [expose]).")]
+    UnknownService(String),
+    #[error("Profile offers service '{0}', which nobody is allowed to use (see allow and authorized_peers).")]
+    Unusable(String),
+}
+
+/// Errors related to connect flags given before and after `connect`.
+#[derive(Error, Debug)]
+pub enum Connect {
+    #[error("{0} given before and after 'connect', with different values.")]
+    Conflict(&'static str),
+    #[error("{0} can't be combined with {1}.")]
+    Incompatible(&'static str, &'static str),
+}
+
+/// Errors related to connecting to multiple peers at once (`--remote`).
+#[derive(Error, Debug)]
+pub enum Remotes {
+    #[error("Multiple remote peers can't be combined with {0}.")]
+    Conflict(&'static str),
+    #[error(
+        "Multiple remote peers need a command to run, e.g.:
+p2shd web1 --remote web2 -- uptime"
+    )]
+    NoCommand,
+}
+
+/// Errors related to named sessions (`--session`).
+#[derive(Error, Debug)]
+pub enum Session {
+    #[error("Invalid session name '{0}', only letters, digits, '-', '_' and '.' are allowed.")]
+    InvalidName(String),
+}
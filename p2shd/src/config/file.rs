@@ -0,0 +1,146 @@
+//! The configuration file `config.toml` in the configuration directory.
+//!
+//! All settings are optional, command line arguments take precedence.
+
+use serde::Deserialize;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf};
+
+use crate::{dns::DnsProtocol, metrics::ExporterEntry, scheduler::JobEntry, sealed_state::StateKey};
+
+/// Contents of `config.toml`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Kademlia protocol id, e.g. "/p2shd/kad/1.0.0" for a private p2shd DHT.
+    pub kad_protocol: Option<String>,
+    /// Bootstrap nodes as multiaddrs including the `/p2p/` peer id.
+    /// An empty list disables bootstrapping (LAN only operation).
+    pub bootstrap: Option<Vec<String>>,
+    /// Identify protocol version, other p2shd nodes announcing a different major version get
+    /// warned about.
+    pub identify_protocol: Option<String>,
+    /// Identify agent string, the p2shd version gets appended.
+    pub identify_agent: Option<String>,
+    /// DNS servers to use instead of the system resolver.
+    pub dns_servers: Option<Vec<IpAddr>>,
+    /// How to talk to `dns_servers`: "udp", "tls" or "https".
+    pub dns_protocol: Option<DnsProtocol>,
+    /// Port of `dns_servers`, defaults to the standard port of `dns_protocol`.
+    pub dns_port: Option<u16>,
+    /// Name in the TLS certificates of `dns_servers`, needed for "tls" and "https".
+    pub dns_tls_name: Option<String>,
+    /// Dial from the same local port, so NAT mappings stay the same across reconnects.
+    pub sticky_port: Option<bool>,
+    /// Environment variables to pass to remote shells (ssh `SendEnv` patterns), an empty
+    /// list passes none.
+    pub send_env: Option<Vec<String>>,
+    /// Network interface to listen on and dial from.
+    pub bind_interface: Option<String>,
+    /// Maximum number of DHT queries for finding the peer to connect to.
+    pub discovery_attempts: Option<u32>,
+    /// Seconds after which to give up finding the peer to connect to.
+    pub discovery_timeout: Option<u64>,
+    /// Publish our signed blocklist in the DHT for others to subscribe to.
+    pub publish_blocklist: Option<bool>,
+    /// Whether `p2shd listen` publishes its observed addresses as a signed DHT record, off by
+    /// default.
+    pub publish_addresses: Option<bool>,
+    /// Encrypt state files (address cache, routing table, ...) with a random key in
+    /// "key_file" `state_key` or one derived from a "passphrase", see `sealed_state`.
+    pub encrypt_state: Option<StateKey>,
+    /// Peer ids or `peers` names of the only peers allowed to connect to us, everybody
+    /// (not blocked) if not set.
+    pub allowed_peers: Option<Vec<String>>,
+    /// Peer ids or `peers` names of the only peers `p2shd listen` serves tunnels to
+    /// (ssh, services, banner, ...), everybody if not set. Others can still use us for DHT
+    /// routing.
+    pub authorized_peers: Option<Vec<String>>,
+    /// Peers to leave out of discovery: Peer ids or `agent:<pattern>` (identify agent
+    /// version, `*` matching anything).
+    pub ignore: Option<Vec<String>>,
+    /// Peer ids or `peers` names of our own other devices, to sync the address book
+    /// with (see `p2shd peer add`).
+    pub linked_devices: Option<Vec<String>>,
+    /// Address book: Peers by name, so they can be connected to via `p2shd <name>`.
+    pub peers: Option<HashMap<String, PeerEntry>>,
+    /// Which address book peers the daemon keeps resolving in the background:
+    /// `true` (all, the default), `false` (none) or a list of names.
+    pub warm_cache: Option<WarmCache>,
+    /// Recurring jobs of `p2shd listen`.
+    pub jobs: Option<Vec<JobEntry>>,
+    /// Where `p2shd listen` pushes its metrics to.
+    pub metrics: Option<Vec<ExporterEntry>>,
+    /// Timeouts `p2shd listen` enforces per service ("ssh", "forward" or an `expose` name).
+    pub timeouts: Option<HashMap<String, TimeoutsEntry>>,
+    /// The only peers `p2shd listen --vpn` accepts VPN links from: Peer id or `peers`
+    /// name to the address of our end of its link, e.g. `laptop = "10.99.0.1/30"`.
+    pub vpn: Option<HashMap<String, String>>,
+    /// Local services `p2shd listen` makes available by name.
+    pub expose: Option<HashMap<String, ExposeEntry>>,
+    /// Public profile `p2shd listen` publishes in the DHT, none if not set.
+    pub profile: Option<ProfileEntry>,
+    /// File `p2shd listen` shows clients before their ssh session starts, like sshd's
+    /// `Banner`. Relative to the configuration directory.
+    pub banner: Option<PathBuf>,
+}
+
+/// An exposed service: Just its address or a table with further settings.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ExposeEntry {
+    Addr(String),
+    Table(ExposeTable),
+}
+
+/// An exposed service in table form.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExposeTable {
+    /// Address of the service, e.g. "127.0.0.1:3000".
+    pub addr: String,
+    /// Peer ids or `peers` names allowed to use the service, `authorized_peers` if not set
+    /// (nobody if those are not set either).
+    pub allow: Option<Vec<String>>,
+}
+
+/// Our public profile, see `profile`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileEntry {
+    /// Display name.
+    pub name: String,
+    /// How to contact us, e.g. an email address.
+    pub contact: Option<String>,
+    /// Names of offered `expose`d services, only their allowed peers can use them.
+    pub services: Option<Vec<String>>,
+}
+
+/// Timeouts of a service, in seconds.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsEntry {
+    /// Close tunnels without any traffic for this long.
+    pub idle: Option<u64>,
+    /// Close tunnels this long after they got opened.
+    pub absolute: Option<u64>,
+}
+
+/// An address book entry.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PeerEntry {
+    /// The peer's id.
+    pub id: String,
+    /// Port sshd listens on at the peer, if not the one its p2shd connects to by default.
+    pub port: Option<u16>,
+    /// Whether `p2shd listen` should stay connected to this peer at all times.
+    pub keep_connected: Option<bool>,
+}
+
+/// Setting of `warm_cache`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WarmCache {
+    All(bool),
+    List(Vec<String>),
+}
@@ -0,0 +1,405 @@
+//! Control socket: A JSON-RPC 2.0 API for driving the daemon programmatically.
+//!
+//! `p2shd listen` accepts connections on the Unix socket `control.sock` in
+//! the configuration directory, sessions started with `--session <name>` on
+//! `sessions/<name>.sock`. Requests and responses are single lines of
+//! JSON, e.g.:
+//!
+//! ```text
+//! {"jsonrpc": "2.0", "id": 1, "method": "resolve_peer", "params": {"peer": "12D3KooW..."}}
+//! {"jsonrpc": "2.0", "id": 1, "result": {"addresses": ["/ip4/192.0.2.1/tcp/4001"]}}
+//! ```
+//!
+//! Methods:
+//!
+//! - `resolve_peer {peer}`: Addresses of `peer`, looked up in the DHT.
+//! - `connect {peer}`: Connect to `peer`, resolves once connected.
+//! - `list_peers`: Connected peers, with address and round trip time.
+//! - `status`: Our peer id, addresses, NAT status, routing table size, DHT queries
+//!   in progress, jobs and warm peers.
+//! - `shutdown`: Persist state, terminate ssh/mosh clients and exit once they did.
+//! - `add_forward {forward, remote}`: Add a forwarding like `-L` (or `-R` if
+//!   `remote`) to the session, e.g. `{"forward": "5432:db:5432"}`. Resolves
+//!   once it is set up.
+//! - `remove_forward {listen, remote}`: Stop a forwarding added via
+//!   `add_forward`, given by its listen address.
+//! - `list_forwards`: Forwardings of the session.
+//! - `lookup_profile {peer}`: The public profile `peer` publishes, verified.
+//! - `subscribe_events`: Turns the connection into a stream of notifications,
+//!   one per event (see `events`) from then on:
+//!   `{"jsonrpc": "2.0", "method": "event", "params": {"event": "..."}}`.
+//!
+//! Calls get handed to the behaviour as `ControlRequest`s via a `Controller`,
+//! the same way `forward::Opener` hands out tunnels. The gRPC API (`grpc`)
+//! makes the same calls, except for `subscribe_events`.
+
+use async_std::{
+    os::unix::net::{UnixListener, UnixStream},
+    task,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    io::{self, BufReader},
+    prelude::*,
+    stream::BoxStream,
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    events,
+    forward::{self, PortForward},
+    profile::Profile,
+    ssh,
+};
+
+mod error;
+
+/// Some server side error, see JSON-RPC 2.0.
+const SERVER_ERROR: i64 = -32000;
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// A call to be handled by the behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    ResolvePeer(PeerId),
+    Connect(PeerId),
+    ListPeers,
+    Status,
+    Shutdown,
+    AddForward { forward: PortForward, remote: bool },
+    RemoveForward { listen: SocketAddr, remote: bool },
+    ListForwards,
+    LookupProfile(PeerId),
+}
+
+/// Result of a `Call`, serializes to the JSON-RPC result.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Reply {
+    /// For `Call::ResolvePeer`.
+    Addresses { addresses: Vec<String> },
+    /// For `Call::Connect`.
+    Connected { peer: String },
+    /// For `Call::ListPeers`.
+    Peers { peers: Vec<PeerInfo> },
+    Status(Status),
+    /// For `Call::ListForwards`.
+    Forwards { forwards: Vec<ForwardInfo> },
+    /// For `Call::LookupProfile`.
+    Profile(Profile),
+    /// For `Call::Shutdown` and changes to forwardings.
+    Done,
+}
+
+/// A forwarding of the session.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardInfo {
+    /// As given to `-L` or `-R`, `[bind_address:]port:host:hostport`.
+    pub forward: String,
+    /// Whether the peer listens (`-R`).
+    pub remote: bool,
+    /// Whether it can be removed, only those added via `add_forward` can.
+    pub removable: bool,
+}
+
+/// A connected peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer: String,
+    /// Address we dialed the peer at, `None` if it connected to us.
+    pub address: Option<String>,
+    pub rtt_ms: Option<u64>,
+}
+
+/// Status of the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub peer_id: String,
+    /// Whether we serve tunnels (`p2shd listen`).
+    pub serving: bool,
+    pub connected_peers: usize,
+    /// Tunnels from peers currently being served.
+    pub active_tunnels: usize,
+    pub listen_addrs: Vec<String>,
+    /// Our addresses as observed by peers.
+    pub external_addrs: Vec<String>,
+    pub nat: Nat,
+    pub routing_table_size: usize,
+    /// DHT queries in progress.
+    pub queries: usize,
+    pub warm_peers: Vec<String>,
+    pub jobs: Vec<JobInfo>,
+}
+
+/// Whether we are behind a NAT, judged by how peers observe us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Nat {
+    /// No addresses observed yet.
+    Unknown,
+    /// Peers see us at an address we listen on.
+    Public,
+    /// Peers see us at addresses we don't listen on.
+    Private,
+}
+
+/// Status of a scheduled job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    /// Seconds since the Unix epoch.
+    pub last_run: Option<u64>,
+    pub last_result: Option<String>,
+}
+
+/// A call, with where its result goes.
+pub struct ControlRequest {
+    pub call: Call,
+    /// Receives the result or an error message.
+    pub reply: oneshot::Sender<Result<Reply, String>>,
+}
+
+/// Handle for passing calls to the behaviour.
+#[derive(Clone)]
+pub struct Controller {
+    tx: mpsc::UnboundedSender<ControlRequest>,
+}
+
+impl Controller {
+    /// A controller and the receiving end the behaviour has to serve.
+    pub fn new() -> (Controller, mpsc::UnboundedReceiver<ControlRequest>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Controller { tx }, rx)
+    }
+
+    /// Make a call, resolving to its result or an error message.
+    pub async fn call(&self, call: Call) -> Result<Reply, String> {
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .unbounded_send(ControlRequest { call, reply })
+            .map_err(|_| "Daemon is shutting down.".to_string())?;
+        result
+            .await
+            .unwrap_or_else(|_| Err("Daemon is shutting down.".into()))
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct PeerParams {
+    peer: String,
+}
+
+#[derive(Deserialize)]
+struct AddForwardParams {
+    forward: String,
+    #[serde(default)]
+    remote: bool,
+}
+
+#[derive(Deserialize)]
+struct RemoveForwardParams {
+    listen: String,
+    #[serde(default)]
+    remote: bool,
+}
+
+/// Accept control connections on the socket at `path`, until failure.
+pub async fn serve(path: PathBuf, controller: Controller) -> io::Result<()> {
+    // Left over from a previous run, unless that is still running (e.g. a session of the same name):
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            let msg = format!("{} is in use.", path.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+        }
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path).await?;
+    // Anybody able to connect controls the daemon:
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    log::info!("Control socket listening at {}", path.display());
+    serve_listener(listener, controller).await
+}
+
+/// Accept control connections on `listener`, e.g. one passed via socket activation.
+pub async fn serve_listener(listener: UnixListener, controller: Controller) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let controller = controller.clone();
+        task::spawn(async move {
+            if let Err(e) = handle(socket, controller).await {
+                log::debug!("Control connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle<S>(socket: S, controller: Controller) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = socket.split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, shutdown) = match serde_json::from_str::<RpcRequest>(&line) {
+            Err(e) => (error(Value::Null, PARSE_ERROR, &e.to_string()), false),
+            Ok(req) if req.jsonrpc == "2.0" && req.method == "subscribe_events" => {
+                let response = json!({"jsonrpc": "2.0", "id": req.id, "result": {}});
+                writer.write_all(format!("{}\n", response).as_bytes()).await?;
+                return send_events(writer).await;
+            }
+            Ok(req) => {
+                let shutdown = req.method == "shutdown";
+                (respond(req, &controller).await, shutdown)
+            }
+        };
+        writer.write_all(format!("{}\n", response).as_bytes()).await?;
+        writer.flush().await?;
+        if shutdown && response.get("result").is_some() {
+            log::info!("Shutting down, as requested via control socket.");
+            ssh::wait_children().await;
+            std::process::exit(0);
+        }
+    }
+    Ok(())
+}
+
+/// Send events as notifications, until the client goes away.
+async fn send_events<W: AsyncWrite + Unpin>(mut writer: W) -> io::Result<()> {
+    let mut events = events::subscribe();
+    writer.flush().await?;
+    while let Some(event) = events.next().await {
+        let notification = json!({"jsonrpc": "2.0", "method": "event", "params": {"event": event}});
+        writer.write_all(format!("{}\n", notification).as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+async fn respond(req: RpcRequest, controller: &Controller) -> Value {
+    if req.jsonrpc != "2.0" {
+        return error(req.id, INVALID_REQUEST, "Only JSON-RPC 2.0 is supported.");
+    }
+    let call = match parse_call(&req.method, req.params) {
+        Ok(call) => call,
+        Err((code, msg)) => return error(req.id, code, &msg),
+    };
+    match controller.call(call).await {
+        Ok(reply) => json!({"jsonrpc": "2.0", "id": req.id, "result": reply}),
+        Err(msg) => error(req.id, SERVER_ERROR, &msg),
+    }
+}
+
+fn parse_call(method: &str, params: Value) -> Result<Call, (i64, String)> {
+    let peer = |params: Value| {
+        let p: PeerParams =
+            serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+        p.peer
+            .parse()
+            .map_err(|_| (INVALID_PARAMS, error::Control::InvalidPeerId(p.peer).to_string()))
+    };
+    match method {
+        "resolve_peer" => Ok(Call::ResolvePeer(peer(params)?)),
+        "connect" => Ok(Call::Connect(peer(params)?)),
+        "list_peers" => Ok(Call::ListPeers),
+        "status" => Ok(Call::Status),
+        "shutdown" => Ok(Call::Shutdown),
+        "add_forward" => {
+            let p: AddForwardParams = serde_json::from_value(params).map_err(invalid)?;
+            Ok(Call::AddForward {
+                forward: p.forward.parse().map_err(invalid)?,
+                remote: p.remote,
+            })
+        }
+        "remove_forward" => {
+            let p: RemoveForwardParams = serde_json::from_value(params).map_err(invalid)?;
+            Ok(Call::RemoveForward {
+                listen: forward::parse_listen_addr(&p.listen).map_err(invalid)?,
+                remote: p.remote,
+            })
+        }
+        "list_forwards" => Ok(Call::ListForwards),
+        "lookup_profile" => Ok(Call::LookupProfile(peer(params)?)),
+        m => Err((
+            METHOD_NOT_FOUND,
+            error::Control::UnknownMethod(m.into()).to_string(),
+        )),
+    }
+}
+
+/// Make a single call on the control socket at `path`, resolving to its result.
+///
+/// JSON-RPC errors become `io::ErrorKind::Other` errors carrying the message.
+pub async fn call(path: &Path, method: &str, params: Value) -> io::Result<Value> {
+    let mut socket = UnixStream::connect(path).await?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    socket.write_all(format!("{}\n", request).as_bytes()).await?;
+    let mut line = String::new();
+    BufReader::new(socket).read_line(&mut line).await?;
+    parse_response(&line)
+}
+
+/// Events of the daemon at control socket `path`, see `subscribe_events`. Ends when the
+/// daemon goes away.
+pub async fn subscribe_events(path: &Path) -> io::Result<BoxStream<'static, io::Result<String>>> {
+    let mut socket = UnixStream::connect(path).await?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "subscribe_events"});
+    socket.write_all(format!("{}\n", request).as_bytes()).await?;
+    let mut lines = BufReader::new(socket).lines();
+    let line = lines
+        .next()
+        .await
+        .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?;
+    parse_response(&line)?;
+    Ok(lines
+        .map(|line| {
+            let notification: Value = serde_json::from_str(&line?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let event = notification.pointer("/params/event").and_then(Value::as_str);
+            Ok(event.unwrap_or_default().to_string())
+        })
+        .boxed())
+}
+
+/// The result of a response line, its error message as error.
+fn parse_response(line: &str) -> io::Result<Value> {
+    let mut response: Value =
+        serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if let Some(message) = response.pointer("/error/message").and_then(Value::as_str) {
+        return Err(io::Error::new(io::ErrorKind::Other, message.to_string()));
+    }
+    Ok(response["result"].take())
+}
+
+fn invalid(e: impl ToString) -> (i64, String) {
+    (INVALID_PARAMS, e.to_string())
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
@@ -0,0 +1,12 @@
+//! Errors that can happen while handling control requests.
+
+use thiserror::Error;
+
+/// Errors related to JSON-RPC calls.
+#[derive(Error, Debug)]
+pub enum Control {
+    #[error("Unknown method '{0}'.")]
+    UnknownMethod(String),
+    #[error("Invalid peer id '{0}'.")]
+    InvalidPeerId(String),
+}
@@ -0,0 +1,119 @@
+//! Crash reports: What p2shd was up to when it panicked.
+//!
+//! On panics a report gets written to the configuration directory: Build
+//! info, the panic message and location, a snapshot of the daemon's state
+//! (counts only: sessions, tunnels, peers, routing table) and the last
+//! recorded events (if `--record-events` is on). Recorded events are
+//! sanitized and key material never makes it anywhere near the report, only
+//! the panic message might mention a peer.
+
+use once_cell::sync::OnceCell;
+use std::{
+    fmt::Write as _,
+    panic,
+    path::PathBuf,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{config, control::Nat, events};
+
+/// Where bugs get reported.
+const ISSUES_URL: &str = "https://github.com/eskimor/p2sh/issues";
+
+static REPORTER: OnceCell<Reporter> = OnceCell::new();
+
+struct Reporter {
+    path: PathBuf,
+    started: Instant,
+    snapshot: Mutex<Snapshot>,
+}
+
+/// State of the daemon, as far as it is of interest for crash reports.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snapshot {
+    pub serving: bool,
+    /// Our own ssh sessions running.
+    pub sessions: usize,
+    /// Tunnels from peers being served.
+    pub active_tunnels: usize,
+    pub connected_peers: usize,
+    pub routing_table_size: usize,
+    pub nat: Option<Nat>,
+}
+
+/// Write a crash report to `path` on panics.
+///
+/// Calling it more than once has no effect.
+pub fn install(path: PathBuf) {
+    let reporter = Reporter {
+        path,
+        started: Instant::now(),
+        snapshot: Mutex::new(Snapshot::default()),
+    };
+    if REPORTER.set(reporter).is_err() {
+        return;
+    }
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let reporter = match REPORTER.get() {
+            Some(r) => r,
+            None => return,
+        };
+        let report = render(reporter, &info.to_string());
+        match config::write_private(&reporter.path, report.as_bytes()) {
+            Ok(()) => eprintln!(
+                "p2shd: Crashed, sorry! A report is at {}, please attach it to a bug report at {}. \
+                 It contains no key material, but have a look before sharing it.",
+                reporter.path.display(),
+                ISSUES_URL
+            ),
+            Err(e) => eprintln!("p2shd: Crashed, writing a crash report to {} failed: {}", reporter.path.display(), e),
+        }
+    }));
+}
+
+/// Record the current state, for the next crash report.
+pub fn update(snapshot: Snapshot) {
+    if let Some(reporter) = REPORTER.get() {
+        // Don't block, the next update will do:
+        if let Ok(mut s) = reporter.snapshot.try_lock() {
+            *s = snapshot;
+        }
+    }
+}
+
+fn render(reporter: &Reporter, panic: &str) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "p2shd {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "Platform: {} {}", std::env::consts::OS, std::env::consts::ARCH);
+    let _ = writeln!(report, "Uptime: {}s", reporter.started.elapsed().as_secs());
+    let _ = writeln!(report, "Panic: {}", panic);
+    // Don't block or panic (again) in a panic hook:
+    match reporter.snapshot.try_lock() {
+        Ok(s) => {
+            let _ = writeln!(report, "Serving: {}", s.serving);
+            let _ = writeln!(report, "Sessions: {}", s.sessions);
+            let _ = writeln!(report, "Active tunnels: {}", s.active_tunnels);
+            let _ = writeln!(report, "Connected peers: {}", s.connected_peers);
+            let _ = writeln!(report, "Routing table size: {}", s.routing_table_size);
+            let _ = writeln!(report, "NAT: {:?}", s.nat);
+        }
+        Err(_) => {
+            let _ = writeln!(report, "State: unavailable");
+        }
+    }
+    match events::recent() {
+        Some(recent) => {
+            let _ = writeln!(report, "\nLast events:");
+            for e in recent {
+                let _ = writeln!(report, "{}", e);
+            }
+        }
+        None => {
+            let _ = writeln!(report, "\nNo events recorded, see `--record-events`.");
+        }
+    }
+    report
+}
@@ -0,0 +1,106 @@
+//! `p2shd dashboard`: Live view of a running `p2shd listen`, in the terminal.
+//!
+//! Subscribes to the daemon's events on the control socket and, whenever
+//! something happened, fetches `status` and `list_peers` and redraws the whole
+//! screen, until interrupted. Round trip times change without events, so it
+//! also redraws every `--interval`. If the daemon goes away, the dashboard
+//! says so and picks up again once it is back.
+
+use anyhow::{Context as AnyhowContext, Result};
+use async_std::{future, task};
+use futures::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crate::control::{self, PeerInfo, Status};
+
+/// Clears the screen and moves the cursor to the top left.
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Wait this long after an event, so bursts of them cause a single redraw.
+const SETTLE: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize)]
+struct Peers {
+    peers: Vec<PeerInfo>,
+}
+
+/// Show the dashboard for the daemon at control socket `path`, refreshing on events and at
+/// least every `interval`.
+pub async fn run(path: &Path, interval: Duration) -> Result<()> {
+    let mut events = None;
+    loop {
+        if events.is_none() {
+            events = control::subscribe_events(path).await.ok();
+        }
+        let screen = match fetch(path).await {
+            Ok((status, peers)) => render(&status, &peers),
+            Err(e) => format!("Waiting for p2shd listen ({}): {:#}\n", path.display(), e),
+        };
+        let mut stdout = io::stdout();
+        write!(stdout, "{}{}", CLEAR, screen)?;
+        stdout.flush()?;
+        let stream = match &mut events {
+            Some(s) => s,
+            None => {
+                task::sleep(interval).await;
+                continue;
+            }
+        };
+        let next = future::timeout(interval, stream.next()).await;
+        match next {
+            Err(_) => (),
+            Ok(Some(Ok(_))) => {
+                task::sleep(SETTLE).await;
+                while let Some(Some(Ok(_))) = stream.next().now_or_never() {}
+            }
+            // Daemon went away:
+            Ok(_) => events = None,
+        }
+    }
+}
+
+async fn fetch(path: &Path) -> Result<(Status, Vec<PeerInfo>)> {
+    let status = call(path, "status").await?;
+    let peers: Peers = call(path, "list_peers").await?;
+    Ok((status, peers.peers))
+}
+
+async fn call<T: DeserializeOwned>(path: &Path, method: &str) -> Result<T> {
+    let result = control::call(path, method, json!({})).await?;
+    serde_json::from_value(result).with_context(|| format!("Unexpected {} reply", method))
+}
+
+fn render(status: &Status, peers: &[PeerInfo]) -> String {
+    let mut screen = String::new();
+    let _ = writeln!(screen, "p2shd {} ({})", status.peer_id, if status.serving { "serving" } else { "not serving" });
+    let _ = writeln!(screen);
+    let _ = writeln!(screen, "NAT:                {:?}", status.nat);
+    let _ = writeln!(screen, "Routing table:      {} peers", status.routing_table_size);
+    let _ = writeln!(screen, "DHT queries:        {}", status.queries);
+    let _ = writeln!(screen, "Active tunnels:     {}", status.active_tunnels);
+    let _ = writeln!(screen, "Listen addresses:   {}", status.listen_addrs.join(", "));
+    let _ = writeln!(screen, "External addresses: {}", status.external_addrs.join(", "));
+    let _ = writeln!(screen);
+    let _ = writeln!(screen, "Connected peers ({}):", status.connected_peers);
+    for p in peers {
+        let rtt = p.rtt_ms.map(|r| format!("{}ms", r)).unwrap_or_else(|| "-".into());
+        let addr = p.address.as_deref().unwrap_or("inbound");
+        let _ = writeln!(screen, "  {:<52} {:>7}  {}", p.peer, rtt, addr);
+    }
+    if !status.jobs.is_empty() {
+        let _ = writeln!(screen);
+        let _ = writeln!(screen, "Jobs:");
+        for j in &status.jobs {
+            let result = j.last_result.as_deref().unwrap_or("not run yet");
+            let _ = writeln!(screen, "  {:<20} {:<16} {}", j.name, j.schedule, result);
+        }
+    }
+    screen
+}
@@ -0,0 +1,177 @@
+//! Reports on failed connects: Every address tried, why dialing it failed
+//! and where we had it from.
+//!
+//! Printed on stderr when giving up on a peer and written as JSON to
+//! `dial_report.json` in the configuration directory, see `p2shd debug
+//! dial-report`.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, path::Path, time::Duration};
+
+use crate::{
+    config::path_exists,
+    format_version::{self, FormatVersion},
+    sealed_state,
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// Why dialing an address failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialFailure {
+    Timeout,
+    Refused,
+    /// Some other peer answered at the address.
+    WrongPeer,
+    /// Connected, but the security or multiplexing handshake failed.
+    Negotiation,
+    /// Anything else, e.g. no route to the host.
+    Unreachable,
+}
+
+impl DialFailure {
+    /// Best guess from `error` and its sources, libp2p does not tell more.
+    pub fn classify(error: &dyn Error) -> DialFailure {
+        let mut messages = error.to_string().to_lowercase();
+        let mut source = error.source();
+        while let Some(e) = source {
+            messages.push(' ');
+            messages.push_str(&e.to_string().to_lowercase());
+            source = e.source();
+        }
+        if messages.contains("timed out") || messages.contains("timeout") {
+            DialFailure::Timeout
+        } else if messages.contains("refused") {
+            DialFailure::Refused
+        } else if ["secio", "handshake", "multistream", "protocol", "upgrade"]
+            .iter()
+            .any(|m| messages.contains(m))
+        {
+            DialFailure::Negotiation
+        } else {
+            DialFailure::Unreachable
+        }
+    }
+}
+
+impl fmt::Display for DialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DialFailure::Timeout => "timeout",
+            DialFailure::Refused => "refused",
+            DialFailure::WrongPeer => "wrong peer id",
+            DialFailure::Negotiation => "negotiation failed",
+            DialFailure::Unreachable => "unreachable",
+        })
+    }
+}
+
+/// Where we learned an address from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddrSource {
+    /// Kademlia, queries or routing table.
+    Dht,
+    Mdns,
+    /// The peer told us via identify.
+    Identify,
+    /// The peer's published address record, see `addr_record`.
+    AddrRecord,
+    /// Cached from a previous run.
+    Cache,
+}
+
+impl fmt::Display for AddrSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AddrSource::Dht => "DHT",
+            AddrSource::Mdns => "mDNS",
+            AddrSource::Identify => "identify",
+            AddrSource::AddrRecord => "address record",
+            AddrSource::Cache => "cache",
+        })
+    }
+}
+
+/// One dial of a failed connect.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Dial {
+    pub addr: String,
+    pub source: AddrSource,
+    /// `None` if dialing worked, the tunnel failed later on.
+    pub failure: Option<DialFailure>,
+}
+
+/// Everything tried for connecting to a peer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DialReport {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    pub peer: String,
+    pub discovery_attempts: u32,
+    pub elapsed_secs: u64,
+    pub dials: Vec<Dial>,
+}
+
+impl DialReport {
+    pub fn new(peer: &PeerId, discovery_attempts: u32, elapsed: Duration) -> DialReport {
+        DialReport {
+            format: FORMAT,
+            peer: peer.to_string(),
+            discovery_attempts,
+            elapsed_secs: elapsed.as_secs(),
+            dials: Vec::new(),
+        }
+    }
+
+    /// Add a dial of `addr`, with its outcome.
+    pub fn push(&mut self, addr: &Multiaddr, source: AddrSource, failure: Option<DialFailure>) {
+        self.dials.push(Dial {
+            addr: addr.to_string(),
+            source,
+            failure,
+        });
+    }
+
+    /// Write the report as JSON to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let encoded = serde_json::to_vec_pretty(self).expect("Serializing dial report can't fail.");
+        sealed_state::write(path, &encoded).with_context(|| error::DialReport::Write(path.into()))
+    }
+
+    /// Read the report last written to `path`, see `save`.
+    pub fn load(path: &Path) -> Result<DialReport> {
+        let exists = path_exists(path).with_context(|| error::DialReport::Read(path.into()))?;
+        if !exists {
+            return Err(error::DialReport::NoReport(path.into()).into());
+        }
+        let raw = sealed_state::read(path).with_context(|| error::DialReport::Read(path.into()))?;
+        format_version::check(path, &raw, FORMAT)?;
+        serde_json::from_slice(&raw).with_context(|| error::DialReport::Read(path.into()))
+    }
+}
+
+impl fmt::Display for DialReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Connecting to {} failed, after {} DHT queries in {}s.",
+            self.peer, self.discovery_attempts, self.elapsed_secs
+        )?;
+        if self.dials.is_empty() {
+            return write!(f, "\nNo addresses found to dial.");
+        }
+        for d in &self.dials {
+            let outcome = d.failure.map_or("dialed, tunnel failed".to_string(), |e| e.to_string());
+            write!(f, "\n  {} (from {}): {}", d.addr, d.source, outcome)?;
+        }
+        Ok(())
+    }
+}
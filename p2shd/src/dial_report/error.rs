@@ -0,0 +1,15 @@
+//! Errors that can happen while writing or reading dial reports.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to dial report persistence.
+#[derive(Error, Debug)]
+pub enum DialReport {
+    #[error("No dial report found at '{0}', no connect failed yet.")]
+    NoReport(PathBuf),
+    #[error("Reading dial report '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Writing dial report '{0}' failed.")]
+    Write(PathBuf),
+}
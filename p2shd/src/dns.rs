@@ -0,0 +1,247 @@
+//! DNS resolution of `/dnsaddr` bootstrap entries and `/dns4`, `/dns6` multiaddrs.
+//!
+//! All lookups go through one `Resolver`, which either uses the system
+//! configuration or the configured servers, optionally via DNS over TLS or
+//! HTTPS for privacy and for networks with broken DNS.
+//!
+//! A `/dnsaddr/<domain>` resolves to the `dnsaddr=<multiaddr>` entries of the
+//! TXT records at `_dnsaddr.<domain>`, which might again be `/dnsaddr`
+//! addresses. This allows operators to rotate bootstrap servers without users
+//! having to edit their configuration.
+
+use anyhow::{Context as AnyhowContext, Result};
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::Deserialize;
+use std::{io, net::IpAddr, result, str::FromStr};
+use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+
+use crate::config::{parse_bootstrap_node, BootstrapNode, Config};
+
+mod error;
+pub mod transport;
+
+/// Upper bound on TXT lookups per `/dnsaddr` resolution, guarding against loops.
+const MAX_LOOKUPS: usize = 32;
+
+/// Protocol used for talking to configured DNS servers.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    /// Plain DNS.
+    Udp,
+    /// DNS over TLS.
+    Tls,
+    /// DNS over HTTPS.
+    Https,
+}
+
+impl DnsProtocol {
+    fn default_port(self) -> u16 {
+        match self {
+            DnsProtocol::Udp => 53,
+            DnsProtocol::Tls => 853,
+            DnsProtocol::Https => 443,
+        }
+    }
+}
+
+impl Default for DnsProtocol {
+    fn default() -> DnsProtocol {
+        DnsProtocol::Udp
+    }
+}
+
+impl FromStr for DnsProtocol {
+    type Err = error::Resolver;
+
+    fn from_str(s: &str) -> result::Result<DnsProtocol, Self::Err> {
+        match s {
+            "udp" => Ok(DnsProtocol::Udp),
+            "tls" => Ok(DnsProtocol::Tls),
+            "https" => Ok(DnsProtocol::Https),
+            _ => Err(error::Resolver::InvalidProtocol(s.into())),
+        }
+    }
+}
+
+/// Servers to talk to via DNS over TLS or HTTPS, as far as the enabled TLS feature supports it.
+#[cfg(feature = "rustls")]
+fn encrypted_servers(
+    protocol: DnsProtocol,
+    servers: &[IpAddr],
+    port: u16,
+    tls_name: String,
+) -> result::Result<NameServerConfigGroup, error::Resolver> {
+    Ok(match protocol {
+        DnsProtocol::Https => NameServerConfigGroup::from_ips_https(servers, port, tls_name),
+        _ => NameServerConfigGroup::from_ips_tls(servers, port, tls_name),
+    })
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn encrypted_servers(
+    protocol: DnsProtocol,
+    servers: &[IpAddr],
+    port: u16,
+    tls_name: String,
+) -> result::Result<NameServerConfigGroup, error::Resolver> {
+    match protocol {
+        DnsProtocol::Https => Err(error::Resolver::Unsupported("https")),
+        _ => Ok(NameServerConfigGroup::from_ips_tls(servers, port, tls_name)),
+    }
+}
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+fn encrypted_servers(
+    protocol: DnsProtocol,
+    _: &[IpAddr],
+    _: u16,
+    _: String,
+) -> result::Result<NameServerConfigGroup, error::Resolver> {
+    match protocol {
+        DnsProtocol::Https => Err(error::Resolver::Unsupported("https")),
+        _ => Err(error::Resolver::Unsupported("tls")),
+    }
+}
+
+/// Resolver for all DNS lookups p2shd does.
+#[derive(Clone)]
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+}
+
+impl Resolver {
+    /// Create a resolver as configured, falling back to the system's DNS configuration if no
+    /// servers are configured.
+    pub async fn new(cfg: &Config) -> Result<Resolver> {
+        let servers = cfg.dns_servers();
+        if servers.is_empty() {
+            return Resolver::from_system_conf().await;
+        }
+        let protocol = cfg.dns_protocol();
+        let port = cfg.dns_port().unwrap_or_else(|| protocol.default_port());
+        let group = match protocol {
+            DnsProtocol::Udp => NameServerConfigGroup::from_ips_clear(servers, port),
+            DnsProtocol::Tls | DnsProtocol::Https => {
+                let tls_name = cfg
+                    .dns_tls_name()
+                    .ok_or(error::Resolver::MissingTlsName)?
+                    .to_string();
+                encrypted_servers(protocol, servers, port, tls_name)?
+            }
+        };
+        log::debug!("Using DNS servers {:?} via {:?}", servers, protocol);
+        let config = ResolverConfig::from_parts(None, Vec::new(), group);
+        let inner = TokioAsyncResolver::tokio(config, ResolverOpts::default())
+            .await
+            .context(error::Resolver::Setup)?;
+        Ok(Resolver { inner })
+    }
+
+    /// Create a resolver using the system's DNS configuration.
+    pub async fn from_system_conf() -> Result<Resolver> {
+        let inner = TokioAsyncResolver::tokio_from_system_conf()
+            .await
+            .context(error::Resolver::SystemConf)?;
+        Ok(Resolver { inner })
+    }
+
+    /// Replace a leading `/dns4` or `/dns6` of `addr` by the resolved IP address.
+    ///
+    /// Other addresses are returned unchanged.
+    pub fn resolve_multiaddr(&self, addr: Multiaddr) -> BoxFuture<'static, io::Result<Multiaddr>> {
+        let resolver = self.inner.clone();
+        async move {
+            let target = match addr.iter().next() {
+                Some(Protocol::Dns4(name)) => Some((name.into_owned(), true)),
+                Some(Protocol::Dns6(name)) => Some((name.into_owned(), false)),
+                _ => None,
+            };
+            let (name, want_v4) = match target {
+                Some(t) => t,
+                None => return Ok(addr),
+            };
+            let ips = resolver
+                .lookup_ip(name.clone())
+                .await
+                .map_err(|e| other_err(error::Lookup::Failed(name.clone(), e)))?;
+            let ip = ips
+                .iter()
+                .find(|ip| ip.is_ipv4() == want_v4)
+                .ok_or_else(|| other_err(error::Lookup::NoAddress(name.clone())))?;
+            let host = match ip {
+                IpAddr::V4(ip) => Protocol::Ip4(ip),
+                IpAddr::V6(ip) => Protocol::Ip6(ip),
+            };
+            log::trace!("Resolved {} to {}", name, ip);
+            Ok(std::iter::once(host).chain(addr.iter().skip(1)).collect())
+        }
+        .boxed()
+    }
+
+    /// Resolve `/dnsaddr/<domain>` to the nodes behind it.
+    ///
+    /// Failures are logged, resulting in fewer or no nodes.
+    pub fn resolve_dnsaddr(
+        &self,
+        domain: String,
+        peer_id: Option<PeerId>,
+    ) -> BoxFuture<'static, Vec<BootstrapNode>> {
+        let resolver = self.inner.clone();
+        async move {
+            let mut nodes = Vec::new();
+            let mut pending = vec![domain];
+            let mut lookups = 0;
+            while let Some(domain) = pending.pop() {
+                if lookups >= MAX_LOOKUPS {
+                    log::warn!("Giving up resolving /dnsaddr, too many lookups.");
+                    break;
+                }
+                lookups += 1;
+                let name = format!("_dnsaddr.{}", domain);
+                let txt = match resolver.txt_lookup(name.clone()).await {
+                    Ok(txt) => txt,
+                    Err(e) => {
+                        log::warn!("Resolving {} failed: {}", name, e);
+                        continue;
+                    }
+                };
+                for record in txt.iter() {
+                    let data: Vec<u8> = record.txt_data().iter().flat_map(|d| d.iter().cloned()).collect();
+                    let addr = match parse_dnsaddr_txt(&data) {
+                        Some(a) => a,
+                        None => continue,
+                    };
+                    match addr.iter().next() {
+                        Some(Protocol::Dnsaddr(d)) => pending.push(d.into_owned()),
+                        _ => match parse_bootstrap_node(addr.clone()) {
+                            Ok(node) => {
+                                if peer_id.as_ref().map_or(true, |p| *p == node.peer_id) {
+                                    nodes.push(node);
+                                }
+                            }
+                            Err(e) => log::debug!("Ignoring {} in {}: {:#}", addr, name, e),
+                        },
+                    }
+                }
+            }
+            log::debug!("Resolved /dnsaddr to {} node(s).", nodes.len());
+            nodes
+        }
+        .boxed()
+    }
+}
+
+fn other_err(e: error::Lookup) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Extract the multiaddr of a `dnsaddr=<multiaddr>` TXT record.
+fn parse_dnsaddr_txt(data: &[u8]) -> Option<Multiaddr> {
+    let txt = std::str::from_utf8(data).ok()?;
+    txt.strip_prefix("dnsaddr=")?.parse().ok()
+}
@@ -0,0 +1,32 @@
+//! Errors that can happen during DNS resolution.
+
+use thiserror::Error;
+
+/// Errors related to setting up the resolver.
+#[derive(Error, Debug)]
+pub enum Resolver {
+    #[error("Reading the system's DNS configuration failed.")]
+    SystemConf,
+    #[error("Setting up the DNS resolver failed.")]
+    Setup,
+    #[error(
+        "No TLS name given for DNS servers.
+
+DNS over TLS/HTTPS needs the name the servers' certificates are issued for,
+e.g. --dns-tls-name cloudflare-dns.com"
+    )]
+    MissingTlsName,
+    #[error("Invalid DNS protocol '{0}', supported are 'udp', 'tls' and 'https'.")]
+    InvalidProtocol(String),
+    #[error("DNS protocol '{0}' is not supported by this build, see the rustls and native-tls features.")]
+    Unsupported(&'static str),
+}
+
+/// Errors related to resolving multiaddrs.
+#[derive(Error, Debug)]
+pub enum Lookup {
+    #[error("Resolving '{0}' failed.")]
+    Failed(String, #[source] trust_dns_resolver::error::ResolveError),
+    #[error("'{0}' did not resolve to any address of the requested family.")]
+    NoAddress(String),
+}
@@ -0,0 +1,67 @@
+//! Transport resolving `/dns4` and `/dns6` addresses via our `Resolver`.
+//!
+//! Replacement for libp2p's `DnsConfig`, which always uses the system resolver.
+
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{
+    core::transport::{Transport, TransportError},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use std::io;
+
+use super::Resolver;
+
+/// Wraps a transport, resolving DNS names before dialing.
+#[derive(Clone)]
+pub struct DnsTransport<T> {
+    inner: T,
+    resolver: Resolver,
+}
+
+impl<T> DnsTransport<T> {
+    pub fn new(inner: T, resolver: Resolver) -> DnsTransport<T> {
+        DnsTransport { inner, resolver }
+    }
+}
+
+impl<T> Transport for DnsTransport<T>
+where
+    T: Transport<Error = io::Error> + Send + 'static,
+    T::Dial: Send + 'static,
+    T::Output: Send + 'static,
+{
+    type Output = T::Output;
+    type Error = io::Error;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        self.inner.listen_on(addr)
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let is_dns = match addr.iter().next() {
+            Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) => true,
+            _ => false,
+        };
+        if !is_dns {
+            return Ok(self.inner.dial(addr)?.boxed());
+        }
+        let inner = self.inner;
+        let resolving = self.resolver.resolve_multiaddr(addr);
+        Ok(async move {
+            let resolved = resolving.await?;
+            match inner.dial(resolved) {
+                Ok(dial) => dial.await,
+                Err(TransportError::MultiaddrNotSupported(a)) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Resolved address '{}' is not supported.", a),
+                )),
+                Err(TransportError::Other(e)) => Err(e),
+            }
+        }
+        .boxed())
+    }
+}
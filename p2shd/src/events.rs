@@ -0,0 +1,171 @@
+//! Ring buffer of recent swarm and behaviour events for post-mortem debugging.
+//!
+//! If enabled, the last N events get recorded in memory (sanitized: IP
+//! addresses are replaced by their class) and are dumped to a file on exit and
+//! on panics, so intermittent resolution failures on user machines can be
+//! diagnosed after the fact.
+//!
+//! The buffer is global, as the panic hook needs access to it.
+//!
+//! Independently of recording, events can be watched live via `subscribe`
+//! (`subscribe_events` on the control socket, used by `p2shd dashboard`).
+
+use anyhow::{Context as AnyhowContext, Result};
+use futures::channel::mpsc;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    collections::VecDeque,
+    fs,
+    net::IpAddr,
+    panic,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    config::{self, path_exists},
+    net,
+};
+
+mod error;
+
+static RECORDER: OnceCell<Recorder> = OnceCell::new();
+
+/// Events queued per subscriber at most, further ones get dropped for it.
+const SUBSCRIBER_QUEUE: usize = 64;
+
+static SUBSCRIBERS: Lazy<Mutex<Vec<mpsc::Sender<String>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+struct Recorder {
+    capacity: usize,
+    started: Instant,
+    /// Where to dump the events to.
+    path: PathBuf,
+    events: Mutex<VecDeque<String>>,
+}
+
+/// Start recording the last `capacity` events, dumping them to `path` on panic.
+///
+/// Calling it more than once has no effect.
+pub fn enable(capacity: usize, path: PathBuf) {
+    let recorder = Recorder {
+        capacity,
+        started: Instant::now(),
+        path,
+        events: Mutex::new(VecDeque::with_capacity(capacity)),
+    };
+    if RECORDER.set(recorder).is_err() {
+        return;
+    }
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        record(format!("panic: {}", info));
+        if let Err(e) = dump() {
+            eprintln!("{:#}", e);
+        }
+        previous(info)
+    }));
+}
+
+/// Record an event, if recording is enabled, and pass it on to subscribers.
+pub fn record(event: impl Into<String>) {
+    let recorder = RECORDER.get();
+    // Don't block or panic (again) in a panic hook:
+    let mut subscribers = match SUBSCRIBERS.try_lock() {
+        Ok(s) if !s.is_empty() => Some(s),
+        _ => None,
+    };
+    if recorder.is_none() && subscribers.is_none() {
+        return;
+    }
+    let event = event.into();
+    if let Some(subscribers) = &mut subscribers {
+        for s in subscribers.iter_mut() {
+            // Slow subscribers miss events rather than holding us up:
+            let _ = s.try_send(event.clone());
+        }
+        subscribers.retain(|s| !s.is_closed());
+    }
+    let recorder = match recorder {
+        None => return,
+        Some(r) => r,
+    };
+    let mut events = match recorder.events.try_lock() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    if events.len() >= recorder.capacity {
+        events.pop_front();
+    }
+    let elapsed = recorder.started.elapsed();
+    events.push_back(format!(
+        "[{:>6}.{:03}] {}",
+        elapsed.as_secs(),
+        elapsed.subsec_millis(),
+        event
+    ));
+}
+
+/// Events recorded from now on, whether recording is enabled or not.
+pub fn subscribe() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+    SUBSCRIBERS.lock().expect("Event subscribers lock poisoned.").push(tx);
+    rx
+}
+
+/// The recorded events, oldest first. `None` if recording is not enabled or
+/// the buffer is busy.
+pub fn recent() -> Option<Vec<String>> {
+    let events = RECORDER.get()?.events.try_lock().ok()?;
+    Some(events.iter().cloned().collect())
+}
+
+/// Write all recorded events to the dump file, if recording is enabled.
+pub fn dump() -> Result<()> {
+    let recorder = match RECORDER.get() {
+        None => return Ok(()),
+        Some(r) => r,
+    };
+    let events = match recorder.events.try_lock() {
+        Ok(e) => e,
+        Err(_) => return Ok(()),
+    };
+    let mut contents = events.iter().cloned().collect::<Vec<_>>().join("\n");
+    contents.push('\n');
+    config::write_private(&recorder.path, contents.as_bytes())
+        .with_context(|| error::Events::Write(recorder.path.clone()))
+}
+
+/// Read the events dumped to `path` by a previous run.
+pub fn read_dump(path: &Path) -> Result<String> {
+    let exists = path_exists(path).with_context(|| error::Events::Read(path.into()))?;
+    if !exists {
+        return Err(error::Events::NoDump(path.into()).into());
+    }
+    fs::read_to_string(path).with_context(|| error::Events::Read(path.into()))
+}
+
+/// Render a multiaddr with IP addresses replaced by their class, so dumps can be shared.
+pub fn sanitize_addr(addr: &Multiaddr) -> String {
+    addr.iter()
+        .map(|p| match p {
+            Protocol::Ip4(ip) => format!("/ip4/<{}>", ip_class(&ip.into())),
+            Protocol::Ip6(ip) => format!("/ip6/<{}>", ip_class(&ip.into())),
+            Protocol::Dns4(_) => "/dns4/<name>".to_string(),
+            Protocol::Dns6(_) => "/dns6/<name>".to_string(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+fn ip_class(ip: &IpAddr) -> &'static str {
+    if ip.is_loopback() {
+        "loopback"
+    } else if net::is_lan(ip) {
+        "lan"
+    } else {
+        "public"
+    }
+}
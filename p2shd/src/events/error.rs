@@ -0,0 +1,20 @@
+//! Errors that can happen while dumping or reading recorded events.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to the event dump.
+#[derive(Error, Debug)]
+pub enum Events {
+    #[error(
+        "No event dump found at '{0}'.
+
+Events are only recorded with --record-events, they get dumped on exit and on
+crashes."
+    )]
+    NoDump(PathBuf),
+    #[error("Reading event dump '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Writing event dump '{0}' failed.")]
+    Write(PathBuf),
+}
@@ -0,0 +1,98 @@
+//! Format versions of the state files p2shd keeps in its config directory.
+//!
+//! Every state file carries a `format` field, `<major>.<minor>`. Minor
+//! versions only add fields, which older readers ignore, so any minor of a
+//! known major can be read. A newer major is refused with a message saying
+//! so, instead of failing to decode or, worse, decoding it wrongly and
+//! overwriting it on the next save. Files from before there were versions
+//! have no `format` and are treated as 1.0.
+//!
+//! The version gets checked on the raw file, before decoding it, as a newer
+//! major might not decode at all.
+
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, path::Path, str::FromStr};
+
+mod error;
+
+pub use error::FormatVersion as Error;
+
+/// Format version of a state file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FormatVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl FormatVersion {
+    pub const fn new(major: u32, minor: u32) -> FormatVersion {
+        FormatVersion { major, minor }
+    }
+}
+
+impl Default for FormatVersion {
+    /// The format of files written before there were versions.
+    fn default() -> FormatVersion {
+        FormatVersion::new(1, 0)
+    }
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for FormatVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FormatVersion, Self::Err> {
+        let invalid = || Error::Invalid(s.into());
+        let mut parts = s.splitn(2, '.');
+        let major = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        let minor = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        Ok(FormatVersion::new(major, minor))
+    }
+}
+
+impl TryFrom<String> for FormatVersion {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<FormatVersion, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<FormatVersion> for String {
+    fn from(v: FormatVersion) -> String {
+        v.to_string()
+    }
+}
+
+/// Just the version of a state file, whatever else is in there.
+#[derive(Deserialize)]
+struct Stamp {
+    #[serde(default)]
+    format: FormatVersion,
+}
+
+/// Make sure we can read the state file `path` with contents `raw`, written
+/// in the format `supported` or an older one.
+///
+/// Content that does not decode at all is left to the actual decoding to
+/// report.
+pub fn check(path: &Path, raw: &[u8], supported: FormatVersion) -> Result<(), Error> {
+    let found = match serde_json::from_slice::<Stamp>(raw) {
+        Ok(stamp) => stamp.format,
+        Err(_) => return Ok(()),
+    };
+    if found.major > supported.major {
+        return Err(Error::Newer {
+            path: path.into(),
+            found,
+            supported,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,22 @@
+//! Errors that can happen while checking format versions of state files.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to format versions.
+#[derive(Error, Debug)]
+pub enum FormatVersion {
+    #[error("Invalid format version '{0}', expected <major>.<minor>.")]
+    Invalid(String),
+    #[error(
+        "'{path}' is in format {found}, written by a newer p2shd; this one only supports {}.x.
+
+Upgrade p2shd, or move the file away to start over with it empty.",
+        supported.major
+    )]
+    Newer {
+        path: PathBuf,
+        found: super::FormatVersion,
+        supported: super::FormatVersion,
+    },
+}
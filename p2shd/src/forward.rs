@@ -0,0 +1,580 @@
+//! Opening tunnels from outside the swarm, for forwarding local connections.
+//!
+//! Local servers (like the SOCKS server or `-L` listeners) run as their own
+//! tasks. They ask the behaviour for tunnels via an `Opener`, the behaviour
+//! opens them and hands the ready substream back.
+//!
+//! Remote forwardings (`-R`) work the other way round: A `Request::Listen`
+//! tunnel makes the peer listen for as long as that tunnel stays open. It
+//! opens a `Request::Reverse` tunnel back to us for every connection it
+//! accepts, which we connect to the local destination.
+//!
+//! The `-L` and `-R` forwardings of a session get negotiated in one go, via a
+//! single `Request::Forwards` listing all of them. The peer checks each one
+//! (connecting to the destination of `-L` ones once, starting to listen for
+//! `-R` ones) before answering with a verdict per entry, the tunnel then
+//! serves as control tunnel of the `-R` ones.
+//! Peers not supporting this get asked for each forwarding on its own.
+
+use async_std::{
+    io::{stdin, stdout},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future, io,
+    prelude::*,
+};
+use libp2p::PeerId;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::tunnel::{self, Request, Timeouts, TunnelStream};
+
+mod error;
+
+/// Answers to `request_lines` may not be larger than this.
+const MAX_LINES_SIZE: u64 = 64 * 1024;
+
+/// How long `request_lines` waits for the complete answer, once the tunnel is open.
+const LINES_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long checking a `Request::Tcp` entry of `Request::Forwards` may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A port forwarding, `[bind_address:]port:host:hostport` as for `-L` and `-R`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortForward {
+    /// Where to accept connections: Locally for `-L`, at the peer for `-R`.
+    pub listen: SocketAddr,
+    /// Destination: As reachable from the peer for `-L`, from us for `-R`.
+    pub host: String,
+    pub port: u16,
+}
+
+/// A tunnel requested via an `Opener`.
+pub struct StreamRequest {
+    pub peer: PeerId,
+    /// Receives the opened stream, or why opening failed.
+    pub reply: oneshot::Sender<io::Result<TunnelStream>>,
+}
+
+/// Handle for requesting tunnels from the behaviour.
+#[derive(Clone)]
+pub struct Opener {
+    tx: mpsc::UnboundedSender<StreamRequest>,
+}
+
+impl Opener {
+    /// An opener and the receiving end the behaviour has to serve.
+    pub fn new() -> (Opener, mpsc::UnboundedReceiver<StreamRequest>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Opener { tx }, rx)
+    }
+
+    /// Open a tunnel to `peer` and send `request` on it.
+    pub async fn open(&self, peer: PeerId, request: &Request) -> io::Result<TunnelStream> {
+        let (reply, opened) = oneshot::channel();
+        self.tx
+            .unbounded_send(StreamRequest { peer, reply })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Swarm is gone."))?;
+        let mut stream = opened
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Swarm is gone."))??;
+        let timeouts = tunnel::request(&mut stream, request).await?;
+        if timeouts != Timeouts::default() {
+            log::info!("Peer enforces timeouts on '{}': {:?}", request, timeouts);
+        }
+        Ok(stream)
+    }
+}
+
+/// Serve an inbound `Request::Tcp`, connecting the tunnel to `host:port`.
+pub async fn serve_tcp(mut stream: TunnelStream, host: &str, port: u16, timeouts: Timeouts) -> io::Result<()> {
+    let socket = match TcpStream::connect((host, port)).await {
+        Ok(s) => s,
+        Err(e) => {
+            tunnel::reject(&mut stream, &format!("connecting failed: {}", e)).await?;
+            return Err(e);
+        }
+    };
+    tunnel::accept(&mut stream, &timeouts).await?;
+    let (sr, sw) = stream.split();
+    let (tr, tw) = socket.split();
+    tunnel::bridge_with_timeouts(sr, sw, tr, tw, &timeouts).await
+}
+
+impl FromStr for PortForward {
+    type Err = error::PortForward;
+
+    fn from_str(s: &str) -> Result<PortForward, Self::Err> {
+        let invalid = || error::PortForward::Invalid(s.into());
+        let (rest, port) = split_last(s).ok_or_else(invalid)?;
+        let (rest, host) = split_last(rest).ok_or_else(invalid)?;
+        let (bind, listen_port) = match split_last(rest) {
+            Some((bind, listen_port)) => {
+                let bind = bind.trim_start_matches('[').trim_end_matches(']');
+                (bind.parse().map_err(|_| invalid())?, listen_port)
+            }
+            None => (IpAddr::V4(Ipv4Addr::LOCALHOST), rest),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(PortForward {
+            listen: SocketAddr::new(bind, listen_port.parse().map_err(|_| invalid())?),
+            host: host.into(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}:[{}]:{}", self.listen, self.host, self.port)
+        } else {
+            write!(f, "{}:{}:{}", self.listen, self.host, self.port)
+        }
+    }
+}
+
+/// Split off the last `:` separated part, which may be an IPv6 address in brackets.
+fn split_last(s: &str) -> Option<(&str, &str)> {
+    let (rest, last) = if s.ends_with(']') {
+        let open = s.rfind('[')?;
+        (&s[..open], &s[open + 1..s.len() - 1])
+    } else {
+        match s.rfind(':') {
+            Some(colon) => return Some((&s[..colon], &s[colon + 1..])),
+            None => return None,
+        }
+    };
+    if rest.is_empty() {
+        return None;
+    }
+    Some((rest.strip_suffix(':')?, last))
+}
+
+/// A port or address to listen on, ports are on the loopback interface.
+pub fn parse_listen_addr(s: &str) -> Result<SocketAddr, error::PortForward> {
+    match s.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        Err(_) => s
+            .parse()
+            .map_err(|_| error::PortForward::InvalidListenAddr(s.into())),
+    }
+}
+
+/// Accept connections on `listen`, opening a tunnel with `request` to `peer` for each.
+pub async fn listen(listen: SocketAddr, request: Request, peer: PeerId, opener: Opener) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    forward_accepted(listener, listen, request, peer, opener).await
+}
+
+/// Like `listen`, for an already bound `listener`.
+async fn forward_accepted(
+    listener: TcpListener,
+    listen: SocketAddr,
+    request: Request,
+    peer: PeerId,
+    opener: Opener,
+) -> io::Result<()> {
+    log::info!("Forwarding {} to '{}' via {}", listen, request, peer);
+    loop {
+        let (socket, from) = listener.accept().await?;
+        let (request, peer, opener) = (request.clone(), peer.clone(), opener.clone());
+        task::spawn(async move {
+            let result = match opener.open(peer, &request).await {
+                Ok(stream) => {
+                    let (sr, sw) = stream.split();
+                    let (tr, tw) = socket.split();
+                    tunnel::bridge(sr, sw, tr, tw).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::info!("Forwarding connection from {} to '{}' failed: {}", from, request, e);
+            }
+        });
+    }
+}
+
+/// Open a tunnel with `request` to `peer` and bridge it to stdin/stdout.
+pub async fn run_stdio(request: Request, peer: PeerId, opener: Opener) -> io::Result<()> {
+    let stream = opener.open(peer, &request).await?;
+    let (sr, sw) = stream.split();
+    tunnel::bridge(sr, sw, stdin(), stdout()).await
+}
+
+/// Send a request answered with lines of text (e.g. `Request::Services`) to `peer`.
+///
+/// The lines are meant for the terminal, so control characters get stripped.
+pub async fn request_lines(request: Request, peer: PeerId, opener: Opener) -> io::Result<Vec<String>> {
+    let stream = opener.open(peer, &request).await?;
+    let read = io::BufReader::new(stream.take(MAX_LINES_SIZE)).lines().try_collect::<Vec<_>>();
+    let lines = async_std::future::timeout(LINES_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer took too long to answer."))??;
+    Ok(lines.iter().map(|l| printable(l)).collect())
+}
+
+/// `line` without control characters (except tabs), so peers can't mess with the terminal.
+fn printable(line: &str) -> String {
+    line.chars().filter(|c| *c == '\t' || !c.is_control()).collect()
+}
+
+/// Answer an inbound request with `lines`, see `request_lines`.
+pub async fn serve_lines(mut stream: TunnelStream, lines: Vec<String>) -> io::Result<()> {
+    tunnel::accept(&mut stream, &Timeouts::default()).await?;
+    for line in lines {
+        stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+    stream.close().await
+}
+
+/// Have `peer` listen on `fwd.listen`, connections get forwarded back to `fwd.host:fwd.port`.
+///
+/// Resolves once the peer stopped listening. Inbound `Request::Reverse`
+/// tunnels have to be served via `serve_tcp`.
+pub async fn request_listen(fwd: PortForward, peer: PeerId, opener: Opener) -> io::Result<()> {
+    let control = opener.open(peer.clone(), &Request::Listen { addr: fwd.listen }).await?;
+    log::info!("Remote forwarding {} via {}", fwd, peer);
+    hold_listen(control).await
+}
+
+/// Keep the control tunnel of a `Request::Listen` open, until the peer closes it.
+async fn hold_listen(mut control: TunnelStream) -> io::Result<()> {
+    // The peer listens as long as the control tunnel is open, nothing gets sent on it:
+    let mut buf = [0u8; 64];
+    while control.read(&mut buf).await? != 0 {}
+    Err(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Peer closed the forwarding.",
+    ))
+}
+
+/// Set up `fwd` via `peer` (as for `-R` if `remote`, `-L` otherwise), added
+/// to a running session.
+///
+/// `ready` gets told whether setting it up worked, once it did the
+/// forwarding runs until it fails or the peer closes it.
+pub async fn add(
+    fwd: PortForward,
+    remote: bool,
+    peer: PeerId,
+    opener: Opener,
+    ready: oneshot::Sender<io::Result<()>>,
+) -> io::Result<()> {
+    let running = if remote {
+        match opener.open(peer.clone(), &Request::Listen { addr: fwd.listen }).await {
+            Ok(control) => {
+                log::info!("Remote forwarding {} via {}", fwd, peer);
+                hold_listen(control).boxed()
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return Ok(());
+            }
+        }
+    } else {
+        match TcpListener::bind(fwd.listen).await {
+            Ok(listener) => {
+                let request = Request::Tcp {
+                    host: fwd.host.clone(),
+                    port: fwd.port,
+                };
+                forward_accepted(listener, fwd.listen, request, peer, opener).boxed()
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return Ok(());
+            }
+        }
+    };
+    let _ = ready.send(Ok(()));
+    running.await
+}
+
+/// Serve an inbound `Request::Listen`: Listen on `addr` for as long as
+/// `control` is open, forwarding connections back to `peer`.
+pub async fn serve_listen(
+    mut control: TunnelStream,
+    addr: SocketAddr,
+    peer: PeerId,
+    opener: Opener,
+    timeouts: Timeouts,
+) -> io::Result<()> {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tunnel::reject(&mut control, &format!("listening failed: {}", e)).await?;
+            return Err(e);
+        }
+    };
+    tunnel::accept(&mut control, &Timeouts::default()).await?;
+    log::info!("Listening on {} for {}", addr, peer);
+    let accepting = accept_reverse(listener, addr, peer.clone(), opener, timeouts);
+    let closed = async {
+        let mut buf = [0u8; 64];
+        while control.read(&mut buf).await? != 0 {}
+        log::info!("{} closed forwarding of {}", peer, addr);
+        Ok(())
+    };
+    futures::pin_mut!(accepting, closed);
+    future::select(accepting, closed).await.factor_first().0
+}
+
+/// Forward connections accepted at `addr` back to `peer`, via `Request::Reverse` tunnels.
+async fn accept_reverse(
+    listener: TcpListener,
+    addr: SocketAddr,
+    peer: PeerId,
+    opener: Opener,
+    timeouts: Timeouts,
+) -> io::Result<()> {
+    loop {
+        let (socket, from) = listener.accept().await?;
+        let (peer, opener) = (peer.clone(), opener.clone());
+        task::spawn(async move {
+            let result = match opener.open(peer.clone(), &Request::Reverse { addr }).await {
+                Ok(stream) => {
+                    let (sr, sw) = stream.split();
+                    let (tr, tw) = socket.split();
+                    tunnel::bridge_with_timeouts(sr, sw, tr, tw, &timeouts).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::info!("Forwarding connection from {} to {} failed: {}", from, peer, e);
+            }
+        });
+    }
+}
+
+/// Set up the `local` (`-L`) and `remote` (`-R`) forwardings via `peer`.
+///
+/// All of them get negotiated in a single `Request::Forwards`, denied ones
+/// are reported and skipped. Resolves once a forwarding failed or the peer
+/// closed the remote ones, with an error if all got denied.
+pub async fn negotiate(
+    local: Vec<PortForward>,
+    remote: Vec<PortForward>,
+    peer: PeerId,
+    opener: Opener,
+) -> io::Result<()> {
+    let entries: Vec<_> = local
+        .iter()
+        .map(|f| Request::Tcp {
+            host: f.host.clone(),
+            port: f.port,
+        })
+        .chain(remote.iter().map(|f| Request::Listen { addr: f.listen }))
+        .collect();
+    let mut control = match opener.open(peer.clone(), &Request::Forwards(entries.clone())).await {
+        Ok(control) => control,
+        Err(e) => {
+            log::debug!("Negotiating forwardings failed ({}), requesting them one by one.", e);
+            return run_each(local, remote, peer, opener).await;
+        }
+    };
+    let verdicts = tunnel::read_verdicts(&mut control, entries.len()).await?;
+    let (mut accepted_local, mut accepted_remote) = (Vec::new(), Vec::new());
+    let forwards = local.into_iter().map(|f| (f, true)).chain(remote.into_iter().map(|f| (f, false)));
+    for ((fwd, is_local), verdict) in forwards.zip(verdicts) {
+        match verdict {
+            Err(reason) => log::error!("{} denied forwarding {}: {}", peer, fwd, reason),
+            Ok(()) if is_local => accepted_local.push(fwd),
+            Ok(()) => {
+                log::info!("Remote forwarding {} via {}", fwd, peer);
+                accepted_remote.push(fwd);
+            }
+        }
+    }
+    if accepted_local.is_empty() && accepted_remote.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "All forwardings got denied."));
+    }
+    let listening = accepted_local.into_iter().map(|fwd| {
+        let request = Request::Tcp {
+            host: fwd.host.clone(),
+            port: fwd.port,
+        };
+        listen(fwd.listen, request, peer.clone(), opener.clone()).boxed()
+    });
+    let mut running: Vec<_> = listening.collect();
+    if !accepted_remote.is_empty() {
+        // The peer listens as long as the control tunnel is open, nothing more gets sent on it:
+        running.push(
+            async move {
+                let mut buf = [0u8; 64];
+                while control.read(&mut buf).await? != 0 {}
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Peer closed the remote forwardings.",
+                ))
+            }
+            .boxed(),
+        );
+    }
+    future::select_all(running).await.0
+}
+
+/// Set up forwardings with peers not supporting `Request::Forwards`, one request each.
+async fn run_each(
+    local: Vec<PortForward>,
+    remote: Vec<PortForward>,
+    peer: PeerId,
+    opener: Opener,
+) -> io::Result<()> {
+    let local = local.into_iter().map(|fwd| {
+        let request = Request::Tcp {
+            host: fwd.host.clone(),
+            port: fwd.port,
+        };
+        listen(fwd.listen, request, peer.clone(), opener.clone()).boxed()
+    });
+    let remote = remote
+        .into_iter()
+        .map(|fwd| request_listen(fwd, peer.clone(), opener.clone()).boxed());
+    future::select_all(local.chain(remote)).await.0
+}
+
+/// Whether `host`:`port` can be connected to, like `serve_tcp` would.
+async fn check_tcp(host: &str, port: u16) -> Result<(), String> {
+    match async_std::future::timeout(CHECK_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("connecting failed: {}", e)),
+        Err(_) => Err("connecting timed out".to_string()),
+    }
+}
+
+/// Serve an inbound `Request::Forwards`: Give a verdict on each entry, then
+/// listen on the addresses of accepted `Request::Listen` entries for as long
+/// as `control` is open.
+pub async fn serve_forwards(
+    mut control: TunnelStream,
+    entries: Vec<Request>,
+    allowed: bool,
+    peer: PeerId,
+    opener: Opener,
+    timeouts: Timeouts,
+) -> io::Result<()> {
+    tunnel::accept(&mut control, &Timeouts::default()).await?;
+    let mut listeners = Vec::new();
+    let mut verdicts = Vec::new();
+    for entry in entries {
+        let verdict = match entry {
+            _ if !allowed => Err("forwarding not allowed".to_string()),
+            // Connections get made once the client's listener accepts one, until then we can
+            // only tell whether the destination is reachable at all:
+            Request::Tcp { host, port } => check_tcp(&host, port).await,
+            Request::Listen { addr } => match TcpListener::bind(addr).await {
+                Ok(l) => {
+                    log::info!("Listening on {} for {}", addr, peer);
+                    listeners.push(accept_reverse(l, addr, peer.clone(), opener.clone(), timeouts).boxed());
+                    Ok(())
+                }
+                Err(e) => Err(format!("listening on {} failed: {}", addr, e)),
+            },
+            _ => Err("not a forwarding".to_string()),
+        };
+        verdicts.push(verdict);
+    }
+    tunnel::send_verdicts(&mut control, &verdicts).await?;
+    let closed = async {
+        let mut buf = [0u8; 64];
+        while control.read(&mut buf).await? != 0 {}
+        log::info!("{} closed its forwardings", peer);
+        Ok(())
+    }
+    .boxed_local();
+    if listeners.is_empty() {
+        return closed.await;
+    }
+    let accepting = future::select_all(listeners).map(|(result, _, _)| result);
+    future::select(accepting, closed).await.factor_first().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward(listen: &str, host: &str, port: u16) -> PortForward {
+        PortForward {
+            listen: listen.parse().unwrap(),
+            host: host.into(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_port_forwards() {
+        let cases = [
+            ("8080:localhost:80", forward("127.0.0.1:8080", "localhost", 80)),
+            ("0.0.0.0:8080:db:5432", forward("0.0.0.0:8080", "db", 5432)),
+            ("[::1]:8080:localhost:80", forward("[::1]:8080", "localhost", 80)),
+            ("8080:[::1]:80", forward("127.0.0.1:8080", "::1", 80)),
+            ("[::]:8080:[fe80::1]:22", forward("[::]:8080", "fe80::1", 22)),
+            ("8080:10.0.0.1:80", forward("127.0.0.1:8080", "10.0.0.1", 80)),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(&input.parse::<PortForward>().unwrap(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_port_forwards() {
+        let cases = [
+            "",
+            "80",
+            "localhost:80",
+            "8080::80",
+            "8080:localhost:",
+            "8080:localhost:http",
+            ":localhost:80",
+            "70000:localhost:80",
+            "somehost:8080:localhost:80",
+            "8080:[::1:80",
+            "8080:::1]:80",
+            "1:2:8080:localhost:80",
+        ];
+        for input in &cases {
+            assert!(input.parse::<PortForward>().is_err(), "'{}' got accepted", input);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for input in &["8080:localhost:80", "[::1]:8080:[::1]:80", "0.0.0.0:1:db:2"] {
+            let parsed: PortForward = input.parse().unwrap();
+            assert_eq!(parsed.to_string().parse::<PortForward>().unwrap(), parsed, "{}", input);
+        }
+        assert_eq!(
+            "8080:[::1]:80".parse::<PortForward>().unwrap().to_string(),
+            "127.0.0.1:8080:[::1]:80"
+        );
+    }
+
+    #[test]
+    fn splits_last_part() {
+        let cases = [
+            ("a:b", Some(("a", "b"))),
+            ("a:b:c", Some(("a:b", "c"))),
+            ("a:", Some(("a", ""))),
+            ("a:[::1]", Some(("a", "::1"))),
+            ("[::1]:80", Some(("[::1]", "80"))),
+            ("a", None),
+            ("[::1]", None),
+            ("a[::1]", None),
+            ("::1]", None),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(split_last(input), *expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Errors that can happen while setting up forwardings.
+
+use thiserror::Error;
+
+/// Errors related to `-L` and `-R` arguments.
+#[derive(Error, Debug)]
+pub enum PortForward {
+    #[error(
+        "Invalid port forwarding '{0}', expected [bind_address:]port:host:hostport.
+
+E.g. '8080:localhost:80' or '0.0.0.0:5432:db.lan:5432'."
+    )]
+    Invalid(String),
+    #[error("Invalid listen address '{0}', expected a port or an address like '127.0.0.1:8080'.")]
+    InvalidListenAddr(String),
+}
@@ -0,0 +1,132 @@
+//! gRPC flavour of the control API, for typed clients (feature `grpc`).
+//!
+//! Served by `p2shd listen --grpc <addr>`, see `proto/control.proto`. Calls
+//! are the very same as those of the JSON-RPC control socket (`control`).
+
+use futures_timer::Delay;
+use std::{net::SocketAddr, time::Duration};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    control::{Call, Controller, Reply},
+    ssh,
+};
+
+/// Generated from `proto/control.proto`.
+pub mod proto {
+    tonic::include_proto!("p2shd.control");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+/// Serve the gRPC API on `addr`, until failure.
+pub async fn serve(addr: SocketAddr, controller: Controller) -> Result<(), tonic::transport::Error> {
+    log::info!("gRPC control API listening on {}", addr);
+    Server::builder()
+        .add_service(ControlServer::new(Service { controller }))
+        .serve(addr)
+        .await
+}
+
+struct Service {
+    controller: Controller,
+}
+
+impl Service {
+    async fn call(&self, call: Call) -> Result<Reply, Status> {
+        self.controller.call(call).await.map_err(Status::unavailable)
+    }
+}
+
+fn parse_peer(peer: &str) -> Result<libp2p::PeerId, Status> {
+    peer.parse()
+        .map_err(|_| Status::invalid_argument(format!("Invalid peer id '{}'.", peer)))
+}
+
+fn unexpected(reply: Reply) -> Status {
+    Status::internal(format!("Unexpected reply: {:?}", reply))
+}
+
+#[tonic::async_trait]
+impl Control for Service {
+    async fn resolve_peer(
+        &self,
+        request: Request<proto::PeerRequest>,
+    ) -> Result<Response<proto::ResolvePeerReply>, Status> {
+        let peer = parse_peer(&request.get_ref().peer)?;
+        match self.call(Call::ResolvePeer(peer)).await? {
+            Reply::Addresses { addresses } => Ok(Response::new(proto::ResolvePeerReply { addresses })),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    async fn connect(
+        &self,
+        request: Request<proto::PeerRequest>,
+    ) -> Result<Response<proto::ConnectReply>, Status> {
+        let peer = parse_peer(&request.get_ref().peer)?;
+        match self.call(Call::Connect(peer)).await? {
+            Reply::Connected { peer } => Ok(Response::new(proto::ConnectReply { peer })),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    async fn list_peers(
+        &self,
+        _: Request<proto::Empty>,
+    ) -> Result<Response<proto::ListPeersReply>, Status> {
+        match self.call(Call::ListPeers).await? {
+            Reply::Peers { peers } => Ok(Response::new(proto::ListPeersReply {
+                peers: peers
+                    .into_iter()
+                    .map(|p| proto::PeerInfo {
+                        peer: p.peer,
+                        address: p.address.unwrap_or_default(),
+                        rtt_ms: p.rtt_ms.unwrap_or_default(),
+                    })
+                    .collect(),
+            })),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    async fn status(&self, _: Request<proto::Empty>) -> Result<Response<proto::StatusReply>, Status> {
+        match self.call(Call::Status).await? {
+            Reply::Status(s) => Ok(Response::new(proto::StatusReply {
+                peer_id: s.peer_id,
+                serving: s.serving,
+                connected_peers: s.connected_peers as u64,
+                warm_peers: s.warm_peers,
+                active_tunnels: s.active_tunnels as u64,
+                listen_addrs: s.listen_addrs,
+                external_addrs: s.external_addrs,
+                nat: format!("{:?}", s.nat).to_lowercase(),
+                routing_table_size: s.routing_table_size as u64,
+                queries: s.queries as u64,
+                jobs: s
+                    .jobs
+                    .into_iter()
+                    .map(|j| proto::JobInfo {
+                        name: j.name,
+                        schedule: j.schedule,
+                        last_run: j.last_run.unwrap_or_default(),
+                        last_result: j.last_result.unwrap_or_default(),
+                    })
+                    .collect(),
+            })),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    async fn shutdown(&self, _: Request<proto::Empty>) -> Result<Response<proto::Empty>, Status> {
+        self.call(Call::Shutdown).await?;
+        // State is saved, give the response a moment to get out:
+        tokio::spawn(async {
+            Delay::new(Duration::from_millis(200)).await;
+            log::info!("Shutting down, as requested via gRPC.");
+            ssh::wait_children().await;
+            std::process::exit(0);
+        });
+        Ok(Response::new(proto::Empty {}))
+    }
+}
@@ -0,0 +1,121 @@
+//! Minimal HTTP endpoint for monitoring and container healthchecks.
+//!
+//! `p2shd listen --http-status 127.0.0.1:8042` serves:
+//!
+//! - `/healthz`: `200` with `{"status": "ok"}` while the swarm is responsive, `503` otherwise.
+//! - `/status`: Listen and observed addresses, NAT status, routing table size
+//!   and active tunnels, as `control::Status`.
+//! - `/peers`: Connected peers, as `control::PeerInfo` list.
+//! - `/metrics`: Metrics in Prometheus' text format, see `prometheus`.
+//!
+//! There is no authentication, so this is meant to listen on localhost only.
+
+use async_std::{
+    future::timeout,
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::{io::BufReader, prelude::*};
+use serde_json::json;
+use std::{io, net::SocketAddr, time::Duration};
+
+use crate::{
+    control::{Call, Controller, Reply},
+    prometheus,
+};
+
+/// Content type of Prometheus' text format.
+const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4";
+
+/// How long the swarm may take to answer, before `/healthz` reports failure.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests with longer headers get dropped.
+const MAX_HEADER_LINES: usize = 100;
+
+/// Request line and headers may not be larger than this, in bytes.
+const MAX_HEADER_SIZE: u64 = 16 * 1024;
+
+/// How long clients may take to send request line and headers.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve HTTP requests on `listen`, until failure.
+pub async fn serve(listen: SocketAddr, controller: Controller) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("HTTP status endpoint listening on {}", listen);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let controller = controller.clone();
+        task::spawn(async move {
+            if let Err(e) = handle(socket, controller).await {
+                log::debug!("HTTP status request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(socket: TcpStream, controller: Controller) -> io::Result<()> {
+    let mut reader = BufReader::new((&socket).take(MAX_HEADER_SIZE));
+    let request_line = timeout(HEADER_TIMEOUT, read_head(&mut reader))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Reading the request timed out."))??;
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match controller.call(Call::Status).await {
+            Ok(Reply::Status(s)) => ("200 OK", PROMETHEUS_TYPE, prometheus::render(&s)),
+            Ok(_) => unreachable!("Status calls get status replies."),
+            Err(e) => ("503 Service Unavailable", PROMETHEUS_TYPE, format!("# {}\n", e)),
+        },
+        (Some(method), Some(path)) => {
+            let (status, body) = match method {
+                "GET" => route(path, &controller).await,
+                _ => ("405 Method Not Allowed", json!({"error": "only GET is supported"})),
+            };
+            (status, "application/json", format!("{}\n", body))
+        }
+        _ => ("400 Bad Request", "application/json", format!("{}\n", json!({"error": "invalid request line"}))),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    (&socket).write_all(response.as_bytes()).await?;
+    (&socket).flush().await
+}
+
+/// Read the request line and skip the headers, which are of no interest but have to be read
+/// before answering.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    for _ in 0..MAX_HEADER_LINES {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+async fn route(path: &str, controller: &Controller) -> (&'static str, serde_json::Value) {
+    let call = match path {
+        "/healthz" => {
+            return match timeout(HEALTH_TIMEOUT, controller.call(Call::Status)).await {
+                Ok(Ok(_)) => ("200 OK", json!({"status": "ok"})),
+                Ok(Err(e)) => ("503 Service Unavailable", json!({"status": "failing", "error": e})),
+                Err(_) => ("503 Service Unavailable", json!({"status": "failing", "error": "timeout"})),
+            }
+        }
+        "/status" => Call::Status,
+        "/peers" => Call::ListPeers,
+        _ => return ("404 Not Found", json!({"error": "not found"})),
+    };
+    match controller.call(call).await {
+        Ok(Reply::Peers { peers }) => ("200 OK", json!(peers)),
+        Ok(reply) => ("200 OK", json!(reply)),
+        Err(e) => ("503 Service Unavailable", json!({"error": e})),
+    }
+}
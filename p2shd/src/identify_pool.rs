@@ -0,0 +1,135 @@
+//! Identifying newly discovered peers, a few at a time.
+//!
+//! mDNS and DHT discovery tend to come in bursts, e.g. a busy LAN announces
+//! dozens of peers at once. Connecting to all of them for identify (which
+//! tells their listen addresses and protocol version) in arrival order would
+//! have the peer we actually want queue up behind unrelated ones. Instead at
+//! most `MAX_IN_FLIGHT` peers get identified concurrently, targets first.
+
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Maximum number of peers being identified at once.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// An identification not finished after this counts as failed, freeing its slot.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers waiting to be identified and those being identified.
+#[derive(Default)]
+pub struct IdentifyPool {
+    queue: VecDeque<PeerId>,
+    /// Peers being identified, with when we started.
+    in_flight: HashMap<PeerId, Instant>,
+    /// Peers queued or identified already in this run, not to be queued again.
+    seen: HashSet<PeerId>,
+}
+
+impl IdentifyPool {
+    pub fn new() -> IdentifyPool {
+        IdentifyPool::default()
+    }
+
+    /// Queue a newly discovered `peer`, at the front if we are looking for `target`s.
+    pub fn discovered(&mut self, peer: &PeerId, target: bool) {
+        if target {
+            // Targets jump the queue, even if seen before:
+            self.queue.retain(|p| p != peer);
+            if !self.in_flight.contains_key(peer) {
+                self.queue.push_front(peer.clone());
+            }
+        } else if self.seen.insert(peer.clone()) {
+            self.queue.push_back(peer.clone());
+        }
+        self.seen.insert(peer.clone());
+    }
+
+    /// `peer` got identified (or identifying it failed), freeing its slot.
+    pub fn finished(&mut self, peer: &PeerId) {
+        self.in_flight.remove(peer);
+        self.queue.retain(|p| p != peer);
+        self.seen.insert(peer.clone());
+    }
+
+    /// Next peer to identify, if there is one and a free slot.
+    pub fn next(&mut self) -> Option<PeerId> {
+        self.in_flight.retain(|peer, started| {
+            let alive = started.elapsed() < IDENTIFY_TIMEOUT;
+            if !alive {
+                log::debug!("Identifying {} timed out.", peer);
+            }
+            alive
+        });
+        if self.in_flight.len() >= MAX_IN_FLIGHT {
+            return None;
+        }
+        let peer = self.queue.pop_front()?;
+        self.in_flight.insert(peer.clone(), Instant::now());
+        Some(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::from(Keypair::generate_ed25519().public())).collect()
+    }
+
+    #[test]
+    fn targets_jump_the_queue() {
+        let mut pool = IdentifyPool::new();
+        let p = peers(3);
+        pool.discovered(&p[0], false);
+        pool.discovered(&p[1], false);
+        pool.discovered(&p[2], true);
+        // Seen before, still moves to the front once it is a target:
+        pool.discovered(&p[1], true);
+        assert_eq!(pool.next(), Some(p[1].clone()));
+        assert_eq!(pool.next(), Some(p[2].clone()));
+        assert_eq!(pool.next(), Some(p[0].clone()));
+        assert_eq!(pool.next(), None);
+    }
+
+    #[test]
+    fn seen_peers_are_not_queued_again() {
+        let mut pool = IdentifyPool::new();
+        let p = peers(2);
+        pool.discovered(&p[0], false);
+        pool.discovered(&p[0], false);
+        assert_eq!(pool.next(), Some(p[0].clone()));
+        pool.finished(&p[0]);
+        pool.discovered(&p[0], false);
+        // Finished without being discovered first counts as seen too:
+        pool.finished(&p[1]);
+        pool.discovered(&p[1], false);
+        assert_eq!(pool.next(), None);
+        // Targets get identified again:
+        pool.discovered(&p[0], true);
+        assert_eq!(pool.next(), Some(p[0].clone()));
+    }
+
+    #[test]
+    fn in_flight_is_capped_and_finished_frees_a_slot() {
+        let mut pool = IdentifyPool::new();
+        let p = peers(MAX_IN_FLIGHT + 1);
+        for peer in &p {
+            pool.discovered(peer, false);
+        }
+        for peer in &p[..MAX_IN_FLIGHT] {
+            assert_eq!(pool.next().as_ref(), Some(peer));
+        }
+        assert_eq!(pool.next(), None);
+        // Being identified already, a target does not get queued twice:
+        pool.discovered(&p[0], true);
+        assert_eq!(pool.next(), None);
+        pool.finished(&p[0]);
+        assert_eq!(pool.next(), Some(p[MAX_IN_FLIGHT].clone()));
+        assert_eq!(pool.next(), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Peers to leave out of discovery, e.g. the office's hundred IPFS nodes.
+//!
+//! Entries of `ignore` in the configuration file are peer ids or
+//! `agent:<pattern>`, matched against the agent version peers announce via
+//! identify, `*` matching anything (e.g. `agent:go-ipfs/*`). Peers can only
+//! be matched by agent once identified, from then on they are ignored like
+//! listed ones.
+//!
+//! Ignored peers are not added to the address cache, the routing table
+//! snapshot or Kademlia and are not logged.
+
+use anyhow::Result;
+use libp2p::PeerId;
+use std::collections::HashSet;
+
+mod error;
+
+/// Ignored peers and agent patterns.
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreList {
+    peers: HashSet<PeerId>,
+    agents: Vec<String>,
+}
+
+impl IgnoreList {
+    /// Parse `ignore` entries from the configuration file.
+    pub fn parse(entries: &[String]) -> Result<IgnoreList> {
+        let mut list = IgnoreList::default();
+        for entry in entries {
+            match entry.strip_prefix("agent:") {
+                Some("") => return Err(error::Ignore::InvalidEntry(entry.clone()).into()),
+                Some(pattern) => list.agents.push(pattern.into()),
+                None => {
+                    let peer = entry
+                        .parse()
+                        .map_err(|_| error::Ignore::InvalidEntry(entry.clone()))?;
+                    list.peers.insert(peer);
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    /// Whether `peer` is listed or matched an agent pattern before.
+    pub fn is_ignored(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    /// Whether identified `peer` runs an ignored `agent`, ignoring it from now on if so.
+    pub fn check_agent(&mut self, peer: &PeerId, agent: &str) -> bool {
+        if self.agents.iter().any(|p| glob_match(p, agent)) {
+            self.peers.insert(peer.clone());
+        }
+        self.is_ignored(peer)
+    }
+}
+
+/// Whether `s` matches `pattern`, in which `*` stands for any (possibly empty) string.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match s.strip_prefix(first) {
+        None => return false,
+        Some(rest) => rest,
+    };
+    let parts: Vec<_> = parts.collect();
+    let last = match parts.split_last() {
+        // No `*` at all:
+        None => return rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    None => return false,
+                    Some(i) => rest = &rest[i + part.len()..],
+                }
+            }
+            last
+        }
+    };
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn glob_matches() {
+        let cases = [
+            ("go-ipfs/0.5.0", "go-ipfs/0.5.0", true),
+            ("go-ipfs/0.5.0", "go-ipfs/0.5.1", false),
+            ("go-ipfs/0.5.0", "go-ipfs/0.5.0/", false),
+            ("go-ipfs/*", "go-ipfs/0.5.0", true),
+            ("go-ipfs/*", "go-ipfs/", true),
+            ("go-ipfs/*", "rust-ipfs/0.1", false),
+            ("*/0.5.0", "go-ipfs/0.5.0", true),
+            ("*/0.5.0", "go-ipfs/0.5.0-rc1", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("go-*/*-rc*", "go-ipfs/0.5.0-rc1", true),
+            ("go-*/*-rc*", "go-ipfs/0.5.0", false),
+            ("*ipfs*", "go-ipfs/0.5.0", true),
+            ("**", "x", true),
+            // Prefix and suffix must not overlap:
+            ("a*a", "a", false),
+            ("a*a", "aa", true),
+            ("a*a", "aba", true),
+            ("ab*ba", "aba", false),
+            ("*ab*ba", "aba", false),
+            ("*ab*ba", "abba", true),
+        ];
+        for (pattern, s, expected) in &cases {
+            assert_eq!(glob_match(pattern, s), *expected, "'{}' vs '{}'", pattern, s);
+        }
+    }
+
+    #[test]
+    fn parses_peers_and_agents() {
+        let (listed, other) = (
+            PeerId::from(Keypair::generate_ed25519().public()),
+            PeerId::from(Keypair::generate_ed25519().public()),
+        );
+        let mut list = IgnoreList::parse(&[listed.to_base58(), "agent:go-ipfs/*".into()]).unwrap();
+        assert!(list.is_ignored(&listed));
+        assert!(!list.is_ignored(&other));
+        assert!(!list.check_agent(&other, "rust-libp2p/0.19"));
+        assert!(list.check_agent(&other, "go-ipfs/0.5.0"));
+        // Remembered, whatever it announces next:
+        assert!(list.is_ignored(&other));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in &["agent:", "agent", "not-a-peer-id", ""] {
+            assert!(IgnoreList::parse(&[entry.to_string()]).is_err(), "'{}' got accepted", entry);
+        }
+    }
+}
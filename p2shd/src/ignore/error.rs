@@ -0,0 +1,14 @@
+//! Errors that can happen while parsing the ignore list.
+
+use thiserror::Error;
+
+/// Errors related to `ignore` entries.
+#[derive(Error, Debug)]
+pub enum Ignore {
+    #[error(
+        "Invalid ignore entry '{0}', expected a peer id or 'agent:<pattern>'.
+
+E.g. 'agent:go-ipfs/*' ignores all go-ipfs nodes."
+    )]
+    InvalidEntry(String),
+}
@@ -0,0 +1,30 @@
+//! Network interfaces to restrict listening and dialing to (`--bind-interface`).
+
+use std::{net::IpAddr, result};
+
+use crate::net;
+
+mod error;
+
+/// Result type with errors specific to this module.
+type Result<T> = result::Result<T, error::Interface>;
+
+/// IP addresses currently assigned to interface `name`.
+///
+/// IPv6 link local addresses are skipped, they are no use without a scope id.
+pub fn addresses(name: &str) -> Result<Vec<IpAddr>> {
+    let addrs: Vec<_> = get_if_addrs::get_if_addrs()
+        .map_err(error::Interface::List)?
+        .into_iter()
+        .filter(|i| i.name == name)
+        .map(|i| i.ip())
+        .filter(|ip| match ip {
+            IpAddr::V6(ip) => !net::is_unicast_link_local(ip),
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+    if addrs.is_empty() {
+        return Err(error::Interface::NoAddresses(name.into()));
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,13 @@
+//! Errors that can happen while looking up network interfaces.
+
+use std::io;
+use thiserror::Error;
+
+/// Errors related to `--bind-interface`.
+#[derive(Error, Debug)]
+pub enum Interface {
+    #[error("Listing network interfaces failed: {0}")]
+    List(io::Error),
+    #[error("Interface '{0}' does not exist or has no usable address.")]
+    NoAddresses(String),
+}
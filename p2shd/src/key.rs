@@ -0,0 +1,436 @@
+//! Inspection, encryption and rotation of node keys.
+//!
+//! Prints identities in the encodings used by other libp2p tools (ipfs, ...),
+//! so users can cross check them.
+//!
+//! Key files can be encrypted with a passphrase (`p2shd key encrypt`): The
+//! key gets derived via Argon2id and the key file sealed with
+//! XChaCha20-Poly1305. The passphrase is read from `P2SHD_KEY_PASSPHRASE` if
+//! set (e.g. for services), asked for on the terminal otherwise.
+//!
+//! The Argon2id parameters (`KdfParams`) are stored in the file next to the
+//! salt, so they can be raised for new files without breaking existing ones.
+//!
+//! `p2shd key rotate` replaces the node key, see `rotation` for how peers
+//! learn about the new one.
+
+use anyhow::{Context as AnyhowContext, Result};
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, Aead, NewAead},
+    XChaCha20Poly1305,
+};
+use libp2p::{identity::PublicKey, PeerId};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt, fs,
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+
+use crate::{
+    config::{generate_key, path_exists, read_key, Config, KeyType},
+    rotation,
+};
+
+mod error;
+
+/// Environment variable to read the passphrase of encrypted key files from.
+pub const PASSPHRASE_VAR: &str = "P2SHD_KEY_PASSPHRASE";
+
+/// Start of encrypted key files, followed by a version byte.
+const MAGIC: &[u8] = b"p2shd-key";
+
+/// Format version of encrypted key files.
+///
+/// 2: `KdfParams` after the version byte, version 1 files used `KdfParams::LEGACY`.
+const VERSION: u8 = 2;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Limits of `KdfParams` read from files, so a broken file can't make us allocate or
+/// compute without bounds: 1 GiB memory, 64 passes, 16 lanes.
+const MAX_MEM_COST: u32 = 1024 * 1024;
+const MAX_TIME_COST: u32 = 64;
+const MAX_LANES: u32 = 16;
+
+/// Multicodec code of "libp2p-key", used in CIDs of peer ids.
+const LIBP2P_KEY_CODEC: u8 = 0x72;
+
+/// Argon2id cost parameters of a passphrase derived key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct KdfParams {
+    /// Memory in KiB.
+    pub mem_cost: u32,
+    /// Number of passes.
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+impl KdfParams {
+    /// For new files: 64 MiB, 3 passes, 4 lanes.
+    pub(crate) const CURRENT: KdfParams = KdfParams {
+        mem_cost: 64 * 1024,
+        time_cost: 3,
+        lanes: 4,
+    };
+
+    /// Files from before the parameters got stored, the defaults of rust-argon2 0.8.
+    pub(crate) const LEGACY: KdfParams = KdfParams {
+        mem_cost: 4096,
+        time_cost: 3,
+        lanes: 1,
+    };
+
+    /// Length of the encoding.
+    pub(crate) const LEN: usize = 12;
+
+    /// Memory, passes and lanes, big endian.
+    pub(crate) fn to_bytes(&self) -> [u8; KdfParams::LEN] {
+        let mut raw = [0u8; KdfParams::LEN];
+        raw[..4].copy_from_slice(&self.mem_cost.to_be_bytes());
+        raw[4..8].copy_from_slice(&self.time_cost.to_be_bytes());
+        raw[8..].copy_from_slice(&self.lanes.to_be_bytes());
+        raw
+    }
+
+    /// Decode `to_bytes`, `None` if it is too short or out of bounds.
+    pub(crate) fn from_bytes(raw: &[u8]) -> Option<KdfParams> {
+        if raw.len() != KdfParams::LEN {
+            return None;
+        }
+        let field = |i: usize| u32::from_be_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        let params = KdfParams {
+            mem_cost: field(0),
+            time_cost: field(4),
+            lanes: field(8),
+        };
+        let valid = (1..=MAX_LANES).contains(&params.lanes)
+            && (1..=MAX_TIME_COST).contains(&params.time_cost)
+            && (8 * params.lanes..=MAX_MEM_COST).contains(&params.mem_cost);
+        if valid {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+/// Public information about a node key.
+pub struct KeyInfo {
+    pub public: PublicKey,
+    pub peer_id: PeerId,
+}
+
+impl KeyInfo {
+    pub fn new(public: PublicKey) -> KeyInfo {
+        let peer_id = PeerId::from(public.clone());
+        KeyInfo { public, peer_id }
+    }
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Key type:            {}", key_type(&self.public))?;
+        writeln!(f, "Public key (base64): {}", public_key_base64(&self.public))?;
+        writeln!(f, "PeerId (base58btc):  {}", self.peer_id.to_base58())?;
+        writeln!(f, "PeerId (CIDv1):      {}", peer_id_to_cid(&self.peer_id))?;
+        writeln!(f, "Fingerprint:         {}", fingerprint_sha256(&self.public))?;
+        write!(f, "Fingerprint (hex):   {}", fingerprint_hex(&self.public))
+    }
+}
+
+/// Read the key stored at `path` and gather its public information.
+pub fn inspect(path: &Path) -> Result<KeyInfo> {
+    Ok(KeyInfo::new(read_key(path)?.public()))
+}
+
+/// Human readable name of the key's type.
+pub fn key_type(public: &PublicKey) -> &'static str {
+    match public {
+        PublicKey::Ed25519(_) => "Ed25519",
+        PublicKey::Secp256k1(_) => "Secp256k1",
+        PublicKey::Rsa(_) => "RSA",
+    }
+}
+
+/// The protobuf encoding of the public key (as used in peer ids), base64 encoded.
+pub fn public_key_base64(public: &PublicKey) -> String {
+    base64::encode(&public.clone().into_protobuf_encoding())
+}
+
+/// `text` as QR code, drawn with Unicode half blocks for showing it in a terminal.
+///
+/// Drawn black on white with a quiet zone, whatever the terminal's colors, as scanners need
+/// dark modules on a light background.
+pub fn qr_code(text: &str) -> Result<String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|_| error::Key::QrCode(text.into()))?;
+    let drawn = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Dark)
+        .light_color(Dense1x2::Light)
+        .quiet_zone(true)
+        .build();
+    Ok(drawn
+        .lines()
+        .map(|line| format!("\x1b[30;107m{}\x1b[0m", line))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// The peer id as CIDv1 with codec libp2p-key, in multibase base32 (as shown by ipfs).
+pub fn peer_id_to_cid(peer_id: &PeerId) -> String {
+    let mut cid = vec![1, LIBP2P_KEY_CODEC];
+    cid.extend_from_slice(peer_id.as_bytes());
+    format!("b{}", data_encoding::BASE32_NOPAD.encode(&cid).to_lowercase())
+}
+
+/// SHA256 of the protobuf encoded public key, in OpenSSH style ("SHA256:<base64>").
+pub fn fingerprint_sha256(public: &PublicKey) -> String {
+    let digest = Sha256::digest(&public.clone().into_protobuf_encoding());
+    format!(
+        "SHA256:{}",
+        base64::encode_config(&digest, base64::STANDARD_NO_PAD)
+    )
+}
+
+/// SHA256 of the protobuf encoded public key, as colon separated hex.
+pub fn fingerprint_hex(public: &PublicKey) -> String {
+    let digest = Sha256::digest(&public.clone().into_protobuf_encoding());
+    digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Whether `raw` is the content of an encrypted key file.
+pub fn is_encrypted(raw: &[u8]) -> bool {
+    raw.starts_with(MAGIC)
+}
+
+/// Decrypt the content of an encrypted key file, asking for the passphrase.
+pub fn decrypt(raw: &[u8], path: &Path) -> Result<Vec<u8>> {
+    let header = MAGIC.len() + 1;
+    if raw.len() < header {
+        return Err(error::Key::Truncated(path.into()).into());
+    }
+    let (params, rest) = match raw[MAGIC.len()] {
+        1 => (KdfParams::LEGACY, &raw[header..]),
+        VERSION if raw.len() >= header + KdfParams::LEN => {
+            let (params, rest) = raw[header..].split_at(KdfParams::LEN);
+            let params =
+                KdfParams::from_bytes(params).ok_or_else(|| error::Key::KdfParams(path.into()))?;
+            (params, rest)
+        }
+        VERSION => return Err(error::Key::Truncated(path.into()).into()),
+        v => return Err(error::Key::UnknownVersion(path.into(), v).into()),
+    };
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        return Err(error::Key::Truncated(path.into()).into());
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+    let passphrase = passphrase(&format!("Passphrase for {}: ", path.display()), false)?;
+    let cipher = cipher(&passphrase, salt, &params)?;
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), sealed)
+        .map_err(|_| error::Key::WrongPassphrase(path.into()).into())
+}
+
+/// `p2shd key encrypt`: Encrypt the key file at `path` with a new passphrase.
+pub fn encrypt_file(path: &Path) -> Result<()> {
+    let raw = fs::read(path).with_context(|| error::Key::Read(path.into()))?;
+    if is_encrypted(&raw) {
+        return Err(error::Key::AlreadyEncrypted(path.into()).into());
+    }
+    // Make sure it is a key we can use:
+    read_key(path)?;
+    write_key_file(path, &encrypt(&raw, path)?)
+}
+
+/// Encrypt the content of a key file to be stored at `path`, asking for a new passphrase.
+fn encrypt(raw: &[u8], path: &Path) -> Result<Vec<u8>> {
+    let passphrase = passphrase(&format!("New passphrase for {}: ", path.display()), true)?;
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let params = KdfParams::CURRENT;
+    let sealed = cipher(&passphrase, &salt, &params)?
+        .encrypt(GenericArray::from_slice(&nonce), raw)
+        .map_err(|_| error::Key::Encrypt)?;
+    let mut encrypted = MAGIC.to_vec();
+    encrypted.push(VERSION);
+    encrypted.extend_from_slice(&params.to_bytes());
+    encrypted.extend_from_slice(&salt);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&sealed);
+    Ok(encrypted)
+}
+
+/// `p2shd key decrypt`: Store the key file at `path` unencrypted again.
+pub fn decrypt_file(path: &Path) -> Result<()> {
+    let raw = fs::read(path).with_context(|| error::Key::Read(path.into()))?;
+    if !is_encrypted(&raw) {
+        return Err(error::Key::NotEncrypted(path.into()).into());
+    }
+    write_key_file(path, &decrypt(&raw, path)?)
+}
+
+/// `p2shd keygen`: Generate a key of type `key_type` at `path`, resolving to its peer id.
+///
+/// Existing files only get overwritten if `force` is given.
+pub fn generate(path: &Path, key_type: KeyType, force: bool) -> Result<PeerId> {
+    let exists = path_exists(path).with_context(|| error::Key::Read(path.into()))?;
+    if exists && !force {
+        return Err(error::Key::Exists(path.into()).into());
+    }
+    let (key, encoded) = generate_key(key_type);
+    write_key_file(path, &encoded)?;
+    Ok(PeerId::from(key.public()))
+}
+
+/// `p2shd key rotate`: Replace the node key by a new one of type `--key-type`.
+///
+/// The old key file is kept next to it (`.<old peer id>.old`, so keys of
+/// earlier rotations stay as well) and signs a rotation record naming the new
+/// peer id, which gets appended to the earlier ones for `p2shd listen` to
+/// publish. An encrypted key file stays encrypted, with a new passphrase.
+pub fn rotate(cfg: &Config) -> Result<()> {
+    let path = cfg.get_key_file();
+    let raw = fs::read(&path).with_context(|| error::Key::Read(path.clone()))?;
+    let old = read_key(&path)?;
+    let (new, mut encoded) = generate_key(cfg.opts.key_type);
+    if is_encrypted(&raw) {
+        encoded = encrypt(&encoded, &path)?;
+    }
+    let record = rotation::sign(&old, &new.public())?;
+
+    // Old key and record first, so an interruption never loses the old identity:
+    let old_path = path.with_extension(format!("{}.old", PeerId::from(old.public())));
+    // Already there if an earlier attempt got interrupted:
+    let kept = path_exists(&old_path).with_context(|| error::Key::Write(old_path.clone()))?;
+    if !kept {
+        fs::copy(&path, &old_path).with_context(|| error::Key::Write(old_path.clone()))?;
+    }
+    rotation::append_own(&cfg.get_rotation_file(), &record)?;
+    write_key_file(&path, &encoded)?;
+
+    println!("Rotated node key {}:", path.display());
+    println!("Old peer id: {}", PeerId::from(old.public()));
+    println!("New peer id: {}", PeerId::from(new.public()));
+    println!("Restart p2shd listen, it then tells peers using the old id about the new one.");
+    Ok(())
+}
+
+/// Replace the key file at `path` atomically, never readable by others on the way.
+fn write_key_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let write_err = || error::Key::Write(path.into());
+    let tmp = path.with_extension("tmp");
+    // Left over from an interrupted run:
+    let _ = fs::remove_file(&tmp);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .with_context(write_err)?;
+    file.write_all(contents).with_context(write_err)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o400)).with_context(write_err)?;
+    fs::rename(&tmp, path).with_context(write_err)
+}
+
+/// The passphrase, from `PASSPHRASE_VAR` or asked for with `prompt`, twice if `confirm`.
+fn passphrase(prompt: &str, confirm: bool) -> Result<String> {
+    if let Ok(p) = env::var(PASSPHRASE_VAR) {
+        return Ok(p);
+    }
+    let p = rpassword::read_password_from_tty(Some(prompt)).context(error::Key::NoPassphrase)?;
+    if confirm {
+        let again = rpassword::read_password_from_tty(Some("Repeat passphrase: "))
+            .context(error::Key::NoPassphrase)?;
+        if p != again {
+            return Err(error::Key::PassphraseMismatch.into());
+        }
+    }
+    if p.is_empty() {
+        return Err(error::Key::EmptyPassphrase.into());
+    }
+    Ok(p)
+}
+
+/// Cipher keyed with what Argon2id derives from `passphrase` and `salt`, with `params`.
+pub(crate) fn cipher(passphrase: &str, salt: &[u8], params: &KdfParams) -> Result<XChaCha20Poly1305> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        hash_length: 32,
+        mem_cost: params.mem_cost,
+        time_cost: params.time_cost,
+        lanes: params.lanes,
+        ..argon2::Config::default()
+    };
+    let key = argon2::hash_raw(passphrase.as_bytes(), salt, &config).context(error::Key::Encrypt)?;
+    Ok(XChaCha20Poly1305::new(*GenericArray::from_slice(&key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ed25519 public key of RFC 8032 test 1, protobuf encoded.
+    fn public_key() -> PublicKey {
+        let encoded = data_encoding::HEXLOWER
+            .decode(b"08011220d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+            .unwrap();
+        PublicKey::from_protobuf_encoding(&encoded).unwrap()
+    }
+
+    #[test]
+    fn peer_id_to_cid_matches_spec() {
+        // Example from the libp2p peer id spec:
+        let peer: PeerId = "QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N".parse().unwrap();
+        assert_eq!(
+            peer_id_to_cid(&peer),
+            "bafzbeie5745rpv2m6tjyuugywy4d5ewrqgqqhfnf445he3omzpjbx5xqxe"
+        );
+    }
+
+    #[test]
+    fn fingerprint_sha256_is_openssh_style() {
+        assert_eq!(
+            fingerprint_sha256(&public_key()),
+            "SHA256:a6RRjw1cfyKdMO3hIL7Y0gJ/MKiCh63UXV7vzkgrZrI"
+        );
+    }
+
+    #[test]
+    fn fingerprint_hex_is_colon_separated() {
+        assert_eq!(
+            fingerprint_hex(&public_key()),
+            "6b:a4:51:8f:0d:5c:7f:22:9d:30:ed:e1:20:be:d8:d2:02:7f:30:a8:82:87:ad:d4:5d:5e:ef:ce:48:2b:66:b2"
+        );
+    }
+
+    #[test]
+    fn kdf_params_round_trip_and_bounds() {
+        for params in &[KdfParams::CURRENT, KdfParams::LEGACY] {
+            assert_eq!(KdfParams::from_bytes(&params.to_bytes()), Some(*params));
+        }
+        let invalid = [
+            KdfParams { lanes: 0, ..KdfParams::CURRENT },
+            KdfParams { lanes: MAX_LANES + 1, ..KdfParams::CURRENT },
+            KdfParams { time_cost: 0, ..KdfParams::CURRENT },
+            KdfParams { mem_cost: MAX_MEM_COST + 1, ..KdfParams::CURRENT },
+            // Argon2 needs at least 8 KiB per lane:
+            KdfParams { mem_cost: 31, lanes: 4, ..KdfParams::CURRENT },
+        ];
+        for params in &invalid {
+            assert_eq!(KdfParams::from_bytes(&params.to_bytes()), None, "{:?}", params);
+        }
+        assert_eq!(KdfParams::from_bytes(&KdfParams::CURRENT.to_bytes()[1..]), None);
+    }
+}
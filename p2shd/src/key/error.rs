@@ -0,0 +1,37 @@
+//! Errors that can happen while handling encrypted node keys.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to encrypting and decrypting key files.
+#[derive(Error, Debug)]
+pub enum Key {
+    #[error("Reading keyfile '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Writing keyfile '{0}' failed.")]
+    Write(PathBuf),
+    #[error("Encrypted keyfile '{0}' is truncated.")]
+    Truncated(PathBuf),
+    #[error("Encrypted keyfile '{0}' has unknown format version {1}, it needs a newer p2shd.")]
+    UnknownVersion(PathBuf, u8),
+    #[error("Encrypted keyfile '{0}' has invalid key derivation parameters.")]
+    KdfParams(PathBuf),
+    #[error("Wrong passphrase for keyfile '{0}' (or the file got corrupted).")]
+    WrongPassphrase(PathBuf),
+    #[error("Keyfile '{0}' exists already, pass --force to overwrite it.")]
+    Exists(PathBuf),
+    #[error("Keyfile '{0}' is encrypted already.")]
+    AlreadyEncrypted(PathBuf),
+    #[error("Keyfile '{0}' is not encrypted.")]
+    NotEncrypted(PathBuf),
+    #[error("Reading the passphrase failed, set P2SHD_KEY_PASSPHRASE when running without a terminal.")]
+    NoPassphrase,
+    #[error("The passphrases did not match.")]
+    PassphraseMismatch,
+    #[error("An empty passphrase would not protect anything.")]
+    EmptyPassphrase,
+    #[error("Encrypting the key failed.")]
+    Encrypt,
+    #[error("'{0}' does not fit into a QR code.")]
+    QrCode(String),
+}
@@ -1,2 +1,60 @@
+//! p2shd as a library, `main.rs` is the command line front end on top of it.
+//!
+//! This stays a single crate rather than a workspace of protocol, core and
+//! CLI crates, as the module graph has no such layers to cut along:
+//! `tunnel`, which a protocol crate would hold, records into `prometheus`
+//! and `trace`. `config` holds the command line options, but also the path
+//! helpers and key handling the stores (`blocklist`, `book_sync`,
+//! `rotation`, `sealed_state`, ...) and `transport` build on, while itself
+//! depending on those; it can only go into the core crate, leaving nothing
+//! but `main.rs` for the CLI one, which is what this library/binary split
+//! already is.
+
+pub mod addr_cache;
+pub mod addr_record;
+pub mod backoff;
+pub mod blocklist;
+pub mod book_sync;
+pub mod completions;
 pub mod config;
+pub mod control;
+pub mod crash;
 pub mod behaviour;
+pub mod dashboard;
+pub mod dial_report;
+pub mod dns;
+pub mod events;
+pub mod format_version;
+pub mod forward;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http_status;
+pub mod identify_pool;
+pub mod ignore;
+pub mod interface;
+pub mod key;
+pub mod log_format;
+pub mod log_sampling;
+pub mod metrics;
+pub mod net;
+pub mod otlp;
+pub mod plan;
+pub mod predictor;
+pub mod profile;
+pub mod prometheus;
+pub mod resources;
+pub mod rotation;
+pub mod routing_table;
+pub mod scheduler;
+pub mod sealed_state;
+pub mod signed_record;
+pub mod socks;
+pub mod ssh;
+pub mod store;
+pub mod systemd;
+pub mod trace;
+pub mod transport;
+pub mod trust;
+pub mod tunnel;
+pub mod vpn;
+pub mod watchdog;
@@ -0,0 +1,131 @@
+//! Log output formats, `--log-format`.
+//!
+//! `text` is env_logger's usual output. `json` writes one JSON object per
+//! line, for journald, ELK and the like:
+//!
+//! ```json
+//! {"ts":"2020-05-20T10:12:01.123Z","level":"INFO","target":"p2shd::behaviour",
+//!  "message":"Opening tunnel to 12D3KooW... via [\"/ip4/10.0.0.2/tcp/4001\"] ...",
+//!  "event":"Opening tunnel to {peer_id} via [{multiaddr}] ...",
+//!  "peer_id":"12D3KooW...","multiaddr":"/ip4/10.0.0.2/tcp/4001"}
+//! ```
+//!
+//! Log messages are plain text, so `peer_id`, `multiaddr` and `query_id` get
+//! picked out of them (the first of each kind). `event` is the message with
+//! those replaced by placeholders, the same for every occurrence of a log
+//! message, for grouping.
+//!
+//! Filtering via `RUST_LOG` works as before for both formats.
+
+use libp2p::{Multiaddr, PeerId};
+use serde_json::{json, Map, Value};
+use std::{io::Write, str::FromStr};
+
+mod error;
+
+/// How log records get written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = error::LogFormat;
+
+    fn from_str(s: &str) -> Result<LogFormat, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(error::LogFormat::Unknown(s.into())),
+        }
+    }
+}
+
+/// Set up logging in `format`.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = to_json(record.level(), record.target(), &record.args().to_string());
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+fn to_json(level: log::Level, target: &str, message: &str) -> Value {
+    let mut fields = Map::new();
+    let mut event = String::with_capacity(message.len());
+    let mut rest = message;
+    while !rest.is_empty() {
+        let start = rest.find(|c: char| !is_separator(c)).unwrap_or_else(|| rest.len());
+        event.push_str(&rest[..start]);
+        rest = &rest[start..];
+        // libp2p's `Debug` of query ids:
+        if let Some((id, len)) = query_id(rest) {
+            event.push_str("{query_id}");
+            fields.entry("query_id").or_insert_with(|| id.into());
+            rest = &rest[len..];
+            continue;
+        }
+        let end = rest.find(is_separator).unwrap_or_else(|| rest.len());
+        let (token, trailing) = split_trailing(&rest[..end]);
+        match classify(token) {
+            Some((field, value)) => {
+                event.push('{');
+                event.push_str(field);
+                event.push('}');
+                fields.entry(field).or_insert_with(|| value.into());
+            }
+            None => event.push_str(token),
+        }
+        event.push_str(trailing);
+        rest = &rest[end..];
+    }
+    let mut line = json!({
+        "ts": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": level.to_string(),
+        "target": target,
+        "message": message,
+        "event": event,
+    });
+    if let Value::Object(line) = &mut line {
+        line.extend(fields);
+    }
+    line
+}
+
+/// Characters around ids in log messages: Whitespace and `Debug` punctuation.
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || "[](){},\"'".contains(c)
+}
+
+/// Split off sentence punctuation, e.g. `12D3KooW...:` or `/ip4/1.2.3.4/tcp/1.`
+fn split_trailing(token: &str) -> (&str, &str) {
+    let end = token.trim_end_matches(|c| c == '.' || c == ':' || c == '!' || c == ';').len();
+    token.split_at(end)
+}
+
+/// The id of a `QueryId(<id>)` at the start of `s` and its length, if there is one.
+fn query_id(s: &str) -> Option<(u64, usize)> {
+    const PREFIX: &str = "QueryId(";
+    if !s.starts_with(PREFIX) {
+        return None;
+    }
+    let end = s.find(')')?;
+    let id = s[PREFIX.len()..end].parse().ok()?;
+    Some((id, end + 1))
+}
+
+/// The field `token` is the value of, if any.
+fn classify(token: &str) -> Option<(&'static str, String)> {
+    if token.starts_with('/') && token.len() > 1 {
+        return token.parse::<Multiaddr>().ok().map(|a| ("multiaddr", a.to_string()));
+    }
+    // Shorter ones are no peer ids, saves trying to decode every word:
+    if token.len() >= 40 {
+        return PeerId::from_str(token).ok().map(|p| ("peer_id", p.to_base58()));
+    }
+    None
+}
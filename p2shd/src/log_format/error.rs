@@ -0,0 +1,10 @@
+//! Errors that can happen while setting up logging.
+
+use thiserror::Error;
+
+/// Errors related to `--log-format`.
+#[derive(Error, Debug)]
+pub enum LogFormat {
+    #[error("Unknown log format '{0}', expected `text` or `json`.")]
+    Unknown(String),
+}
@@ -0,0 +1,87 @@
+//! Rate limiting for log messages in hot paths.
+//!
+//! Some messages get logged on every poll wakeup or every discovered peer, so
+//! with debug logging enabled they would flood the log and slow down the very
+//! thing being debugged. Such call sites log through a `Sampler` instead: At
+//! most one message per `SAMPLE_INTERVAL` and site makes it through, the
+//! suppressed ones only get counted and are summarized every
+//! `SUMMARY_INTERVAL`. Sites are identified by `&'static str`, so neither
+//! sampling nor counting allocates. Sites logging about several things at
+//! once (e.g. each peer being queried for) can be sampled per thing, they
+//! are told apart by a hash of it.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Minimum time between two messages of the same site.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often to log counts of suppressed messages.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Site {
+    /// When a message of this site got logged last.
+    last: Option<Instant>,
+    /// Messages suppressed since the last summary.
+    suppressed: u64,
+}
+
+/// Decides which messages of hot paths get logged, see module docs.
+#[derive(Default)]
+pub struct Sampler {
+    /// By site and hash of what it logs about, see `sample_for`.
+    sites: HashMap<(&'static str, u64), Site>,
+}
+
+impl Sampler {
+    pub fn new() -> Sampler {
+        Sampler::default()
+    }
+
+    /// Whether to log a message of `site` now. If not, it gets counted as suppressed.
+    pub fn sample(&mut self, site: &'static str) -> bool {
+        self.sample_hashed(site, 0)
+    }
+
+    /// Like `sample`, but separately for each `key` (e.g. a peer), so messages about one don't
+    /// suppress those about another.
+    pub fn sample_for(&mut self, site: &'static str, key: &impl Hash) -> bool {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.sample_hashed(site, hasher.finish())
+    }
+
+    fn sample_hashed(&mut self, site: &'static str, key: u64) -> bool {
+        let s = self.sites.entry((site, key)).or_default();
+        let now = Instant::now();
+        match s.last {
+            Some(last) if now.duration_since(last) < SAMPLE_INTERVAL => {
+                s.suppressed += 1;
+                false
+            }
+            _ => {
+                s.last = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Log how many messages got suppressed per site since the last summary.
+    pub fn summarize(&mut self) {
+        let mut suppressed: HashMap<&'static str, u64> = HashMap::new();
+        for ((site, _), s) in self.sites.iter_mut().filter(|(_, s)| s.suppressed > 0) {
+            *suppressed.entry(*site).or_default() += s.suppressed;
+            s.suppressed = 0;
+        }
+        for (site, count) in suppressed {
+            log::debug!("Suppressed {} '{}' log messages in the last {:?}.", count, site, SUMMARY_INTERVAL);
+        }
+        // Sites that went quiet:
+        self.sites
+            .retain(|_, s| s.last.map_or(false, |l| l.elapsed() < SUMMARY_INTERVAL));
+    }
+}
@@ -1,66 +1,532 @@
 use {
     anyhow,
-    anyhow::Result,
-    async_std::{io, task},
-    futures::prelude::*,
+    anyhow::{Context as _, Result},
+    async_std::{io, os::unix::net::UnixListener, task},
+    futures::{channel::mpsc, prelude::*},
     libp2p::{
-        build_development_transport,
         kad::record::store::MemoryStore,
         kad::{record::Key, Kademlia, KademliaEvent, PutRecordOk, Quorum, Record},
         mdns::{Mdns, MdnsEvent},
         swarm::NetworkBehaviourEventProcess,
         NetworkBehaviour, PeerId, Swarm,
     },
-    std::task::{Context, Poll},
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        os::unix::{fs::DirBuilderExt, io::FromRawFd},
+        task::{Context, Poll},
+        time::Duration,
+    },
+    serde_json::json,
     structopt::StructOpt,
+    tokio::signal::unix::{signal, SignalKind},
+};
+
+use p2shd::{
+    addr_cache::AddrCache,
+    behaviour::{Mode, P2shd, P2shdEvent, PersistentState},
+    blocklist::Blocklist,
+    book_sync::{self, SyncedBook},
+    completions, config, crash,
+    control,
+    config::{AuthCommand, Command, Config, DebugCommand, ForwardCommand, KeyCommand, PeerCommand, TrustCommand},
+    dashboard,
+    dial_report::DialReport,
+    dns, events,
+    http_status, interface, log_format,
+    forward::{self, Opener},
+    key, routing_table::RoutingTable, sealed_state, socks, ssh,
+    store::Store,
+    systemd, trace,
+    transport, trust,
+    tunnel::Request,
+    vpn, watchdog,
 };
 
-use p2shd::{behaviour::P2shd, config, config::Config};
+/// How often `p2shd lookup-profile` tries fetching the profile.
+const PROFILE_LOOKUP_ATTEMPTS: usize = 3;
+
+/// Wait before each attempt, the first one giving bootstrapping a chance.
+const PROFILE_LOOKUP_DELAY: Duration = Duration::from_secs(5);
+
+/// Tasks carrying out the command send the exit code here once done, the swarm then shuts
+/// down cleanly (see `start`).
+type Exit = mpsc::UnboundedSender<i32>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let opts = config::Opts::from_args();
+    log_format::init(opts.log_format);
+
+    let cfg = Config::new(opts)?;
+
+    crash::install(cfg.get_crash_report_file());
+    if let Some(capacity) = cfg.opts.record_events {
+        events::enable(capacity, cfg.get_events_dump_file());
+    }
+    if let Some(endpoint) = &cfg.opts.otlp_traces {
+        trace::enable(endpoint.clone())?;
+        task::spawn(trace::run_exporter());
+    }
 
-    let cfg = Config::new(config::Opts::from_args())?;
+    match &cfg.opts.cmd {
+        Some(Command::Listen {
+            sshd,
+            sshd_ports,
+            allow_forwarding,
+            vpn,
+            advertise_resources,
+            ..
+        }) => {
+            let resolver = dns::Resolver::new(&cfg).await?;
+            let mode = Mode::Listen {
+                sshd: *sshd,
+                sshd_ports: sshd_ports.clone(),
+                warm: cfg.warm_peers()?,
+                keep_connected: cfg.keep_connected_peers(),
+                allow_forwarding: *allow_forwarding,
+                vpn: if *vpn { cfg.vpn_peers.clone() } else { HashMap::new() },
+                advertise_resources: *advertise_resources,
+            };
+            return start(&cfg, mode, resolver);
+        }
+        Some(Command::Socks { peer, .. })
+        | Some(Command::Open { peer, .. })
+        | Some(Command::Vpn { peer, .. })
+        | Some(Command::Resources { peer })
+        | Some(Command::Sync { device: peer }) => {
+            let peer = cfg.lookup_peer(peer)?;
+            let resolver = dns::Resolver::new(&cfg).await?;
+            let mode = Mode::Forward {
+                peer,
+                reverse: Vec::new(),
+            };
+            return start(&cfg, mode, resolver);
+        }
+        Some(Command::LookupProfile { .. }) => {
+            let resolver = dns::Resolver::new(&cfg).await?;
+            return start(&cfg, Mode::Query, resolver);
+        }
+        Some(Command::Wait { peer, timeout }) => {
+            let peer = cfg.lookup_peer(peer)?;
+            let resolver = dns::Resolver::new(&cfg).await?;
+            if let Some(secs) = *timeout {
+                task::spawn(async move {
+                    task::sleep(Duration::from_secs(secs)).await;
+                    log::error!("Peer not online after {} seconds, giving up.", secs);
+                    std::process::exit(1);
+                });
+            }
+            return start(&cfg, Mode::Wait(peer), resolver);
+        }
+        Some(cmd) => return run_command(&cfg, cmd),
+        None => (),
+    }
 
-    match &cfg.opts.remote_id {
+    match cfg.remote_peers.first() {
         None => {
             let local_key = cfg.get_node_key()?;
             let local_peer_id = PeerId::from(local_key.public());
             println!("Our peer id: {}", &local_peer_id);
             Ok(())
         }
-        Some(remote_id) => {
-            start(&cfg, remote_id)
+        Some(remote_peer) => {
+            let resolver = dns::Resolver::new(&cfg).await?;
+            let opts = &cfg.opts.connect;
+            // Forwarding to multiple peers is rejected by `Config::new`:
+            let mode = if opts.local_forwards.is_empty() && opts.remote_forwards.is_empty() {
+                Mode::Connect(cfg.remote_peers.clone())
+            } else {
+                Mode::Forward {
+                    peer: remote_peer.clone(),
+                    reverse: opts.remote_forwards.clone(),
+                }
+            };
+            start(&cfg, mode, resolver)
+        }
+    }
+}
+
+fn run_command(cfg: &Config, cmd: &Command) -> Result<()> {
+    match cmd {
+        Command::Listen { .. } => unreachable!("Listen is handled in main."),
+        Command::Connect(_) => unreachable!("Connect is turned into `opts.connect` by `Config::new`."),
+        Command::Socks { .. }
+        | Command::Open { .. }
+        | Command::Vpn { .. }
+        | Command::Resources { .. }
+        | Command::Sync { .. } => {
+            unreachable!("Forwarding commands are handled in main.")
+        }
+        Command::LookupProfile { .. } => unreachable!("Lookups are handled in main."),
+        Command::Wait { .. } => unreachable!("Wait is handled in main."),
+        Command::Status { json } => run_status(cfg, *json),
+        Command::Peers => run_peers(cfg),
+        Command::Dashboard { interval } => {
+            let interval = Duration::from_secs((*interval).max(1));
+            task::block_on(dashboard::run(&cfg.get_control_socket_file(), interval))
+        }
+        Command::Id { qr } => run_id(cfg, *qr),
+        Command::Completions { shell } => Ok(completions::generate(*shell, &mut std::io::stdout())?),
+        Command::CompletePeers => {
+            print!("{}", completions::peer_names(cfg));
+            Ok(())
+        }
+        Command::Keygen { key_type, output, force } => {
+            let path = output.clone().unwrap_or_else(|| cfg.get_key_file());
+            let peer_id = key::generate(&path, key_type.unwrap_or(cfg.opts.key_type), *force)?;
+            println!("Wrote {}, peer id: {}", path.display(), peer_id);
+            Ok(())
+        }
+        Command::Key(KeyCommand::Inspect { file }) => {
+            println!("{}", key::inspect(file)?);
+            Ok(())
+        }
+        Command::Key(KeyCommand::Encrypt { file }) => key::encrypt_file(file),
+        Command::Key(KeyCommand::Decrypt { file }) => key::decrypt_file(file),
+        Command::Key(KeyCommand::Rotate) => key::rotate(cfg),
+        Command::Debug(DebugCommand::DumpEvents) => {
+            print!("{}", events::read_dump(&cfg.get_events_dump_file())?);
+            Ok(())
+        }
+        Command::Debug(DebugCommand::DialReport { json }) => {
+            let report = DialReport::load(&cfg.get_dial_report_file())?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", report);
+            }
+            Ok(())
+        }
+        Command::Auth(cmd) => run_auth_command(cfg, cmd),
+        Command::Forward(cmd) => run_forward_command(cfg, cmd),
+        Command::Peer(cmd) => run_peer_command(cfg, cmd),
+        Command::Trust(TrustCommand::Refresh { peer }) => trust::refresh(cfg, &cfg.lookup_peer(peer)?),
+        Command::Trust(TrustCommand::Quarantine) => trust::print_quarantine(cfg),
+        Command::Cp { recursive, paths } => {
+            let status = ssh::copy(cfg, paths, *recursive)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
+}
+
+fn run_auth_command(cfg: &Config, cmd: &AuthCommand) -> Result<()> {
+    let mut blocklist = sealed_state::load_or_reset(cfg.get_blocklist_file(), Blocklist::load)?;
+    match cmd {
+        AuthCommand::Block { entry } => blocklist.block(entry.clone()),
+        AuthCommand::Unblock { entry } => {
+            if !blocklist.unblock(entry) {
+                println!("{} was not blocked.", entry);
+            }
+        }
+        AuthCommand::Subscribe { peer } => blocklist.subscribe(peer.clone()),
+        AuthCommand::Unsubscribe { peer } => {
+            if !blocklist.unsubscribe(peer) {
+                println!("Not subscribed to {}.", peer);
+            }
+        }
+        AuthCommand::List => {
+            println!("Blocked:");
+            for entry in blocklist.entries() {
+                println!("  {}", entry);
+            }
+            println!("Subscribed to:");
+            for peer in blocklist.subscriptions() {
+                println!("  {}", peer);
+            }
+            for (peer, count) in blocklist.imported_counts() {
+                println!("Imported {} entries from {}", count, peer);
+            }
+        }
+    }
+    blocklist.save()
+}
+
+/// Call `method` on the control socket of the running `p2shd listen`.
+fn call_daemon(cfg: &Config, method: &str) -> Result<serde_json::Value> {
+    let path = cfg.get_control_socket_file();
+    task::block_on(control::call(&path, method, json!({})))
+        .with_context(|| format!("Calling p2shd listen ({}) failed, is it running?", path.display()))
+}
+
+fn run_status(cfg: &Config, json: bool) -> Result<()> {
+    let status = call_daemon(cfg, "status")?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    let list = |field: &str| -> Vec<String> {
+        status[field]
+            .as_array()
+            .map(|a| a.iter().filter_map(|v| v.as_str().map(String::from)).collect())
+            .unwrap_or_default()
+    };
+    println!("Peer id: {}", status["peer_id"].as_str().unwrap_or("?"));
+    println!("Serving tunnels: {}", status["serving"].as_bool().unwrap_or(false));
+    println!("Connected peers: {}", status["connected_peers"]);
+    println!("Active tunnels: {}", status["active_tunnels"]);
+    println!("Routing table size: {}", status["routing_table_size"]);
+    println!("NAT: {}", status["nat"].as_str().unwrap_or("?"));
+    println!("Listen addresses: {}", list("listen_addrs").join(", "));
+    println!("External addresses: {}", list("external_addrs").join(", "));
+    println!("Warm peers: {}", list("warm_peers").join(", "));
+    for job in status["jobs"].as_array().into_iter().flatten() {
+        println!(
+            "Job {} ({}): {}",
+            job["name"].as_str().unwrap_or("?"),
+            job["schedule"].as_str().unwrap_or("?"),
+            job["last_result"].as_str().unwrap_or("not run yet"),
+        );
+    }
+    Ok(())
+}
+
+fn run_peers(cfg: &Config) -> Result<()> {
+    let result = call_daemon(cfg, "list_peers")?;
+    for peer in result["peers"].as_array().into_iter().flatten() {
+        let address = peer["address"].as_str().unwrap_or("inbound");
+        match peer["rtt_ms"].as_u64() {
+            Some(rtt) => println!("{} {} {}ms", peer["peer"].as_str().unwrap_or("?"), address, rtt),
+            None => println!("{} {}", peer["peer"].as_str().unwrap_or("?"), address),
+        }
+    }
+    Ok(())
+}
+
+fn run_id(cfg: &Config, qr: bool) -> Result<()> {
+    let public = cfg.get_existing_node_key()?.public();
+    let peer_id = PeerId::from(public.clone());
+    println!("Peer id: {}", peer_id);
+    println!("Public key: {}", key::public_key_base64(&public));
+    match task::block_on(control::call(&cfg.get_control_socket_file(), "status", json!({}))) {
+        Ok(status) => {
+            let addrs = ["listen_addrs", "external_addrs"]
+                .iter()
+                .flat_map(|field| status[field].as_array().cloned().unwrap_or_default());
+            for addr in addrs.filter_map(|a| a.as_str().map(String::from)) {
+                println!("Address: {}/p2p/{}", addr, peer_id);
+            }
         }
+        Err(_) => println!("Addresses: unknown, p2shd listen is not running."),
     }
+    if qr {
+        println!("{}", key::qr_code(&peer_id.to_string())?);
+    }
+    Ok(())
 }
 
-fn start(cfg: &Config, remote_peer_id: &PeerId) -> Result<()> {
+fn run_peer_command(cfg: &Config, cmd: &PeerCommand) -> Result<()> {
+    let mut book = SyncedBook::load(cfg.get_synced_book_file())?;
+    match cmd {
+        PeerCommand::Add { name, peer, port } => {
+            let device = PeerId::from(cfg.get_node_key()?.public());
+            book.set(name, peer, *port, &device);
+        }
+        PeerCommand::Remove { name } => {
+            let device = PeerId::from(cfg.get_node_key()?.public());
+            if !book.remove(name, &device) {
+                println!("There is no synced entry named {}.", name);
+            }
+        }
+        PeerCommand::List => {
+            let in_file = |name: &str| cfg.file.peers.as_ref().map_or(false, |p| p.contains_key(name));
+            for entry in &cfg.address_book {
+                let origin = if in_file(&entry.name) { "config.toml" } else { "synced" };
+                println!("{} {} ({})", entry.name, entry.peer_id, origin);
+            }
+            return Ok(());
+        }
+    }
+    book.save()
+}
+
+fn run_forward_command(cfg: &Config, cmd: &ForwardCommand) -> Result<()> {
+    let (session, method, params) = match cmd {
+        ForwardCommand::Add {
+            session,
+            remote,
+            forward,
+        } => (session, "add_forward", json!({"forward": forward.to_string(), "remote": remote})),
+        ForwardCommand::Remove {
+            session,
+            remote,
+            listen,
+        } => (session, "remove_forward", json!({"listen": listen.to_string(), "remote": remote})),
+        ForwardCommand::List { session } => (session, "list_forwards", json!({})),
+    };
+    let path = cfg.get_session_socket_file(session);
+    let result = task::block_on(control::call(&path, method, params))
+        .with_context(|| format!("Calling session '{}' ({}) failed", session, path.display()))?;
+    if let Some(forwards) = result.get("forwards").and_then(|f| f.as_array()) {
+        for f in forwards {
+            let remote = f.get("remote").and_then(|r| r.as_bool()).unwrap_or(false);
+            let removable = f.get("removable").and_then(|r| r.as_bool()).unwrap_or(false);
+            println!(
+                "{} {}{}",
+                if remote { "-R" } else { "-L" },
+                f.get("forward").and_then(|f| f.as_str()).unwrap_or("?"),
+                if removable { "" } else { " (from the command line)" },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn start(cfg: &Config, mode: Mode, resolver: dns::Resolver) -> Result<()> {
     let local_key = cfg.get_node_key()?;
     let local_peer_id = PeerId::from(local_key.public());
     log::info!("Our peer id: {}", &local_peer_id);
 
-    // Set up a an encrypted DNS-enabled TCP Transport over the Mplex protocol.
-    let transport = build_development_transport(local_key.clone())?;
+    let forward_peer = match &mode {
+        Mode::Forward { peer, .. } => Some(peer.clone()),
+        _ => None,
+    };
+    let listening_mode = matches!(mode, Mode::Listen { .. });
+    let blocklist = sealed_state::load_or_reset(cfg.get_blocklist_file(), Blocklist::load_shared)?;
+    let bind_addrs = match cfg.bind_interface() {
+        Some(name) => interface::addresses(name)?,
+        None => Vec::new(),
+    };
+
+    // Set up an encrypted DNS-enabled TCP Transport, dialing via `--proxy` if configured.
+    let transport = transport::build_transport(
+        local_key.clone(),
+        cfg,
+        resolver.clone(),
+        blocklist.clone(),
+        &bind_addrs,
+    )?;
 
     // We create a custom network behaviour that combines Kademlia and mDNS.
 
     // Create a swarm to manage peers and events.
     let mut swarm = {
-        let behaviour = P2shd::new(&local_key, remote_peer_id.clone())?;
-        Swarm::new(transport, behaviour, local_peer_id)
+        let addr_cache = sealed_state::load_or_reset(cfg.get_addr_cache_file(), AddrCache::load)?;
+        let routing_table = sealed_state::load_or_reset(cfg.get_routing_table_file(), RoutingTable::load)?;
+        let store = match cfg.get_record_store_file() {
+            None => Store::memory(local_peer_id.clone()),
+            Some(path) => sealed_state::load_or_reset(path, |p| Store::load(local_peer_id.clone(), p))?,
+        };
+        let state = PersistentState {
+            store,
+            addr_cache,
+            routing_table,
+            blocklist,
+        };
+        let behaviour = P2shd::new(cfg, &local_key, mode, resolver, state)?;
+        Swarm::new(transport, behaviour, local_peer_id.clone())
     };
 
-    // Listen on all interfaces and whatever port the OS assigns.
-    Swarm::listen_on(&mut swarm, format!("/ip4/0.0.0.0/tcp/{}", cfg.opts.port.unwrap_or(0)).parse()?)?;
+    let (exit, mut exit_code) = mpsc::unbounded();
+    if let Some(peer) = forward_peer {
+        spawn_forwarders(cfg, local_peer_id, peer, swarm.opener(), exit.clone());
+    }
+    let controller = swarm.controller();
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(signal) => log::info!("Received {}, shutting down ...", signal),
+            Err(e) => {
+                log::warn!("Installing signal handlers failed: {}", e);
+                return;
+            }
+        }
+        // Stops serving tunnels, terminates ssh clients and persists state:
+        if let Err(e) = controller.call(control::Call::Shutdown).await {
+            log::warn!("Clean shutdown failed: {}", e);
+        }
+        ssh::wait_children().await;
+        std::process::exit(0);
+    });
+    if let Some(Command::LookupProfile { peer }) = &cfg.opts.cmd {
+        let (peer, controller) = (cfg.lookup_peer(peer)?, swarm.controller());
+        task::spawn(lookup_profile(peer, controller, exit));
+    }
+    if let Some(name) = &cfg.opts.connect.session {
+        let path = cfg.get_session_socket_file(name);
+        if let Some(dir) = path.parent() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let controller = swarm.controller();
+        task::spawn(async move {
+            if let Err(e) = control::serve(path, controller).await {
+                log::error!("Session control socket failed: {}", e);
+            }
+        });
+    }
+    if listening_mode {
+        let (path, controller) = (cfg.get_control_socket_file(), swarm.controller());
+        // With socket activation, the first passed socket is the control socket:
+        let activated = systemd::listen_fds().into_iter().next();
+        task::spawn(async move {
+            let result = match activated {
+                Some(fd) => {
+                    log::info!("Control socket passed by systemd.");
+                    // Safe: systemd passed this fd to us, as a listening unix socket.
+                    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+                    control::serve_listener(listener, controller).await
+                }
+                None => control::serve(path, controller).await,
+            };
+            if let Err(e) = result {
+                log::error!("Control socket failed: {}", e);
+            }
+        });
+        if let Some(interval) = systemd::watchdog_interval() {
+            task::spawn(systemd::run_watchdog(interval, swarm.controller()));
+        }
+        if let Some(Command::Listen { watchdog: Some(secs), watchdog_exit, .. }) = &cfg.opts.cmd {
+            watchdog::spawn(vec![swarm.heartbeat()], Duration::from_secs(*secs), *watchdog_exit);
+        }
+        for exporter in &cfg.metrics {
+            task::spawn(exporter.clone().run(swarm.controller()));
+        }
+        if let Some(Command::Listen { http_status: Some(addr), .. }) = &cfg.opts.cmd {
+            let (addr, controller) = (*addr, swarm.controller());
+            task::spawn(async move {
+                if let Err(e) = http_status::serve(addr, controller).await {
+                    log::error!("HTTP status endpoint failed: {}", e);
+                }
+            });
+        }
+        #[cfg(feature = "grpc")]
+        {
+            if let Some(Command::Listen { grpc: Some(addr), .. }) = &cfg.opts.cmd {
+                let (addr, controller) = (*addr, swarm.controller());
+                tokio::spawn(async move {
+                    if let Err(e) = p2shd::grpc::serve(addr, controller).await {
+                        log::error!("gRPC server failed: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    // Listen on all interfaces (or those of `--bind-interface`) and whatever port the OS assigns.
+    let port = cfg.opts.port.unwrap_or(0);
+    if bind_addrs.is_empty() {
+        Swarm::listen_on(&mut swarm, format!("/ip4/0.0.0.0/tcp/{}", port).parse()?)?;
+    }
+    for ip in &bind_addrs {
+        let addr = match ip {
+            IpAddr::V4(ip) => format!("/ip4/{}/tcp/{}", ip, port),
+            IpAddr::V6(ip) => format!("/ip6/{}/tcp/{}", ip, port),
+        };
+        Swarm::listen_on(&mut swarm, addr.parse()?)?;
+    }
 
     let mut listening = false;
-    task::block_on(future::poll_fn(move |cx: &mut Context| {
+    let code = task::block_on(future::poll_fn(move |cx: &mut Context| {
         loop {
+            if let Poll::Ready(Some(code)) = exit_code.poll_next_unpin(cx) {
+                // Stops serving tunnels, terminates ssh clients and persists state:
+                swarm.shutdown();
+                return Poll::Ready(Ok(code));
+            }
             match swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(event)) => log::info!("{:?}", event),
-                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                // Only one session per run, for now:
+                Poll::Ready(Some(P2shdEvent::SessionFinished(code))) => std::process::exit(code),
+                Poll::Ready(Some(P2shdEvent::PeerNotFound(e))) => return Poll::Ready(Err(e.into())),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
                 Poll::Pending => {
                     if !listening {
                         let mut listeners = Swarm::listeners(&swarm);
@@ -68,15 +534,157 @@ fn start(cfg: &Config, remote_peer_id: &PeerId) -> Result<()> {
                             log::info!("Listening on {:?}", a);
                             listening=true;
                         }
+                        if listening {
+                            systemd::notify("READY=1");
+                        }
                     }
                     break
                 }
             }
         }
         Poll::Pending
-    }))
+    }))?;
+    task::block_on(ssh::wait_children());
+    std::process::exit(code)
+}
+
+/// Print the profile of `peer` and exit, retrying while we are still joining the DHT.
+async fn lookup_profile(peer: PeerId, controller: control::Controller, exit: Exit) {
+    let mut result = Err(String::new());
+    for _ in 0..PROFILE_LOOKUP_ATTEMPTS {
+        task::sleep(PROFILE_LOOKUP_DELAY).await;
+        result = controller.call(control::Call::LookupProfile(peer.clone())).await;
+        if result.is_ok() {
+            break;
+        }
+    }
+    match result {
+        Ok(control::Reply::Profile(profile)) => {
+            println!("Peer: {}\n{}", peer, profile);
+            let _ = exit.unbounded_send(0);
+            return;
+        }
+        Ok(reply) => log::error!("Unexpected reply: {:?}", reply),
+        Err(e) => log::error!("{}", e),
+    }
+    let _ = exit.unbounded_send(1);
+}
+
+/// Resolves once we receive SIGINT or SIGTERM, to the name of the signal.
+async fn wait_for_signal() -> io::Result<&'static str> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let (term, int) = (term.recv(), int.recv());
+    futures::pin_mut!(term, int);
+    Ok(match future::select(term, int).await {
+        future::Either::Left(_) => "SIGTERM",
+        future::Either::Right(_) => "SIGINT",
+    })
 }
 
+/// Start the local servers forwarding connections through `peer`.
+///
+/// Once a command is done, its exit code gets sent to `exit`.
+fn spawn_forwarders(cfg: &Config, local: PeerId, peer: PeerId, opener: Opener, exit: Exit) {
+    if let Some(Command::Socks { listen, .. }) = &cfg.opts.cmd {
+        let (listen, peer, opener, exit) = (*listen, peer.clone(), opener.clone(), exit.clone());
+        task::spawn(async move {
+            if let Err(e) = socks::serve(listen, peer, opener).await {
+                log::error!("SOCKS server failed: {}", e);
+                let _ = exit.unbounded_send(1);
+            }
+        });
+    }
+    if let Some(Command::Open { service, local, .. }) = &cfg.opts.cmd {
+        let (service, local, peer, opener, exit) =
+            (service.clone(), *local, peer.clone(), opener.clone(), exit.clone());
+        task::spawn(async move {
+            let result = match (service, local) {
+                (None, _) => forward::request_lines(Request::Services, peer, opener)
+                    .await
+                    .map(|services| {
+                        for s in services {
+                            println!("{}", s);
+                        }
+                    }),
+                (Some(name), Some(local)) => {
+                    forward::listen(local, Request::Service { name }, peer, opener).await
+                }
+                (Some(name), None) => forward::run_stdio(Request::Service { name }, peer, opener).await,
+            };
+            let code = match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    log::error!("{}", e);
+                    1
+                }
+            };
+            let _ = exit.unbounded_send(code);
+        });
+    }
+    if let Some(Command::Resources { .. }) = &cfg.opts.cmd {
+        let (peer, opener, exit) = (peer.clone(), opener.clone(), exit.clone());
+        task::spawn(async move {
+            let code = match forward::request_lines(Request::Resources, peer, opener).await {
+                Ok(lines) => {
+                    for l in lines {
+                        println!("{}", l);
+                    }
+                    0
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    1
+                }
+            };
+            let _ = exit.unbounded_send(code);
+        });
+    }
+    if let Some(Command::Sync { .. }) = &cfg.opts.cmd {
+        let (path, peer) = (cfg.get_synced_book_file(), peer.clone());
+        let (opener, exit) = (opener.clone(), exit.clone());
+        task::spawn(async move {
+            let code = match book_sync::sync(path, local, peer.clone(), opener).await {
+                Ok(changed) => {
+                    println!("Synced with {}, got {} changed entries.", peer, changed);
+                    0
+                }
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    1
+                }
+            };
+            let _ = exit.unbounded_send(code);
+        });
+    }
+    if let Some(Command::Vpn { address, .. }) = &cfg.opts.cmd {
+        let (address, peer, opener, exit) = (*address, peer.clone(), opener.clone(), exit.clone());
+        task::spawn(async move {
+            let result = async {
+                let stream = opener.open(peer, &Request::Vpn).await?;
+                vpn::run(stream, vpn::Tun::create(address).await?).await
+            };
+            let code = match result.await {
+                Ok(()) => 0,
+                Err(e) => {
+                    log::error!("VPN link failed: {}", e);
+                    1
+                }
+            };
+            let _ = exit.unbounded_send(code);
+        });
+    }
+    let connect = &cfg.opts.connect;
+    let (local, remote) = (connect.local_forwards.clone(), connect.remote_forwards.clone());
+    if !local.is_empty() || !remote.is_empty() {
+        task::spawn(async move {
+            if let Err(e) = forward::negotiate(local, remote, peer, opener).await {
+                log::error!("Forwarding failed: {}", e);
+                let _ = exit.unbounded_send(1);
+            }
+        });
+    }
+}
 
 // fn main() {
 //     let raw_stdin = 0;
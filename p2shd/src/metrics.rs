@@ -0,0 +1,241 @@
+//! Pushing metrics of `p2shd listen` to monitoring systems.
+//!
+//! Exporters are configured in `config.toml`, each with its own interval and
+//! optionally only some metric families:
+//!
+//! ```toml
+//! [[metrics]]
+//! type = "statsd"
+//! address = "127.0.0.1:8125"
+//! prefix = "p2shd"
+//! interval = 10
+//!
+//! [[metrics]]
+//! type = "otlp"
+//! endpoint = "http://127.0.0.1:4318/v1/metrics"
+//! families = ["peers", "tunnels"]
+//! ```
+//!
+//! statsd gets gauges via UDP, OTLP gets them as JSON via plain HTTP (put a
+//! collector on localhost for anything else). All values come from
+//! `control::Status`, so they are the same the control API shows.
+
+use anyhow::{Context as AnyhowContext, Result};
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use futures_timer::Delay;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    control::{Call, Controller, Nat, Reply, Status},
+    otlp,
+};
+
+mod error;
+
+/// Push interval if none is configured, in seconds.
+const DEFAULT_INTERVAL: u64 = 30;
+
+/// Give up on pushes taking longer than this.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An exporter as found in the configuration file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExporterEntry {
+    /// Seconds between pushes.
+    pub interval: Option<u64>,
+    /// Metric families to push, all if not set.
+    pub families: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub backend: Backend,
+}
+
+/// Where metrics get pushed to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    /// statsd (or compatible) server, gauges as `<prefix>.<family>:<value>|g`.
+    Statsd { address: String, prefix: Option<String> },
+    /// OpenTelemetry collector, via OTLP/HTTP with JSON encoding.
+    Otlp { endpoint: String },
+}
+
+/// Groups of metrics, for selecting which ones an exporter pushes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    /// Number of connected peers.
+    Peers,
+    /// Number of tunnels being served.
+    Tunnels,
+    /// Size of the Kademlia routing table.
+    RoutingTable,
+    /// Number of addresses peers observed us at.
+    ExternalAddrs,
+    /// Whether we are reachable without NAT traversal: 1 if so, 0 if not, -1 if unknown.
+    Public,
+}
+
+const FAMILIES: [Family; 5] = [
+    Family::Peers,
+    Family::Tunnels,
+    Family::RoutingTable,
+    Family::ExternalAddrs,
+    Family::Public,
+];
+
+impl Family {
+    fn name(self) -> &'static str {
+        match self {
+            Family::Peers => "peers",
+            Family::Tunnels => "tunnels",
+            Family::RoutingTable => "routing_table",
+            Family::ExternalAddrs => "external_addrs",
+            Family::Public => "public",
+        }
+    }
+
+    fn value(self, status: &Status) -> i64 {
+        match self {
+            Family::Peers => status.connected_peers as i64,
+            Family::Tunnels => status.active_tunnels as i64,
+            Family::RoutingTable => status.routing_table_size as i64,
+            Family::ExternalAddrs => status.external_addrs.len() as i64,
+            Family::Public => match status.nat {
+                Nat::Public => 1,
+                Nat::Private => 0,
+                Nat::Unknown => -1,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Family {
+    type Err = error::Metrics;
+
+    fn from_str(s: &str) -> Result<Family, Self::Err> {
+        FAMILIES
+            .iter()
+            .cloned()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| error::Metrics::UnknownFamily(s.into()))
+    }
+}
+
+/// A validated exporter.
+#[derive(Debug, Clone)]
+pub struct Exporter {
+    pub backend: Backend,
+    pub interval: Duration,
+    pub families: Vec<Family>,
+}
+
+impl Exporter {
+    /// Validate an exporter from the configuration file.
+    pub fn from_entry(entry: &ExporterEntry) -> Result<Exporter> {
+        let families = match &entry.families {
+            Some(names) => names.iter().map(|n| n.parse()).collect::<Result<_, _>>()?,
+            None => FAMILIES.to_vec(),
+        };
+        if let Backend::Otlp { endpoint } = &entry.backend {
+            otlp::check_endpoint(endpoint)?;
+        }
+        Ok(Exporter {
+            backend: entry.backend.clone(),
+            interval: Duration::from_secs(entry.interval.unwrap_or(DEFAULT_INTERVAL).max(1)),
+            families,
+        })
+    }
+
+    /// Push metrics every `interval`, forever.
+    pub async fn run(self, controller: Controller) {
+        loop {
+            Delay::new(self.interval).await;
+            let status = match controller.call(Call::Status).await {
+                Ok(Reply::Status(status)) => status,
+                Ok(_) => unreachable!("Status calls get status replies."),
+                Err(e) => {
+                    log::warn!("Getting metrics failed: {}", e);
+                    continue;
+                }
+            };
+            let samples: Vec<_> = self.families.iter().map(|&f| (f, f.value(&status))).collect();
+            let push = async_std::future::timeout(PUSH_TIMEOUT, self.push(&samples));
+            match push.await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => log::warn!("Pushing metrics to {} failed: {:#}", self.backend, e),
+                Err(_) => log::warn!("Pushing metrics to {} timed out.", self.backend),
+            }
+        }
+    }
+
+    async fn push(&self, samples: &[(Family, i64)]) -> Result<()> {
+        match &self.backend {
+            Backend::Statsd { address, prefix } => {
+                let prefix = prefix.as_deref().unwrap_or("p2shd");
+                let datagram: String = samples
+                    .iter()
+                    .map(|(f, v)| format!("{}.{}:{}|g\n", prefix, f, v))
+                    .collect();
+                let addr = address
+                    .as_str()
+                    .to_socket_addrs()
+                    .await?
+                    .next()
+                    .ok_or_else(|| error::Metrics::Unresolvable(address.clone()))?;
+                let socket = UdpSocket::bind(if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).await?;
+                socket.send_to(datagram.as_bytes(), addr).await?;
+            }
+            Backend::Otlp { endpoint } => otlp::post(endpoint, "/v1/metrics", &otlp_body(samples)).await?,
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Statsd { address, .. } => write!(f, "statsd at {}", address),
+            Backend::Otlp { endpoint } => write!(f, "OTLP at {}", endpoint),
+        }
+    }
+}
+
+/// Validate all configured exporters.
+pub fn parse_exporters(entries: &[ExporterEntry]) -> Result<Vec<Exporter>> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| Exporter::from_entry(e).with_context(|| error::Metrics::InvalidExporter(i + 1)))
+        .collect()
+}
+
+/// `samples` as OTLP `ExportMetricsServiceRequest`, in its JSON encoding.
+fn otlp_body(samples: &[(Family, i64)]) -> serde_json::Value {
+    let now = otlp::unix_nanos(SystemTime::now());
+    let metrics: Vec<_> = samples
+        .iter()
+        .map(|(f, v)| {
+            json!({
+                "name": format!("p2shd.{}", f),
+                "gauge": {"dataPoints": [{"timeUnixNano": now, "asInt": v.to_string()}]},
+            })
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": otlp::resource(),
+            "scopeMetrics": [{"scope": otlp::scope(), "metrics": metrics}],
+        }]
+    })
+}
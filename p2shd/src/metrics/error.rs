@@ -0,0 +1,16 @@
+//! Errors that can happen while exporting metrics.
+
+use thiserror::Error;
+
+/// Errors related to metrics exporters.
+#[derive(Error, Debug)]
+pub enum Metrics {
+    #[error("Invalid metrics exporter #{0}.")]
+    InvalidExporter(usize),
+    #[error(
+        "Unknown metric family '{0}', expected 'peers', 'tunnels', 'routing_table', 'external_addrs' or 'public'."
+    )]
+    UnknownFamily(String),
+    #[error("Resolving '{0}' yielded no address.")]
+    Unresolvable(String),
+}
@@ -0,0 +1,21 @@
+//! Classifying IP addresses, shared by everything deciding by address class.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Private, link local and unique local addresses, not loopback.
+pub(crate) fn is_lan(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => is_unique_local(ip) || is_unicast_link_local(ip),
+    }
+}
+
+/// fc00::/7
+pub(crate) fn is_unique_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xfe00) == 0xfc00
+}
+
+/// fe80::/10
+pub(crate) fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
@@ -0,0 +1,75 @@
+//! Minimal OTLP/HTTP client, for pushing metrics and traces to an OpenTelemetry collector.
+//!
+//! Requests are JSON encoded and go via plain HTTP, so the collector is
+//! expected on localhost or in a trusted network.
+
+use anyhow::Result;
+use async_std::net::TcpStream;
+use futures::{io::BufReader, prelude::*};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod error;
+
+/// Check `endpoint` is an `http://host:port[/path]` URL.
+pub fn check_endpoint(endpoint: &str) -> Result<(), error::Otlp> {
+    split_endpoint(endpoint, "/").map(|_| ())
+}
+
+/// POST `body` to `endpoint`, to `default_path` if `endpoint` has none.
+pub async fn post(endpoint: &str, default_path: &str, body: &Value) -> Result<()> {
+    let (host, path) = split_endpoint(endpoint, default_path)?;
+    let body = body.to_string();
+    let mut socket = TcpStream::connect(host).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    socket.write_all(request.as_bytes()).await?;
+    let mut status_line = String::new();
+    BufReader::new(socket).read_line(&mut status_line).await?;
+    let ok = status_line.split_whitespace().nth(1).map_or(false, |c| c.starts_with('2'));
+    if !ok {
+        return Err(error::Otlp::Rejected(status_line.trim().into()).into());
+    }
+    Ok(())
+}
+
+/// The OTLP `resource` we report as.
+pub fn resource() -> Value {
+    json!({"attributes": [attribute("service.name", "p2shd")]})
+}
+
+/// The OTLP instrumentation `scope` of everything we export.
+pub fn scope() -> Value {
+    json!({"name": "p2shd"})
+}
+
+/// A string valued OTLP attribute (`KeyValue`).
+pub fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+/// `time` in nanoseconds since the Unix epoch, as OTLP's JSON encoding wants them.
+pub fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+/// Host (with port) and path of an `http://` endpoint.
+fn split_endpoint<'a>(endpoint: &'a str, default_path: &'a str) -> Result<(&'a str, &'a str), error::Otlp> {
+    let rest = endpoint
+        .strip_prefix("http://")
+        .ok_or_else(|| error::Otlp::InvalidEndpoint(endpoint.into()))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, default_path),
+    };
+    if !host.contains(':') {
+        return Err(error::Otlp::InvalidEndpoint(endpoint.into()));
+    }
+    Ok((host, path))
+}
@@ -0,0 +1,12 @@
+//! Errors that can happen while talking to an OpenTelemetry collector.
+
+use thiserror::Error;
+
+/// Errors related to OTLP/HTTP.
+#[derive(Error, Debug)]
+pub enum Otlp {
+    #[error("Invalid OTLP endpoint '{0}', expected 'http://host:port[/path]'.")]
+    InvalidEndpoint(String),
+    #[error("Collector rejected the request: '{0}'")]
+    Rejected(String),
+}
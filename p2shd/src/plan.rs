@@ -0,0 +1,108 @@
+//! `p2shd connect --dry-run`: What connecting would do, without doing it.
+//!
+//! Discovery runs as usual. Once it found addresses, instead of dialing them
+//! the plan gets printed: Each address with where we learned it from, how
+//! likely dialing it is to work (see `predictor`), the order they would be
+//! dialed in and what would run over the tunnel.
+
+use libp2p::{Multiaddr, PeerId};
+use std::{fmt, time::Duration};
+
+use crate::{
+    control::Nat,
+    dial_report::AddrSource,
+    predictor::AddrClass,
+    trust::Trust,
+};
+
+/// An address we would dial, or skip.
+pub struct PlannedDial {
+    pub addr: Multiaddr,
+    pub source: AddrSource,
+    /// Predicted probability of the dial succeeding.
+    pub prediction: f64,
+}
+
+/// What would run once the tunnel is open.
+pub enum Action {
+    /// ssh, with its command line.
+    Ssh(Vec<String>),
+    /// mosh, with the ssh command starting mosh-server.
+    Mosh(String),
+    /// The tunnel bridged to stdio.
+    Stdio,
+}
+
+/// The plan for connecting to one peer.
+pub struct Plan {
+    pub peer: PeerId,
+    pub trust: Trust,
+    pub nat: Nat,
+    /// Discovery attempts until addresses were found and how long they took.
+    pub attempts: u32,
+    pub elapsed: Duration,
+    /// The existing connection to the peer would be used, nothing dialed.
+    pub connected: bool,
+    /// Last address we successfully connected with, dialed right away.
+    pub fast_path: Option<Multiaddr>,
+    /// In dialing order.
+    pub dials: Vec<PlannedDial>,
+    /// Predicted to fail, not dialed.
+    pub skipped: Vec<PlannedDial>,
+    /// Delay between starting dials.
+    pub stagger: Duration,
+    pub action: Action,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Plan for connecting to {} ({}):", self.peer, self.trust)?;
+        writeln!(
+            f,
+            "  Discovery: {} attempt(s), {:.1}s, our NAT status: {:?}",
+            self.attempts,
+            self.elapsed.as_secs_f64(),
+            self.nat
+        )?;
+        if let Some(addr) = &self.fast_path {
+            writeln!(f, "  Last good address, dialed before discovery finishes: {}", addr)?;
+        }
+        if self.connected {
+            writeln!(f, "  Already connected, the tunnel would use that connection.")?;
+        } else {
+            writeln!(
+                f,
+                "  Dialing in this order, each {}ms after the previous one or once it failed:",
+                self.stagger.as_millis()
+            )?;
+            for (n, d) in self.dials.iter().enumerate() {
+                writeln!(f, "    {}. {}", n + 1, describe(d))?;
+            }
+            for d in &self.skipped {
+                writeln!(f, "    skipped: {}", describe(d))?;
+            }
+        }
+        // libp2p has no hole punching (yet), relays only if the peer announced circuit addresses:
+        let relayed = self.dials.iter().any(|d| AddrClass::of(&d.addr) == AddrClass::Relay);
+        writeln!(
+            f,
+            "  Relay: {}, hole punching: not supported",
+            if relayed { "via the relayed addresses above" } else { "none" }
+        )?;
+        match &self.action {
+            Action::Ssh(args) => write!(f, "  Then running: {}", args.join(" ")),
+            Action::Mosh(ssh) => write!(f, "  Then running mosh, starting mosh-server via: {}", ssh),
+            Action::Stdio => write!(f, "  Then bridging the tunnel to stdin/stdout."),
+        }
+    }
+}
+
+fn describe(d: &PlannedDial) -> String {
+    format!(
+        "{} ({:?}, from {}, predicted {:.0}%)",
+        d.addr,
+        AddrClass::of(&d.addr),
+        d.source,
+        d.prediction * 100.0
+    )
+}
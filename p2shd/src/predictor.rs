@@ -0,0 +1,351 @@
+//! Dial success prediction, for trying the most promising addresses first.
+//!
+//! Outcomes of past dials are counted per address class (loopback, LAN,
+//! public, relayed, ...) and our own NAT situation, which together decide
+//! most of whether a dial can work at all: A public address is fine from
+//! anywhere, a LAN address only if we happen to be in the same network, a
+//! relayed one works from behind any NAT but is slow. The counts get
+//! persisted in the address cache, so the model improves across runs.
+//!
+//! Classes predicted to fail get pruned, but not always: Otherwise they would
+//! never be dialed again and a class that works again (e.g. after a network
+//! change) could never recover. Once in a while pruned addresses are kept as
+//! last resort, their outcomes then update the counts as usual.
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{control::Nat, net};
+
+/// Counts get halved once they reach this, so old outcomes fade out.
+const MAX_COUNT: u32 = 100;
+
+/// Classes with fewer outcomes than this are never pruned.
+const MIN_SAMPLES: u32 = 10;
+
+/// Addresses predicted to succeed less likely than this are pruned.
+const MIN_PREDICTION: f64 = 0.05;
+
+/// Share of `Predictor::rank` calls that keep pruned addresses, after all others.
+const EXPLORATION: f64 = 0.1;
+
+/// What kind of address, regarding reachability.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddrClass {
+    Loopback,
+    /// Private, link local or unique local address.
+    Lan,
+    Public,
+    /// DNS name, can't tell more without resolving.
+    Dns,
+    /// Via a relay (`/p2p-circuit`).
+    Relay,
+    Other,
+}
+
+impl AddrClass {
+    pub fn of(addr: &Multiaddr) -> AddrClass {
+        if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            return AddrClass::Relay;
+        }
+        let ip: IpAddr = match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => ip.into(),
+            Some(Protocol::Ip6(ip)) => ip.into(),
+            Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) => return AddrClass::Dns,
+            _ => return AddrClass::Other,
+        };
+        match ip {
+            ip if ip.is_loopback() => AddrClass::Loopback,
+            ip if net::is_lan(&ip) => AddrClass::Lan,
+            _ => AddrClass::Public,
+        }
+    }
+
+    /// Order to try classes in without any outcomes to go by, lower first:
+    /// Nearby before far away, relays last as they are slow.
+    fn preference(self) -> u8 {
+        match self {
+            AddrClass::Loopback => 0,
+            AddrClass::Lan => 1,
+            AddrClass::Public => 2,
+            AddrClass::Dns => 3,
+            AddrClass::Other => 4,
+            AddrClass::Relay => 5,
+        }
+    }
+}
+
+/// Whether `addr` is an IPv6 address, these get tried first (as in RFC 8305).
+fn is_ip6(addr: &Multiaddr) -> bool {
+    matches!(addr.iter().next(), Some(Protocol::Ip6(_)) | Some(Protocol::Dns6(_)))
+}
+
+/// Persisted dial outcomes of one class, see `Predictor::stats`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DialStat {
+    pub nat: Nat,
+    pub class: AddrClass,
+    pub successes: u32,
+    pub failures: u32,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Outcomes {
+    successes: u32,
+    failures: u32,
+}
+
+impl Outcomes {
+    /// Success probability, with a uniform prior (Laplace's rule of succession).
+    fn probability(&self) -> f64 {
+        f64::from(self.successes + 1) / f64::from(self.successes + self.failures + 2)
+    }
+}
+
+/// Predicts dial success from past outcomes.
+#[derive(Default)]
+pub struct Predictor {
+    outcomes: HashMap<(Nat, AddrClass), Outcomes>,
+}
+
+impl Predictor {
+    pub fn from_stats(stats: Vec<DialStat>) -> Predictor {
+        let outcomes = stats
+            .into_iter()
+            .map(|s| {
+                let o = Outcomes {
+                    successes: s.successes,
+                    failures: s.failures,
+                };
+                ((s.nat, s.class), o)
+            })
+            .collect();
+        Predictor { outcomes }
+    }
+
+    /// All outcomes, for persisting them.
+    pub fn stats(&self) -> Vec<DialStat> {
+        self.outcomes
+            .iter()
+            .map(|(&(nat, class), o)| DialStat {
+                nat,
+                class,
+                successes: o.successes,
+                failures: o.failures,
+            })
+            .collect()
+    }
+
+    /// Probability of a dial to `addr` succeeding, given our `nat` situation.
+    pub fn predict(&self, nat: Nat, addr: &Multiaddr) -> f64 {
+        self.get(nat, AddrClass::of(addr)).probability()
+    }
+
+    /// Record the outcome of a dial to `addr`.
+    pub fn record(&mut self, nat: Nat, addr: &Multiaddr, success: bool) {
+        let class = AddrClass::of(addr);
+        log::debug!(
+            "Dial to {} ({:?}, NAT {:?}): predicted {:.2}, {}",
+            addr,
+            class,
+            nat,
+            self.predict(nat, addr),
+            if success { "succeeded" } else { "failed" }
+        );
+        let o = self.outcomes.entry((nat, class)).or_default();
+        if success {
+            o.successes += 1;
+        } else {
+            o.failures += 1;
+        }
+        if o.successes + o.failures >= MAX_COUNT {
+            o.successes /= 2;
+            o.failures /= 2;
+        }
+    }
+
+    /// Order `addrs` by predicted success, most promising first, dropping
+    /// those that are almost certain to fail.
+    ///
+    /// Equally promising addresses are ordered by class (see
+    /// `AddrClass::preference`), IPv6 before IPv4. Nothing gets dropped if that
+    /// would leave no address at all, or when exploring (see `EXPLORATION`).
+    pub fn rank(&self, nat: Nat, addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        let explore = rand::random::<f64>() < EXPLORATION;
+        self.rank_exploring(nat, addrs, explore)
+    }
+
+    /// `rank`, with the decision whether to explore already made.
+    fn rank_exploring(&self, nat: Nat, addrs: Vec<Multiaddr>, explore: bool) -> Vec<Multiaddr> {
+        let mut ranked: Vec<_> = addrs
+            .into_iter()
+            .map(|a| (self.get(nat, AddrClass::of(&a)), a))
+            .collect();
+        // Stable, so otherwise equal addresses keep their order:
+        ranked.sort_by(|(a, a_addr), (b, b_addr)| {
+            b.probability()
+                .partial_cmp(&a.probability())
+                .expect("No NaN.")
+                .then_with(|| AddrClass::of(a_addr).preference().cmp(&AddrClass::of(b_addr).preference()))
+                .then_with(|| is_ip6(b_addr).cmp(&is_ip6(a_addr)))
+        });
+        let promising = |o: &Outcomes| o.successes + o.failures < MIN_SAMPLES || o.probability() >= MIN_PREDICTION;
+        if explore && ranked.iter().any(|(o, _)| !promising(o)) {
+            log::debug!("Keeping unpromising addresses, to see whether they work again.");
+        } else if !explore && ranked.iter().any(|(o, _)| promising(o)) {
+            ranked.retain(|(o, a)| {
+                let keep = promising(o);
+                if !keep {
+                    log::debug!("Not dialing {}, predicted {:.2}", a, o.probability());
+                }
+                keep
+            });
+        }
+        ranked.into_iter().map(|(_, a)| a).collect()
+    }
+
+    fn get(&self, nat: Nat, class: AddrClass) -> Outcomes {
+        self.outcomes.get(&(nat, class)).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    fn stat(class: AddrClass, successes: u32, failures: u32) -> DialStat {
+        DialStat {
+            nat: Nat::Private,
+            class,
+            successes,
+            failures,
+        }
+    }
+
+    fn outcomes(predictor: &Predictor, class: AddrClass) -> (u32, u32) {
+        let o = predictor.get(Nat::Private, class);
+        (o.successes, o.failures)
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        let cases = [
+            ("/ip4/127.0.0.1/tcp/1", AddrClass::Loopback),
+            ("/ip6/::1/tcp/1", AddrClass::Loopback),
+            ("/ip4/192.168.1.1/tcp/1", AddrClass::Lan),
+            ("/ip4/169.254.0.1/tcp/1", AddrClass::Lan),
+            ("/ip6/fd00::1/tcp/1", AddrClass::Lan),
+            ("/ip6/fe80::1/tcp/1", AddrClass::Lan),
+            ("/ip4/1.2.3.4/tcp/1", AddrClass::Public),
+            ("/ip6/2001:db8::1/tcp/1", AddrClass::Public),
+            ("/dns4/example.org/tcp/1", AddrClass::Dns),
+            ("/ip4/1.2.3.4/tcp/1/p2p-circuit", AddrClass::Relay),
+            ("/memory/1", AddrClass::Other),
+        ];
+        for (a, class) in &cases {
+            assert_eq!(AddrClass::of(&addr(a)), *class, "{}", a);
+        }
+    }
+
+    #[test]
+    fn records_outcomes_per_nat_and_class() {
+        let mut predictor = Predictor::default();
+        let lan = addr("/ip4/192.168.1.1/tcp/1");
+        assert!((predictor.predict(Nat::Private, &lan) - 0.5).abs() < 1e-9);
+        predictor.record(Nat::Private, &lan, true);
+        predictor.record(Nat::Private, &lan, true);
+        predictor.record(Nat::Private, &lan, false);
+        assert_eq!(outcomes(&predictor, AddrClass::Lan), (2, 1));
+        assert!((predictor.predict(Nat::Private, &lan) - 0.6).abs() < 1e-9);
+        assert!((predictor.predict(Nat::Public, &lan) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn counts_get_halved_at_max() {
+        let mut predictor = Predictor::default();
+        let public = addr("/ip4/1.2.3.4/tcp/1");
+        for i in 0..MAX_COUNT - 1 {
+            predictor.record(Nat::Private, &public, i % 3 == 0);
+        }
+        assert_eq!(outcomes(&predictor, AddrClass::Public), (33, 66));
+        predictor.record(Nat::Private, &public, false);
+        assert_eq!(outcomes(&predictor, AddrClass::Public), (16, 33));
+    }
+
+    #[test]
+    fn ties_are_broken_by_class_then_ip6_first() {
+        let predictor = Predictor::default();
+        let addrs = vec![
+            addr("/ip4/1.2.3.4/tcp/1/p2p-circuit"),
+            addr("/ip4/1.2.3.4/tcp/1"),
+            addr("/ip6/2001:db8::1/tcp/1"),
+            addr("/ip4/192.168.1.1/tcp/1"),
+            addr("/ip4/127.0.0.1/tcp/1"),
+        ];
+        let expected = vec![
+            addr("/ip4/127.0.0.1/tcp/1"),
+            addr("/ip4/192.168.1.1/tcp/1"),
+            addr("/ip6/2001:db8::1/tcp/1"),
+            addr("/ip4/1.2.3.4/tcp/1"),
+            addr("/ip4/1.2.3.4/tcp/1/p2p-circuit"),
+        ];
+        // Nothing is unpromising without outcomes, exploring makes no difference:
+        assert_eq!(predictor.rank(Nat::Private, addrs), expected);
+    }
+
+    #[test]
+    fn likely_successes_come_first() {
+        let predictor = Predictor::from_stats(vec![
+            stat(AddrClass::Lan, 1, 5),
+            stat(AddrClass::Public, 5, 1),
+        ]);
+        let lan = addr("/ip4/192.168.1.1/tcp/1");
+        let public = addr("/ip4/1.2.3.4/tcp/1");
+        let ranked = predictor.rank(Nat::Private, vec![lan.clone(), public.clone()]);
+        assert_eq!(ranked, vec![public, lan]);
+    }
+
+    #[test]
+    fn prunes_unpromising_unless_exploring() {
+        // Predicted 1 / 32, below `MIN_PREDICTION`:
+        let predictor = Predictor::from_stats(vec![
+            stat(AddrClass::Lan, 0, 30),
+            stat(AddrClass::Public, 10, 0),
+        ]);
+        let lan = addr("/ip4/192.168.1.1/tcp/1");
+        let public = addr("/ip4/1.2.3.4/tcp/1");
+        let addrs = vec![lan.clone(), public.clone()];
+        assert_eq!(predictor.rank_exploring(Nat::Private, addrs.clone(), false), vec![public.clone()]);
+        assert_eq!(predictor.rank_exploring(Nat::Private, addrs, true), vec![public, lan]);
+    }
+
+    #[test]
+    fn keeps_unpromising_if_nothing_else_is_left() {
+        let predictor = Predictor::from_stats(vec![stat(AddrClass::Lan, 0, 30)]);
+        let lan = addr("/ip4/192.168.1.1/tcp/1");
+        for &explore in &[false, true] {
+            assert_eq!(predictor.rank_exploring(Nat::Private, vec![lan.clone()], explore), vec![lan.clone()]);
+        }
+    }
+
+    #[test]
+    fn keeps_classes_with_few_samples() {
+        // Fewer than `MIN_SAMPLES` outcomes, all failures:
+        let predictor = Predictor::from_stats(vec![
+            stat(AddrClass::Lan, 0, MIN_SAMPLES - 1),
+            stat(AddrClass::Public, 10, 0),
+        ]);
+        let lan = addr("/ip4/192.168.1.1/tcp/1");
+        let public = addr("/ip4/1.2.3.4/tcp/1");
+        for &explore in &[false, true] {
+            let ranked = predictor.rank_exploring(Nat::Private, vec![lan.clone(), public.clone()], explore);
+            assert_eq!(ranked, vec![public.clone(), lan.clone()]);
+        }
+    }
+}
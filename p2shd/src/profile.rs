@@ -0,0 +1,64 @@
+//! Public profiles: Opt-in, signed "directory" records in the DHT.
+//!
+//! With `[profile]` configured, `p2shd listen` publishes a display name, a
+//! contact hint and the names of services it offers under
+//! `/p2shd/profile/<peer id>`, for `p2shd lookup-profile` to fetch. As for
+//! address records, the signature is checked against the key of the peer
+//! id, so nobody can publish profiles for others.
+//!
+//! Profiles are public, anybody knowing the peer id can read them.
+
+use anyhow::Result;
+use libp2p::{identity, kad::record::Key, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+
+use crate::signed_record::Kind;
+
+/// Profiles, see `signed_record`.
+const KIND: Kind = Kind::new("/p2shd/profile/", "Profile");
+
+/// A peer's public profile.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// How to reach the person behind the peer, e.g. an email address.
+    pub contact: Option<String>,
+    /// Names of offered services, see `[expose]` for who is allowed to use them.
+    pub services: Vec<String>,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Name: {}", self.name)?;
+        if let Some(contact) = &self.contact {
+            writeln!(f, "Contact: {}", contact)?;
+        }
+        if self.services.is_empty() {
+            write!(f, "Services: none")
+        } else {
+            write!(f, "Services: {}", self.services.join(", "))
+        }
+    }
+}
+
+/// DHT key the profile of `peer` is published under.
+pub fn record_key(peer: &PeerId) -> Key {
+    KIND.record_key(peer)
+}
+
+/// The publisher of a profile record, `None` if `key` is not a profile record key.
+pub fn publisher_of(key: &Key) -> Option<PeerId> {
+    KIND.publisher_of(key)
+}
+
+/// `profile`, valid for `ttl` and signed with `key`, for publishing it in the DHT.
+pub fn sign(key: &identity::Keypair, profile: &Profile, ttl: Duration) -> Result<Vec<u8>> {
+    KIND.sign(key, profile, Some(ttl))
+}
+
+/// The profile of a record published by `publisher`, if the signature checks
+/// out and it did not expire yet.
+pub fn verify(publisher: &PeerId, raw: &[u8]) -> Result<Profile> {
+    KIND.verify(publisher, raw)
+}
@@ -0,0 +1,161 @@
+//! Prometheus metrics, served as `/metrics` by the HTTP status endpoint.
+//!
+//! Gauges (connected peers, tunnels, routing table size, DHT queries) come
+//! from `control::Status` at scrape time. Counters and histograms of things
+//! that happen (dial failures, bytes relayed, query latencies, session
+//! durations) get recorded in a global registry, as they happen all over
+//! the place.
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    control::{Nat, Status},
+    dial_report::DialFailure,
+};
+
+/// Bucket bounds for DHT query latencies, in seconds.
+const QUERY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Bucket bounds for session durations, in seconds.
+const SESSION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 14400.0, 86400.0];
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry {
+    dial_failures: Mutex::new(BTreeMap::new()),
+    bytes_relayed: AtomicU64::new(0),
+    query_duration: Histogram::new(QUERY_BUCKETS),
+    session_duration: Histogram::new(SESSION_BUCKETS),
+});
+
+struct Registry {
+    /// By failure kind.
+    dial_failures: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_relayed: AtomicU64,
+    query_duration: Histogram,
+    session_duration: Histogram,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    values: Mutex<HistogramValues>,
+}
+
+#[derive(Default)]
+struct HistogramValues {
+    /// Per bucket, not cumulative.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            values: Mutex::new(HistogramValues {
+                buckets: vec![0; bounds.len()],
+                ..HistogramValues::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let mut values = self.values.lock().expect("Histogram lock poisoned.");
+        if let Some(i) = self.bounds.iter().position(|&b| secs <= b) {
+            values.buckets[i] += 1;
+        }
+        values.sum += secs;
+        values.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let values = self.values.lock().expect("Histogram lock poisoned.");
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&values.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, values.count);
+        let _ = writeln!(out, "{}_sum {}", name, values.sum);
+        let _ = writeln!(out, "{}_count {}", name, values.count);
+    }
+}
+
+/// Dialing a peer failed.
+pub fn dial_failed(failure: DialFailure) {
+    let kind = match failure {
+        DialFailure::Timeout => "timeout",
+        DialFailure::Refused => "refused",
+        DialFailure::WrongPeer => "wrong_peer",
+        DialFailure::Negotiation => "negotiation",
+        DialFailure::Unreachable => "unreachable",
+    };
+    *REGISTRY.dial_failures.lock().expect("Metrics lock poisoned.").entry(kind).or_default() += 1;
+}
+
+/// `bytes` went through a tunnel, in either direction.
+pub fn relayed(bytes: u64) {
+    REGISTRY.bytes_relayed.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// A DHT query for a peer finished after `took`.
+pub fn query_finished(took: Duration) {
+    REGISTRY.query_duration.observe(took);
+}
+
+/// A session (ssh, forwarding or vpn tunnel) we served got closed after `took`.
+pub fn session_finished(took: Duration) {
+    REGISTRY.session_duration.observe(took);
+}
+
+/// All metrics in Prometheus' text format, gauges from `status`.
+pub fn render(status: &Status) -> String {
+    let mut out = String::new();
+    let nat_public = match status.nat {
+        Nat::Public => 1,
+        Nat::Private => 0,
+        Nat::Unknown => -1,
+    };
+    let gauges = [
+        ("p2shd_connected_peers", "Number of connected peers.", status.connected_peers as i64),
+        ("p2shd_active_tunnels", "Number of tunnels being served.", status.active_tunnels as i64),
+        ("p2shd_routing_table_size", "Peers in the Kademlia routing table.", status.routing_table_size as i64),
+        ("p2shd_dht_queries", "DHT queries in progress.", status.queries as i64),
+        ("p2shd_nat_public", "1 if reachable without NAT traversal, 0 if not, -1 if unknown.", nat_public),
+    ];
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    let _ = writeln!(out, "# HELP p2shd_dial_failures_total Failed dials, by kind of failure.");
+    let _ = writeln!(out, "# TYPE p2shd_dial_failures_total counter");
+    for (kind, count) in REGISTRY.dial_failures.lock().expect("Metrics lock poisoned.").iter() {
+        let _ = writeln!(out, "p2shd_dial_failures_total{{kind=\"{}\"}} {}", kind, count);
+    }
+    let _ = writeln!(out, "# HELP p2shd_relayed_bytes_total Bytes that went through tunnels.");
+    let _ = writeln!(out, "# TYPE p2shd_relayed_bytes_total counter");
+    let _ = writeln!(out, "p2shd_relayed_bytes_total {}", REGISTRY.bytes_relayed.load(Ordering::Relaxed));
+    REGISTRY.query_duration.render(
+        &mut out,
+        "p2shd_dht_query_duration_seconds",
+        "Time DHT queries for peers took.",
+    );
+    REGISTRY.session_duration.render(
+        &mut out,
+        "p2shd_session_duration_seconds",
+        "How long served sessions (ssh, forwardings, vpn links) were open.",
+    );
+    out
+}
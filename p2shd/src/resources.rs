@@ -0,0 +1,45 @@
+//! Resource advertisement: Load, uptime and health of exposed services.
+//!
+//! Daemons started with `--advertise-resources` answer `Request::Resources`
+//! with one `key value...` line per item, e.g.:
+//!
+//! ```text
+//! load 0.52 0.48 0.40
+//! uptime 123456
+//! service grafana up
+//! ```
+//!
+//! Unknown lines are to be ignored by readers, so items can be added later.
+
+use async_std::{fs, future::timeout, net::TcpStream};
+use std::time::Duration;
+
+use crate::config::Service;
+
+/// How long a service may take to accept a connection, before being reported `down`.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Current resources of this machine, as lines for `Request::Resources`.
+///
+/// Only `services` the requesting peer may use are to be passed.
+pub async fn gather(services: &[Service]) -> Vec<String> {
+    let mut lines = Vec::new();
+    // Linux only for now, other systems just don't report these:
+    if let Ok(loadavg) = fs::read_to_string("/proc/loadavg").await {
+        let load: Vec<_> = loadavg.split_whitespace().take(3).collect();
+        if load.len() == 3 {
+            lines.push(format!("load {}", load.join(" ")));
+        }
+    }
+    if let Ok(uptime) = fs::read_to_string("/proc/uptime").await {
+        let secs = uptime.split('.').next().and_then(|s| s.parse::<u64>().ok());
+        if let Some(secs) = secs {
+            lines.push(format!("uptime {}", secs));
+        }
+    }
+    for service in services {
+        let up = matches!(timeout(HEALTH_TIMEOUT, TcpStream::connect(service.addr)).await, Ok(Ok(_)));
+        lines.push(format!("service {} {}", service.name, if up { "up" } else { "down" }));
+    }
+    lines
+}
@@ -0,0 +1,218 @@
+//! Key rotation: Telling peers which id a node moved on to.
+//!
+//! `p2shd key rotate` replaces the node key and signs a continuity record
+//! with the old one, naming the new peer id. `p2shd listen` publishes that
+//! record under `/p2shd/rotated/<old peer id>`. Clients looking for the old
+//! id fetch it alongside their DHT query and, if the old key's signature
+//! checks out, follow the rotation and remember it for later runs. Only the
+//! old key can vouch for a new one, so nobody else can redirect clients.
+//!
+//! Each rotation appends its record to `rotation.json` and all of them get
+//! published, so clients that still know an id from several rotations ago
+//! follow the chain hop by hop.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{
+    identity::{self, PublicKey},
+    kad::record::Key,
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{path_exists, write_atomically},
+    format_version::{self, FormatVersion},
+    sealed_state,
+    signed_record::{Kind, SignedRecord},
+};
+
+mod error;
+
+/// Version of the format of `known_rotations.json`, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// Version of the format of `rotation.json`, 2.0 renamed the signed part of records.
+const OWN_FORMAT: FormatVersion = FormatVersion::new(2, 0);
+
+/// Rotation records, published under the old peer id, see `signed_record`.
+const KIND: Kind = Kind::new("/p2shd/rotated/", "Rotation record");
+
+/// Rotations followed in a row at most, in case of cycles.
+const MAX_HOPS: usize = 16;
+
+/// The rotation, as signed.
+#[derive(Serialize, Deserialize)]
+struct Rotation {
+    old: String,
+    new: String,
+}
+
+/// DHT key the rotation away from `old` is published under.
+pub fn record_key(old: &PeerId) -> Key {
+    KIND.record_key(old)
+}
+
+/// The rotated peer id of a rotation record, `None` if `key` is not a rotation record key.
+pub fn publisher_of(key: &Key) -> Option<PeerId> {
+    KIND.publisher_of(key)
+}
+
+/// Record of rotating from `old` to `new`, signed with `old`.
+pub fn sign(old: &identity::Keypair, new: &PublicKey) -> Result<Vec<u8>> {
+    let rotation = Rotation {
+        old: PeerId::from(old.public()).to_base58(),
+        new: PeerId::from(new.clone()).to_base58(),
+    };
+    // Rotations don't expire, clients might come back after a long time:
+    KIND.sign(old, rotation, None)
+}
+
+/// The peer id `old` rotated to, if the record is signed by `old`'s key.
+pub fn verify(old: &PeerId, raw: &[u8]) -> Result<PeerId> {
+    let rotation: Rotation = KIND.verify(old, raw)?;
+    if rotation.old != old.to_base58() {
+        return Err(error::Rotation::WrongPeer(old.clone()).into());
+    }
+    rotation.new.parse().map_err(|_| error::Rotation::Decode(old.clone()).into())
+}
+
+/// On disk representation of our own rotation records, oldest first.
+#[derive(Serialize, Deserialize, Default)]
+struct OwnFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    records: Vec<SignedRecord>,
+}
+
+/// `rotation.json` as written by `p2shd key rotate`, a single record in older versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredOwn {
+    Chain(OwnFile),
+    Single(SignedRecord),
+}
+
+/// Our own rotation records written by `p2shd key rotate`, each with the id rotated from.
+pub fn load_own(path: &Path) -> Result<Vec<(PeerId, Vec<u8>)>> {
+    read_own(path)?
+        .records
+        .into_iter()
+        .map(|signed| {
+            let rotation: Rotation = signed
+                .unverified_payload()
+                .with_context(|| error::Rotation::DecodeFile(path.into()))?;
+            let old = rotation
+                .old
+                .parse()
+                .map_err(|_| error::Rotation::DecodeFile(path.into()))?;
+            Ok((old, signed.to_vec()))
+        })
+        .collect()
+}
+
+/// Add a record made by `sign` to our own ones at `path`.
+///
+/// Replaces a record from the same key, left by an interrupted rotation.
+pub fn append_own(path: &Path, record: &[u8]) -> Result<()> {
+    let mut file = read_own(path)?;
+    let signed = SignedRecord::from_slice(record).expect("Records come from `sign`.");
+    file.records.retain(|r| !r.same_key(&signed));
+    file.records.push(signed);
+    file.format = OWN_FORMAT;
+    let encoded = serde_json::to_vec_pretty(&file).expect("Serializing rotation can't fail.");
+    write_atomically(path, &encoded).with_context(|| error::Rotation::Write(path.into()))
+}
+
+fn read_own(path: &Path) -> Result<OwnFile> {
+    let exists = path_exists(path).with_context(|| error::Rotation::Read(path.into()))?;
+    if !exists {
+        return Ok(OwnFile::default());
+    }
+    let raw = fs::read(path).with_context(|| error::Rotation::Read(path.into()))?;
+    format_version::check(path, &raw, OWN_FORMAT)?;
+    let stored = serde_json::from_slice(&raw).with_context(|| error::Rotation::DecodeFile(path.into()))?;
+    Ok(match stored {
+        StoredOwn::Chain(file) => file,
+        StoredOwn::Single(signed) => OwnFile {
+            format: OWN_FORMAT,
+            records: vec![signed],
+        },
+    })
+}
+
+/// On disk representation of `KnownRotations`.
+#[derive(Serialize, Deserialize, Default)]
+struct RotationsFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    rotations: Vec<RotationEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RotationEntry {
+    old: String,
+    new: String,
+}
+
+/// Rotations of other peers we learned, persisted across runs.
+pub struct KnownRotations {
+    path: PathBuf,
+    rotations: HashMap<PeerId, PeerId>,
+}
+
+impl KnownRotations {
+    /// Load the rotations stored at `path`, a missing file means none are known.
+    pub fn load(path: PathBuf) -> Result<KnownRotations> {
+        let exists = path_exists(&path).with_context(|| error::Rotation::Read(path.clone()))?;
+        let file = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::Rotation::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw).with_context(|| error::Rotation::DecodeFile(path.clone()))?
+        } else {
+            RotationsFile::default()
+        };
+        let rotations = file
+            .rotations
+            .into_iter()
+            .filter_map(|e| Some((e.old.parse().ok()?, e.new.parse().ok()?)))
+            .collect();
+        Ok(KnownRotations { path, rotations })
+    }
+
+    /// The peer id `peer` ended up at, after all known rotations.
+    pub fn follow(&self, peer: &PeerId) -> PeerId {
+        let mut current = peer;
+        for _ in 0..MAX_HOPS {
+            match self.rotations.get(current) {
+                None => break,
+                Some(next) => current = next,
+            }
+        }
+        current.clone()
+    }
+
+    /// Remember that `old` rotated to `new`, written to disk right away.
+    pub fn insert(&mut self, old: PeerId, new: PeerId) -> Result<()> {
+        self.rotations.insert(old, new);
+        let file = RotationsFile {
+            format: FORMAT,
+            rotations: self
+                .rotations
+                .iter()
+                .map(|(old, new)| RotationEntry {
+                    old: old.to_base58(),
+                    new: new.to_base58(),
+                })
+                .collect(),
+        };
+        let encoded = serde_json::to_vec_pretty(&file).expect("Serializing rotations can't fail.");
+        sealed_state::write(&self.path, &encoded).with_context(|| error::Rotation::Write(self.path.clone()))
+    }
+}
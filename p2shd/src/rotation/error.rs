@@ -0,0 +1,20 @@
+//! Errors that can happen while checking or storing key rotations.
+
+use libp2p::PeerId;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to rotation records and learned rotations.
+#[derive(Error, Debug)]
+pub enum Rotation {
+    #[error("Rotation record of {0} could not be decoded.")]
+    Decode(PeerId),
+    #[error("Rotation record claiming to rotate {0} names a different peer id.")]
+    WrongPeer(PeerId),
+    #[error("Reading rotations '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Invalid rotations file '{0}'.")]
+    DecodeFile(PathBuf),
+    #[error("Writing rotations '{0}' failed.")]
+    Write(PathBuf),
+}
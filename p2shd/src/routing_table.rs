@@ -0,0 +1,119 @@
+//! Snapshots of the Kademlia routing table.
+//!
+//! Kademlia does not expose its k-buckets including addresses, so we mirror
+//! them from `RoutingUpdated` events. Loading the snapshot on startup means we
+//! don't depend on the bootstrap nodes being up to rejoin the DHT.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    config::path_exists,
+    format_version::{self, FormatVersion},
+    sealed_state,
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// Mirror of the k-bucket entries of our Kademlia instance.
+pub struct RoutingTable {
+    /// Where to store the snapshot.
+    path: PathBuf,
+    peers: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Whether there are changes not yet written to disk.
+    dirty: bool,
+}
+
+/// On disk representation of `RoutingTable`.
+#[derive(Serialize, Deserialize, Default)]
+struct SnapshotFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    peers: Vec<PeerEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerEntry {
+    peer_id: String,
+    addrs: Vec<String>,
+}
+
+impl RoutingTable {
+    /// Load the snapshot stored at `path`, a missing file results in an empty table.
+    pub fn load(path: PathBuf) -> Result<RoutingTable> {
+        let exists =
+            path_exists(&path).with_context(|| error::RoutingTable::Read(path.clone()))?;
+        let file = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::RoutingTable::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw)
+                .with_context(|| error::RoutingTable::Decode(path.clone()))?
+        } else {
+            SnapshotFile::default()
+        };
+
+        let peers = file
+            .peers
+            .into_iter()
+            .filter_map(|entry| {
+                let peer_id = entry.peer_id.parse().ok()?;
+                let addrs: Vec<Multiaddr> =
+                    entry.addrs.iter().filter_map(|a| a.parse().ok()).collect();
+                if addrs.is_empty() {
+                    None
+                } else {
+                    Some((peer_id, addrs))
+                }
+            })
+            .collect();
+        Ok(RoutingTable { path, peers, dirty: false })
+    }
+
+    /// A peer got added to the routing table or its addresses changed.
+    pub fn update(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>) {
+        self.peers.insert(peer_id, addrs);
+        self.dirty = true;
+    }
+
+    /// A peer got evicted from the routing table.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        if self.peers.remove(peer_id).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Iterate all peers in the snapshot and their addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &[Multiaddr])> {
+        self.peers.iter().map(|(p, a)| (p, a.as_slice()))
+    }
+
+    /// Write the snapshot to disk, if there were any changes since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let file = SnapshotFile {
+            format: FORMAT,
+            peers: self
+                .peers
+                .iter()
+                .map(|(peer_id, addrs)| PeerEntry {
+                    peer_id: peer_id.to_base58(),
+                    addrs: addrs.iter().map(|a| a.to_string()).collect(),
+                })
+                .collect(),
+        };
+        let encoded =
+            serde_json::to_vec_pretty(&file).expect("Serializing routing table can't fail.");
+        sealed_state::write(&self.path, &encoded)
+            .with_context(|| error::RoutingTable::Write(self.path.clone()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+//! Errors that can happen while loading or storing routing table snapshots.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to routing table persistence.
+#[derive(Error, Debug)]
+pub enum RoutingTable {
+    #[error("Reading routing table snapshot '{0}' failed.")]
+    Read(PathBuf),
+    #[error(
+        "Invalid routing table snapshot '{0}'.
+
+You can safely delete the file, p2shd will rebuild it on the next run."
+    )]
+    Decode(PathBuf),
+    #[error("Writing routing table snapshot '{0}' failed.")]
+    Write(PathBuf),
+}
@@ -0,0 +1,216 @@
+//! Recurring jobs of the daemon, scheduled via cron expressions.
+//!
+//! Jobs are configured in `config.toml`:
+//!
+//! ```toml
+//! [[jobs]]
+//! name = "nightly-sync"
+//! schedule = "0 3 * * *"
+//! task = "command"
+//! command = ["p2shd", "workstation", "--", "rsync -a data/ backup/"]
+//!
+//! [[jobs]]
+//! name = "warmup"
+//! schedule = "*/15 * * * *"
+//! task = "warm_cache"
+//! ```
+//!
+//! Commands run on their own thread, so they can't block the daemon. A
+//! command that is still running when its job fires again is not started a
+//! second time.
+
+use anyhow::{Context as AnyhowContext, Result};
+use chrono::Local;
+use futures::{channel::mpsc, prelude::*};
+use futures_timer::Delay;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    process::Command,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub mod cron;
+mod error;
+
+use cron::Schedule;
+
+/// A job as found in the configuration file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobEntry {
+    pub name: String,
+    /// Cron expression.
+    pub schedule: String,
+    #[serde(flatten)]
+    pub task: Task,
+}
+
+/// What a job does.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum Task {
+    /// Refresh the addresses of the `warm_cache` peers.
+    WarmCache,
+    /// Run a local command, e.g. `p2shd <peer> -- <remote command>`.
+    Command { command: Vec<String> },
+}
+
+/// A validated job.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub name: String,
+    pub schedule: Schedule,
+    pub task: Task,
+}
+
+/// How a job fared the last time it ran.
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub last_run: Option<SystemTime>,
+    /// `None` while running or if never run.
+    pub last_result: Option<String>,
+}
+
+/// Fires jobs according to their schedule.
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    status: Vec<JobStatus>,
+    /// Fires at the start of every minute.
+    tick: Delay,
+    /// Jobs with a command still running.
+    running: HashSet<usize>,
+    /// Results of finished commands: Job index and result.
+    finished_tx: mpsc::UnboundedSender<(usize, String)>,
+    finished_rx: mpsc::UnboundedReceiver<(usize, String)>,
+}
+
+impl Job {
+    /// Validate a job from the configuration file.
+    pub fn from_entry(entry: &JobEntry) -> Result<Job> {
+        let schedule = entry
+            .schedule
+            .parse()
+            .with_context(|| error::Job::InvalidSchedule(entry.name.clone()))?;
+        if let Task::Command { command } = &entry.task {
+            if command.is_empty() {
+                return Err(error::Job::EmptyCommand(entry.name.clone()).into());
+            }
+        }
+        Ok(Job {
+            name: entry.name.clone(),
+            schedule,
+            task: entry.task.clone(),
+        })
+    }
+}
+
+/// Validate all configured jobs.
+pub fn parse_jobs(entries: &[JobEntry]) -> Result<Vec<Job>> {
+    let mut names = HashSet::new();
+    entries
+        .iter()
+        .map(|e| {
+            if !names.insert(e.name.as_str()) {
+                return Err(error::Job::DuplicateName(e.name.clone()).into());
+            }
+            Job::from_entry(e)
+        })
+        .collect()
+}
+
+impl Scheduler {
+    pub fn new(jobs: Vec<Job>) -> Scheduler {
+        let status = jobs
+            .iter()
+            .map(|j| JobStatus {
+                name: j.name.clone(),
+                schedule: j.schedule.to_string(),
+                last_run: None,
+                last_result: None,
+            })
+            .collect();
+        let (finished_tx, finished_rx) = mpsc::unbounded();
+        Scheduler {
+            jobs,
+            status,
+            tick: Delay::new(until_next_minute()),
+            running: HashSet::new(),
+            finished_tx,
+            finished_rx,
+        }
+    }
+
+    /// Status of all jobs, for status reporting.
+    pub fn status(&self) -> &[JobStatus] {
+        &self.status
+    }
+
+    /// Run due commands and return due tasks the caller has to take care of
+    /// (everything but `Task::Command`).
+    pub fn poll(&mut self, cx: &mut Context) -> Vec<Task> {
+        while let Poll::Ready(Some((i, result))) = self.finished_rx.poll_next_unpin(cx) {
+            log::info!("Job '{}' finished: {}", self.jobs[i].name, result);
+            self.running.remove(&i);
+            self.status[i].last_result = Some(result);
+        }
+        let mut due = Vec::new();
+        if self.jobs.is_empty() {
+            return due;
+        }
+        while let Poll::Ready(()) = self.tick.poll_unpin(cx) {
+            self.tick.reset(until_next_minute());
+            let now = Local::now();
+            for i in 0..self.jobs.len() {
+                if self.jobs[i].schedule.matches(&now) {
+                    if let Some(task) = self.fire(i) {
+                        due.push(task);
+                    }
+                }
+            }
+        }
+        due
+    }
+
+    fn fire(&mut self, i: usize) -> Option<Task> {
+        let job = &self.jobs[i];
+        if self.running.contains(&i) {
+            log::warn!("Job '{}' is still running, skipping this run.", job.name);
+            return None;
+        }
+        log::info!("Running job '{}'", job.name);
+        self.status[i].last_run = Some(SystemTime::now());
+        self.status[i].last_result = None;
+        match &job.task {
+            Task::Command { command } => {
+                self.running.insert(i);
+                let command = command.clone();
+                let tx = self.finished_tx.clone();
+                thread::spawn(move || {
+                    let result = match Command::new(&command[0]).args(&command[1..]).status() {
+                        Ok(status) => status.to_string(),
+                        Err(e) => format!("failed to run: {}", e),
+                    };
+                    let _ = tx.unbounded_send((i, result));
+                });
+                None
+            }
+            task => {
+                self.status[i].last_result = Some("started".into());
+                Some(task.clone())
+            }
+        }
+    }
+}
+
+/// Time until the next full minute, so jobs fire at the start of their minute.
+fn until_next_minute() -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let into_minute = Duration::from_millis((now.as_millis() % 60_000) as u64);
+    Duration::from_secs(60) - into_minute
+}
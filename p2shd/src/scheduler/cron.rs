@@ -0,0 +1,214 @@
+//! Minimal cron schedule expressions.
+//!
+//! The classic five fields are supported: minute, hour, day of month, month
+//! and day of week (0 or 7 being Sunday). Each field is `*`, a number, a range
+//! `a-b`, any of those with a step `/n`, or a comma separated list thereof.
+//! As with cron, if both day of month and day of week are restricted, either
+//! of them matching is enough. A field starting with `*` (like `*/2`) counts
+//! as unrestricted for that.
+
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+use std::{fmt, str::FromStr};
+
+use super::error;
+
+/// A parsed cron expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// The original expression, for display.
+    expr: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+/// Allowed values of a single field, as bit set.
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    bits: u64,
+    /// Whether the field starts with `*`, needed for the day of month/week special case.
+    any: bool,
+}
+
+impl Schedule {
+    /// Whether the schedule fires in the minute `t` falls in.
+    pub fn matches<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> bool {
+        let day = self.days.contains(t.day());
+        let weekday = self.weekdays.contains(t.weekday().num_days_from_sunday());
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes.contains(t.minute())
+            && self.hours.contains(t.hour())
+            && self.months.contains(t.month())
+            && day_matches
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = error::Schedule;
+
+    fn from_str(s: &str) -> Result<Schedule, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(error::Schedule::FieldCount(s.into()));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday:
+        if weekdays.bits & (1 << 7) != 0 {
+            weekdays.bits |= 1;
+        }
+        Ok(Schedule {
+            expr: s.into(),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl Field {
+    fn contains(&self, v: u32) -> bool {
+        self.bits & (1 << v) != 0
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Field, error::Schedule> {
+    let invalid = || error::Schedule::InvalidField(field.into());
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            None => (part, 1),
+            Some(i) => (&part[..i], part[i + 1..].parse().map_err(|_| invalid())?),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            let from = range[..i].parse().map_err(|_| invalid())?;
+            let to = range[i + 1..].parse().map_err(|_| invalid())?;
+            (from, to)
+        } else {
+            let v = range.parse().map_err(|_| invalid())?;
+            // `5/10` means starting at 5, every 10:
+            (v, if step > 1 { max } else { v })
+        };
+        if from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        for v in (from..=to).step_by(step) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(Field {
+        bits,
+        any: field.starts_with('*'),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // June 2020, the 1st is a Monday, the 7th a Sunday:
+        Utc.ymd(2020, 6, day).and_hms(hour, minute, 0)
+    }
+
+    fn field(field: &str, min: u32, max: u32) -> Vec<u32> {
+        let parsed = parse_field(field, min, max).unwrap();
+        (min..=max).filter(|v| parsed.contains(*v)).collect()
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(field("*", 1, 5), vec![1, 2, 3, 4, 5]);
+        assert_eq!(field("3", 0, 59), vec![3]);
+        assert_eq!(field("10-13", 0, 59), vec![10, 11, 12, 13]);
+        assert_eq!(field("1,5,7", 0, 59), vec![1, 5, 7]);
+        assert_eq!(field("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(field("10-20/5", 0, 59), vec![10, 15, 20]);
+        assert_eq!(field("50/5", 0, 59), vec![50, 55]);
+        assert_eq!(field("1-2,*/20", 0, 59), vec![0, 1, 2, 20, 40]);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for expr in &[
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "1, * * * *",
+            "-1 * * * *",
+        ] {
+            assert!(expr.parse::<Schedule>().is_err(), "'{}' got accepted", expr);
+        }
+    }
+
+    #[test]
+    fn matches_minute_and_hour() {
+        let schedule: Schedule = "30 3 * * *".parse().unwrap();
+        assert!(schedule.matches(&at(1, 3, 30)));
+        assert!(!schedule.matches(&at(1, 3, 31)));
+        assert!(!schedule.matches(&at(1, 4, 30)));
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        for expr in &["0 0 * * 0", "0 0 * * 7"] {
+            let schedule: Schedule = expr.parse().unwrap();
+            assert!(schedule.matches(&at(7, 0, 0)), "{}", expr);
+            assert!(!schedule.matches(&at(1, 0, 0)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn restricted_day_of_month_or_week_is_enough() {
+        // The 16th (a Tuesday) or any Monday:
+        let schedule: Schedule = "0 0 16 * 1".parse().unwrap();
+        assert!(schedule.matches(&at(16, 0, 0)));
+        assert!(schedule.matches(&at(8, 0, 0)));
+        assert!(!schedule.matches(&at(9, 0, 0)));
+    }
+
+    #[test]
+    fn unrestricted_day_of_month_or_week_must_match_both() {
+        // Every Monday, `*` day of month:
+        let schedule: Schedule = "0 0 * * 1".parse().unwrap();
+        assert!(schedule.matches(&at(8, 0, 0)));
+        assert!(!schedule.matches(&at(9, 0, 0)));
+        // Mondays on odd days, `*/2` counts as unrestricted:
+        let schedule: Schedule = "0 0 */2 * 1".parse().unwrap();
+        assert!(schedule.matches(&at(1, 0, 0)));
+        assert!(!schedule.matches(&at(8, 0, 0)));
+        assert!(!schedule.matches(&at(3, 0, 0)));
+    }
+
+    #[test]
+    fn displays_expression() {
+        let schedule: Schedule = "*/5 * * * 1-5".parse().unwrap();
+        assert_eq!(schedule.to_string(), "*/5 * * * 1-5");
+    }
+}
@@ -0,0 +1,27 @@
+//! Errors that can happen while setting up scheduled jobs.
+
+use thiserror::Error;
+
+/// Errors related to cron expressions.
+#[derive(Error, Debug)]
+pub enum Schedule {
+    #[error(
+        "Invalid schedule '{0}', expected five fields: minute hour day-of-month month day-of-week.
+
+E.g. '0 3 * * *' for every night at 03:00."
+    )]
+    FieldCount(String),
+    #[error("Invalid schedule field '{0}'.")]
+    InvalidField(String),
+}
+
+/// Errors related to job configuration.
+#[derive(Error, Debug)]
+pub enum Job {
+    #[error("Invalid schedule of job '{0}'.")]
+    InvalidSchedule(String),
+    #[error("Job '{0}' has an empty command.")]
+    EmptyCommand(String),
+    #[error("There are multiple jobs named '{0}'.")]
+    DuplicateName(String),
+}
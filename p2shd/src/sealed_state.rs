@@ -0,0 +1,392 @@
+//! Encrypting state files at rest.
+//!
+//! With `encrypt_state` in `config.toml`, state files that reveal whom we
+//! talk to (address cache, routing table snapshot, record store, blocklist,
+//! synced address book, known rotations, dial reports) get sealed with
+//! XChaCha20-Poly1305 when written. The key is either a random one stored in
+//! `state_key` in the configuration directory (`"key_file"`, nothing to type,
+//! but anybody who can read that file can read the state) or derived via
+//! Argon2id from a passphrase (`"passphrase"`, read from
+//! `P2SHD_STATE_PASSPHRASE` if set, asked for on the terminal otherwise).
+//! Neither depends on the node key, so `p2shd key rotate` keeps the state
+//! readable.
+//!
+//! The key is only derived once a sealed file is read or a state file
+//! written, so commands not touching state never ask for a passphrase.
+//! A wrong passphrase is told apart from broken files via `state_salt.check`.
+//! `state_salt` holds the Argon2id parameters (see `key::KdfParams`) along
+//! with the salt.
+//! Unsealed files are still read, they get sealed on their next save. With a
+//! forgotten passphrase or a lost `state_key` sealed files can't be read
+//! anymore, `load_or_reset` then starts them over empty.
+
+use anyhow::{Context as AnyhowContext, Result};
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, Aead, NewAead},
+    XChaCha20Poly1305,
+};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde::Deserialize;
+use std::{
+    env, fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{path_exists, write_atomically},
+    key::{self, KdfParams},
+};
+
+mod error;
+
+/// Environment variable to read the state passphrase from.
+pub const PASSPHRASE_VAR: &str = "P2SHD_STATE_PASSPHRASE";
+
+/// Start of sealed state files, followed by a version byte.
+const MAGIC: &[u8] = b"p2shd-state";
+
+/// Sealed into `state_salt.check`, to tell a wrong passphrase from a broken file.
+const CHECK: &[u8] = b"p2shd state passphrase check";
+
+/// Format version of sealed state files.
+const VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+
+static SEALER: OnceCell<Sealer> = OnceCell::new();
+
+/// Where the key for sealing state files comes from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StateKey {
+    KeyFile,
+    Passphrase,
+}
+
+struct Sealer {
+    source: StateKey,
+    /// The random key for `StateKey::KeyFile`, created on first use.
+    key_file: PathBuf,
+    /// Argon2id salt for `StateKey::Passphrase`, created on first use.
+    salt_file: PathBuf,
+    cipher: OnceCell<XChaCha20Poly1305>,
+}
+
+impl Sealer {
+    fn cipher(&self) -> Result<&XChaCha20Poly1305> {
+        self.cipher.get_or_try_init(|| match self.source {
+            StateKey::KeyFile => {
+                let secret = self.key()?;
+                Ok(XChaCha20Poly1305::new(GenericArray::clone_from_slice(&secret)))
+            }
+            StateKey::Passphrase => self.passphrase_cipher(&passphrase()?),
+        })
+    }
+
+    /// The cipher for `passphrase`, checked to be the one used so far.
+    fn passphrase_cipher(&self, passphrase: &str) -> Result<XChaCha20Poly1305> {
+        let (params, salt) = self.salt()?;
+        let cipher = key::cipher(passphrase, &salt, &params)?;
+        self.check(&cipher)?;
+        Ok(cipher)
+    }
+
+    /// Make sure `cipher` is the one state files got sealed with so far.
+    fn check(&self, cipher: &XChaCha20Poly1305) -> Result<()> {
+        let path = self.salt_file.with_extension("check");
+        let err = || error::SealedState::Salt(path.clone());
+        if path_exists(&path).with_context(err)? {
+            let raw = fs::read(&path).with_context(err)?;
+            if raw.len() < NONCE_LEN {
+                return Err(err().into());
+            }
+            let (nonce, sealed) = raw.split_at(NONCE_LEN);
+            return match cipher.decrypt(GenericArray::from_slice(nonce), sealed) {
+                Ok(check) if check == CHECK => Ok(()),
+                _ => Err(error::SealedState::WrongPassphrase.into()),
+            };
+        }
+        let mut file = vec![0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut file);
+        let sealed = cipher
+            .encrypt(GenericArray::from_slice(&file), CHECK)
+            .map_err(|_| error::SealedState::Seal)?;
+        file.extend_from_slice(&sealed);
+        write_atomically(&path, &file).with_context(err)?;
+        Ok(())
+    }
+
+    fn key(&self) -> Result<Vec<u8>> {
+        let path = &self.key_file;
+        let err = || error::SealedState::KeyFile(path.clone());
+        if path_exists(path).with_context(err)? {
+            let secret = fs::read(path).with_context(err)?;
+            if secret.len() != KEY_LEN {
+                return Err(err().into());
+            }
+            return Ok(secret);
+        }
+        let mut secret = vec![0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        // Never readable by others, not even while being written:
+        let tmp = path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(err)?;
+        file.write_all(&secret).with_context(err)?;
+        fs::rename(&tmp, path).with_context(err)?;
+        Ok(secret)
+    }
+
+    /// The Argon2id parameters and salt, the file holds the encoded parameters followed by
+    /// the salt. Files from before the parameters got stored hold just the salt.
+    fn salt(&self) -> Result<(KdfParams, Vec<u8>)> {
+        let path = &self.salt_file;
+        let err = || error::SealedState::Salt(path.clone());
+        if path_exists(path).with_context(err)? {
+            let raw = fs::read(path).with_context(err)?;
+            if raw.len() == SALT_LEN {
+                return Ok((KdfParams::LEGACY, raw));
+            }
+            if raw.len() != KdfParams::LEN + SALT_LEN {
+                return Err(err().into());
+            }
+            let (params, salt) = raw.split_at(KdfParams::LEN);
+            return Ok((KdfParams::from_bytes(params).ok_or_else(err)?, salt.to_vec()));
+        }
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let mut file = KdfParams::CURRENT.to_bytes().to_vec();
+        file.extend_from_slice(&salt);
+        write_atomically(path, &file).with_context(err)?;
+        Ok((KdfParams::CURRENT, salt))
+    }
+}
+
+/// Seal state files written from now on with the key from `source`.
+///
+/// `key_file` is where to keep the random key, `salt_file` where to keep the
+/// passphrase's salt. Calling it more than once has no effect.
+pub fn enable(source: StateKey, key_file: PathBuf, salt_file: PathBuf) {
+    let _ = SEALER.set(Sealer {
+        source,
+        key_file,
+        salt_file,
+        cipher: OnceCell::new(),
+    });
+}
+
+/// Read the state file at `path`, unsealing it if it is sealed.
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    unseal(SEALER.get(), path, fs::read(path)?)
+}
+
+/// Write the state file at `path` atomically, sealed if enabled.
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    match SEALER.get() {
+        None => Ok(write_atomically(path, contents)?),
+        Some(sealer) => Ok(write_atomically(path, &seal(sealer, contents)?)?),
+    }
+}
+
+/// The contents of the state file at `path`, read as `raw`.
+fn unseal(sealer: Option<&Sealer>, path: &Path, raw: Vec<u8>) -> Result<Vec<u8>> {
+    if !raw.starts_with(MAGIC) {
+        return Ok(raw);
+    }
+    let sealer = sealer.ok_or_else(|| error::SealedState::NotEnabled(path.into()))?;
+    let header = MAGIC.len() + 1;
+    if raw.len() < header + NONCE_LEN {
+        return Err(error::SealedState::Truncated(path.into()).into());
+    }
+    if raw[MAGIC.len()] != VERSION {
+        return Err(error::SealedState::UnknownVersion(path.into(), raw[MAGIC.len()]).into());
+    }
+    let (nonce, sealed) = raw[header..].split_at(NONCE_LEN);
+    sealer
+        .cipher()?
+        .decrypt(GenericArray::from_slice(nonce), sealed)
+        .map_err(|_| error::SealedState::Unseal(path.into()).into())
+}
+
+/// `contents` sealed, as written to state files.
+fn seal(sealer: &Sealer, contents: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let sealed = sealer
+        .cipher()?
+        .encrypt(GenericArray::from_slice(&nonce), contents)
+        .map_err(|_| error::SealedState::Seal)?;
+    let mut file = MAGIC.to_vec();
+    file.push(VERSION);
+    file.extend_from_slice(&nonce);
+    file.extend_from_slice(&sealed);
+    Ok(file)
+}
+
+/// Load a state file at `path` via `load`, starting over empty if it can't be unsealed.
+///
+/// The unreadable file is kept as `.unreadable` next to it, so saving does not
+/// overwrite it. Other errors, e.g. a wrong passphrase, are passed on.
+pub fn load_or_reset<T>(path: PathBuf, load: impl Fn(PathBuf) -> Result<T>) -> Result<T> {
+    let unreadable = |e: &anyhow::Error| {
+        e.chain().any(|cause| match cause.downcast_ref::<error::SealedState>() {
+            Some(error::SealedState::Unseal(_)) | Some(error::SealedState::Truncated(_)) => true,
+            _ => false,
+        })
+    };
+    match load(path.clone()) {
+        Ok(state) => Ok(state),
+        Err(e) if !unreadable(&e) => Err(e),
+        Err(e) => {
+            let aside = path.with_extension("unreadable");
+            fs::rename(&path, &aside).with_context(|| error::SealedState::Reset(path.clone()))?;
+            log::warn!("{:#}", e);
+            log::warn!("Starting over without it, the old file is kept as '{}'.", aside.display());
+            load(path)
+        }
+    }
+}
+
+fn passphrase() -> Result<String> {
+    if let Ok(p) = env::var(PASSPHRASE_VAR) {
+        return Ok(p);
+    }
+    let p = rpassword::read_password_from_tty(Some("Passphrase for p2shd state: "))
+        .context(error::SealedState::NoPassphrase)?;
+    if p.is_empty() {
+        return Err(error::SealedState::EmptyPassphrase.into());
+    }
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a test, `SEALER` is left alone as tests share it.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("p2shd-sealed-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn new_sealer(source: StateKey, dir: &Path) -> Sealer {
+        Sealer {
+            source,
+            key_file: dir.join("state_key"),
+            salt_file: dir.join("state_salt"),
+            cipher: OnceCell::new(),
+        }
+    }
+
+    fn is_error(e: &anyhow::Error, pred: impl Fn(&error::SealedState) -> bool) -> bool {
+        e.chain().any(|c| c.downcast_ref::<error::SealedState>().map_or(false, &pred))
+    }
+
+    #[test]
+    fn seal_unseal_round_trip() {
+        let dir = temp_dir("round-trip");
+        let sealer = new_sealer(StateKey::KeyFile, &dir);
+        let path = dir.join("state.json");
+        let sealed = seal(&sealer, b"{\"peers\": []}").unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(unseal(Some(&sealer), &path, sealed.clone()).unwrap(), b"{\"peers\": []}");
+        // Nonces are random:
+        assert_ne!(seal(&sealer, b"{\"peers\": []}").unwrap(), sealed);
+        // The key file is kept, so another run can read the state:
+        let again = new_sealer(StateKey::KeyFile, &dir);
+        assert_eq!(unseal(Some(&again), &path, sealed).unwrap(), b"{\"peers\": []}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase_is_detected() {
+        let dir = temp_dir("passphrase");
+        // Cheap parameters, which also get read back from the salt file:
+        let params = KdfParams {
+            mem_cost: 64,
+            time_cost: 1,
+            lanes: 1,
+        };
+        let mut salt_file = params.to_bytes().to_vec();
+        salt_file.extend_from_slice(&[7u8; SALT_LEN]);
+        fs::write(dir.join("state_salt"), salt_file).unwrap();
+        let right = new_sealer(StateKey::Passphrase, &dir);
+        let _ = right.cipher.set(right.passphrase_cipher("right").unwrap());
+        let sealed = seal(&right, b"state").unwrap();
+        assert!(dir.join("state_salt.check").exists());
+        let again = new_sealer(StateKey::Passphrase, &dir);
+        let _ = again.cipher.set(again.passphrase_cipher("right").unwrap());
+        assert_eq!(unseal(Some(&again), &dir.join("state.json"), sealed).unwrap(), b"state");
+        let wrong = new_sealer(StateKey::Passphrase, &dir);
+        let e = wrong.passphrase_cipher("wrong").err().expect("Wrong passphrase accepted.");
+        assert!(is_error(&e, |e| matches!(e, error::SealedState::WrongPassphrase)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_and_tampered_files_are_refused() {
+        let dir = temp_dir("truncated");
+        let sealer = new_sealer(StateKey::KeyFile, &dir);
+        let path = dir.join("state.json");
+        let mut sealed = seal(&sealer, b"state").unwrap();
+        let e = unseal(Some(&sealer), &path, sealed[..MAGIC.len() + 10].to_vec()).unwrap_err();
+        assert!(is_error(&e, |e| matches!(e, error::SealedState::Truncated(_))));
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        let e = unseal(Some(&sealer), &path, sealed).unwrap_err();
+        assert!(is_error(&e, |e| matches!(e, error::SealedState::Unseal(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let dir = temp_dir("plaintext");
+        let path = dir.join("state.json");
+        fs::write(&path, b"{}").unwrap();
+        // Whether or not sealing is enabled:
+        assert_eq!(read(&path).unwrap(), b"{}");
+        let sealer = new_sealer(StateKey::KeyFile, &dir);
+        assert_eq!(unseal(Some(&sealer), &path, b"{}".to_vec()).unwrap(), b"{}");
+        // Sealed files can't be read without a key:
+        let sealed = seal(&sealer, b"{}").unwrap();
+        let e = unseal(None, &path, sealed).unwrap_err();
+        assert!(is_error(&e, |e| matches!(e, error::SealedState::NotEnabled(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_or_reset_moves_unreadable_files_aside() {
+        let dir = temp_dir("reset");
+        let path = dir.join("state.json");
+        fs::write(&path, b"garbage").unwrap();
+        let load = |p: PathBuf| -> Result<bool> {
+            if p.exists() {
+                Err(error::SealedState::Unseal(p).into())
+            } else {
+                Ok(true)
+            }
+        };
+        assert!(load_or_reset(path.clone(), load).unwrap());
+        assert!(!path.exists());
+        assert_eq!(fs::read(dir.join("state.unreadable")).unwrap(), b"garbage");
+
+        // Other errors are passed on, the file stays:
+        fs::write(&path, b"garbage").unwrap();
+        let fail = |_: PathBuf| -> Result<bool> { Err(error::SealedState::WrongPassphrase.into()) };
+        assert!(load_or_reset(path.clone(), fail).is_err());
+        assert!(path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
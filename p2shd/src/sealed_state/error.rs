@@ -0,0 +1,36 @@
+//! Errors that can happen while sealing or unsealing state files.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to encrypted state files.
+#[derive(Error, Debug)]
+pub enum SealedState {
+    #[error("'{0}' is encrypted, but `encrypt_state` is not set in config.toml.")]
+    NotEnabled(PathBuf),
+    #[error("Encrypted state file '{0}' is truncated.")]
+    Truncated(PathBuf),
+    #[error("Encrypted state file '{0}' has unknown format version {1}, it needs a newer p2shd.")]
+    UnknownVersion(PathBuf, u8),
+    #[error(
+        "Decrypting state file '{0}' failed.
+
+Either the passphrase is wrong, the state key got lost or the file got
+corrupted."
+    )]
+    Unseal(PathBuf),
+    #[error("Encrypting state failed.")]
+    Seal,
+    #[error("Accessing the state key salt '{0}' failed.")]
+    Salt(PathBuf),
+    #[error("Accessing the state key '{0}' failed.")]
+    KeyFile(PathBuf),
+    #[error("Moving unreadable state file '{0}' aside failed.")]
+    Reset(PathBuf),
+    #[error("Reading the state passphrase failed, set P2SHD_STATE_PASSPHRASE when running without a terminal.")]
+    NoPassphrase,
+    #[error("Wrong passphrase for p2shd state.")]
+    WrongPassphrase,
+    #[error("An empty passphrase would not protect anything.")]
+    EmptyPassphrase,
+}
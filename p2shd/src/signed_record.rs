@@ -0,0 +1,186 @@
+//! Signed DHT records, as published under `<prefix><peer id>`.
+//!
+//! Address records, profiles, rotation records and blocklists are all
+//! published this way: A JSON encoded payload, signed with the key of the
+//! peer id in the record key. Readers check the signature against that
+//! key, so nobody can publish records for others. The signature also covers
+//! the record key, so a record can't be passed off as one of another kind
+//! (records signed over the bare payload, by older versions, are refused).
+//! Payloads signed with a time to live carry an expiry, readers refuse them
+//! afterwards.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{
+    identity::{self, PublicKey},
+    kad::record::Key,
+    PeerId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod error;
+
+/// A kind of signed record, e.g. profiles.
+pub struct Kind {
+    /// Prefix of DHT keys records of this kind are published under.
+    prefix: &'static str,
+    /// What records of this kind are called in error messages, e.g. "Profile".
+    name: &'static str,
+}
+
+/// A payload, as signed.
+#[derive(Serialize, Deserialize)]
+struct Content<T> {
+    #[serde(flatten)]
+    payload: T,
+    /// Seconds since the Unix epoch after which the payload must not be used anymore.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+/// A record as published in the DHT.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedRecord {
+    /// JSON encoded `Content`, the signature is over it and the record key, see
+    /// `Kind::signed_bytes`. Named after the kind of record in older versions.
+    #[serde(alias = "addrs", alias = "content", alias = "rotation", alias = "list")]
+    payload: String,
+    /// Protobuf encoded public key of the publisher, base64 encoded.
+    public_key: String,
+    /// Base64 encoded.
+    signature: String,
+}
+
+impl Kind {
+    pub const fn new(prefix: &'static str, name: &'static str) -> Kind {
+        Kind { prefix, name }
+    }
+
+    /// DHT key the record of `peer` is published under.
+    pub fn record_key(&self, peer: &PeerId) -> Key {
+        Key::new(&format!("{}{}", self.prefix, peer.to_base58()))
+    }
+
+    /// The publisher of a record, `None` if `key` is not a key of this kind.
+    pub fn publisher_of(&self, key: &Key) -> Option<PeerId> {
+        let key = std::str::from_utf8(key.as_ref()).ok()?;
+        key.strip_prefix(self.prefix)?.parse().ok()
+    }
+
+    /// `payload` signed with `key`, valid for `ttl` if given, for publishing it in the DHT.
+    pub fn sign<T: Serialize>(
+        &self,
+        key: &identity::Keypair,
+        payload: T,
+        ttl: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let content = Content {
+            payload,
+            expires: ttl.map(|ttl| unix_secs(SystemTime::now() + ttl)),
+        };
+        let payload = serde_json::to_string(&content).expect("Serializing record can't fail.");
+        let publisher = PeerId::from(key.public());
+        let signature = key.sign(&self.signed_bytes(&publisher, &payload))?;
+        let signed = SignedRecord {
+            payload,
+            public_key: base64::encode(&key.public().into_protobuf_encoding()),
+            signature: base64::encode(&signature),
+        };
+        Ok(serde_json::to_vec(&signed).expect("Serializing record can't fail."))
+    }
+
+    /// The payload of a record published by `publisher`, if the signature checks out and it did
+    /// not expire yet.
+    pub fn verify<T: DeserializeOwned>(&self, publisher: &PeerId, raw: &[u8]) -> Result<T> {
+        let decode_err = || error::SignedRecord::Decode(self.name, publisher.clone());
+        let signed: SignedRecord = serde_json::from_slice(raw).with_context(decode_err)?;
+        let key = base64::decode(&signed.public_key).with_context(decode_err)?;
+        let key = PublicKey::from_protobuf_encoding(&key).with_context(decode_err)?;
+        if PeerId::from(key.clone()) != *publisher {
+            return Err(error::SignedRecord::WrongKey(self.name, publisher.clone()).into());
+        }
+        let signature = base64::decode(&signed.signature).with_context(decode_err)?;
+        if !key.verify(&self.signed_bytes(publisher, &signed.payload), &signature) {
+            return Err(error::SignedRecord::InvalidSignature(self.name, publisher.clone()).into());
+        }
+        let content: Content<T> = serde_json::from_str(&signed.payload).with_context(decode_err)?;
+        if content.expires.map_or(false, |e| e < unix_secs(SystemTime::now())) {
+            return Err(error::SignedRecord::Expired(self.name, publisher.clone()).into());
+        }
+        Ok(content.payload)
+    }
+
+    /// What gets signed: The record key, a newline and the payload.
+    fn signed_bytes(&self, publisher: &PeerId, payload: &str) -> Vec<u8> {
+        format!("{}{}\n{}", self.prefix, publisher.to_base58(), payload).into_bytes()
+    }
+}
+
+impl SignedRecord {
+    /// Decode a record made by `Kind::sign`.
+    pub fn from_slice(raw: &[u8]) -> serde_json::Result<SignedRecord> {
+        serde_json::from_slice(raw)
+    }
+
+    /// Encoded like `Kind::sign` does.
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Serializing record can't fail.")
+    }
+
+    /// The payload, without checking the signature. Only for records we signed ourselves.
+    pub fn unverified_payload<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str::<Content<T>>(&self.payload).map(|c| c.payload)
+    }
+
+    /// Whether both records are signed with the same key.
+    pub fn same_key(&self, other: &SignedRecord) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Payload {
+        n: u32,
+    }
+
+    const ONE: Kind = Kind::new("/p2shd/one/", "One");
+    const OTHER: Kind = Kind::new("/p2shd/other/", "Other");
+
+    #[test]
+    fn verifies_own_kind() {
+        let key = identity::Keypair::generate_ed25519();
+        let publisher = PeerId::from(key.public());
+        let raw = ONE.sign(&key, Payload { n: 1 }, None).unwrap();
+        let payload: Payload = ONE.verify(&publisher, &raw).unwrap();
+        assert_eq!(payload, Payload { n: 1 });
+    }
+
+    #[test]
+    fn refuses_other_kind() {
+        let key = identity::Keypair::generate_ed25519();
+        let publisher = PeerId::from(key.public());
+        let raw = ONE.sign(&key, Payload { n: 1 }, None).unwrap();
+        assert!(OTHER.verify::<Payload>(&publisher, &raw).is_err());
+    }
+
+    #[test]
+    fn refuses_signatures_over_bare_payload() {
+        let key = identity::Keypair::generate_ed25519();
+        let publisher = PeerId::from(key.public());
+        let payload = serde_json::to_string(&Content { payload: Payload { n: 1 }, expires: None }).unwrap();
+        let legacy = SignedRecord {
+            signature: base64::encode(&key.sign(payload.as_bytes()).unwrap()),
+            payload,
+            public_key: base64::encode(&key.public().into_protobuf_encoding()),
+        };
+        assert!(ONE.verify::<Payload>(&publisher, &legacy.to_vec()).is_err());
+    }
+}
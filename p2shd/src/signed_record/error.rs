@@ -0,0 +1,17 @@
+//! Errors that can happen while checking signed records published by other peers.
+
+use libp2p::PeerId;
+use thiserror::Error;
+
+/// Errors related to signed records, the first field says what kind of record it is.
+#[derive(Error, Debug)]
+pub enum SignedRecord {
+    #[error("{0} published by {1} could not be decoded.")]
+    Decode(&'static str, PeerId),
+    #[error("{0} claiming to be published by {1} is signed by a different key.")]
+    WrongKey(&'static str, PeerId),
+    #[error("{0} published by {1} has an invalid signature.")]
+    InvalidSignature(&'static str, PeerId),
+    #[error("{0} published by {1} has expired.")]
+    Expired(&'static str, PeerId),
+}
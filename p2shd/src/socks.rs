@@ -0,0 +1,146 @@
+//! Local SOCKS5 server, forwarding connections through a peer.
+//!
+//! Every accepted connection gets its own tunnel, the peer connects to the
+//! requested destination, so everything reachable from the peer (e.g. its
+//! LAN) becomes reachable locally. Only unauthenticated `CONNECT` is
+//! supported, which is what browsers and most tools use.
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::{io, prelude::*};
+use libp2p::PeerId;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{
+    forward::Opener,
+    tunnel::{self, Request},
+};
+
+mod error;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_AUTH: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_FAILURE: u8 = 1;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Accept SOCKS5 connections on `listen`, forwarding them via `peer`.
+pub async fn serve(listen: SocketAddr, peer: PeerId, opener: Opener) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("SOCKS5 server listening on {}, forwarding via {}", listen, peer);
+    loop {
+        let (socket, from) = listener.accept().await?;
+        let peer = peer.clone();
+        let opener = opener.clone();
+        task::spawn(async move {
+            if let Err(e) = handle(socket, peer, opener).await {
+                log::info!("SOCKS connection from {} failed: {}", from, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut socket: TcpStream, peer: PeerId, opener: Opener) -> io::Result<()> {
+    let (host, port) = match handshake(&mut socket).await {
+        Ok(dest) => dest,
+        Err((reply, e)) => {
+            if let Some(reply) = reply {
+                send_reply(&mut socket, reply).await?;
+            }
+            return Err(to_io_error(e));
+        }
+    };
+    log::debug!("SOCKS connect to {}:{} via {}", host, port, peer);
+    let stream = match opener.open(peer, &Request::Tcp { host, port }).await {
+        Ok(s) => s,
+        Err(e) => {
+            send_reply(&mut socket, REPLY_FAILURE).await?;
+            return Err(e);
+        }
+    };
+    send_reply(&mut socket, REPLY_SUCCEEDED).await?;
+    let (sr, sw) = stream.split();
+    let (tr, tw) = socket.split();
+    tunnel::bridge(sr, sw, tr, tw).await
+}
+
+/// Negotiate authentication and read the connect request, returning the destination.
+///
+/// On error, the reply to send (if any) is returned along with the error.
+async fn handshake(socket: &mut TcpStream) -> Result<(String, u16), (Option<u8>, error::Socks)> {
+    let io_err = |e| (None, error::Socks::Io(e));
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).await.map_err(io_err)?;
+    if header[0] != VERSION {
+        return Err((None, error::Socks::UnsupportedVersion(header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut methods).await.map_err(io_err)?;
+    if !methods.contains(&NO_AUTH) {
+        socket
+            .write_all(&[VERSION, NO_ACCEPTABLE_AUTH])
+            .await
+            .map_err(io_err)?;
+        return Err((None, error::Socks::NoAcceptableAuth));
+    }
+    socket.write_all(&[VERSION, NO_AUTH]).await.map_err(io_err)?;
+
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await.map_err(io_err)?;
+    if request[1] != CMD_CONNECT {
+        return Err((
+            Some(REPLY_COMMAND_NOT_SUPPORTED),
+            error::Socks::UnsupportedCommand(request[1]),
+        ));
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await.map_err(io_err)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await.map_err(io_err)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8];
+            socket.read_exact(&mut len).await.map_err(io_err)?;
+            let mut name = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut name).await.map_err(io_err)?;
+            String::from_utf8(name).map_err(|_| (Some(REPLY_FAILURE), error::Socks::InvalidDomain))?
+        }
+        t => {
+            return Err((
+                Some(REPLY_ADDRESS_NOT_SUPPORTED),
+                error::Socks::UnsupportedAddressType(t),
+            ))
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await.map_err(io_err)?;
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+/// Send a reply, with an unspecified bound address.
+async fn send_reply(socket: &mut TcpStream, reply: u8) -> io::Result<()> {
+    socket
+        .write_all(&[VERSION, reply, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+fn to_io_error(e: error::Socks) -> io::Error {
+    match e {
+        error::Socks::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
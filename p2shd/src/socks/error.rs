@@ -0,0 +1,20 @@
+//! Errors that can happen while serving SOCKS5 clients.
+
+use thiserror::Error;
+
+/// Errors related to the SOCKS5 handshake.
+#[derive(Error, Debug)]
+pub enum Socks {
+    #[error("Reading from or writing to the SOCKS client failed.")]
+    Io(#[source] std::io::Error),
+    #[error("SOCKS version {0} is not supported, only SOCKS5 is.")]
+    UnsupportedVersion(u8),
+    #[error("SOCKS client does not support unauthenticated access.")]
+    NoAcceptableAuth,
+    #[error("SOCKS command {0} is not supported, only CONNECT is.")]
+    UnsupportedCommand(u8),
+    #[error("SOCKS address type {0} is not supported.")]
+    UnsupportedAddressType(u8),
+    #[error("SOCKS client sent an invalid domain name.")]
+    InvalidDomain,
+}
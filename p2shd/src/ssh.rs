@@ -0,0 +1,353 @@
+//! Running ssh sessions over tunnels.
+//!
+//! On the client side the tunnel is either bridged to stdio (for use as ssh
+//! `ProxyCommand`) or to a loopback listener the spawned ssh client connects
+//! to. On the serving side it gets connected to the local ssh daemon.
+
+use async_std::{
+    io::{stdin, stdout},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::{io, prelude::*};
+use futures_timer::Delay;
+use libp2p::PeerId;
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashSet,
+    env,
+    net::SocketAddr,
+    process::{Command, ExitStatus},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::process::{self as async_process, Child};
+
+use crate::{
+    config::Config,
+    forward::{self, Opener},
+    tunnel::{self, Request, Timeouts, TunnelStream},
+};
+
+/// Process ids of running ssh/mosh clients, for terminating them on shutdown.
+static CHILDREN: Lazy<Mutex<HashSet<u32>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// How long ssh/mosh clients get to exit after `terminate_children`, before they get killed.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long before a session expires the user gets warned.
+const EXPIRY_WARNINGS: &[Duration] = &[Duration::from_secs(10 * 60), Duration::from_secs(60)];
+
+/// Arguments passed on to the ssh client.
+#[derive(Clone, Debug, Default)]
+pub struct ClientArgs {
+    /// Login name.
+    pub user: Option<String>,
+    /// Options, passed before the host.
+    pub options: Vec<String>,
+    /// Passed after the host: More options or the command to run.
+    pub trailing: Vec<String>,
+}
+
+impl ClientArgs {
+    pub fn from_config(cfg: &Config) -> ClientArgs {
+        let mut options = send_env_option(&cfg.send_env());
+        if cfg.remote_peers.len() > 1 {
+            // Several sessions can't share stdin:
+            options.push("-n".into());
+        }
+        options.extend(cfg.opts.connect.ssh_args.iter().cloned());
+        ClientArgs {
+            user: cfg.opts.connect.user.clone(),
+            options,
+            trailing: cfg.opts.connect.trailing_ssh_args.clone(),
+        }
+    }
+}
+
+/// Run an ssh client connected to `peer` via `stream`, resolving once ssh exits.
+///
+/// The tunnel gets bridged to a listener on a random loopback port, which the
+/// ssh client is pointed at.
+pub async fn run_client<S>(stream: S, peer: &PeerId, args: &ClientArgs) -> io::Result<ExitStatus>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    log::info!("Connecting ssh via tunnel (local port {}) ...", port);
+    let child = ssh_command(peer, args, port, "127.0.0.1").spawn()?;
+    task::spawn(async move {
+        let result = async {
+            let (socket, _) = listener.accept().await?;
+            let (sr, sw) = stream.split();
+            let (tr, tw) = socket.split();
+            tunnel::bridge(sr, sw, tr, tw).await
+        };
+        if let Err(e) = result.await {
+            log::debug!("Tunnel closed: {}", e);
+        }
+    });
+    wait_child(child).await
+}
+
+/// Run ssh directly to `host`, for peers not supporting tunnels.
+///
+/// Only to be used with an address the peer has been authenticated at, so we
+/// don't end up at a stranger's machine which inherited the peer's old IP.
+pub async fn run_direct(
+    host: String,
+    port: u16,
+    peer: &PeerId,
+    args: &ClientArgs,
+) -> io::Result<ExitStatus> {
+    let child = ssh_command(peer, args, port, &host).spawn()?;
+    wait_child(child).await
+}
+
+/// ssh options for passing the environment variables matching `patterns`.
+///
+/// `SendEnv` accumulates, so user options can still add more.
+fn send_env_option(patterns: &[String]) -> Vec<String> {
+    if patterns.is_empty() {
+        return Vec::new();
+    }
+    vec!["-o".into(), format!("SendEnv={}", patterns.join(" "))]
+}
+
+/// The ssh command line for connecting to `peer` at `host` and `port`.
+///
+/// `HostKeyAlias` makes ssh check the host key against the peer id, instead
+/// of against whatever address we happen to connect to.
+fn ssh_command(peer: &PeerId, args: &ClientArgs, port: u16, host: &str) -> async_process::Command {
+    let mut cmd = async_process::Command::new("ssh");
+    cmd.args(ssh_args(peer, args, &port.to_string(), host));
+    cmd
+}
+
+/// The ssh command line `run_client` runs, for showing it.
+pub fn client_command_line(peer: &PeerId, args: &ClientArgs) -> Vec<String> {
+    let mut line = vec!["ssh".to_string()];
+    line.extend(ssh_args(peer, args, "<local port>", "127.0.0.1"));
+    line
+}
+
+fn ssh_args(peer: &PeerId, args: &ClientArgs, port: &str, host: &str) -> Vec<String> {
+    let mut line = Vec::new();
+    if let Some(user) = &args.user {
+        line.extend(vec!["-l".to_string(), user.clone()]);
+    }
+    // User options first, they must not override the port:
+    line.extend(args.options.iter().cloned());
+    line.extend(vec![
+        "-o".to_string(),
+        format!("HostKeyAlias={}", peer),
+        "-p".to_string(),
+        port.to_string(),
+        host.to_string(),
+    ]);
+    line.extend(args.trailing.iter().cloned());
+    line
+}
+
+/// Copy files via scp, with p2shd as `ProxyCommand`.
+///
+/// Remote paths are given as `[user@]<peer>:<path>`, where `peer` can be a
+/// peer id or address book name. The last path is the destination.
+pub fn copy(cfg: &Config, paths: &[String], recursive: bool) -> Result<ExitStatus> {
+    let mut cmd = Command::new("scp");
+    if recursive {
+        cmd.arg("-r");
+    }
+    if let Some(user) = &cfg.opts.connect.user {
+        cmd.arg("-o").arg(format!("User={}", user));
+    }
+    cmd.arg("-o").arg(format!("ProxyCommand={}", proxy_command(cfg, "%h")?));
+    for p in paths {
+        cmd.arg(to_scp_path(cfg, p)?);
+    }
+    log::debug!("Running {:?}", cmd);
+    Ok(cmd.status()?)
+}
+
+/// `ProxyCommand` running this very p2shd binary with our configuration in stdio mode.
+fn proxy_command(cfg: &Config, peer: &str) -> io::Result<String> {
+    let exe = env::current_exe()?;
+    Ok(format!(
+        "{} --config-dir {} --key-file {} --stdio {}",
+        shell_quote(&exe.to_string_lossy()),
+        shell_quote(&cfg.config_dir().to_string_lossy()),
+        shell_quote(&cfg.get_key_file().to_string_lossy()),
+        peer,
+    ))
+}
+
+/// The ssh command mosh should use for starting `mosh-server` at `peer`.
+///
+/// ssh goes through the tunnel, only mosh's UDP traffic goes to the peer
+/// directly.
+pub fn mosh_ssh_command(cfg: &Config, peer: &PeerId) -> io::Result<String> {
+    let args = ClientArgs::from_config(cfg);
+    let mut cmd = vec![
+        "ssh".to_string(),
+        "-o".into(),
+        shell_quote(&format!("ProxyCommand={}", proxy_command(cfg, &peer.to_string())?)),
+        "-o".into(),
+        format!("HostKeyAlias={}", peer),
+    ];
+    if let Some(user) = &args.user {
+        cmd.push("-l".into());
+        cmd.push(shell_quote(user));
+    }
+    cmd.extend(args.options.iter().map(|o| shell_quote(o)));
+    Ok(cmd.join(" "))
+}
+
+/// Run mosh to `host`, with `ssh` as returned by `mosh_ssh_command`.
+///
+/// `command` is run remotely instead of a login shell, if given.
+pub async fn run_mosh(ssh: String, host: String, command: Vec<String>) -> io::Result<ExitStatus> {
+    let mut cmd = async_process::Command::new("mosh");
+    // The host we give mosh is what UDP goes to, don't let it look elsewhere:
+    cmd.arg(format!("--ssh={}", ssh))
+        .arg("--experimental-remote-ip=local")
+        .arg(host);
+    if !command.is_empty() {
+        cmd.arg("--").args(command);
+    }
+    log::debug!("Running {:?}", cmd);
+    let child = cmd.spawn()?;
+    wait_child(child).await
+}
+
+/// Replace the peer name in a remote scp path with the peer id.
+fn to_scp_path(cfg: &Config, path: &str) -> Result<String> {
+    // As with scp, a colon before any slash marks a remote path:
+    let colon = match path.find(':') {
+        Some(i) if !path[..i].contains('/') => i,
+        _ => return Ok(path.into()),
+    };
+    let (host, rest) = path.split_at(colon);
+    let (user, name) = match host.rfind('@') {
+        Some(i) => (&host[..=i], &host[i + 1..]),
+        None => ("", host),
+    };
+    Ok(format!("{}{}{}", user, cfg.lookup_peer(name)?, rest))
+}
+
+/// Quote for `/bin/sh`, ssh runs `ProxyCommand` via the shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Bridge `stream` to stdin/stdout, for use as ssh `ProxyCommand`.
+pub async fn run_stdio<S>(stream: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (sr, sw) = stream.split();
+    tunnel::bridge(sr, sw, stdin(), stdout()).await
+}
+
+/// Serve an inbound tunnel, connecting it to the ssh daemon at `sshd`.
+pub async fn serve(mut stream: TunnelStream, sshd: SocketAddr, timeouts: Timeouts) -> io::Result<()> {
+    let socket = match TcpStream::connect(sshd).await {
+        Ok(s) => s,
+        Err(e) => {
+            tunnel::reject(&mut stream, "ssh daemon not reachable").await?;
+            return Err(e);
+        }
+    };
+    tunnel::accept(&mut stream, &timeouts).await?;
+    let (sr, sw) = stream.split();
+    let (tr, tw) = socket.split();
+    tunnel::bridge_with_timeouts(sr, sw, tr, tw, &timeouts).await
+}
+
+/// Print the peer's banner (on stderr, as ssh does), if it has one.
+///
+/// Peers without a banner or too old to know about banners reject the request,
+/// that is fine.
+pub async fn show_banner(peer: PeerId, opener: Opener) {
+    match forward::request_lines(Request::Banner, peer, opener).await {
+        Ok(lines) => {
+            for line in lines {
+                eprintln!("{}", line);
+            }
+        }
+        Err(e) => log::debug!("No banner: {}", e),
+    }
+}
+
+/// Tell the user (on stderr, as ssh does) about the peer's timeouts and
+/// warn shortly before the session expires.
+pub async fn warn_expiry(timeouts: Timeouts) {
+    if let Some(idle) = timeouts.idle {
+        eprintln!("p2shd: Peer closes sessions idle for {}.", format_duration(idle));
+    }
+    let absolute = match timeouts.absolute {
+        None => return,
+        Some(a) => a,
+    };
+    eprintln!("p2shd: Session will expire in {}.", format_duration(absolute));
+    let mut elapsed = Duration::from_secs(0);
+    for &before in EXPIRY_WARNINGS {
+        if absolute <= before || absolute - before <= elapsed {
+            continue;
+        }
+        Delay::new(absolute - before - elapsed).await;
+        elapsed = absolute - before;
+        // The terminal is likely in raw mode while ssh runs:
+        eprint!("\r\np2shd: Session will expire in {}.\r\n", format_duration(before));
+    }
+}
+
+/// Human readable duration, in minutes where sensible.
+fn format_duration(d: Duration) -> String {
+    match d.as_secs() {
+        s if s < 120 => format!("{} seconds", s),
+        s => format!("{} minutes", s / 60),
+    }
+}
+
+/// Send SIGTERM to all running ssh/mosh clients, e.g. because we are shutting down.
+pub fn terminate_children() {
+    for &pid in CHILDREN.lock().expect("Children lock poisoned.").iter() {
+        log::debug!("Terminating child process {}", pid);
+        // Safe: Plain syscall, the pid is one of our children not yet waited for.
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+/// Wait for the clients `terminate_children` terminated to exit, killing the ones still running
+/// after `TERMINATE_TIMEOUT`. For right before exiting the process.
+pub async fn wait_children() {
+    let start = Instant::now();
+    while !CHILDREN.lock().expect("Children lock poisoned.").is_empty() {
+        if start.elapsed() >= TERMINATE_TIMEOUT {
+            for &pid in CHILDREN.lock().expect("Children lock poisoned.").iter() {
+                log::warn!("Child process {} did not exit in time, killing it.", pid);
+                // Safe: Plain syscall, the pid is one of our children not yet waited for.
+                unsafe {
+                    libc::kill(pid as libc::pid_t, libc::SIGKILL);
+                }
+            }
+            return;
+        }
+        Delay::new(Duration::from_millis(50)).await;
+    }
+}
+
+/// Wait for `child` to exit, it gets terminated by `terminate_children` meanwhile.
+///
+/// Waiting is driven by `SIGCHLD`, no thread blocks on the child meanwhile.
+async fn wait_child(child: Child) -> io::Result<ExitStatus> {
+    let pid = child.id();
+    CHILDREN.lock().expect("Children lock poisoned.").insert(pid);
+    let status = child.await;
+    CHILDREN.lock().expect("Children lock poisoned.").remove(&pid);
+    status
+}
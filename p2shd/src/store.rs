@@ -0,0 +1,295 @@
+//! Kademlia record store, optionally persisted to disk.
+//!
+//! Records and provider records are kept in a `MemoryStore`, if a path is
+//! given they are also written to a file on `flush` and loaded on startup, so
+//! records we hold for the network survive daemon restarts.
+
+use anyhow::{Context as AnyhowContext, Result};
+use libp2p::{
+    kad::record::{
+        store::{self, MemoryStore, RecordStore},
+        Key, ProviderRecord, Record,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::path_exists,
+    format_version::{self, FormatVersion},
+    sealed_state,
+};
+
+mod error;
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// A `MemoryStore` with optional persistence.
+pub struct Store {
+    inner: MemoryStore,
+    /// Keys of all provider records, ours and those we hold for others: `MemoryStore` only
+    /// lists our own.
+    provider_keys: HashSet<Key>,
+    /// Where to persist the store, `None` for memory only operation.
+    path: Option<PathBuf>,
+    /// Whether there are changes not yet written to disk.
+    dirty: bool,
+}
+
+/// On disk representation of `Store`.
+#[derive(Serialize, Deserialize, Default)]
+struct StoreFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    records: Vec<RecordEntry>,
+    providers: Vec<ProviderEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordEntry {
+    /// Base64 encoded.
+    key: String,
+    /// Base64 encoded.
+    value: String,
+    publisher: Option<String>,
+    /// Seconds since the UNIX epoch.
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProviderEntry {
+    /// Base64 encoded.
+    key: String,
+    provider: String,
+    /// Seconds since the UNIX epoch.
+    expires: Option<u64>,
+}
+
+impl Store {
+    /// A store living in memory only.
+    pub fn memory(local_id: PeerId) -> Store {
+        Store {
+            inner: MemoryStore::new(local_id),
+            provider_keys: HashSet::new(),
+            path: None,
+            dirty: false,
+        }
+    }
+
+    /// A store persisted at `path`, loading any records stored there.
+    ///
+    /// Expired or undecodable entries are skipped.
+    pub fn load(local_id: PeerId, path: PathBuf) -> Result<Store> {
+        let exists = path_exists(&path).with_context(|| error::Store::Read(path.clone()))?;
+        let file: StoreFile = if exists {
+            let raw = sealed_state::read(&path).with_context(|| error::Store::Read(path.clone()))?;
+            format_version::check(&path, &raw, FORMAT)?;
+            serde_json::from_slice(&raw).with_context(|| error::Store::Decode(path.clone()))?
+        } else {
+            StoreFile::default()
+        };
+
+        let mut inner = MemoryStore::new(local_id);
+        let mut provider_keys = HashSet::new();
+        for r in file.records {
+            let decoded = (|| {
+                let key = Key::from(base64::decode(&r.key).ok()?);
+                let mut record = Record::new(key, base64::decode(&r.value).ok()?);
+                record.publisher = match r.publisher {
+                    None => None,
+                    Some(p) => Some(p.parse().ok()?),
+                };
+                record.expires = match r.expires {
+                    None => None,
+                    Some(e) => Some(to_instant(e)?),
+                };
+                Some(record)
+            })();
+            match decoded {
+                None => log::debug!("Ignoring invalid or expired record in store."),
+                Some(record) => {
+                    if let Err(e) = inner.put(record) {
+                        log::warn!("Loading record failed: {:?}", e);
+                    }
+                }
+            }
+        }
+        for p in file.providers {
+            let decoded = (|| {
+                let key = Key::from(base64::decode(&p.key).ok()?);
+                let mut record = ProviderRecord::new(key, p.provider.parse().ok()?);
+                record.expires = match p.expires {
+                    None => None,
+                    Some(e) => Some(to_instant(e)?),
+                };
+                Some(record)
+            })();
+            match decoded {
+                None => log::debug!("Ignoring invalid or expired provider record in store."),
+                Some(record) => {
+                    let key = record.key.clone();
+                    match inner.add_provider(record) {
+                        Ok(()) => {
+                            provider_keys.insert(key);
+                        }
+                        Err(e) => log::warn!("Loading provider record failed: {:?}", e),
+                    }
+                }
+            }
+        }
+        Ok(Store {
+            inner,
+            provider_keys,
+            path: Some(path),
+            dirty: false,
+        })
+    }
+
+    /// Write the store to disk, if it is persistent and changed since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        let path = match &self.path {
+            None => return Ok(()),
+            Some(p) => p.clone(),
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let file = StoreFile {
+            format: FORMAT,
+            records: self
+                .inner
+                .records()
+                .map(|r| RecordEntry {
+                    key: base64::encode(r.key.to_vec()),
+                    value: base64::encode(&r.value),
+                    publisher: r.publisher.as_ref().map(|p| p.to_base58()),
+                    expires: r.expires.map(to_unix_secs),
+                })
+                .collect(),
+            providers: self
+                .provider_keys
+                .iter()
+                .flat_map(|k| self.inner.providers(k))
+                .map(|p| ProviderEntry {
+                    key: base64::encode(p.key.to_vec()),
+                    provider: p.provider.to_base58(),
+                    expires: p.expires.map(to_unix_secs),
+                })
+                .collect(),
+        };
+        let encoded = serde_json::to_vec(&file).expect("Serializing record store can't fail.");
+        sealed_state::write(&path, &encoded).with_context(|| error::Store::Write(path.clone()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl<'a> RecordStore<'a> for Store {
+    type RecordsIter = <MemoryStore as RecordStore<'a>>::RecordsIter;
+    type ProvidedIter = <MemoryStore as RecordStore<'a>>::ProvidedIter;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&'a mut self, r: Record) -> store::Result<()> {
+        self.dirty = true;
+        self.inner.put(r)
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        self.dirty = true;
+        self.inner.remove(k)
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        self.inner.records()
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> store::Result<()> {
+        self.dirty = true;
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.provider_keys.insert(key);
+        Ok(())
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+        self.dirty = true;
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.remove(k);
+        }
+    }
+}
+
+/// Convert an expiry `Instant` to wall clock time, for storing it.
+fn to_unix_secs(expires: Instant) -> u64 {
+    let remaining = expires.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Convert stored wall clock time back to an `Instant`, `None` if already expired.
+fn to_instant(unix_secs: u64) -> Option<Instant> {
+    let expires = UNIX_EPOCH + Duration::from_secs(unix_secs);
+    let remaining = expires.duration_since(SystemTime::now()).ok()?;
+    Some(Instant::now() + remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use std::{env, fs};
+
+    fn peer() -> PeerId {
+        PeerId::from(Keypair::generate_ed25519().public())
+    }
+
+    fn providers(store: &Store, key: &Key) -> Vec<PeerId> {
+        store.providers(key).into_iter().map(|p| p.provider).collect()
+    }
+
+    #[test]
+    fn load_flush_round_trip() {
+        let path = env::temp_dir().join(format!("p2shd-store-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (local, foreign) = (peer(), peer());
+        let (record, ours, theirs) = (
+            Key::from(b"record".to_vec()),
+            Key::from(b"ours".to_vec()),
+            Key::from(b"theirs".to_vec()),
+        );
+        let mut store = Store::load(local.clone(), path.clone()).unwrap();
+        store.put(Record::new(record.clone(), b"value".to_vec())).unwrap();
+        store.add_provider(ProviderRecord::new(ours.clone(), local.clone())).unwrap();
+        store.add_provider(ProviderRecord::new(theirs.clone(), foreign.clone())).unwrap();
+        store.flush().unwrap();
+
+        let loaded = Store::load(local.clone(), path.clone()).unwrap();
+        assert_eq!(loaded.get(&record).unwrap().value, b"value");
+        assert_eq!(providers(&loaded, &ours), vec![local]);
+        // Held for the network, not provided by us:
+        assert_eq!(providers(&loaded, &theirs), vec![foreign]);
+        fs::remove_file(&path).unwrap();
+    }
+}
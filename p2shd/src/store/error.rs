@@ -0,0 +1,20 @@
+//! Errors that can happen while loading or storing Kademlia records.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to record store persistence.
+#[derive(Error, Debug)]
+pub enum Store {
+    #[error("Reading record store '{0}' failed.")]
+    Read(PathBuf),
+    #[error(
+        "Invalid record store '{0}'.
+
+Delete the file to start with an empty store, records will be republished
+by their publishers eventually."
+    )]
+    Decode(PathBuf),
+    #[error("Writing record store '{0}' failed.")]
+    Write(PathBuf),
+}
@@ -0,0 +1,83 @@
+//! systemd integration: Socket activation and `sd_notify` readiness/watchdog.
+//!
+//! Implements the small bits of the protocols we need directly (see
+//! `sd_listen_fds(3)` and `sd_notify(3)`), so no libsystemd is needed. All of
+//! this is a no-op when not running under systemd.
+
+use futures_timer::Delay;
+use std::{
+    env,
+    os::unix::{io::RawFd, net::UnixDatagram},
+    process,
+    time::Duration,
+};
+
+use crate::control::{Call, Controller};
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors passed via socket activation, if any were passed to us.
+///
+/// The environment variables get removed, so child processes don't get confused.
+pub fn listen_fds() -> Vec<RawFd> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map_or(false, |pid| pid == process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, if it asked for notifications.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        None => return,
+        Some(p) => p,
+    };
+    if path.to_string_lossy().starts_with('@') {
+        // Abstract sockets are not supported by std:
+        log::warn!("Abstract NOTIFY_SOCKET not supported, not notifying systemd.");
+        return;
+    }
+    let result = UnixDatagram::unbound().and_then(|s| s.send_to(state.as_bytes(), &path));
+    if let Err(e) = result {
+        log::warn!("Notifying systemd ({}) failed: {}", state, e);
+    }
+}
+
+/// How often to send `WATCHDOG=1`, `None` if the watchdog is not enabled.
+///
+/// Half the configured watchdog timeout, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map_or(true, |pid| pid == process::id());
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if !for_us || usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Send `WATCHDOG=1` every `interval`, as long as the swarm answers calls.
+pub async fn run_watchdog(interval: Duration, controller: Controller) {
+    loop {
+        Delay::new(interval).await;
+        // A stuck swarm does not answer, then systemd restarts us:
+        match async_std::future::timeout(interval, controller.call(Call::Status)).await {
+            Ok(Ok(_)) => notify("WATCHDOG=1"),
+            _ => log::warn!("Swarm unresponsive, not petting the systemd watchdog."),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Tracing session setup across peers, exported to an OpenTelemetry collector.
+//!
+//! With `--otlp-traces <endpoint>`, the steps of setting up a session become
+//! spans: The whole setup on the connecting side, with each DHT query and
+//! each attempt to open the tunnel as children (dials being events of the
+//! latter), each tunnel request and serving it on the accepting side. The
+//! trace context travels along with tunnel requests as W3C `traceparent`
+//! (`ssh traceparent=00-<trace id>-<span id>-01`), so if both daemons export
+//! to collectors, a session's journey shows up as one trace.
+//!
+//! Only tunnels speaking `/p2shd/tunnel/1.1.0` carry the context, peers strip
+//! it whether they trace or not. Peers predating it only speak 1.0.0 and get
+//! bare requests, as do all peers without `--otlp-traces`.
+//!
+//! The tracer is global, as spans get started deep down in tunnel handling.
+//!
+//! This is deliberately not built on `tracing`, `tracing-opentelemetry` and
+//! `opentelemetry-otlp`: Their OTLP exporter talks gRPC and needs a tokio
+//! runtime (and, at versions fitting our dependencies, a C++ build of
+//! grpcio), while p2shd runs on async-std and already pushes metrics as
+//! OTLP/HTTP JSON. Spans go through the same small client, see `otlp`, and
+//! a handful of spans per session don't need a sampling or layering
+//! framework.
+
+use anyhow::Result;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use futures_timer::Delay;
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::{fmt, mem, str::FromStr, sync::Mutex, time::Duration, time::SystemTime};
+
+use crate::otlp;
+
+mod error;
+
+/// How often finished spans get exported.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spans beyond this many waiting for export get dropped, e.g. while the collector is down.
+const MAX_PENDING: usize = 1000;
+
+static TRACER: OnceCell<Tracer> = OnceCell::new();
+
+struct Tracer {
+    endpoint: String,
+    /// Finished spans, in OTLP's JSON encoding.
+    pending: Mutex<Vec<Value>>,
+}
+
+/// Position in a trace, as passed to peers: The trace and the span to continue from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl fmt::Display for TraceContext {
+    /// As W3C `traceparent`, always sampled.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-01",
+            HEXLOWER_PERMISSIVE.encode(&self.trace_id),
+            HEXLOWER_PERMISSIVE.encode(&self.span_id)
+        )
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = error::Trace;
+
+    fn from_str(s: &str) -> Result<TraceContext, Self::Err> {
+        let invalid = || error::Trace::InvalidContext(s.into());
+        let mut parts = s.split('-');
+        let (trace_id, span_id) = match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("00"), Some(trace), Some(span), Some(_flags), None) => (trace, span),
+            _ => return Err(invalid()),
+        };
+        let mut context = TraceContext {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+        };
+        let decode = |hex: &str, out: &mut [u8]| {
+            let bytes = HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).map_err(|_| invalid())?;
+            if bytes.len() != out.len() {
+                return Err(invalid());
+            }
+            out.copy_from_slice(&bytes);
+            Ok(())
+        };
+        decode(trace_id, &mut context.trace_id)?;
+        decode(span_id, &mut context.span_id)?;
+        Ok(context)
+    }
+}
+
+/// What a span stands for, as OTLP's `SpanKind`.
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A running span, exported once finished.
+pub struct Span {
+    name: &'static str,
+    kind: Kind,
+    context: TraceContext,
+    parent: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    /// Things that happened during the span, with when.
+    events: Vec<(SystemTime, String)>,
+}
+
+impl Span {
+    /// Start a span, continuing the trace of `parent` or starting a new one.
+    ///
+    /// `None` if tracing is not enabled.
+    pub fn start(name: &'static str, kind: Kind, parent: Option<&TraceContext>) -> Option<Span> {
+        TRACER.get()?;
+        let mut rng = rand::thread_rng();
+        let mut context = TraceContext {
+            trace_id: parent.map_or([0; 16], |p| p.trace_id),
+            span_id: [0; 8],
+        };
+        if parent.is_none() {
+            rng.fill_bytes(&mut context.trace_id);
+        }
+        rng.fill_bytes(&mut context.span_id);
+        Some(Span {
+            name,
+            kind,
+            context,
+            parent: parent.map(|p| p.span_id),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            events: Vec::new(),
+        })
+    }
+
+    /// Context for children of this span, e.g. on the peer.
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    /// Attach an attribute, e.g. the peer involved.
+    pub fn set(&mut self, key: &'static str, value: impl ToString) {
+        self.attributes.push((key, value.to_string()));
+    }
+
+    /// Note that something happened, e.g. a dial failed.
+    pub fn event(&mut self, name: impl ToString) {
+        self.events.push((SystemTime::now(), name.to_string()));
+    }
+
+    /// End the span, `error` is why the step failed, if it did.
+    pub fn finish(self, error: Option<String>) {
+        let tracer = match TRACER.get() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .map(|(k, v)| otlp::attribute(k, v))
+            .collect();
+        let events: Vec<_> = self
+            .events
+            .iter()
+            .map(|(at, name)| json!({"timeUnixNano": otlp::unix_nanos(*at), "name": name}))
+            .collect();
+        let status = match &error {
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({"code": 1}),
+        };
+        let mut span = json!({
+            "traceId": HEXLOWER_PERMISSIVE.encode(&self.context.trace_id),
+            "spanId": HEXLOWER_PERMISSIVE.encode(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": otlp::unix_nanos(self.start),
+            "endTimeUnixNano": otlp::unix_nanos(SystemTime::now()),
+            "attributes": attributes,
+            "events": events,
+            "status": status,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = HEXLOWER_PERMISSIVE.encode(&parent).into();
+        }
+        let mut pending = tracer.pending.lock().expect("Tracer lock poisoned.");
+        if pending.len() < MAX_PENDING {
+            pending.push(span);
+        }
+    }
+}
+
+/// Start a child of `parent`, if there is one.
+pub fn child(name: &'static str, kind: Kind, parent: Option<&Span>) -> Option<Span> {
+    parent.and_then(|p| Span::start(name, kind, Some(&p.context)))
+}
+
+/// Finish `span`, if there is one, according to `result`.
+pub fn finish<T, E: fmt::Display>(span: Option<Span>, result: &Result<T, E>) {
+    if let Some(span) = span {
+        span.finish(result.as_ref().err().map(|e| e.to_string()));
+    }
+}
+
+/// Start tracing, exporting spans to the collector at `endpoint` once `run_exporter` runs.
+///
+/// Calling it more than once has no effect.
+pub fn enable(endpoint: String) -> Result<()> {
+    otlp::check_endpoint(&endpoint)?;
+    let _ = TRACER.set(Tracer {
+        endpoint,
+        pending: Mutex::new(Vec::new()),
+    });
+    Ok(())
+}
+
+/// Export finished spans every `FLUSH_INTERVAL`, forever.
+pub async fn run_exporter() {
+    let tracer = match TRACER.get() {
+        Some(tracer) => tracer,
+        None => return,
+    };
+    loop {
+        Delay::new(FLUSH_INTERVAL).await;
+        let spans = mem::take(&mut *tracer.pending.lock().expect("Tracer lock poisoned."));
+        if spans.is_empty() {
+            continue;
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": otlp::resource(),
+                "scopeSpans": [{"scope": otlp::scope(), "spans": spans}],
+            }]
+        });
+        if let Err(e) = otlp::post(&tracer.endpoint, "/v1/traces", &body).await {
+            log::warn!("Exporting traces to {} failed: {:#}", tracer.endpoint, e);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Errors that can happen while tracing.
+
+use thiserror::Error;
+
+/// Errors related to trace contexts.
+#[derive(Error, Debug)]
+pub enum Trace {
+    #[error("Invalid traceparent '{0}'.")]
+    InvalidContext(String),
+}
@@ -0,0 +1,125 @@
+//! The libp2p transport used by p2shd.
+//!
+//! Mirrors libp2p's development transport (TCP + DNS, secio, yamux/mplex) but
+//! allows for routing outbound connections through a proxy and resolves DNS
+//! names via our own resolver. Connections violating the blocklist are
+//! dropped before any handshake (blocked addresses, dials are not even
+//! attempted, see `blocked`) or right after authentication (blocked peers). With `allowed_peers` configured, inbound
+//! connections of other peers get dropped right after authentication too, so
+//! they don't get to speak any protocol (not even identify).
+//!
+//! With `--sticky-port` outbound connections are all dialed from the same
+//! local port, see `sticky`.
+
+use futures::future;
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        ConnectedPoint,
+        transport::{boxed::Boxed, Transport},
+        upgrade,
+    },
+    identity, mplex, secio,
+    tcp::TcpConfig,
+    yamux, PeerId,
+};
+use std::{collections::HashSet, io, net::IpAddr, time::Duration};
+
+use crate::{
+    blocklist::SharedBlocklist,
+    config::Config,
+    dns::{transport::DnsTransport, Resolver},
+};
+
+mod blocked;
+mod error;
+pub mod proxy;
+mod sticky;
+
+use blocked::BlockedTransport;
+use proxy::ProxyTransport;
+use sticky::{StickyPort, StickyTransport};
+
+/// The fully upgraded transport, as handed to the `Swarm`.
+pub type P2shdTransport = Boxed<(PeerId, StreamMuxerBox), io::Error>;
+
+/// Build the transport according to the given configuration.
+///
+/// Connections get dialed from `bind_addrs`, if given (see `--bind-interface`).
+pub fn build_transport(
+    local_key: identity::Keypair,
+    cfg: &Config,
+    resolver: Resolver,
+    blocklist: SharedBlocklist,
+    bind_addrs: &[IpAddr],
+) -> io::Result<P2shdTransport> {
+    let sticky = if cfg.sticky_port() {
+        Some(StickyPort::load(cfg.get_sticky_port_file()))
+    } else {
+        None
+    };
+    let tcp = StickyTransport::new(sticky, bind_addrs.to_vec())
+        .or_transport(TcpConfig::new().nodelay(true));
+    let tcp = DnsTransport::new(BlockedTransport::new(tcp, blocklist.clone(), None), resolver.clone());
+    // The proxy comes first, so it gets to see (and resolve) DNS names itself:
+    let proxy = ProxyTransport::new(cfg.opts.proxy.clone(), cfg.opts.proxy_bypass.clone());
+    let base = BlockedTransport::new(proxy, blocklist.clone(), Some(resolver)).or_transport(tcp);
+
+    let allowed: Option<HashSet<PeerId>> = cfg.allowed_peers.as_ref().map(|p| p.iter().cloned().collect());
+    let addr_blocklist = blocklist.clone();
+    // Dials got checked by `BlockedTransport` already:
+    let base = base.and_then(move |stream, endpoint| {
+        let addr = match &endpoint {
+            ConnectedPoint::Dialer { .. } => return future::ready(Ok(stream)),
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+        let blocked = addr_blocklist
+            .read()
+            .expect("Blocklist lock poisoned.")
+            .is_addr_blocked(addr);
+        future::ready(if blocked {
+            log::debug!("Dropping connection with blocked address {}", addr);
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Address {} is blocked.", addr),
+            ))
+        } else {
+            Ok(stream)
+        })
+    });
+
+    Ok(base
+        .upgrade(upgrade::Version::V1)
+        .authenticate(secio::SecioConfig::new(local_key))
+        .multiplex(upgrade::SelectUpgrade::new(
+            yamux::Config::default(),
+            mplex::MplexConfig::new(),
+        ))
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+        .and_then(move |(peer, muxer), endpoint| {
+            let blocked = blocklist
+                .read()
+                .expect("Blocklist lock poisoned.")
+                .is_peer_blocked(&peer);
+            let inbound = matches!(endpoint, ConnectedPoint::Listener { .. });
+            let allowed = allowed.as_ref().map_or(true, |a| a.contains(&peer));
+            future::ready(if blocked {
+                log::debug!("Dropping connection with blocked peer {}", peer);
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Peer {} is blocked.", peer),
+                ))
+            } else if inbound && !allowed {
+                log::debug!("Dropping connection from peer {}, not in allowed_peers", peer);
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Peer {} is not allowed to connect.", peer),
+                ))
+            } else {
+                Ok((peer, muxer))
+            })
+        })
+        .timeout(Duration::from_secs(20))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .boxed())
+}
@@ -0,0 +1,90 @@
+//! Refusing dials to blocked addresses, before anything gets sent their way.
+//!
+//! Wraps the transport actually connecting: Below `DnsTransport` it sees the
+//! resolved addresses. Around `ProxyTransport`, which leaves DNS names to the
+//! proxy, names get resolved via our `Resolver` just for checking them, as
+//! long as there are any blocked networks at all.
+
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{
+    core::transport::{Transport, TransportError},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use std::io;
+
+use crate::{blocklist::SharedBlocklist, dns::Resolver};
+
+/// Wraps a transport, refusing to dial blocked addresses.
+#[derive(Clone)]
+pub struct BlockedTransport<T> {
+    inner: T,
+    blocklist: SharedBlocklist,
+    /// For checking DNS names the inner transport does not get resolved.
+    resolver: Option<Resolver>,
+}
+
+impl<T> BlockedTransport<T> {
+    pub fn new(inner: T, blocklist: SharedBlocklist, resolver: Option<Resolver>) -> BlockedTransport<T> {
+        BlockedTransport {
+            inner,
+            blocklist,
+            resolver,
+        }
+    }
+}
+
+impl<T> Transport for BlockedTransport<T>
+where
+    T: Transport<Error = io::Error> + Send + 'static,
+    T::Dial: Send + 'static,
+    T::Output: Send + 'static,
+{
+    type Output = T::Output;
+    type Error = io::Error;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        self.inner.listen_on(addr)
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let (blocked, check_name) = {
+            let blocklist = self.blocklist.read().expect("Blocklist lock poisoned.");
+            (blocklist.is_addr_blocked(&addr), blocklist.has_networks() && is_dns(&addr))
+        };
+        if blocked {
+            log::debug!("Not dialing blocked address {}", addr);
+            return Err(TransportError::Other(blocked_err(&addr)));
+        }
+        // Dials only connect once polled, so nothing happens before the check below:
+        let dial = self.inner.dial(addr.clone())?;
+        let resolving = match self.resolver {
+            Some(resolver) if check_name => resolver.resolve_multiaddr(addr.clone()),
+            _ => return Ok(dial.boxed()),
+        };
+        let blocklist = self.blocklist;
+        Ok(async move {
+            let resolved = resolving.await?;
+            if blocklist.read().expect("Blocklist lock poisoned.").is_addr_blocked(&resolved) {
+                log::debug!("Not dialing {}, resolved to blocked {}", addr, resolved);
+                return Err(blocked_err(&resolved));
+            }
+            dial.await
+        }
+        .boxed())
+    }
+}
+
+fn is_dns(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) => true,
+        _ => false,
+    }
+}
+
+fn blocked_err(addr: &Multiaddr) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("Address {} is blocked.", addr))
+}
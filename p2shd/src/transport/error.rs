@@ -0,0 +1,26 @@
+//! Errors that can happen while setting up or using the transport.
+
+use thiserror::Error;
+
+/// Errors related to dialing via a proxy.
+#[derive(Error, Debug)]
+pub enum Proxy {
+    #[error(
+        "Invalid proxy '{0}'.
+
+Supported are 'socks5://host:port' and 'http://host:port' (HTTP CONNECT)."
+    )]
+    InvalidUrl(String),
+    #[error("Proxy refused SOCKS5 authentication method, only unauthenticated access is supported.")]
+    Socks5AuthRefused,
+    #[error("Proxy refused SOCKS5 connect request with reply code {0}.")]
+    Socks5ConnectFailed(u8),
+    #[error("Domain name '{0}' is too long for SOCKS5, which allows at most 255 bytes.")]
+    Socks5NameTooLong(String),
+    #[error("Proxy sent an invalid SOCKS5 reply.")]
+    Socks5InvalidReply,
+    #[error("Proxy refused HTTP CONNECT with: '{0}'")]
+    HttpConnectFailed(String),
+    #[error("Proxy sent an overlong or invalid HTTP response.")]
+    HttpInvalidResponse,
+}
@@ -0,0 +1,276 @@
+//! Outbound TCP dialing via a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! `ProxyTransport` only ever dials, it is meant to be combined with a plain
+//! TCP transport via `or_transport`: Addresses it does not want to handle
+//! (no proxy configured, bypassed destinations, listening) are reported as
+//! `MultiaddrNotSupported` and thus end up at the plain transport.
+
+use async_std::net::TcpStream;
+use futures::{future::BoxFuture, prelude::*};
+use ipnet::IpNet;
+use libp2p::{
+    core::transport::{ListenerEvent, Transport, TransportError},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use std::{
+    fmt, io,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::net;
+
+use super::error;
+
+/// Maximum size of the HTTP CONNECT response header we are willing to read.
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+/// A configured proxy server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+    Socks5 { host: String, port: u16 },
+    HttpConnect { host: String, port: u16 },
+}
+
+impl FromStr for Proxy {
+    type Err = error::Proxy;
+
+    fn from_str(s: &str) -> Result<Proxy, Self::Err> {
+        let invalid = || error::Proxy::InvalidUrl(s.into());
+        let (scheme, rest) = match s.find("://") {
+            Some(i) => (&s[..i], &s[i + 3..]),
+            None => return Err(invalid()),
+        };
+        let rest = rest.trim_end_matches('/');
+        let i = rest.rfind(':').ok_or_else(invalid)?;
+        let host = rest[..i].trim_start_matches('[').trim_end_matches(']');
+        let port = rest[i + 1..].parse().map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = host.to_string();
+        match scheme {
+            "socks5" | "socks5h" => Ok(Proxy::Socks5 { host, port }),
+            "http" => Ok(Proxy::HttpConnect { host, port }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Proxy::Socks5 { host, port } => write!(f, "socks5://{}", format_host_port(host, *port)),
+            Proxy::HttpConnect { host, port } => write!(f, "http://{}", format_host_port(host, *port)),
+        }
+    }
+}
+
+/// Destination of a dial, as extracted from a multiaddr.
+enum Target {
+    Ip(IpAddr, u16),
+    Dns(String, u16),
+}
+
+/// Transport dialing TCP connections through a proxy.
+#[derive(Clone)]
+pub struct ProxyTransport {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    proxy: Option<Proxy>,
+    /// Destinations to be dialed directly.
+    bypass: Vec<IpNet>,
+}
+
+impl ProxyTransport {
+    /// Dial via `proxy`, unless the destination is a LAN address or contained in `bypass`.
+    ///
+    /// With `proxy` being `None` this transport does not support any address.
+    pub fn new(proxy: Option<Proxy>, bypass: Vec<IpNet>) -> ProxyTransport {
+        ProxyTransport {
+            inner: Arc::new(Inner { proxy, bypass }),
+        }
+    }
+
+    /// Whether addresses of the given ip should be dialed directly.
+    fn is_bypassed(&self, ip: &IpAddr) -> bool {
+        ip.is_loopback() || net::is_lan(ip) || self.inner.bypass.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl Transport for ProxyTransport {
+    type Output = TcpStream;
+    type Error = io::Error;
+    type Listener = stream::Pending<Result<ListenerEvent<Self::ListenerUpgrade>, Self::Error>>;
+    type ListenerUpgrade = future::Pending<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let proxy = match &self.inner.proxy {
+            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+            Some(p) => p.clone(),
+        };
+        let target = match multiaddr_to_target(&addr) {
+            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+            Some(t) => t,
+        };
+        match &target {
+            Target::Ip(ip, _) if self.is_bypassed(ip) => {
+                return Err(TransportError::MultiaddrNotSupported(addr))
+            }
+            Target::Dns(name, _) if name == "localhost" => {
+                return Err(TransportError::MultiaddrNotSupported(addr))
+            }
+            _ => (),
+        }
+        log::debug!("Dialing {} via proxy {}", addr, proxy);
+        Ok(async move {
+            match proxy {
+                Proxy::Socks5 { host, port } => socks5_connect(&host, port, &target).await,
+                Proxy::HttpConnect { host, port } => http_connect(&host, port, &target).await,
+            }
+        }
+        .boxed())
+    }
+}
+
+/// Only plain `/ip4|ip6|dns4|dns6/.../tcp/port` addresses are supported.
+fn multiaddr_to_target(addr: &Multiaddr) -> Option<Target> {
+    let mut iter = addr.iter();
+    let host = iter.next()?;
+    let port = match iter.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    if iter.next().is_some() {
+        return None;
+    }
+    match host {
+        Protocol::Ip4(ip) => Some(Target::Ip(ip.into(), port)),
+        Protocol::Ip6(ip) => Some(Target::Ip(ip.into(), port)),
+        Protocol::Dns4(name) | Protocol::Dns6(name) => Some(Target::Dns(name.into_owned(), port)),
+        _ => None,
+    }
+}
+
+/// Put brackets around IPv6 addresses.
+fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn other_err(e: error::Proxy) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Establish a connection to `target` via the SOCKS5 proxy at `host`:`port` (RFC 1928).
+///
+/// Domain names are resolved by the proxy.
+async fn socks5_connect(host: &str, port: u16, target: &Target) -> io::Result<TcpStream> {
+    // The name's length gets sent as a single byte:
+    if let Target::Dns(name, _) = target {
+        if name.len() > 255 {
+            return Err(other_err(error::Proxy::Socks5NameTooLong(name.clone())));
+        }
+    }
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    // Greeting, offering "no authentication" only:
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(other_err(error::Proxy::Socks5AuthRefused));
+    }
+
+    let mut request = vec![5, 1, 0];
+    let target_port = match target {
+        Target::Ip(IpAddr::V4(ip), p) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+            p
+        }
+        Target::Ip(IpAddr::V6(ip), p) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+            p
+        }
+        Target::Dns(name, p) => {
+            request.push(3);
+            request.push(name.len() as u8);
+            request.extend_from_slice(name.as_bytes());
+            p
+        }
+    };
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 5 {
+        return Err(other_err(error::Proxy::Socks5InvalidReply));
+    }
+    if head[1] != 0 {
+        return Err(other_err(error::Proxy::Socks5ConnectFailed(head[1])));
+    }
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(other_err(error::Proxy::Socks5InvalidReply)),
+    };
+    // Bound address and port, we don't need them:
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Establish a connection to `target` via the HTTP proxy at `host`:`port`, using `CONNECT`.
+async fn http_connect(host: &str, port: u16, target: &Target) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let authority = match target {
+        Target::Ip(ip, p) => format_host_port(&ip.to_string(), *p),
+        Target::Dns(name, p) => format_host_port(name, *p),
+    };
+    let request = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        authority
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, so we don't consume any data of the tunnelled connection:
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(other_err(error::Proxy::HttpInvalidResponse));
+        }
+        stream.read_exact(&mut byte).await?;
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1);
+    if !status_line.starts_with("HTTP/1.") || status.is_none() {
+        return Err(other_err(error::Proxy::HttpInvalidResponse));
+    }
+    if status != Some("200") {
+        return Err(other_err(error::Proxy::HttpConnectFailed(status_line.into())));
+    }
+    Ok(stream)
+}
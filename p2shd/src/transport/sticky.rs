@@ -0,0 +1,267 @@
+//! Outbound TCP dialing from a sticky local port.
+//!
+//! NATs with endpoint independent mapping keep using the same external port
+//! for connections from the same local port. Dialing from the same port thus
+//! keeps mappings warm across reconnects (and restarts), which makes it more
+//! likely peers can reach us at the address they observed last time.
+//!
+//! The first dial gets a port from the OS, later ones reuse the port the last
+//! dial actually got bound to (if that one is taken, another one gets picked
+//! and sticks from then on). It is kept in `sticky_port.json` in the
+//! configuration directory, for restarts.
+//!
+//! With `--bind-interface` connections are also dialed from that interface's
+//! address, so they leave via it.
+//!
+//! Like `ProxyTransport` this transport only dials and is meant to be combined
+//! with a plain TCP transport via `or_transport`. socket2 only connects
+//! blocking, so each dial runs on a thread of its own, bounded in time by
+//! `CONNECT_TIMEOUT` and in number by `MAX_PENDING_DIALS`. Dials beyond that
+//! fail right away, they'd leave from the wrong address otherwise.
+
+use async_std::net::TcpStream;
+use futures::{channel::oneshot, future::BoxFuture, prelude::*};
+use libp2p::{
+    core::transport::{ListenerEvent, Transport, TransportError},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::path_exists,
+    format_version::{self, FormatVersion},
+    sealed_state,
+};
+
+/// Version of the file format, see `format_version`.
+const FORMAT: FormatVersion = FormatVersion::new(1, 0);
+
+/// Give up connecting after this long, like the transport gives up on the whole upgrade.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Dials (thus threads) in flight at most.
+const MAX_PENDING_DIALS: usize = 64;
+
+/// The local port dials go out from, shared by all dials and kept across restarts.
+#[derive(Clone)]
+pub struct StickyPort {
+    /// Where to store the port.
+    path: PathBuf,
+    /// `None` until the first dial got bound.
+    port: Arc<Mutex<Option<u16>>>,
+}
+
+/// On disk representation of `StickyPort`.
+#[derive(Serialize, Deserialize)]
+struct StickyPortFile {
+    /// See `format_version`.
+    #[serde(default)]
+    format: FormatVersion,
+    port: u16,
+}
+
+impl StickyPort {
+    /// The port stored at `path`, if there is none (or it can't be read) the next dial picks one.
+    pub fn load(path: PathBuf) -> StickyPort {
+        let port = read_port(&path).unwrap_or_else(|e| {
+            log::debug!("Reading sticky port from {} failed: {:#}", path.display(), e);
+            None
+        });
+        StickyPort {
+            path,
+            port: Arc::new(Mutex::new(port)),
+        }
+    }
+
+    fn get(&self) -> Option<u16> {
+        *self.port.lock().expect("Sticky port lock poisoned.")
+    }
+
+    /// A dial got bound to local `port`, stick to it.
+    fn bound(&self, port: u16) {
+        let mut current = self.port.lock().expect("Sticky port lock poisoned.");
+        if *current == Some(port) {
+            return;
+        }
+        log::debug!("Dialing from local port {} from now on.", port);
+        *current = Some(port);
+        let file = StickyPortFile { format: FORMAT, port };
+        let encoded = serde_json::to_vec(&file).expect("Serializing sticky port can't fail.");
+        if let Err(e) = sealed_state::write(&self.path, &encoded) {
+            log::warn!("Writing sticky port to {} failed: {:#}", self.path.display(), e);
+        }
+    }
+}
+
+fn read_port(path: &Path) -> anyhow::Result<Option<u16>> {
+    if !path_exists(path)? {
+        return Ok(None);
+    }
+    let raw = sealed_state::read(path)?;
+    format_version::check(path, &raw, FORMAT)?;
+    let file: StickyPortFile = serde_json::from_slice(&raw)?;
+    Ok(Some(file.port))
+}
+
+/// Transport dialing IP addresses from a sticky local port and/or a fixed address.
+#[derive(Clone)]
+pub struct StickyTransport {
+    port: Option<StickyPort>,
+    /// Local addresses to dial from, the first one of the destination's family is used.
+    source: Vec<IpAddr>,
+    /// Number of dials in flight.
+    pending: Arc<AtomicUsize>,
+}
+
+/// Counts a dial as pending until dropped.
+struct PendingDial(Arc<AtomicUsize>);
+
+impl Drop for PendingDial {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl StickyTransport {
+    /// Without `port` and `source` this transport does not support any address.
+    pub fn new(port: Option<StickyPort>, source: Vec<IpAddr>) -> StickyTransport {
+        StickyTransport {
+            port,
+            source,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn source_for(&self, dest: &SocketAddr) -> Option<IpAddr> {
+        self.source.iter().find(|ip| ip.is_ipv4() == dest.is_ipv4()).cloned()
+    }
+}
+
+impl Transport for StickyTransport {
+    type Output = TcpStream;
+    type Error = io::Error;
+    type Listener = stream::Pending<Result<ListenerEvent<Self::ListenerUpgrade>, Self::Error>>;
+    type ListenerUpgrade = future::Pending<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let dest = match multiaddr_to_socketaddr(&addr) {
+            Some(dest) => dest,
+            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
+        let (sticky, source) = (self.port, self.source_for(&dest));
+        if sticky.is_none() && source.is_none() {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_DIALS {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            log::debug!("Too many pending dials, not dialing {}", dest);
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::Other,
+                "Too many pending dials.",
+            )));
+        }
+        let pending = PendingDial(self.pending);
+        Ok(async move {
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                let _pending = pending;
+                let port = sticky.as_ref().and_then(StickyPort::get);
+                log::trace!("Dialing {} from {:?}, local port {:?}", dest, source, port);
+                let stream = connect_from(source, port, sticky.is_some(), dest);
+                if let (Some(sticky), Ok(stream)) = (&sticky, &stream) {
+                    if let Ok(local) = stream.local_addr() {
+                        sticky.bound(local.port());
+                    }
+                }
+                let _ = tx.send(stream);
+            });
+            let stream = rx
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Dialing thread died.")))?;
+            Ok(TcpStream::from(stream))
+        }
+        .boxed())
+    }
+}
+
+/// Connect to `dest` from local `port`, or from any port if that one is taken.
+///
+/// With `reuse` the local port can be shared with other connections.
+fn connect_from(
+    source: Option<IpAddr>,
+    port: Option<u16>,
+    reuse: bool,
+    dest: SocketAddr,
+) -> io::Result<std::net::TcpStream> {
+    match connect(source, port, reuse, dest) {
+        Err(e) if port.is_some()
+            && (e.kind() == io::ErrorKind::AddrInUse || e.kind() == io::ErrorKind::AddrNotAvailable) =>
+        {
+            log::debug!("Local port {:?} not usable for {}: {}, using any port.", port, dest, e);
+            connect(source, None, reuse, dest)
+        }
+        r => r,
+    }
+}
+
+fn connect(
+    source: Option<IpAddr>,
+    port: Option<u16>,
+    reuse: bool,
+    dest: SocketAddr,
+) -> io::Result<std::net::TcpStream> {
+    let (domain, unspecified) = match dest {
+        SocketAddr::V4(_) => (Domain::ipv4(), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        SocketAddr::V6(_) => (Domain::ipv6(), IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_nodelay(true)?;
+    if reuse {
+        // Connections to different destinations may share the local port:
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+    if port.is_some() || source.is_some() {
+        let local = SocketAddr::new(source.unwrap_or(unspecified), port.unwrap_or(0));
+        socket.bind(&local.into())?;
+    }
+    socket.connect_timeout(&dest.into(), CONNECT_TIMEOUT)?;
+    Ok(socket.into_tcp_stream())
+}
+
+/// Only plain `/ip4|ip6/.../tcp/port` addresses are supported.
+fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut iter = addr.iter();
+    let ip: IpAddr = match iter.next()? {
+        Protocol::Ip4(ip) => ip.into(),
+        Protocol::Ip6(ip) => ip.into(),
+        _ => return None,
+    };
+    let port = match iter.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    if iter.next().is_some() {
+        return None;
+    }
+    Some(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,188 @@
+//! How well we know a peer, shown before sessions so changes get noticed.
+//!
+//! Peer ids are public keys, so an address book entry pins a name to a key
+//! just like `known_hosts` does for ssh. What is left to tell the user is
+//! whether the peer is pinned at all, whether our policy (`allowed_peers`,
+//! `authorized_peers`) lists it and whether we ever talked to it before.
+//!
+//! ssh pins host keys under the peer id (via `HostKeyAlias`) in its
+//! `known_hosts` and refuses to connect if one changes. `p2shd trust
+//! refresh` is the explicit way out: It moves the old key to a quarantine
+//! file, so the next session asks to confirm the new one.
+//!
+//! The host keys approved this way travel with synced address book entries,
+//! see `book_sync`, so linked devices don't have to confirm them again.
+
+use anyhow::{Context as AnyhowContext, Result};
+use chrono::Local;
+use libp2p::PeerId;
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Output},
+};
+
+use crate::{addr_cache::AddrCache, config::Config};
+
+mod error;
+
+/// Trust level of a peer, most trusted first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trust {
+    /// In the address book and in `allowed_peers` or `authorized_peers`.
+    Allowlisted,
+    /// In the address book only.
+    Pinned,
+    /// Pinned under the peer id it rotated from, see `rotation`. The new id was
+    /// vouched for by the old key, but is not in the address book yet.
+    Rotated,
+    /// Not in the address book, but connected to before.
+    Seen,
+    /// Neither in the address book nor ever connected to.
+    FirstContact,
+}
+
+impl Trust {
+    pub fn of(cfg: &Config, addr_cache: &AddrCache, peer: &PeerId) -> Trust {
+        let pinned = cfg.address_book.iter().any(|e| &e.peer_id == peer);
+        let listed = |peers: &Option<Vec<PeerId>>| peers.as_ref().map_or(false, |p| p.contains(peer));
+        if pinned && (listed(&cfg.allowed_peers) || listed(&cfg.authorized_peers)) {
+            Trust::Allowlisted
+        } else if pinned {
+            Trust::Pinned
+        } else if addr_cache.last_good(peer).is_some() {
+            Trust::Seen
+        } else {
+            Trust::FirstContact
+        }
+    }
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Trust::Allowlisted => "pinned & allowlisted",
+            Trust::Pinned => "pinned",
+            Trust::Rotated => "rotated from a pinned key, update the address book",
+            Trust::Seen => "not pinned, seen before",
+            Trust::FirstContact => "first contact, check the peer id",
+        })
+    }
+}
+
+/// `p2shd trust refresh`: Show the host keys pinned for `peer` and, once the
+/// user confirmed, move them to quarantine.
+pub fn refresh(cfg: &Config, peer: &PeerId) -> Result<()> {
+    let alias = peer.to_base58();
+    // Exits unsuccessfully if there is no such host, that is handled below:
+    let found = keygen(&["-F", &alias])?;
+    let keys: Vec<_> = String::from_utf8_lossy(&found.stdout)
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.trim().is_empty())
+        .map(String::from)
+        .collect();
+    if keys.is_empty() {
+        return Err(error::Trust::NotPinned(peer.clone()).into());
+    }
+    println!("Host keys pinned for {}:", peer);
+    let fingerprints = keygen(&["-l", "-F", &alias])?;
+    for line in String::from_utf8_lossy(&fingerprints.stdout).lines() {
+        if !line.starts_with('#') {
+            println!("  {}", line);
+        }
+    }
+    print!("Only continue if you know why the key changed. Quarantine these keys? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Err(error::Trust::Aborted.into());
+    }
+    let path = cfg.get_quarantine_file();
+    let mut quarantine = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| error::Trust::Write(path.clone()))?;
+    let mut entry = format!("# {}, quarantined {}\n", peer, Local::now().to_rfc3339());
+    for key in keys {
+        entry.push_str(&key);
+        entry.push('\n');
+    }
+    quarantine
+        .write_all(entry.as_bytes())
+        .with_context(|| error::Trust::Write(path.clone()))?;
+    let removed = keygen(&["-R", &alias])?;
+    if !removed.status.success() {
+        let stderr = String::from_utf8_lossy(&removed.stderr).trim().to_string();
+        return Err(error::Trust::Keygen(stderr).into());
+    }
+    println!(
+        "Moved to {}, the next session to {} asks to confirm its new key.",
+        path.display(),
+        peer
+    );
+    Ok(())
+}
+
+/// `p2shd trust quarantine`: Print the quarantined host keys.
+pub fn print_quarantine(cfg: &Config) -> Result<()> {
+    let path = cfg.get_quarantine_file();
+    match fs::read_to_string(&path) {
+        Ok(content) => print!("{}", content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => println!("No host keys quarantined."),
+        Err(e) => return Err(e).with_context(|| error::Trust::Read(path)),
+    }
+    Ok(())
+}
+
+/// The host keys pinned for `peer` in `known_hosts`, as `<type> <base64 key>`, sorted.
+pub fn pinned_host_keys(peer: &PeerId) -> Result<Vec<String>> {
+    let found = keygen(&["-F", &peer.to_base58()])?;
+    let mut keys: Vec<_> = String::from_utf8_lossy(&found.stdout)
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.starts_with('@'))
+        .filter_map(|l| {
+            let fields: Vec<_> = l.split_whitespace().skip(1).take(2).collect();
+            if fields.len() == 2 {
+                Some(fields.join(" "))
+            } else {
+                None
+            }
+        })
+        .collect();
+    keys.sort();
+    Ok(keys)
+}
+
+/// Pin `keys` (as returned by `pinned_host_keys`) for `peer` in `known_hosts`, unless some are
+/// pinned for it already. Whether they got pinned.
+pub fn pin_host_keys(peer: &PeerId, keys: &[String]) -> Result<bool> {
+    // They come from other devices, don't let them smuggle in anything else:
+    let valid = |k: &String| {
+        k.split(' ').count() == 2 && k.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+    };
+    if keys.is_empty() || !keys.iter().all(valid) || !pinned_host_keys(peer)?.is_empty() {
+        return Ok(false);
+    }
+    let home = env::var_os("HOME").ok_or(error::Trust::NoHome)?;
+    let dir = Path::new(&home).join(".ssh");
+    let path = dir.join("known_hosts");
+    let write_err = || error::Trust::WriteKnownHosts(path.clone());
+    fs::create_dir_all(&dir).with_context(write_err)?;
+    let mut known_hosts = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(write_err)?;
+    let lines: String = keys.iter().map(|k| format!("{} {}\n", peer, k)).collect();
+    known_hosts.write_all(lines.as_bytes()).with_context(write_err)?;
+    Ok(true)
+}
+
+/// Run `ssh-keygen` on the user's `known_hosts`.
+fn keygen(args: &[&str]) -> Result<Output> {
+    let output = Command::new("ssh-keygen").args(args).output();
+    output.with_context(|| error::Trust::Keygen("running ssh-keygen failed".into()))
+}
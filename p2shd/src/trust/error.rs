@@ -0,0 +1,24 @@
+//! Errors that can happen while managing pinned host keys.
+
+use libp2p::PeerId;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors related to `p2shd trust`.
+#[derive(Error, Debug)]
+pub enum Trust {
+    #[error("No ssh host key pinned for {0} in known_hosts.")]
+    NotPinned(PeerId),
+    #[error("Aborted, nothing changed.")]
+    Aborted,
+    #[error("ssh-keygen: {0}")]
+    Keygen(String),
+    #[error("Reading quarantined host keys '{0}' failed.")]
+    Read(PathBuf),
+    #[error("Writing quarantined host keys '{0}' failed.")]
+    Write(PathBuf),
+    #[error("HOME is not set, can't find known_hosts.")]
+    NoHome,
+    #[error("Writing known_hosts '{0}' failed.")]
+    WriteKnownHosts(PathBuf),
+}
@@ -0,0 +1,1016 @@
+//! Tunnels: Byte streams to a peer, carried over the libp2p connection.
+//!
+//! Instead of dialing the peer's IP addresses directly (which fails across
+//! NATs and bypasses libp2p), a substream using the `/p2shd/tunnel/1.1.0`
+//! protocol (or `/p2shd/tunnel/1.0.0` with older peers) is opened on the
+//! already established, encrypted connection.
+//!
+//! Connections to peers marked via `keep_connected` are kept open and
+//! re-established when lost, so tunnels to them open without any discovery
+//! delay and they can always reach us.
+//!
+//! Candidate addresses passed to `open_via` get dialed happy eyeballs style
+//! (RFC 8305): In the given order, each `DIAL_STAGGER` after the previous one
+//! or as soon as it failed, so a good address wins fast without waiting for
+//! bad ones to time out. The first connection wins, remaining addresses are
+//! not dialed anymore and extra connections close once idle.
+//!
+//! After protocol negotiation the opening side sends a request line (e.g.
+//! `ssh`), the accepting side answers with `ok` or `error <reason>`. From then
+//! on the substream is a plain byte stream. With 1.1.0, the `ok` carries the
+//! timeouts the accepting side enforces (e.g. `ok idle=1800 absolute=28800`,
+//! in seconds), so clients can warn before they get cut off. When tracing,
+//! the request line carries the trace context as last word, see `trace`.
+//! 1.0.0 peers get neither, they only accept a bare `ok` and reject requests
+//! with anything appended.
+
+use futures::{future, io, prelude::*};
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint},
+    swarm::{
+        NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+        PollParameters,
+    },
+    Multiaddr, PeerId,
+};
+use futures_timer::Delay;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+mod error;
+pub mod handler;
+
+use crate::{
+    dial_report::DialFailure,
+    prometheus,
+    trace::{self, Kind, Span, TraceContext},
+};
+use handler::{HandlerEvent, HandlerIn, TunnelHandler};
+
+/// Maximum length of request and response lines.
+const MAX_LINE: usize = 1024;
+
+/// How long the opening side of an inbound tunnel has to send its request line.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often lost connections to `keep_connected` peers are redialed.
+const REDIAL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay between dialing candidate addresses of `open_via`, as recommended by RFC 8305.
+pub const DIAL_STAGGER: Duration = Duration::from_millis(250);
+
+/// Identifies a tunnel we requested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TunnelId(u64);
+
+/// Version of the tunnel protocol negotiated for a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    /// `/p2shd/tunnel/1.0.0`: Bare request lines.
+    V1_0,
+    /// `/p2shd/tunnel/1.1.0`: Request lines may carry a trace context, `ok` the enforced
+    /// timeouts.
+    V1_1,
+}
+
+/// A tunnel's substream, along with the protocol version spoken on it.
+#[derive(Debug)]
+pub struct TunnelStream {
+    inner: NegotiatedSubstream,
+    version: Version,
+}
+
+impl TunnelStream {
+    pub(crate) fn new(inner: NegotiatedSubstream, version: Version) -> TunnelStream {
+        TunnelStream { inner, version }
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// What the opening side wants the tunnel to be connected to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// The peer's ssh daemon, optionally on a specific port of the daemon's host.
+    Ssh { port: Option<u16> },
+    /// An arbitrary TCP destination, as reachable from the peer.
+    Tcp { host: String, port: u16 },
+    /// Listen on `addr` for as long as this tunnel is open, see `forward::request_listen`.
+    Listen { addr: SocketAddr },
+    /// A connection the peer accepted at `addr`, because we sent a `Request::Listen`.
+    Reverse { addr: SocketAddr },
+    /// A service the peer exposes by name.
+    Service { name: String },
+    /// The names of the services exposed to us, one per line.
+    Services,
+    /// A layer 3 link, see `vpn`.
+    Vpn,
+    /// Load, uptime and service health, see `resources`.
+    Resources,
+    /// The banner to show before the ssh session starts, one line per line.
+    Banner,
+    /// Exchange address books with a linked device, see `book_sync`.
+    Sync,
+    /// Several forwardings at once (`Tcp` and `Listen` entries), accepted or
+    /// denied individually, see `forward::negotiate`.
+    Forwards(Vec<Request>),
+}
+
+impl FromStr for Request {
+    type Err = error::Tunnel;
+
+    fn from_str(s: &str) -> Result<Request, Self::Err> {
+        if let Some(entries) = s.strip_prefix("forwards ") {
+            return entries
+                .split(',')
+                .map(|e| match e.parse()? {
+                    r @ Request::Tcp { .. } | r @ Request::Listen { .. } => Ok(r),
+                    _ => Err(error::Tunnel::UnknownRequest(s.into())),
+                })
+                .collect::<Result<_, _>>()
+                .map(Request::Forwards);
+        }
+        let mut words = s.split(' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("ssh"), None, None) => Ok(Request::Ssh { port: None }),
+            (Some("ssh"), Some(port), None) => match port.parse() {
+                Ok(port) => Ok(Request::Ssh { port: Some(port) }),
+                Err(_) => Err(error::Tunnel::UnknownRequest(s.into())),
+            },
+            (Some("listen"), Some(addr), None) => addr
+                .parse()
+                .map(|addr| Request::Listen { addr })
+                .map_err(|_| error::Tunnel::UnknownRequest(s.into())),
+            (Some("reverse"), Some(addr), None) => addr
+                .parse()
+                .map(|addr| Request::Reverse { addr })
+                .map_err(|_| error::Tunnel::UnknownRequest(s.into())),
+            (Some("service"), Some(name), None) if !name.is_empty() => {
+                Ok(Request::Service { name: name.into() })
+            }
+            (Some("services"), None, None) => Ok(Request::Services),
+            (Some("vpn"), None, None) => Ok(Request::Vpn),
+            (Some("resources"), None, None) => Ok(Request::Resources),
+            (Some("banner"), None, None) => Ok(Request::Banner),
+            (Some("sync"), None, None) => Ok(Request::Sync),
+            (Some("tcp"), Some(dest), None) => {
+                let (host, port) = split_host_port(dest)
+                    .ok_or_else(|| error::Tunnel::UnknownRequest(s.into()))?;
+                Ok(Request::Tcp { host, port })
+            }
+            _ => Err(error::Tunnel::UnknownRequest(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Ssh { port: None } => write!(f, "ssh"),
+            Request::Ssh { port: Some(port) } => write!(f, "ssh {}", port),
+            Request::Tcp { host, port } if host.contains(':') => {
+                write!(f, "tcp [{}]:{}", host, port)
+            }
+            Request::Tcp { host, port } => write!(f, "tcp {}:{}", host, port),
+            Request::Listen { addr } => write!(f, "listen {}", addr),
+            Request::Reverse { addr } => write!(f, "reverse {}", addr),
+            Request::Service { name } => write!(f, "service {}", name),
+            Request::Services => write!(f, "services"),
+            Request::Vpn => write!(f, "vpn"),
+            Request::Resources => write!(f, "resources"),
+            Request::Banner => write!(f, "banner"),
+            Request::Sync => write!(f, "sync"),
+            Request::Forwards(entries) => {
+                let entries: Vec<_> = entries.iter().map(|e| e.to_string()).collect();
+                write!(f, "forwards {}", entries.join(","))
+            }
+        }
+    }
+}
+
+/// Split `host:port`, with IPv6 hosts in brackets.
+fn split_host_port(dest: &str) -> Option<(String, u16)> {
+    let colon = dest.rfind(':')?;
+    let (host, port) = (&dest[..colon], &dest[colon + 1..]);
+    let host = match host.strip_prefix('[') {
+        Some(h) => h.strip_suffix(']').filter(|h| h.contains(':'))?,
+        None if host.contains(&[':', ']'][..]) => return None,
+        None => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Timeouts the accepting side enforces on a tunnel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timeouts {
+    /// Close the tunnel after no data went through for this long.
+    pub idle: Option<Duration>,
+    /// Close the tunnel this long after it got opened.
+    pub absolute: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Parse the `key=seconds` parameters of an `ok` response, ignoring unknown keys.
+    fn from_params(params: &str) -> Timeouts {
+        let mut timeouts = Timeouts::default();
+        for param in params.split(' ') {
+            let mut kv = param.splitn(2, '=');
+            let secs = match kv.clone().nth(1).and_then(|v| v.parse().ok()) {
+                Some(secs) => Duration::from_secs(secs),
+                None => continue,
+            };
+            match kv.next() {
+                Some("idle") => timeouts.idle = Some(secs),
+                Some("absolute") => timeouts.absolute = Some(secs),
+                _ => (),
+            }
+        }
+        timeouts
+    }
+}
+
+impl fmt::Display for Timeouts {
+    /// As parameters of an `ok` response.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(idle) = self.idle {
+            write!(f, " idle={}", idle.as_secs())?;
+        }
+        if let Some(absolute) = self.absolute {
+            write!(f, " absolute={}", absolute.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Events emitted by the `Tunnel` behaviour.
+#[derive(Debug)]
+pub enum TunnelEvent {
+    /// A peer opened a tunnel, it is up to the receiver to `accept` or reject it.
+    Inbound {
+        peer: PeerId,
+        stream: TunnelStream,
+    },
+    /// A tunnel requested via `Tunnel::open` got opened. The request line has
+    /// still to be sent, see `request`.
+    Outbound {
+        peer: PeerId,
+        id: TunnelId,
+        stream: TunnelStream,
+        /// The address we dialed the peer at, `None` if it connected to us.
+        addr: Option<Multiaddr>,
+    },
+    /// A tunnel requested via `Tunnel::open` could not be opened.
+    Failed {
+        peer: PeerId,
+        id: TunnelId,
+        error: String,
+        /// The peer is connected, but does not speak the tunnel protocol.
+        unsupported: bool,
+        /// The address we dialed the peer at, if connected. The connection
+        /// being authenticated proves the peer is reachable at this address.
+        addr: Option<Multiaddr>,
+    },
+    /// Dialing `peer` at `addr` succeeded or failed.
+    Dialed {
+        peer: PeerId,
+        addr: Multiaddr,
+        /// Why it failed, `None` if it succeeded.
+        failure: Option<DialFailure>,
+    },
+}
+
+/// Network behaviour opening and accepting tunnel substreams.
+pub struct Tunnel {
+    next_id: u64,
+    /// Connected peers with the address of the first connection we dialed to them.
+    connected: HashMap<PeerId, Option<Multiaddr>>,
+    /// Tunnels waiting for a connection to the peer.
+    pending: HashMap<PeerId, Vec<TunnelId>>,
+    /// Peers we are dialing.
+    dialing: HashSet<PeerId>,
+    /// Addresses dialed via `open_via`, with the peer they are supposed to be.
+    via: HashMap<Multiaddr, PeerId>,
+    /// Candidate addresses of `open_via` not dialed yet, next one first.
+    staggered: HashMap<PeerId, VecDeque<Multiaddr>>,
+    /// Fires when it is time to dial the next `staggered` addresses.
+    stagger_timer: Delay,
+    /// Peers to stay connected to.
+    keep: HashSet<PeerId>,
+    /// Fires when it is time to redial lost `keep` peers.
+    redial_timer: Delay,
+    actions: VecDeque<NetworkBehaviourAction<HandlerIn, TunnelEvent>>,
+    waker: Option<Waker>,
+}
+
+impl Default for Tunnel {
+    fn default() -> Tunnel {
+        Tunnel {
+            next_id: 0,
+            connected: HashMap::new(),
+            pending: HashMap::new(),
+            dialing: HashSet::new(),
+            via: HashMap::new(),
+            staggered: HashMap::new(),
+            stagger_timer: Delay::new(DIAL_STAGGER),
+            keep: HashSet::new(),
+            redial_timer: Delay::new(REDIAL_INTERVAL),
+            actions: VecDeque::new(),
+            waker: None,
+        }
+    }
+}
+
+impl Tunnel {
+    pub fn new() -> Tunnel {
+        Tunnel::default()
+    }
+
+    /// Stay connected to `peer`, redialing it whenever the connection is lost.
+    pub fn keep_connected(&mut self, peer: PeerId) {
+        if self.connected.contains_key(&peer) {
+            self.notify(&peer, HandlerIn::KeepAlive);
+        } else {
+            self.dial(&peer);
+        }
+        self.keep.insert(peer);
+    }
+
+    /// Whether we are currently connected to `peer`.
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.connected.contains_key(peer)
+    }
+
+    /// Connected peers, with the address we dialed them at (`None` if they connected to us).
+    pub fn connected_peers(&self) -> impl Iterator<Item = (&PeerId, Option<&Multiaddr>)> {
+        self.connected.iter().map(|(p, a)| (p, a.as_ref()))
+    }
+
+    /// Dial `peer`, unless we are connected already.
+    pub fn connect(&mut self, peer: &PeerId) {
+        if !self.connected.contains_key(peer) {
+            self.dial(peer);
+        }
+    }
+
+    fn dial(&mut self, peer: &PeerId) {
+        if self.dialing.insert(peer.clone()) {
+            self.actions.push_back(NetworkBehaviourAction::DialPeer {
+                peer_id: peer.clone(),
+            });
+        }
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+
+    fn notify(&mut self, peer: &PeerId, event: HandlerIn) {
+        self.actions.push_back(NetworkBehaviourAction::NotifyHandler {
+            peer_id: peer.clone(),
+            handler: NotifyHandler::Any,
+            event,
+        });
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+
+    /// Open a tunnel to `peer`, dialing it if we are not yet connected.
+    ///
+    /// Results in a `TunnelEvent::Outbound` or `TunnelEvent::Failed` with the returned id.
+    pub fn open(&mut self, peer: &PeerId) -> TunnelId {
+        let id = TunnelId(self.next_id);
+        self.next_id += 1;
+        if self.connected.contains_key(peer) {
+            self.notify(peer, HandlerIn::Open(id));
+        } else {
+            self.pending
+                .entry(peer.clone())
+                .or_insert_with(Vec::new)
+                .push(id);
+            self.dial(peer);
+        }
+        id
+    }
+
+    /// Like `open`, but dial `addrs` (most promising first, staggered) instead of
+    /// letting the swarm pick addresses.
+    ///
+    /// Only if all of them fail, the peer gets dialed as usual.
+    pub fn open_via(&mut self, peer: &PeerId, addrs: Vec<Multiaddr>) -> TunnelId {
+        if self.connected.contains_key(peer) || self.dialing.contains(peer) || addrs.is_empty() {
+            return self.open(peer);
+        }
+        let id = TunnelId(self.next_id);
+        self.next_id += 1;
+        self.pending
+            .entry(peer.clone())
+            .or_insert_with(Vec::new)
+            .push(id);
+        self.staggered.insert(peer.clone(), addrs.into());
+        self.dial_next(peer);
+        id
+    }
+
+    /// Dial the next `staggered` address of `peer`, if any is left.
+    fn dial_next(&mut self, peer: &PeerId) {
+        let address = match self.staggered.get_mut(peer).and_then(|addrs| addrs.pop_front()) {
+            None => return,
+            Some(a) => a,
+        };
+        if self.staggered.get(peer).map_or(false, |addrs| addrs.is_empty()) {
+            self.staggered.remove(peer);
+        }
+        log::debug!("Dialing {} at {}", peer, address);
+        self.via.insert(address.clone(), peer.clone());
+        self.actions.push_back(NetworkBehaviourAction::DialAddress { address });
+        self.stagger_timer.reset(DIAL_STAGGER);
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+
+    fn dialed(&mut self, peer: &PeerId, addr: &Multiaddr, failure: Option<DialFailure>) {
+        self.actions
+            .push_back(NetworkBehaviourAction::GenerateEvent(TunnelEvent::Dialed {
+                peer: peer.clone(),
+                addr: addr.clone(),
+                failure,
+            }));
+    }
+
+    /// Dialing `addr` failed, move on to the next candidate.
+    fn dial_failed(&mut self, peer: Option<&PeerId>, addr: &Multiaddr, failure: DialFailure) {
+        // Addresses dialed directly (via `open_via`) come without peer:
+        let peer = match peer.cloned().or_else(|| self.via.remove(addr)) {
+            None => return,
+            Some(p) => p,
+        };
+        self.dialed(&peer, addr, Some(failure));
+        if self.connected.contains_key(&peer) {
+            return;
+        }
+        // Don't wait for the stagger delay, as in RFC 8305:
+        self.dial_next(&peer);
+        let still_dialing = self.via.values().any(|p| p == &peer);
+        if !still_dialing && self.pending.contains_key(&peer) {
+            log::debug!("Dialing preferred addresses of {} failed, trying all.", peer);
+            self.dial(&peer);
+        }
+    }
+}
+
+impl NetworkBehaviour for Tunnel {
+    type ProtocolsHandler = TunnelHandler;
+    type OutEvent = TunnelEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        TunnelHandler::new()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        // Kademlia, mDNS and friends know addresses, we don't.
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, peer: &PeerId) {
+        self.connected.entry(peer.clone()).or_insert(None);
+        self.dialing.remove(peer);
+        if self.keep.contains(peer) {
+            self.notify(peer, HandlerIn::KeepAlive);
+        }
+        for id in self.pending.remove(peer).unwrap_or_default() {
+            self.notify(peer, HandlerIn::Open(id));
+        }
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        _: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            let addr = self.connected.entry(peer.clone()).or_insert(None);
+            if addr.is_none() {
+                *addr = Some(address.clone());
+            }
+            match self.via.remove(address) {
+                // Someone else is there now, which is as good as failing for the expected peer:
+                Some(expected) if &expected != peer => {
+                    log::trace!("Dialing {} failed: {}", address, error::Tunnel::WrongPeer(peer.clone()));
+                    self.dial_failed(Some(&expected), address, DialFailure::WrongPeer)
+                }
+                _ => {}
+            }
+            // Connected, the remaining candidates are not needed anymore:
+            self.via.retain(|_, p| p != peer);
+            self.staggered.remove(peer);
+            self.dialed(peer, address, None);
+        }
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn std::error::Error,
+    ) {
+        log::trace!("Dialing {} failed: {}", addr, error);
+        self.dial_failed(peer, addr, DialFailure::classify(error));
+    }
+
+    fn inject_disconnected(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+    }
+
+    fn inject_dial_failure(&mut self, peer: &PeerId) {
+        self.dialing.remove(peer);
+        for id in self.pending.remove(peer).unwrap_or_default() {
+            self.actions
+                .push_back(NetworkBehaviourAction::GenerateEvent(TunnelEvent::Failed {
+                    peer: peer.clone(),
+                    id,
+                    error: "Dialing failed.".into(),
+                    unsupported: false,
+                    addr: None,
+                }));
+        }
+    }
+
+    fn inject_event(&mut self, peer: PeerId, _: ConnectionId, event: HandlerEvent) {
+        let event = match event {
+            HandlerEvent::Inbound(stream) => TunnelEvent::Inbound { peer, stream },
+            HandlerEvent::Outbound(id, stream) => {
+                let addr = self.connected.get(&peer).cloned().flatten();
+                TunnelEvent::Outbound {
+                    peer,
+                    id,
+                    stream,
+                    addr,
+                }
+            }
+            HandlerEvent::Failed {
+                id,
+                error,
+                unsupported,
+            } => {
+                let addr = self.connected.get(&peer).cloned().flatten();
+                TunnelEvent::Failed {
+                    peer,
+                    id,
+                    error,
+                    unsupported,
+                    addr,
+                }
+            }
+        };
+        self.actions
+            .push_back(NetworkBehaviourAction::GenerateEvent(event));
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerIn, TunnelEvent>> {
+        // `dial_next` resets the timer:
+        while !self.staggered.is_empty() && self.stagger_timer.poll_unpin(cx).is_ready() {
+            let peers: Vec<_> = self.staggered.keys().cloned().collect();
+            for peer in peers {
+                self.dial_next(&peer);
+            }
+        }
+        while let Poll::Ready(()) = self.redial_timer.poll_unpin(cx) {
+            self.redial_timer.reset(REDIAL_INTERVAL);
+            let lost: Vec<_> = self
+                .keep
+                .iter()
+                .filter(|p| !self.connected.contains_key(p))
+                .cloned()
+                .collect();
+            for peer in lost {
+                log::debug!("Redialing {}, configured to keep connected.", peer);
+                self.dial(&peer);
+            }
+        }
+        match self.actions.pop_front() {
+            Some(action) => Poll::Ready(action),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Send `request` on a freshly opened tunnel and wait for the peer to accept it.
+///
+/// Resolves to the timeouts the peer enforces on the tunnel.
+pub async fn request(stream: &mut TunnelStream, request: &Request) -> io::Result<Timeouts> {
+    request_traced(stream, request, None).await
+}
+
+/// Like `request`, traced as part of `parent`'s trace (a new one if `None`).
+///
+/// The trace context is only passed on to peers speaking `Version::V1_1`.
+pub async fn request_traced(
+    stream: &mut TunnelStream,
+    request: &Request,
+    parent: Option<&TraceContext>,
+) -> io::Result<Timeouts> {
+    let mut span = Span::start("tunnel request", Kind::Client, parent);
+    let mut line = request.to_string();
+    if let Some(span) = &mut span {
+        span.set("request", request);
+        if stream.version() == Version::V1_1 {
+            line = format!("{} traceparent={}", request, span.context());
+        }
+    }
+    let result = async {
+        write_line(stream, &line).await?;
+        let response = read_line(stream).await?;
+        if response == "ok" || response.starts_with("ok ") {
+            return Ok(Timeouts::from_params(&response[2..]));
+        }
+        let reason = response.trim_start_matches("error").trim();
+        Err(to_io_error(error::Tunnel::Rejected(reason.into())))
+    }
+    .await;
+    trace::finish(span, &result);
+    result
+}
+
+/// Read the request of an inbound tunnel.
+///
+/// The request has to be answered with `accept` or `reject`.
+pub async fn read_request(stream: &mut TunnelStream) -> io::Result<Request> {
+    Ok(read_traced_request(stream).await?.0)
+}
+
+/// Like `read_request`, also resolving to the trace context the peer sent, if any.
+///
+/// Fails with `TimedOut` if the request line did not arrive within `REQUEST_TIMEOUT`.
+pub async fn read_traced_request(stream: &mut TunnelStream) -> io::Result<(Request, Option<TraceContext>)> {
+    let line = async_std::future::timeout(REQUEST_TIMEOUT, read_line(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no tunnel request received"))??;
+    let traced = match stream.version() {
+        Version::V1_0 => None,
+        Version::V1_1 => line.rfind(" traceparent="),
+    };
+    let (request, context) = match traced {
+        Some(i) => {
+            let context = line[i..].trim_start().trim_start_matches("traceparent=").parse();
+            if let Err(e) = &context {
+                log::debug!("Ignoring trace context: {}", e);
+            }
+            (&line[..i], context.ok())
+        }
+        None => (line.as_str(), None),
+    };
+    Ok((request.parse().map_err(to_io_error)?, context))
+}
+
+/// Tell the peer its request got accepted, the tunnel is ready for use afterwards.
+///
+/// `timeouts` are only announced (to `Version::V1_1` peers), enforce them via
+/// `bridge_with_timeouts`.
+pub async fn accept(stream: &mut TunnelStream, timeouts: &Timeouts) -> io::Result<()> {
+    match stream.version() {
+        Version::V1_0 => write_line(stream, "ok").await,
+        Version::V1_1 => write_line(stream, &format!("ok{}", timeouts)).await,
+    }
+}
+
+/// Answer the entries of an accepted `Request::Forwards`, in order.
+pub async fn send_verdicts<S>(stream: &mut S, verdicts: &[Result<(), String>]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    for verdict in verdicts {
+        match verdict {
+            Ok(()) => write_line(stream, "ok").await?,
+            Err(reason) => write_line(stream, &format!("error {}", reason)).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Read the answers to the `count` entries of a `Request::Forwards`, see `send_verdicts`.
+pub async fn read_verdicts<S>(stream: &mut S, count: usize) -> io::Result<Vec<Result<(), String>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut verdicts = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(stream).await?;
+        verdicts.push(match line.as_str() {
+            "ok" => Ok(()),
+            _ => Err(line.trim_start_matches("error").trim().to_string()),
+        });
+    }
+    Ok(verdicts)
+}
+
+/// Tell the peer its request can't be served.
+pub async fn reject<S>(stream: &mut S, reason: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_line(stream, &format!("error {}", reason)).await?;
+    stream.close().await
+}
+
+/// Copy data in both directions until both sides are done.
+///
+/// Each direction gets closed as soon as its reading side reached EOF, so half
+/// closed connections work as expected.
+pub async fn bridge<R1, W1, R2, W2>(r1: R1, mut w1: W1, r2: R2, mut w2: W2) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    let one_to_two = async {
+        prometheus::relayed(io::copy(r1, &mut w2).await?);
+        w2.close().await
+    };
+    let two_to_one = async {
+        prometheus::relayed(io::copy(r2, &mut w1).await?);
+        w1.close().await
+    };
+    future::try_join(one_to_two, two_to_one).await.map(|_| ())
+}
+
+/// Like `bridge`, but fails with `TimedOut` once one of `timeouts` is hit.
+pub async fn bridge_with_timeouts<R1, W1, R2, W2>(
+    r1: R1,
+    mut w1: W1,
+    r2: R2,
+    mut w2: W2,
+    timeouts: &Timeouts,
+) -> io::Result<()>
+where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    if *timeouts == Timeouts::default() {
+        return bridge(r1, w1, r2, w2).await;
+    }
+    let start = Instant::now();
+    let last_activity = Mutex::new(start);
+    let one_to_two = async {
+        copy_tracked(r1, &mut w2, &last_activity).await?;
+        w2.close().await
+    };
+    let two_to_one = async {
+        copy_tracked(r2, &mut w1, &last_activity).await?;
+        w1.close().await
+    };
+    let timeouts = *timeouts;
+    let watchdog = async {
+        loop {
+            let now = Instant::now();
+            let mut deadline = None;
+            if let Some(absolute) = timeouts.absolute {
+                deadline = Some(start + absolute);
+            }
+            if let Some(idle) = timeouts.idle {
+                let idle_deadline = *last_activity.lock().expect("Activity lock poisoned.") + idle;
+                deadline = Some(deadline.map_or(idle_deadline, |d: Instant| d.min(idle_deadline)));
+            }
+            match deadline {
+                Some(d) if d <= now => break,
+                Some(d) => Delay::new(d - now).await,
+                None => return future::pending().await,
+            }
+        }
+        let reason = match timeouts.absolute {
+            Some(absolute) if start.elapsed() >= absolute => "session time limit reached",
+            _ => "idle timeout",
+        };
+        Err(io::Error::new(io::ErrorKind::TimedOut, reason))
+    };
+    let copying = future::try_join(one_to_two, two_to_one).map_ok(|_| ());
+    futures::pin_mut!(copying, watchdog);
+    future::select(copying, watchdog).await.factor_first().0
+}
+
+/// `io::copy`, recording when data last went through.
+async fn copy_tracked<R, W>(mut reader: R, writer: &mut W, last_activity: &Mutex<Instant>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        *last_activity.lock().expect("Activity lock poisoned.") = Instant::now();
+        prometheus::relayed(n as u64);
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+    }
+}
+
+async fn write_line<S>(stream: &mut S, line: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Read a single line, byte by byte so nothing after it gets consumed.
+async fn read_line<S>(stream: &mut S) -> io::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        stream.read_exact(&mut byte).await?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE {
+            return Err(to_io_error(error::Tunnel::InvalidHeader));
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|_| to_io_error(error::Tunnel::InvalidHeader))
+}
+
+fn to_io_error(e: error::Tunnel) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn tcp(host: &str, port: u16) -> Request {
+        Request::Tcp {
+            host: host.into(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_requests() {
+        let cases = [
+            ("ssh", Request::Ssh { port: None }),
+            ("ssh 2222", Request::Ssh { port: Some(2222) }),
+            ("tcp localhost:80", tcp("localhost", 80)),
+            ("tcp 10.0.0.1:5432", tcp("10.0.0.1", 5432)),
+            ("tcp [::1]:22", tcp("::1", 22)),
+            ("tcp [fe80::1%eth0]:22", tcp("fe80::1%eth0", 22)),
+            ("listen 127.0.0.1:8080", Request::Listen { addr: "127.0.0.1:8080".parse().unwrap() }),
+            ("reverse [::1]:8080", Request::Reverse { addr: "[::1]:8080".parse().unwrap() }),
+            ("service web", Request::Service { name: "web".into() }),
+            ("services", Request::Services),
+            ("vpn", Request::Vpn),
+            ("resources", Request::Resources),
+            ("banner", Request::Banner),
+            ("sync", Request::Sync),
+            (
+                "forwards tcp db:5432,listen 127.0.0.1:1",
+                Request::Forwards(vec![
+                    tcp("db", 5432),
+                    Request::Listen { addr: "127.0.0.1:1".parse().unwrap() },
+                ]),
+            ),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(&input.parse::<Request>().unwrap(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases = [
+            "",
+            "ssh ",
+            "ssh http",
+            "ssh 70000",
+            "ssh 22 23",
+            "SSH",
+            "shell",
+            "tcp",
+            "tcp localhost",
+            "tcp localhost:",
+            "tcp :80",
+            "tcp localhost:http",
+            "tcp []:80",
+            "tcp [::1:80",
+            "tcp ::1]:80",
+            "tcp [localhost]:80",
+            "tcp a:b:80",
+            "tcp localhost:80 extra",
+            "listen localhost:80",
+            "reverse 8080",
+            "service",
+            "service ",
+            "service a b",
+            "vpn now",
+            "forwards ",
+            "forwards ssh",
+            "forwards tcp db:5432,",
+            "forwards tcp db:5432,service web",
+            "forwards forwards tcp db:5432",
+        ];
+        for input in &cases {
+            assert!(input.parse::<Request>().is_err(), "'{}' got accepted", input);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let requests = [
+            Request::Ssh { port: None },
+            Request::Ssh { port: Some(22) },
+            tcp("localhost", 80),
+            tcp("::1", 22),
+            Request::Listen { addr: "[::1]:8080".parse().unwrap() },
+            Request::Reverse { addr: "0.0.0.0:1".parse().unwrap() },
+            Request::Service { name: "web".into() },
+            Request::Services,
+            Request::Vpn,
+            Request::Resources,
+            Request::Banner,
+            Request::Sync,
+            Request::Forwards(vec![
+                tcp("::1", 22),
+                Request::Listen { addr: "127.0.0.1:1".parse().unwrap() },
+            ]),
+        ];
+        for request in &requests {
+            assert_eq!(&request.to_string().parse::<Request>().unwrap(), request, "{}", request);
+        }
+        assert_eq!(tcp("::1", 22).to_string(), "tcp [::1]:22");
+    }
+
+    #[test]
+    fn parses_timeouts_ignoring_unknown_keys() {
+        let timeouts = Timeouts::from_params("idle=60 absolute=3600 future=1 broken= idle");
+        assert_eq!(timeouts.idle, Some(Duration::from_secs(60)));
+        assert_eq!(timeouts.absolute, Some(Duration::from_secs(3600)));
+        assert_eq!(Timeouts::from_params(&timeouts.to_string()), timeouts);
+        assert_eq!(Timeouts::from_params(""), Timeouts::default());
+    }
+
+    #[test]
+    fn reads_lines_up_to_max_line() {
+        let read = |input: Vec<u8>| block_on(read_line(&mut io::Cursor::new(input)));
+        assert_eq!(read(b"ssh\nrest".to_vec()).unwrap(), "ssh");
+        assert_eq!(read(b"\n".to_vec()).unwrap(), "");
+        let mut longest = vec![b'a'; MAX_LINE];
+        longest.push(b'\n');
+        assert_eq!(read(longest).unwrap().len(), MAX_LINE);
+        let mut overlong = vec![b'a'; MAX_LINE + 1];
+        overlong.push(b'\n');
+        assert!(read(overlong).is_err());
+        // Without the newline:
+        assert_eq!(read(b"ssh".to_vec()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(read(b"\xff\xfe\n".to_vec()).is_err());
+    }
+}
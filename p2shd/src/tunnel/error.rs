@@ -0,0 +1,17 @@
+//! Errors that can happen while setting up a tunnel.
+
+use libp2p::PeerId;
+use thiserror::Error;
+
+/// Errors related to the tunnel request exchange.
+#[derive(Error, Debug)]
+pub enum Tunnel {
+    #[error("Peer rejected tunnel request: '{0}'")]
+    Rejected(String),
+    #[error("Unknown tunnel request '{0}'.")]
+    UnknownRequest(String),
+    #[error("Received an overlong or invalid tunnel header.")]
+    InvalidHeader,
+    #[error("A different peer ({0}) answered at that address.")]
+    WrongPeer(PeerId),
+}
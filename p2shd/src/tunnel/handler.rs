@@ -0,0 +1,201 @@
+//! Connection handler and upgrade of the tunnel protocol.
+//!
+//! The upgrade only negotiates the protocol version, the request header is
+//! exchanged afterwards by whoever ends up owning the substream, see
+//! `super::request` and `super::accept`.
+
+use futures::future;
+use libp2p::{
+    core::upgrade::{InboundUpgrade, NegotiationError, OutboundUpgrade, UpgradeError, UpgradeInfo},
+    swarm::{
+        KeepAlive, NegotiatedSubstream, ProtocolsHandler, ProtocolsHandlerEvent,
+        ProtocolsHandlerUpgrErr, SubstreamProtocol,
+    },
+};
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll},
+    vec,
+};
+use void::Void;
+
+use super::{TunnelId, TunnelStream, Version};
+
+/// Protocol names of p2shd tunnels, the preferred one first.
+pub const PROTOCOL_NAMES: &[(&[u8], Version)] = &[
+    (b"/p2shd/tunnel/1.1.0", Version::V1_1),
+    (b"/p2shd/tunnel/1.0.0", Version::V1_0),
+];
+
+/// Upgrade negotiating one of `PROTOCOL_NAMES`, handing out the bare substream
+/// and the version agreed on.
+#[derive(Clone, Debug, Default)]
+pub struct TunnelProtocol;
+
+impl UpgradeInfo for TunnelProtocol {
+    type Info = &'static [u8];
+    type InfoIter = vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        PROTOCOL_NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>().into_iter()
+    }
+}
+
+/// The version negotiated as `info`.
+fn version(info: &[u8]) -> Version {
+    PROTOCOL_NAMES
+        .iter()
+        .find(|(name, _)| *name == info)
+        .map_or(Version::V1_0, |(_, version)| *version)
+}
+
+impl<C> InboundUpgrade<C> for TunnelProtocol {
+    type Output = (C, Version);
+    type Error = Void;
+    type Future = future::Ready<Result<(C, Version), Void>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        future::ok((socket, version(info)))
+    }
+}
+
+impl<C> OutboundUpgrade<C> for TunnelProtocol {
+    type Output = (C, Version);
+    type Error = Void;
+    type Future = future::Ready<Result<(C, Version), Void>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        future::ok((socket, version(info)))
+    }
+}
+
+/// Commands for `TunnelHandler`.
+#[derive(Debug)]
+pub enum HandlerIn {
+    /// Open a tunnel.
+    Open(TunnelId),
+    /// Keep the connection open, even without any tunnels.
+    KeepAlive,
+}
+
+/// Events reported by `TunnelHandler`.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    /// The remote opened a tunnel.
+    Inbound(TunnelStream),
+    /// A tunnel we requested got opened.
+    Outbound(TunnelId, TunnelStream),
+    /// A tunnel we requested could not be opened.
+    Failed {
+        id: TunnelId,
+        error: String,
+        /// The remote does not speak the tunnel protocol.
+        unsupported: bool,
+    },
+}
+
+/// Opens and accepts tunnel substreams on a single connection.
+pub struct TunnelHandler {
+    /// Tunnels requested, but not yet requested from the swarm.
+    requested: VecDeque<TunnelId>,
+    /// Number of requested tunnels not yet opened or failed.
+    outstanding: usize,
+    /// Events to be reported.
+    events: VecDeque<HandlerEvent>,
+    /// Whether a tunnel was ever opened on this connection, or the behaviour
+    /// asked us to keep the connection.
+    ///
+    /// Substreams don't keep a connection alive on their own, so once a
+    /// tunnel got opened we keep the connection open for good. Closing
+    /// happens when the session ends and the process exits, or the remote
+    /// closes the connection.
+    used: bool,
+}
+
+impl TunnelHandler {
+    pub fn new() -> TunnelHandler {
+        TunnelHandler {
+            requested: VecDeque::new(),
+            outstanding: 0,
+            events: VecDeque::new(),
+            used: false,
+        }
+    }
+}
+
+impl Default for TunnelHandler {
+    fn default() -> TunnelHandler {
+        TunnelHandler::new()
+    }
+}
+
+impl ProtocolsHandler for TunnelHandler {
+    type InEvent = HandlerIn;
+    type OutEvent = HandlerEvent;
+    type Error = Void;
+    type InboundProtocol = TunnelProtocol;
+    type OutboundProtocol = TunnelProtocol;
+    type OutboundOpenInfo = TunnelId;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(TunnelProtocol)
+    }
+
+    fn inject_fully_negotiated_inbound(&mut self, (stream, version): (NegotiatedSubstream, Version)) {
+        self.used = true;
+        self.events.push_back(HandlerEvent::Inbound(TunnelStream::new(stream, version)));
+    }
+
+    fn inject_fully_negotiated_outbound(&mut self, (stream, version): (NegotiatedSubstream, Version), id: TunnelId) {
+        self.used = true;
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.events.push_back(HandlerEvent::Outbound(id, TunnelStream::new(stream, version)));
+    }
+
+    fn inject_event(&mut self, event: HandlerIn) {
+        match event {
+            HandlerIn::Open(id) => {
+                self.outstanding += 1;
+                self.requested.push_back(id);
+            }
+            HandlerIn::KeepAlive => self.used = true,
+        }
+    }
+
+    fn inject_dial_upgrade_error(&mut self, id: TunnelId, error: ProtocolsHandlerUpgrErr<Void>) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        let unsupported = match &error {
+            ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) => true,
+            _ => false,
+        };
+        self.events.push_back(HandlerEvent::Failed {
+            id,
+            error: format!("{:?}", error),
+            unsupported,
+        });
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.used || self.outstanding > 0 {
+            KeepAlive::Yes
+        } else {
+            KeepAlive::No
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context,
+    ) -> Poll<ProtocolsHandlerEvent<TunnelProtocol, TunnelId, HandlerEvent, Void>> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
+        }
+        if let Some(id) = self.requested.pop_front() {
+            return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(TunnelProtocol),
+                info: id,
+            });
+        }
+        Poll::Pending
+    }
+}
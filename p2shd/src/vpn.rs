@@ -0,0 +1,279 @@
+//! Layer 3 point-to-point links between peers, via TUN interfaces.
+//!
+//! Both sides create a TUN interface (`p2shd0`, `p2shd1`, ...), give it the
+//! configured address and pass IP packets over a `Request::Vpn` tunnel, each
+//! prefixed by its length as 16 bit big endian. Linux only, creating the
+//! interface needs `CAP_NET_ADMIN`.
+//!
+//! Packets from the peer only make it onto the interface if their source is
+//! another address of the link's network (e.g. `10.99.0.2` for `10.99.0.1/30`),
+//! so a peer can't inject packets on behalf of anybody else.
+//!
+//! Running `ip` and accessing the interface block, so that happens on threads of its own.
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future, io,
+    prelude::*,
+};
+use ipnet::IpNet;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    process::Command,
+    sync::mpsc as std_mpsc,
+    thread,
+};
+
+mod error;
+
+/// Packets read from the interface but not yet sent, more get dropped.
+const QUEUE_SIZE: usize = 64;
+
+/// Larger than any MTU we'd see on a TUN interface.
+const MAX_PACKET: usize = 65535;
+
+/// A configured TUN interface, deleted on drop.
+pub struct Tun {
+    file: File,
+    name: String,
+    addr: IpNet,
+}
+
+impl Tun {
+    /// Create a TUN interface, bring it up and assign `addr` to it.
+    pub async fn create(addr: IpNet) -> io::Result<Tun> {
+        blocking(move || Tun::create_blocking(&addr)).await
+    }
+
+    fn create_blocking(addr: &IpNet) -> io::Result<Tun> {
+        let (file, name) = open_tun()?;
+        let tun = Tun { file, name, addr: *addr };
+        ip(&["addr", "add", &addr.to_string(), "dev", &tun.name])?;
+        ip(&["link", "set", "dev", &tun.name, "up"])?;
+        log::info!("VPN interface {} up with address {}", tun.name, addr);
+        Ok(tun)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Tun {
+    fn drop(&mut self) {
+        // Also makes the reading thread's `read` fail, so it terminates:
+        let name = self.name.clone();
+        thread::spawn(move || {
+            if let Err(e) = ip(&["link", "delete", "dev", &name]) {
+                log::warn!("Deleting VPN interface {} failed: {}", name, e);
+            }
+        });
+    }
+}
+
+/// Pass packets between `tun` and `stream` until either side fails or closes.
+pub async fn run<S>(stream: S, tun: Tun) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut sr, mut sw) = stream.split();
+    let own_addr = tun.addr;
+    let mut reader = tun.file.try_clone()?;
+    let mut writer = tun.file.try_clone()?;
+
+    let (mut tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_SIZE);
+    thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(n) => n,
+                Err(_) => return,
+            };
+            match tx.try_send(buf[..n].to_vec()) {
+                Ok(()) => (),
+                // Congested, like any router we drop:
+                Err(e) if e.is_full() => (),
+                Err(_) => return,
+            }
+        }
+    });
+
+    let outgoing = async {
+        while let Some(packet) = rx.next().await {
+            sw.write_all(&(packet.len() as u16).to_be_bytes()).await?;
+            sw.write_all(&packet).await?;
+            sw.flush().await?;
+        }
+        sw.close().await
+    };
+    let (packets, written) = std_mpsc::sync_channel::<Vec<u8>>(QUEUE_SIZE);
+    thread::spawn(move || {
+        for packet in written {
+            if writer.write_all(&packet).is_err() {
+                return;
+            }
+        }
+    });
+    let incoming = async move {
+        let mut len = [0u8; 2];
+        let mut packet = vec![0u8; MAX_PACKET];
+        loop {
+            match sr.read_exact(&mut len).await {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                r => r?,
+            }
+            let packet = &mut packet[..u16::from_be_bytes(len) as usize];
+            sr.read_exact(packet).await?;
+            if !from_link_peer(&own_addr, packet) {
+                log::debug!("Dropping VPN packet with foreign source {:?}", source(packet));
+                continue;
+            }
+            match packets.try_send(packet.to_vec()) {
+                Ok(()) => (),
+                // The interface can't keep up, drop as well:
+                Err(std_mpsc::TrySendError::Full(_)) => (),
+                Err(std_mpsc::TrySendError::Disconnected(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Writing to the interface failed."))
+                }
+            }
+        }
+    };
+    futures::pin_mut!(outgoing, incoming);
+    let result = future::select(outgoing, incoming).await.factor_first().0;
+    log::info!("VPN link on {} closed.", tun.name());
+    result
+}
+
+/// Whether `packet` comes from an address of `own_addr`'s network, other than our own.
+fn from_link_peer(own_addr: &IpNet, packet: &[u8]) -> bool {
+    match source(packet) {
+        Some(src) => src != own_addr.addr() && own_addr.contains(&src),
+        None => false,
+    }
+}
+
+/// Source address of an IPv4 or IPv6 packet, `None` if it is neither.
+fn source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let mut src = [0u8; 4];
+            src.copy_from_slice(&packet[12..16]);
+            Some(Ipv4Addr::from(src).into())
+        }
+        6 if packet.len() >= 40 => {
+            let mut src = [0u8; 16];
+            src.copy_from_slice(&packet[8..24]);
+            Some(Ipv6Addr::from(src).into())
+        }
+        _ => None,
+    }
+}
+
+/// Run `f` on a thread of its own.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    rx.await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "VPN thread died.")))
+}
+
+/// Run `ip` with `args`.
+fn ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(to_io_error(error::Vpn::Ip(args.join(" "), status.to_string())))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_tun() -> io::Result<(File, String)> {
+    use std::{fs::OpenOptions, os::unix::io::AsRawFd};
+
+    // The request argument of `ioctl` is `c_ulong` on glibc, but `c_int` on musl:
+    const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+        _pad: [0; 22],
+    };
+    let template = b"p2shd%d";
+    req.name[..template.len()].copy_from_slice(template);
+    // Safe: `req` is a valid `struct ifreq` for TUNSETIFF and outlives the call.
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = req.name.iter().position(|&b| b == 0).unwrap_or(libc::IFNAMSIZ);
+    Ok((file, String::from_utf8_lossy(&req.name[..len]).into_owned()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_tun() -> io::Result<(File, String)> {
+    Err(to_io_error(error::Vpn::Unsupported))
+}
+
+fn to_io_error(e: error::Vpn) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_from(src: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&src);
+        packet
+    }
+
+    #[test]
+    fn accepts_peer_of_link() {
+        let own: IpNet = "10.99.0.1/30".parse().unwrap();
+        assert!(from_link_peer(&own, &ipv4_from([10, 99, 0, 2])));
+    }
+
+    #[test]
+    fn drops_foreign_and_spoofed_sources() {
+        let own: IpNet = "10.99.0.1/30".parse().unwrap();
+        assert!(!from_link_peer(&own, &ipv4_from([10, 99, 0, 1])));
+        assert!(!from_link_peer(&own, &ipv4_from([10, 99, 0, 5])));
+        assert!(!from_link_peer(&own, &ipv4_from([192, 168, 1, 1])));
+    }
+
+    #[test]
+    fn checks_ipv6_sources() {
+        let own: IpNet = "fd00::1/126".parse().unwrap();
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[8..24].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        assert!(from_link_peer(&own, &packet));
+        packet[8..24].copy_from_slice(&"fd01::2".parse::<Ipv6Addr>().unwrap().octets());
+        assert!(!from_link_peer(&own, &packet));
+    }
+
+    #[test]
+    fn drops_garbage() {
+        let own: IpNet = "10.99.0.1/30".parse().unwrap();
+        assert!(!from_link_peer(&own, &[]));
+        assert!(!from_link_peer(&own, &[0x45, 0, 0]));
+    }
+}
@@ -0,0 +1,13 @@
+//! Errors that can happen while setting up VPN links.
+
+use thiserror::Error;
+
+/// Errors related to TUN interfaces.
+#[derive(Error, Debug)]
+pub enum Vpn {
+    #[error("Running 'ip {0}' failed: {1}")]
+    Ip(String, String),
+    #[cfg(not(target_os = "linux"))]
+    #[error("VPN mode is only supported on Linux.")]
+    Unsupported,
+}
@@ -0,0 +1,107 @@
+//! Detecting hangs of the daemon's subsystems.
+//!
+//! Subsystems (the swarm, which also drives sessions) report progress via a
+//! `Heartbeat`. A plain thread, so it keeps running even if the async
+//! executor is stuck, checks them regularly: If one did not beat for the
+//! configured timeout, the watchdog logs what it knows (which subsystem, for
+//! how long, the recorded events if `--record-events` is on) and, if asked
+//! to, exits so a supervisor like systemd restarts the daemon. A subsystem
+//! hung in a loop can't be restarted from within the process.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::events;
+
+/// How often the watchdog checks heartbeats.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often subsystems should beat, the timeout has to be well beyond this.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest supported timeout.
+pub const MIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exit code on a detected hang (`EX_SOFTWARE`).
+const HANG_EXIT_CODE: i32 = 70;
+
+/// Progress reports of one subsystem.
+#[derive(Clone)]
+pub struct Heartbeat {
+    name: &'static str,
+    started: Instant,
+    /// Milliseconds since `started`, at the last beat.
+    last: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    pub fn new(name: &'static str) -> Heartbeat {
+        Heartbeat {
+            name,
+            started: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Report progress.
+    pub fn beat(&self) {
+        self.last.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the last beat.
+    fn silent_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.started.elapsed().checked_sub(last).unwrap_or_default()
+    }
+}
+
+/// Watch `heartbeats` on a thread of its own, reporting those silent for `timeout`.
+///
+/// With `exit`, the process exits on the first hang.
+pub fn spawn(heartbeats: Vec<Heartbeat>, timeout: Duration, exit: bool) {
+    let timeout = timeout.max(MIN_TIMEOUT);
+    thread::spawn(move || {
+        // Only report each hang once, until the subsystem recovers:
+        let mut hung = vec![false; heartbeats.len()];
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            for (h, hung) in heartbeats.iter().zip(hung.iter_mut()) {
+                let silent = h.silent_for();
+                if silent < timeout {
+                    if *hung {
+                        log::warn!("Watchdog: {} recovered.", h.name);
+                        *hung = false;
+                    }
+                    continue;
+                }
+                if *hung {
+                    continue;
+                }
+                *hung = true;
+                report(h.name, silent, &heartbeats);
+                if exit {
+                    log::error!("Watchdog: Exiting, to get restarted.");
+                    std::process::exit(HANG_EXIT_CODE);
+                }
+            }
+        }
+    });
+}
+
+fn report(name: &str, silent: Duration, heartbeats: &[Heartbeat]) {
+    log::error!("Watchdog: {} made no progress for {:?}, it seems to hang.", name, silent);
+    for h in heartbeats {
+        log::error!("Watchdog: Last heartbeat of {}: {:?} ago.", h.name, h.silent_for());
+    }
+    events::record(format!("watchdog: {} hangs for {:?}", name, silent));
+    match events::dump() {
+        Ok(()) => log::error!("Watchdog: Recorded events (if enabled) are in `p2shd debug dump-events`."),
+        Err(e) => log::error!("Watchdog: {:#}", e),
+    }
+}